@@ -0,0 +1,30 @@
+//! Diff throughput over a large buffer with scattered edits, run with
+//! `cargo bench --bench diff_throughput`.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use pipeline::storage::DiffEngine;
+
+const SIZE: usize = 64 * 1024 * 1024;
+
+fn diff_throughput(c: &mut Criterion) {
+    let old: Vec<u8> = (0..SIZE).map(|i| (i * 31 % 253) as u8).collect();
+    let mut new = old.clone();
+    for i in (0..SIZE).step_by(SIZE / 64) {
+        new[i] ^= 0xFF;
+    }
+
+    let mut group = c.benchmark_group("diff");
+    group.throughput(Throughput::Bytes(SIZE as u64));
+    group.sample_size(10);
+    group.bench_function("compute_binary_diff", |b| b.iter(|| DiffEngine::compute_binary_diff(&old, &new)));
+    group.bench_function("compute_chunked_diff", |b| {
+        b.iter(|| DiffEngine::compute_chunked_diff(&old, &new, 1024 * 1024))
+    });
+    group.bench_function("compute_streaming_diff", |b| {
+        b.iter(|| DiffEngine::compute_streaming_diff(&old[..], &new[..], 1024 * 1024).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, diff_throughput);
+criterion_main!(benches);
@@ -0,0 +1,17 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pipeline::storage::DiffEngine;
+
+fuzz_target!(|input: (Vec<u8>, Vec<u8>, Vec<(usize, usize, Vec<u8>)>)| {
+    let (old, new, operations) = input;
+
+    let diff = DiffEngine::compute_binary_diff(&old, &new);
+    assert_eq!(DiffEngine::apply_diff(&old, &diff).unwrap(), new);
+
+    let patch = DiffEngine::create_patch(&old, &new);
+    assert_eq!(DiffEngine::apply_patch(&old, &patch).unwrap(), new);
+
+    // Arbitrary operations may be rejected, but must never panic
+    let _ = DiffEngine::apply_diff(&old, &operations);
+});
@@ -1,12 +1,22 @@
 use std::collections::HashMap;
-use crate::storage::{ContentStore, ObjectType};
+use crate::storage::{ContentObject, ContentStore, ObjectType};
 use crate::graph::{DependencyGraph, VersionManager};
-use anyhow::Result;
-use clap::{Parser, Subcommand};
-use std::path::PathBuf;
-
-use tracing::{error, info};
-
+use crate::format::{
+    AtlasManifest, AtlasSettings, AudioProcessor, ExternalFile, FormatProcessor, FormatRegistry, ImportConfig, ImportSettings, Model3DProcessor,
+    SpectrogramOptions, TextureProcessor, ThumbnailOptions, TonemapOptions, WaveformOptions, SPECTROGRAM_PREVIEW,
+    TONEMAPPED_PREVIEW, WAVEFORM_PREVIEW, cluster_similar, is_hdr,
+};
+use crate::{Provenance, ResourceMetadata, ResourceProperties, ResourceType, METADATA_SCHEMA_VERSION};
+use anyhow::{bail, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use tracing::{error, info, warn};
+
+/// Binary objects from this size on are diffed segment by segment in parallel.
+const STREAMING_DIFF_THRESHOLD: usize = 16 * 1024 * 1024;
+const STREAMING_DIFF_SEGMENT: usize = 1024 * 1024;
 
 #[derive(Parser)]
 #[command(name = "pipeline")]
@@ -35,8 +45,61 @@ pub enum Commands {
         #[arg(short, long)]
         path: PathBuf,
 
+        /// texture, model3d, audio or binary; detected from the file when omitted
+        #[arg(long)]
+        resource_type: Option<ResourceType>,
+
+        /// Also render a spectrogram preview for audio resources
         #[arg(long)]
-        resource_type: Option<String>,
+        spectrogram: bool,
+    },
+
+    /// Generate waveform and spectrogram previews for a stored audio resource,
+    /// or a tonemapped preview for an HDR texture
+    Preview {
+        hash: String,
+
+        #[arg(long)]
+        spectrogram: bool,
+
+        /// Exposure of the tonemapped preview in stops; automatic when omitted
+        #[arg(long, allow_hyphen_values = true)]
+        exposure: Option<f32>,
+    },
+
+    /// Print the hash of a resource's thumbnail, rendering it if needed
+    Thumb {
+        hash: String,
+
+        /// Also write the thumbnail PNG here
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Longest side in pixels
+        #[arg(long, default_value_t = 256)]
+        size: u32,
+    },
+
+    /// Store a glTF with the files it refers to embedded as a single GLB
+    Pack {
+        path: PathBuf,
+
+        /// Also write the GLB here
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Split a stored GLB into a .gltf, a .bin and image files, storing each
+    Unpack {
+        hash: String,
+
+        /// Directory to write the parts to
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Base name of the written files
+        #[arg(long, default_value = "model")]
+        name: String,
     },
 
     /// Retrieve a resource by hash
@@ -91,25 +154,99 @@ pub enum Commands {
     Diff {
         hash1: String,
         hash2: String,
+
+        /// Where to write the change heatmap when both resources are textures
+        #[arg(long)]
+        heatmap: Option<PathBuf>,
+
+        /// Print the report as JSON instead of text
+        #[arg(long)]
+        json: bool,
+
+        /// Amplitude difference above which audio waveforms count as diverging
+        #[arg(long, default_value_t = 0.01)]
+        threshold: f32,
+    },
+
+    /// Show what a stored model is made of: meshes, bounds, materials,
+    /// textures, skins, animations and extensions
+    Inspect {
+        hash: String,
+
+        /// Print the report as JSON instead of text
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Group stored textures that look alike, such as re-exported copies
+    Dupes {
+        /// Share of perceptual hash bits two textures must have in common,
+        /// from 0 to 1
+        #[arg(long, default_value_t = 0.9)]
+        threshold: f32,
+
+        /// Print the groups as JSON instead of text
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Pack sprite textures into atlas pages with a UV manifest
+    Atlas {
+        #[arg(required = true)]
+        sprites: Vec<PathBuf>,
+
+        /// Directory for the page images and manifest
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Base name of the output files, also identifying the atlas for repacks
+        #[arg(long, default_value = "atlas")]
+        name: String,
+
+        #[arg(long, default_value_t = 2048)]
+        max_size: u32,
+
+        #[arg(long, default_value_t = 2)]
+        padding: u32,
+
+        /// Allow sprites to be rotated by 90 degrees
+        #[arg(long)]
+        rotate: bool,
+
+        /// Round page sizes up to powers of two
+        #[arg(long)]
+        power_of_two: bool,
+
+        #[arg(long, value_enum, default_value_t = ManifestFormat::Json)]
+        manifest_format: ManifestFormat,
     },
 }
 
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ManifestFormat {
+    Json,
+    Bincode,
+}
+
 pub struct PipelineCli {
     store: ContentStore,
     dependency_graph: DependencyGraph,
     version_manager: VersionManager,
+    registry: FormatRegistry,
 }
 
 impl PipelineCli {
     pub fn new(storage_path: PathBuf) -> Result<Self> {
         let store = ContentStore::new(storage_path)?;
-        let dependency_graph = DependencyGraph::new();
+        let dependency_graph = DependencyGraph::load(&store.get_storage_path().join("graph.json"))?;
         let version_manager = VersionManager::new();
+        let registry = FormatRegistry::with_defaults();
 
         Ok(Self {
             store,
             dependency_graph,
             version_manager,
+            registry,
         })
     }
 
@@ -121,20 +258,150 @@ impl PipelineCli {
                 // Implementation here
             }
 
-            Commands::Store {path, resource_type} => {
+            Commands::Store {path, resource_type, spectrogram} => {
                 info!("Storing resource at {}", path.display());
                 let data = tokio::fs::read(&path).await?;
 
-                // Determine resource type from file extension or parameter
-                let _resource_type_str = resource_type.unwrap_or_else(|| {
-                    "default".to_string()
-                });
+                // Determine resource type from the parameter or the file itself
+                let settings = self.settings_for(&path)?;
+                let external = self.registry.dependencies(Some(&path), &data, resource_type.as_ref())?;
+                let cache_key = self.registry.cache_key(Some(&path), &data, resource_type.clone(), &settings, &external);
+                if let Some(hash) = self.store.cached_import(&cache_key)? {
+                    info!("{} is unchanged since it was stored as {}", path.display(), hash);
+                    let importer = self.registry.importer(Some(&path), &data, resource_type.as_ref());
+                    self.store.record_provenance(&hash, &Provenance::now(importer))?;
+                    self.record_source_path(&path, &hash)?;
+                    let object = self.store.retrieve_object(&hash)?;
+                    let audio = self.resource_type_of(&object) == ResourceType::Audio;
+                    if let ObjectType::Resource(metadata) = &object.object_type {
+                        self.track_dependencies(metadata);
+                    }
+                    if audio && spectrogram && !self.store.get_derived(&hash)?.contains_key(SPECTROGRAM_PREVIEW) {
+                        self.store_audio_previews(&hash, &object.data, spectrogram)?;
+                    }
+                    self.store_thumbnail(&hash);
+                    self.save_graph()?;
+                    return Ok(());
+                }
+
+                let imported =
+                    self.registry.import_with_dependencies(Some(&path), &data, resource_type, &settings, external)?;
+                info!("Detected {:?} resource", imported.metadata.resource_type);
 
-                // Store the object
-                let object_type = ObjectType::Blob;
-                let hash = self.store.store_object(object_type, data)?;
+                // Audio keeps a copy of its data for rendering previews
+                let audio = (imported.metadata.resource_type == ResourceType::Audio).then(|| imported.data.clone());
+
+                // Store the files it refers to first, so its edges can point at them
+                self.store_dependencies(&imported.external)?;
+                self.track_dependencies(&imported.metadata);
+                let object_type = ObjectType::Resource(imported.metadata);
+                let hash = self.store.store_object(object_type, imported.data)?;
+                self.store.record_provenance(&hash, &imported.provenance)?;
+                let references = imported.external.iter().map(|file| (file.uri.clone(), ContentObject::compute_hash(&file.data)));
+                self.store.record_references(&hash, &references.collect())?;
+                self.store.record_import_cache(&cache_key, &hash)?;
+                self.record_source_path(&path, &hash)?;
 
                 info!("Stored {} as {}", path.display(), hash);
+
+                if let Some(data) = audio {
+                    self.store_audio_previews(&hash, &data, spectrogram)?;
+                }
+                // HDR sources get a tonemapped preview, even when cooked
+                if is_hdr(&data) {
+                    self.store_tonemapped_preview(&hash, &data, &TonemapOptions::default())?;
+                }
+                self.store_thumbnail(&hash);
+                self.save_graph()?;
+            }
+
+            Commands::Preview {hash, spectrogram, exposure} => {
+                let object = self.store.retrieve_object(&hash)?;
+                let resource_type = self.resource_type_of(&object);
+                if let ObjectType::Resource(metadata) = &object.object_type {
+                    self.dependency_graph.add_resource(metadata.clone());
+                }
+
+                let previews = match resource_type {
+                    ResourceType::Audio => self.store_audio_previews(&hash, &object.data, spectrogram)?,
+                    ResourceType::Texture if is_hdr(&object.data) => {
+                        self.store_tonemapped_preview(&hash, &object.data, &TonemapOptions { exposure })?
+                    }
+                    _ => bail!("{} is not an audio or HDR texture resource", hash),
+                };
+                for (kind, preview) in previews {
+                    println!("{}: {}", kind, preview);
+                }
+                self.save_graph()?;
+            }
+
+            Commands::Thumb {hash, output, size} => {
+                let Some(thumbnail) = self.registry.thumbnail(&mut self.store, &hash, &ThumbnailOptions { size })? else {
+                    bail!("{} is not a resource that can be pictured", hash);
+                };
+                if thumbnail.created {
+                    self.track_dependencies(&thumbnail.metadata);
+                    self.save_graph()?;
+                }
+                if let Some(output) = output {
+                    tokio::fs::write(&output, &thumbnail.data).await?;
+                    info!("Wrote thumbnail of {} to {}", hash, output.display());
+                }
+                println!("{}", thumbnail.hash);
+            }
+
+            Commands::Pack {path, output} => {
+                let data = tokio::fs::read(&path).await?;
+                let packed = Model3DProcessor::new().pack_glb(&data, path.parent())?;
+                let settings = self.settings_for(&path)?;
+                let mut imported = self.registry.import(None, &packed.glb, Some(ResourceType::Model3D), &settings)?;
+
+                // The GLB depends on what it embeds, which is stored on its own
+                // so models sharing a texture share its object
+                self.store_dependencies(&packed.external)?;
+                for file in &packed.external {
+                    let hash = ContentObject::compute_hash(&file.data);
+                    if !imported.metadata.dependencies.contains(&hash) {
+                        imported.metadata.dependencies.push(hash);
+                    }
+                }
+                self.track_dependencies(&imported.metadata);
+                let hash = self.store.store_object(ObjectType::Resource(imported.metadata), imported.data)?;
+                self.store.record_provenance(&hash, &imported.provenance)?;
+                info!("Packed {} into {}", path.display(), hash);
+
+                if let Some(output) = output {
+                    tokio::fs::write(&output, &packed.glb).await?;
+                    info!("Wrote {} to {}", hash, output.display());
+                }
+                self.store_thumbnail(&hash);
+                self.save_graph()?;
+                println!("{}", hash);
+            }
+
+            Commands::Unpack {hash, output, name} => {
+                let object = self.store.retrieve_object(&hash)?;
+                let unpacked = Model3DProcessor::new().unpack_glb(&object.data, &name)?;
+
+                tokio::fs::create_dir_all(&output).await?;
+                let mut files = unpacked.files;
+                for file in &mut files {
+                    file.path = output.join(&file.path);
+                    tokio::fs::write(&file.path, &file.data).await?;
+                }
+                let gltf_path = output.join(format!("{}.gltf", name));
+                tokio::fs::write(&gltf_path, &unpacked.gltf).await?;
+
+                self.store_dependencies(&files)?;
+                let settings = ImportSettings::default();
+                let imported = self.registry.import(Some(&gltf_path), &unpacked.gltf, Some(ResourceType::Model3D), &settings)?;
+                self.track_dependencies(&imported.metadata);
+                let gltf = self.store.store_object(ObjectType::Resource(imported.metadata), imported.data)?;
+                self.store.record_provenance(&gltf, &imported.provenance)?;
+                self.record_source_path(&gltf_path, &gltf)?;
+                info!("Unpacked {} into {} as {}", hash, gltf_path.display(), gltf);
+                self.save_graph()?;
+                println!("{}", gltf);
             }
 
             Commands::Retrieve {hash, output} => {
@@ -174,7 +441,7 @@ impl PipelineCli {
 
             Commands::Log {version_id} => {
                 if let Some(ref vid) = version_id {
-                    let history = self.version_manager.get_version_history(&vid);
+                    let history = self.version_manager.get_version_history(vid);
                     for version in history {
                         println!("{}: {} - {}",
                                  version_id.as_ref().unwrap_or(&"unknown".to_string()),
@@ -215,30 +482,521 @@ impl PipelineCli {
                     let deps = self.dependency_graph.get_dependencies(&h);
                     println!("Dependencies for {}:", h);
                     for dep in deps {
-                        println!(" - {}", dep.hash);
+                        println!(" - {} ({:?})", dep.hash, dep.metadata.resource_type);
+                    }
+                    let affected = self.dependency_graph.get_affected(&h);
+                    if !affected.is_empty() {
+                        println!("Affected by changes to {}:", h);
+                        for node in affected {
+                            println!(" - {} ({:?})", node.hash, node.metadata.resource_type);
+                        }
                     }
                 } else {
                     println!("Dependency graph has {} nodes", self.dependency_graph.node_indices.len());
                 }
             }
 
-            Commands::Diff {hash1, hash2} => {
+            Commands::Diff {hash1, hash2, heatmap, json, threshold} => {
                 info!("Calculating diff between {} and {}", hash1, hash2);
                 let obj1 = self.store.retrieve_object(&hash1)?;
                 let obj2 = self.store.retrieve_object(&hash2)?;
 
-                let diff = crate::storage::DiffEngine::compute_binary_diff(
-                    &obj1.data,
-                    &obj2.data,
-                );
+                match (self.resource_type_of(&obj1), self.resource_type_of(&obj2)) {
+                    (ResourceType::Texture, ResourceType::Texture) => {
+                        self.diff_textures(&obj1, &obj2, heatmap, json).await?;
+                    }
+                    (ResourceType::Model3D, ResourceType::Model3D) => {
+                        Self::diff_models(&obj1, &obj2, json)?;
+                    }
+                    (ResourceType::Audio, ResourceType::Audio) => {
+                        Self::diff_audio(&obj1, &obj2, threshold, json)?;
+                    }
+                    _ => Self::diff_binary(&obj1, &obj2)?,
+                }
+            }
 
-                println!("Found {} differences", diff.len());
-                for (pos, len, _) in diff {
-                    println!("At position {}: {} bytes changed", pos, len);
+            Commands::Inspect {hash, json} => {
+                let object = self.store.retrieve_object(&hash)?;
+                let resource_type = self.resource_type_of(&object);
+                if resource_type != ResourceType::Model3D {
+                    bail!("{} is a {:?} resource; only models can be inspected", hash, resource_type);
+                }
+                let stats = Model3DProcessor::new().stats(&object.data)?;
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&stats)?);
+                } else {
+                    print!("{}", stats);
                 }
             }
+
+            Commands::Dupes {threshold, json} => {
+                self.find_duplicates(threshold, json)?;
+            }
+
+            Commands::Atlas {sprites, output, name, max_size, padding, rotate, power_of_two, manifest_format} => {
+                let settings = AtlasSettings {
+                    max_size,
+                    padding,
+                    allow_rotation: rotate,
+                    power_of_two,
+                };
+                self.pack_atlas(&name, &sprites, &output, settings, manifest_format).await?;
+                self.save_graph()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn diff_textures(
+        &self,
+        old: &ContentObject,
+        new: &ContentObject,
+        heatmap: Option<PathBuf>,
+        json: bool,
+    ) -> Result<()> {
+        let diff = TextureProcessor::new().visual_diff(&old.data, &new.data)?;
+        if let Some(heatmap) = heatmap {
+            tokio::fs::write(&heatmap, &diff.heatmap).await?;
+            info!("Wrote heatmap to {}", heatmap.display());
+        }
+
+        if json {
+            println!("{}", serde_json::to_string_pretty(&diff)?);
+            return Ok(());
+        }
+
+        let describe = |info: &crate::format::ImageInfo| {
+            format!(
+                "{}x{} {} {} ({} channels)",
+                info.width,
+                info.height,
+                info.format.as_deref().unwrap_or("unknown"),
+                info.color_type,
+                info.channels,
+            )
+        };
+
+        println!("Old: {}", describe(&diff.old));
+        println!("New: {}", describe(&diff.new));
+        if diff.dimensions_changed() {
+            println!("Dimensions changed (new image resampled for comparison)");
+        }
+        if diff.format_changed() {
+            println!("Format changed");
+        }
+        if diff.channels_changed() {
+            println!("Channel layout changed");
+        }
+
+        println!(
+            "Changed pixels: {} / {} ({:.2}%)",
+            diff.changed_pixels,
+            diff.total_pixels,
+            diff.changed_pixels as f64 * 100.0 / diff.total_pixels.max(1) as f64,
+        );
+        match diff.float {
+            true => println!("Max abs error: {:.4} (linear HDR values)", diff.max_abs_error),
+            false => println!("Max abs error: {}", diff.max_abs_error),
+        }
+        println!("Mean abs error: {:.4}", diff.mean_abs_error);
+        println!("PSNR: {:.2} dB", diff.psnr);
+        println!("SSIM: {:.4}", diff.ssim);
+        Ok(())
+    }
+
+    fn diff_models(old: &ContentObject, new: &ContentObject, json: bool) -> Result<()> {
+        let diff = Model3DProcessor::new().structural_diff(&old.data, &new.data)?;
+
+        if json {
+            println!("{}", serde_json::to_string_pretty(&diff)?);
+        } else {
+            print!("{}", diff);
+        }
+        Ok(())
+    }
+
+    fn diff_audio(old: &ContentObject, new: &ContentObject, threshold: f32, json: bool) -> Result<()> {
+        let diff = AudioProcessor::new().audio_diff(&old.data, &new.data, threshold)?;
+
+        if json {
+            println!("{}", serde_json::to_string_pretty(&diff)?);
+            return Ok(());
+        }
+
+        let describe = |info: &crate::format::AudioInfo| {
+            format!(
+                "{} {} Hz, {} channels, {} bit, {:.3}s",
+                info.codec,
+                info.sample_rate,
+                info.channels,
+                info.bits_per_sample.map_or("?".to_string(), |b| b.to_string()),
+                info.duration_secs,
+            )
+        };
+
+        println!("Old: {}", describe(&diff.old));
+        println!("New: {}", describe(&diff.new));
+        if diff.format_changed() {
+            println!("Format changed");
+        }
+        println!("Duration delta: {:+.3}s", diff.duration_delta_secs());
+        println!("RMS: {:.2} dB -> {:.2} dB ({:+.2} dB)", diff.old.rms_db, diff.new.rms_db, diff.rms_delta_db());
+        println!("Peak: {:.2} dB -> {:.2} dB ({:+.2} dB)", diff.old.peak_db, diff.new.peak_db, diff.peak_delta_db());
+
+        println!("Found {} diverging ranges above {}", diff.divergent_ranges.len(), diff.threshold);
+        for range in &diff.divergent_ranges {
+            println!(
+                "  {:.3}s - {:.3}s (max difference {:.4})",
+                range.start_secs, range.end_secs, range.max_difference,
+            );
+        }
+        Ok(())
+    }
+
+    /// Clusters the stored textures by perceptual hash. Previews, atlas pages
+    /// and other derived objects are left out, and textures stored before
+    /// hashes were recorded are hashed now.
+    fn find_duplicates(&self, threshold: f32, json: bool) -> Result<()> {
+        if !(0.0..=1.0).contains(&threshold) {
+            bail!("Threshold must be between 0 and 1, not {}", threshold);
+        }
+
+        let hashes = self.store.list_objects()?;
+        let mut derived = HashSet::new();
+        for hash in &hashes {
+            derived.extend(self.store.get_derived(hash)?.into_values());
+        }
+
+        let mut textures = Vec::new();
+        for hash in &hashes {
+            if derived.contains(hash) {
+                continue;
+            }
+            let object = self.store.retrieve_object(hash)?;
+            match self.resource_type_of(&object) {
+                ResourceType::Texture => {}
+                // Atlas pages are made from sprites that are compared on their own
+                ResourceType::Binary => {
+                    if let Ok(manifest) = AtlasManifest::from_json(&object.data) {
+                        derived.extend(manifest.pages.into_iter().map(|page| page.hash));
+                    }
+                    continue;
+                }
+                _ => continue,
+            }
+            let properties = match &object.object_type {
+                ObjectType::Resource(ResourceMetadata { properties: ResourceProperties::Texture(texture), .. }) => {
+                    Some(texture.clone())
+                }
+                _ => None,
+            };
+            let fingerprint = match properties.as_ref().and_then(|texture| texture.perceptual_hashes) {
+                Some(fingerprint) => fingerprint,
+                None => match TextureProcessor::new().perceptual_hashes(&object.data) {
+                    Ok(fingerprint) => fingerprint,
+                    Err(e) => {
+                        warn!("Skipping {}: {:#}", hash, e);
+                        continue;
+                    }
+                },
+            };
+            let size = properties.map(|texture| (texture.width, texture.height));
+            textures.push((hash.clone(), size, fingerprint));
+        }
+        textures.retain(|(hash, _, _)| !derived.contains(hash));
+
+        let fingerprints: Vec<_> = textures.iter().map(|(_, _, fingerprint)| *fingerprint).collect();
+        let clusters = cluster_similar(&fingerprints, threshold);
+        // Each member with its similarity to the first
+        let groups: Vec<Vec<_>> = clusters
+            .iter()
+            .map(|cluster| {
+                cluster
+                    .iter()
+                    .map(|&i| {
+                        let (hash, size, fingerprint) = &textures[i];
+                        (hash, *size, fingerprints[cluster[0]].similarity(fingerprint))
+                    })
+                    .collect()
+            })
+            .collect();
+
+        if json {
+            let groups: Vec<Vec<serde_json::Value>> = groups
+                .iter()
+                .map(|group| {
+                    group
+                        .iter()
+                        .map(|(hash, size, similarity)| {
+                            serde_json::json!({
+                                "hash": hash,
+                                "width": size.map(|(width, _)| width),
+                                "height": size.map(|(_, height)| height),
+                                "similarity": similarity,
+                            })
+                        })
+                        .collect()
+                })
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&groups)?);
+            return Ok(());
+        }
+
+        println!("Found {} groups of similar textures among {} textures", groups.len(), textures.len());
+        for (index, group) in groups.iter().enumerate() {
+            println!("Group {}:", index + 1);
+            for (hash, size, similarity) in group {
+                let size = size.map_or(String::new(), |(width, height)| format!(" {}x{}", width, height));
+                println!("  {}{} ({:.1}%)", hash, size, similarity * 100.0);
+            }
+        }
+        Ok(())
+    }
+
+    /// Packs the sprites into an atlas stored as page textures and a manifest.
+    /// The manifest depends on every page and sprite in the dependency graph;
+    /// an atlas whose recorded sprites and settings still match is reused
+    /// rather than repacked.
+    async fn pack_atlas(
+        &mut self,
+        name: &str,
+        paths: &[PathBuf],
+        output: &Path,
+        settings: AtlasSettings,
+        format: ManifestFormat,
+    ) -> Result<()> {
+        let mut sprites: Vec<(String, Vec<u8>)> = Vec::new();
+        for path in paths {
+            let sprite_name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+            if sprites.iter().any(|(existing, _)| existing == &sprite_name) {
+                bail!("Duplicate sprite name {}", sprite_name);
+            }
+
+            // Sprites are stored as plain textures whatever their import settings
+            let data = tokio::fs::read(path).await?;
+            let imported = self.registry.import(Some(path), &data, Some(ResourceType::Texture), &ImportSettings::default())?;
+            self.dependency_graph.add_resource(imported.metadata.clone());
+            let hash = self.store.store_object(ObjectType::Resource(imported.metadata), imported.data.clone())?;
+            self.store.record_provenance(&hash, &imported.provenance)?;
+            sprites.push((sprite_name, imported.data));
         }
 
+        let named: HashSet<(String, String)> =
+            sprites.iter().map(|(name, data)| (name.clone(), blake3::hash(data).to_string())).collect();
+        let hashes: HashSet<String> = named.iter().map(|(_, hash)| hash.clone()).collect();
+        let record_path = self.store.get_storage_path().join("atlas").join(format!("{}.json", name));
+
+        let previous = match std::fs::read_to_string(&record_path) {
+            Ok(record) => Some(serde_json::from_str::<String>(&record)?),
+            Err(_) => None,
+        };
+        let reusable = previous.and_then(|hash| {
+            let dependencies: HashSet<String> =
+                self.dependency_graph.get_dependencies(&hash).into_iter().map(|node| node.hash).collect();
+            let manifest = AtlasManifest::from_json(&self.store.retrieve_object(&hash).ok()?.data).ok()?;
+            let sprites: HashSet<(String, String)> =
+                manifest.sprites.iter().map(|sprite| (sprite.name.clone(), sprite.hash.clone())).collect();
+            (manifest.settings == settings && sprites == named && hashes.is_subset(&dependencies)).then_some(manifest)
+        });
+
+        let (manifest, pages) = match reusable {
+            Some(manifest) => {
+                info!("Atlas {} is up to date", name);
+                let pages = manifest
+                    .pages
+                    .iter()
+                    .map(|page| Ok(self.store.retrieve_object(&page.hash)?.data))
+                    .collect::<Result<Vec<_>>>()?;
+                (manifest, pages)
+            }
+            None => {
+                let inputs: Vec<(String, &[u8])> = sprites.iter().map(|(name, data)| (name.clone(), &data[..])).collect();
+                let atlas = TextureProcessor::new().pack_atlas(&inputs, &settings)?;
+                self.store_atlas(name, &atlas.manifest, &atlas.page_pngs, &record_path)?;
+                info!("Packed {} sprites into {} pages", atlas.manifest.sprites.len(), atlas.page_pngs.len());
+                (atlas.manifest, atlas.page_pngs)
+            }
+        };
+
+        tokio::fs::create_dir_all(output).await?;
+        for (i, png) in pages.iter().enumerate() {
+            tokio::fs::write(output.join(format!("{}_{}.png", name, i)), png).await?;
+        }
+        let (extension, encoded) = match format {
+            ManifestFormat::Json => ("json", manifest.to_json()?),
+            ManifestFormat::Bincode => ("bin", manifest.to_bincode()?),
+        };
+        tokio::fs::write(output.join(format!("{}.{}", name, extension)), encoded).await?;
+        info!("Wrote atlas {} to {}", name, output.display());
+        Ok(())
+    }
+
+    fn store_atlas(&mut self, name: &str, manifest: &AtlasManifest, pages: &[Vec<u8>], record_path: &Path) -> Result<()> {
+        let sprite_hashes: Vec<String> = manifest.sprite_hashes().into_iter().map(String::from).collect();
+
+        for (index, (page, png)) in manifest.pages.iter().zip(pages).enumerate() {
+            let mut metadata = TextureProcessor::new().get_metadata(png)?;
+            metadata.dependencies = manifest
+                .sprites
+                .iter()
+                .filter(|sprite| sprite.page == index as u32)
+                .map(|sprite| sprite.hash.clone())
+                .collect();
+            self.track_dependencies(&metadata);
+            self.store.store_object(ObjectType::Resource(metadata), png.clone())?;
+            info!("Stored atlas page {} as {}", index, page.hash);
+        }
+
+        // The manifest is what identifies the atlas
+        let data = manifest.to_json()?;
+        let metadata = ResourceMetadata {
+            schema_version: METADATA_SCHEMA_VERSION,
+            hash: blake3::hash(&data).to_string(),
+            resource_type: ResourceType::Binary,
+            size: data.len() as u64,
+            dependencies: manifest.pages.iter().map(|page| page.hash.clone()).chain(sprite_hashes).collect(),
+            properties: ResourceProperties::None,
+        };
+        self.track_dependencies(&metadata);
+        let hash = self.store.store_object(ObjectType::Resource(metadata), data)?;
+
+        if let Some(parent) = record_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(record_path, serde_json::to_string(&hash)?)?;
+        info!("Stored atlas {} manifest as {}", name, hash);
+        Ok(())
+    }
+
+    fn save_graph(&self) -> Result<()> {
+        self.dependency_graph.save(&self.store.get_storage_path().join("graph.json"))
+    }
+
+    /// Stores the files a resource refers to unchanged, each as its own
+    /// resource, so the resource's edges can point at them.
+    fn store_dependencies(&mut self, files: &[ExternalFile]) -> Result<()> {
+        for file in files {
+            let imported = self.registry.import_dependency(file)?;
+            self.track_dependencies(&imported.metadata);
+            let hash = self.store.store_object(ObjectType::Resource(imported.metadata), imported.data)?;
+            self.store.record_provenance(&hash, &imported.provenance)?;
+            self.record_source_path(&file.path, &hash)?;
+            info!("Stored {} referenced as {} as {}", file.path.display(), file.uri, hash);
+            self.store_thumbnail(&hash);
+        }
+        Ok(())
+    }
+
+    /// Records where `hash` was stored from and, when the file held other
+    /// content before, warns about the resources built on the old version.
+    fn record_source_path(&mut self, path: &Path, hash: &str) -> Result<()> {
+        let Some(previous) = self.store.record_source_path(path, hash)? else {
+            return Ok(());
+        };
+        let affected = self.dependency_graph.get_affected(&previous);
+        if !affected.is_empty() {
+            warn!(
+                "{} changed from {}; {} resources depend on the old version",
+                path.display(),
+                previous,
+                affected.len()
+            );
+            for node in affected {
+                warn!(" - {} ({:?})", node.hash, node.metadata.resource_type);
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the resource with edges to each of its dependencies already in
+    /// the graph, replacing the edges an earlier store of it left.
+    fn track_dependencies(&mut self, metadata: &ResourceMetadata) {
+        self.dependency_graph.add_resource(metadata.clone());
+        self.dependency_graph.replace_dependencies(&metadata.hash, &metadata.dependencies);
+    }
+
+    /// Renders previews of an audio object and stores them as textures that
+    /// depend on, and are linked from, the source object.
+    fn store_audio_previews(&mut self, source: &str, data: &[u8], spectrogram: bool) -> Result<Vec<(&'static str, String)>> {
+        let audio = AudioProcessor::new();
+        let mut previews = vec![(WAVEFORM_PREVIEW, audio.render_waveform(data, &WaveformOptions::default())?)];
+        if spectrogram {
+            previews.push((SPECTROGRAM_PREVIEW, audio.render_spectrogram(data, &SpectrogramOptions::default())?));
+        }
+        self.store_previews(source, previews)
+    }
+
+    /// Tonemaps an HDR image and stores the PNG like the audio previews.
+    fn store_tonemapped_preview(
+        &mut self,
+        source: &str,
+        data: &[u8],
+        options: &TonemapOptions,
+    ) -> Result<Vec<(&'static str, String)>> {
+        let png = TextureProcessor::new().render_tonemapped(data, options)?;
+        self.store_previews(source, vec![(TONEMAPPED_PREVIEW, png)])
+    }
+
+    fn store_previews(&mut self, source: &str, previews: Vec<(&'static str, Vec<u8>)>) -> Result<Vec<(&'static str, String)>> {
+        let mut stored = Vec::new();
+        for (kind, png) in previews {
+            let mut metadata = TextureProcessor::new().get_metadata(&png)?;
+            metadata.dependencies.push(source.to_string());
+            self.track_dependencies(&metadata);
+
+            let hash = self.store.store_object(ObjectType::Resource(metadata), png)?;
+            self.store.link_derived(source, kind, &hash)?;
+            info!("Stored {} preview of {} as {}", kind, source, hash);
+            stored.push((kind, hash));
+        }
+        Ok(stored)
+    }
+
+    /// Renders the default-size thumbnail of a stored object. An object
+    /// that cannot be pictured is still stored.
+    fn store_thumbnail(&mut self, hash: &str) {
+        match self.registry.thumbnail(&mut self.store, hash, &ThumbnailOptions::default()) {
+            Ok(Some(thumbnail)) if thumbnail.created => {
+                self.track_dependencies(&thumbnail.metadata);
+                info!("Stored thumbnail of {} as {}", hash, thumbnail.hash);
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Could not render a thumbnail of {}: {:#}", hash, e),
+        }
+    }
+
+    /// Import settings for a source file from the store's import config and
+    /// any sidecar next to it.
+    fn settings_for(&self, path: &Path) -> Result<ImportSettings> {
+        let config = ImportConfig::load(self.store.get_storage_path())?;
+        ImportSettings::for_asset(path, &config)
+    }
+
+    /// Resource type recorded with the object, falling back to sniffing the
+    /// data for objects that were stored as plain blobs.
+    fn resource_type_of(&self, object: &ContentObject) -> ResourceType {
+        match &object.object_type {
+            ObjectType::Resource(metadata) => metadata.resource_type.clone(),
+            _ => self
+                .registry
+                .by_magic(&object.data)
+                .map_or(ResourceType::Binary, |processor| processor.resource_type()),
+        }
+    }
+
+    fn diff_binary(old: &ContentObject, new: &ContentObject) -> Result<()> {
+        let diff = match old.data.len().max(new.data.len()) >= STREAMING_DIFF_THRESHOLD {
+            true => crate::storage::DiffEngine::compute_streaming_diff(&old.data[..], &new.data[..], STREAMING_DIFF_SEGMENT)?
+                .into_patch()
+                .operations,
+            false => crate::storage::DiffEngine::compute_binary_diff(&old.data, &new.data),
+        };
+
+        println!("Found {} differences", diff.len());
+        for (pos, len, _) in diff {
+            println!("At position {}: {} bytes changed", pos, len);
+        }
         Ok(())
     }
 }
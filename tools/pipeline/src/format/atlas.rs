@@ -0,0 +1,389 @@
+use super::TextureProcessor;
+use anyhow::{bail, Context, Result};
+use bincode::{Decode, Encode};
+use image::RgbaImage;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Encode, Decode)]
+pub struct AtlasSettings {
+    /// Largest width and height of a page.
+    pub max_size: u32,
+    /// Pixels around each sprite, filled by extending its edge pixels so
+    /// filtering does not bleed neighbours into it.
+    pub padding: u32,
+    /// Allow sprites to be rotated a quarter turn clockwise for a tighter fit.
+    pub allow_rotation: bool,
+    /// Round page sizes up to powers of two, packing into the largest one
+    /// not above `max_size`.
+    pub power_of_two: bool,
+}
+
+impl Default for AtlasSettings {
+    fn default() -> Self {
+        Self {
+            max_size: 2048,
+            padding: 2,
+            allow_rotation: false,
+            power_of_two: false,
+        }
+    }
+}
+
+/// Where one sprite ended up.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Encode, Decode)]
+pub struct SpriteRect {
+    pub name: String,
+    /// Content hash of the source sprite.
+    pub hash: String,
+    pub page: u32,
+    /// Pixel rectangle in the page, after rotation.
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub rotated: bool,
+    /// `[u0, v0, u1, v1]`, with v running downwards from the top edge.
+    pub uv: [f32; 4],
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Encode, Decode)]
+pub struct AtlasPage {
+    pub width: u32,
+    pub height: u32,
+    /// Content hash of the page's PNG.
+    pub hash: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Encode, Decode)]
+pub struct AtlasManifest {
+    pub settings: AtlasSettings,
+    pub pages: Vec<AtlasPage>,
+    pub sprites: Vec<SpriteRect>,
+}
+
+impl AtlasManifest {
+    pub fn to_json(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec_pretty(self)?)
+    }
+
+    pub fn to_bincode(&self) -> Result<Vec<u8>> {
+        Ok(bincode::encode_to_vec(self, bincode::config::standard())?)
+    }
+
+    pub fn from_json(data: &[u8]) -> Result<Self> {
+        serde_json::from_slice(data).with_context(|| "Invalid atlas manifest")
+    }
+
+    pub fn from_bincode(data: &[u8]) -> Result<Self> {
+        let (manifest, _) = bincode::decode_from_slice(data, bincode::config::standard())
+            .with_context(|| "Invalid atlas manifest")?;
+        Ok(manifest)
+    }
+
+    /// Hashes of every source sprite, in manifest order.
+    pub fn sprite_hashes(&self) -> Vec<&str> {
+        self.sprites.iter().map(|sprite| sprite.hash.as_str()).collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Atlas {
+    pub manifest: AtlasManifest,
+    pub pages: Vec<RgbaImage>,
+    /// The pages encoded as PNG, as hashed in the manifest.
+    pub page_pngs: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Rect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Rect {
+    fn right(&self) -> u32 {
+        self.x + self.width
+    }
+
+    fn bottom(&self) -> u32 {
+        self.y + self.height
+    }
+
+    fn intersects(&self, other: &Rect) -> bool {
+        self.x < other.right() && other.x < self.right() && self.y < other.bottom() && other.y < self.bottom()
+    }
+
+    fn contains(&self, other: &Rect) -> bool {
+        self.x <= other.x && self.y <= other.y && self.right() >= other.right() && self.bottom() >= other.bottom()
+    }
+}
+
+/// MaxRects bin packing with the best-short-side-fit heuristic.
+struct MaxRects {
+    free: Vec<Rect>,
+}
+
+impl MaxRects {
+    fn new(size: u32) -> Self {
+        Self { free: vec![Rect { x: 0, y: 0, width: size, height: size }] }
+    }
+
+    /// The best spot for a `width` x `height` box, scored by the leftover
+    /// on its tighter side, then its looser side.
+    fn find(&self, width: u32, height: u32) -> Option<(Rect, (u32, u32))> {
+        self.free
+            .iter()
+            .filter(|free| free.width >= width && free.height >= height)
+            .map(|free| {
+                let (dw, dh) = (free.width - width, free.height - height);
+                (Rect { x: free.x, y: free.y, width, height }, (dw.min(dh), dw.max(dh)))
+            })
+            .min_by_key(|&(rect, score)| (score, rect.y, rect.x))
+    }
+
+    fn place(&mut self, used: Rect) {
+        let mut next = Vec::with_capacity(self.free.len() + 4);
+        for free in self.free.drain(..) {
+            if !free.intersects(&used) {
+                next.push(free);
+                continue;
+            }
+            // Keep the parts of the free rectangle on each side of the used one
+            if used.x > free.x {
+                next.push(Rect { width: used.x - free.x, ..free });
+            }
+            if used.right() < free.right() {
+                next.push(Rect { x: used.right(), width: free.right() - used.right(), ..free });
+            }
+            if used.y > free.y {
+                next.push(Rect { height: used.y - free.y, ..free });
+            }
+            if used.bottom() < free.bottom() {
+                next.push(Rect { y: used.bottom(), height: free.bottom() - used.bottom(), ..free });
+            }
+        }
+
+        // Drop free rectangles inside others
+        let mut pruned: Vec<Rect> = Vec::with_capacity(next.len());
+        for (i, rect) in next.iter().enumerate() {
+            let redundant = next
+                .iter()
+                .enumerate()
+                .any(|(j, other)| i != j && other.contains(rect) && (other != rect || j < i));
+            if !redundant {
+                pruned.push(*rect);
+            }
+        }
+        self.free = pruned;
+    }
+}
+
+struct Placement {
+    sprite: usize,
+    page: u32,
+    rect: Rect,
+    rotated: bool,
+}
+
+/// Packs sprites into as few pages as needed. Sprites are named and carry
+/// their content hash; the result only depends on the inputs and settings.
+pub fn pack_atlas(sprites: &[(String, String, RgbaImage)], settings: &AtlasSettings) -> Result<Atlas> {
+    if sprites.is_empty() {
+        bail!("No sprites to pack");
+    }
+    let padding = settings.padding;
+    let outer = |image: &RgbaImage| (image.width() + 2 * padding, image.height() + 2 * padding);
+    // Rounding up afterwards then stays within max_size
+    let max_size = match settings.power_of_two {
+        true => settings.max_size.checked_ilog2().map_or(0, |log| 1 << log),
+        false => settings.max_size,
+    };
+
+    for (name, _, image) in sprites {
+        // Pages are square, so rotating never makes a sprite fit
+        let (w, h) = outer(image);
+        if w > max_size || h > max_size {
+            bail!("Sprite {} ({}x{}) does not fit in a {} page", name, image.width(), image.height(), max_size);
+        }
+    }
+
+    // Largest first packs tightest; names break ties so the order is stable
+    let mut order: Vec<usize> = (0..sprites.len()).collect();
+    order.sort_by(|&a, &b| {
+        let (wa, ha) = sprites[a].2.dimensions();
+        let (wb, hb) = sprites[b].2.dimensions();
+        (wb.max(hb), wb * hb).cmp(&(wa.max(ha), wa * ha)).then_with(|| sprites[a].0.cmp(&sprites[b].0))
+    });
+
+    let mut placements = Vec::new();
+    let mut page = 0;
+    while !order.is_empty() {
+        let mut bin = MaxRects::new(max_size);
+        let mut remaining = Vec::new();
+
+        for index in order {
+            let (w, h) = outer(&sprites[index].2);
+            let upright = bin.find(w, h).map(|(rect, score)| (rect, score, false));
+            let rotated = settings
+                .allow_rotation
+                .then(|| bin.find(h, w).map(|(rect, score)| (rect, score, true)))
+                .flatten();
+
+            match [upright, rotated].into_iter().flatten().min_by_key(|&(_, score, _)| score) {
+                Some((rect, _, rotated)) => {
+                    bin.place(rect);
+                    placements.push(Placement { sprite: index, page, rect, rotated });
+                }
+                None => remaining.push(index),
+            }
+        }
+
+        order = remaining;
+        page += 1;
+    }
+
+    // Shrink each page to what it uses
+    let mut pages: Vec<AtlasPage> = (0..page)
+        .map(|p| {
+            let used = placements.iter().filter(|placement| placement.page == p);
+            let (width, height) = used.fold((1, 1), |(w, h), placement| {
+                (w.max(placement.rect.right()), h.max(placement.rect.bottom()))
+            });
+            AtlasPage { width, height, hash: String::new() }
+        })
+        .collect();
+    if settings.power_of_two {
+        for page in &mut pages {
+            page.width = page.width.next_power_of_two();
+            page.height = page.height.next_power_of_two();
+        }
+    }
+
+    let mut images: Vec<RgbaImage> = pages.iter().map(|page| RgbaImage::new(page.width, page.height)).collect();
+    let mut rects = Vec::with_capacity(placements.len());
+    for placement in &placements {
+        let (name, hash, image) = &sprites[placement.sprite];
+        let image = if placement.rotated { image::imageops::rotate90(image) } else { image.clone() };
+        let (x, y) = (placement.rect.x + padding, placement.rect.y + padding);
+        blit_extruded(&mut images[placement.page as usize], &image, x, y, padding);
+
+        let page = &pages[placement.page as usize];
+        let (width, height) = image.dimensions();
+        rects.push(SpriteRect {
+            name: name.clone(),
+            hash: hash.clone(),
+            page: placement.page,
+            x,
+            y,
+            width,
+            height,
+            rotated: placement.rotated,
+            uv: [
+                x as f32 / page.width as f32,
+                y as f32 / page.height as f32,
+                (x + width) as f32 / page.width as f32,
+                (y + height) as f32 / page.height as f32,
+            ],
+        });
+    }
+    rects.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let page_pngs = images.iter().map(|image| TextureProcessor::encode_png(image.clone())).collect::<Result<Vec<_>>>()?;
+    for (page, png) in pages.iter_mut().zip(&page_pngs) {
+        page.hash = blake3::hash(png).to_string();
+    }
+
+    Ok(Atlas {
+        manifest: AtlasManifest { settings: settings.clone(), pages, sprites: rects },
+        pages: images,
+        page_pngs,
+    })
+}
+
+/// Copies `sprite` to `(x, y)` and repeats its edge pixels `padding` pixels
+/// outwards.
+fn blit_extruded(page: &mut RgbaImage, sprite: &RgbaImage, x: u32, y: u32, padding: u32) {
+    let (width, height) = sprite.dimensions();
+    let left = x - padding;
+    let top = y - padding;
+    for py in top..(y + height + padding).min(page.height()) {
+        for px in left..(x + width + padding).min(page.width()) {
+            let sx = px.clamp(x, x + width - 1) - x;
+            let sy = py.clamp(y, y + height - 1) - y;
+            page.put_pixel(px, py, *sprite.get_pixel(sx, sy));
+        }
+    }
+}
+
+impl TextureProcessor {
+    /// Decodes named sprite images and packs them; see [`pack_atlas`].
+    pub fn pack_atlas(&self, sprites: &[(String, &[u8])], settings: &AtlasSettings) -> Result<Atlas> {
+        let decoded = sprites
+            .iter()
+            .map(|(name, data)| {
+                let image = image::load_from_memory(data)
+                    .with_context(|| format!("Failed to load sprite {}", name))?
+                    .to_rgba8();
+                Ok((name.clone(), blake3::hash(data).to_string(), image))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        pack_atlas(&decoded, settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pack_atlas() {
+        let sprite = |w, h, shade: u8| RgbaImage::from_pixel(w, h, image::Rgba([shade, 0, 0, 255]));
+        let mut sprites: Vec<(String, String, RgbaImage)> = (0..12)
+            .map(|i| (format!("sprite{:02}", i), format!("hash{}", i), sprite(10 + i * 3, 40 - i * 2, i as u8 * 20)))
+            .collect();
+        sprites.push(("tall".to_string(), "hash-tall".to_string(), sprite(8, 60, 255)));
+
+        let settings = AtlasSettings { max_size: 64, padding: 1, allow_rotation: true, power_of_two: true };
+        let atlas = pack_atlas(&sprites, &settings).unwrap();
+        assert!(atlas.pages.len() > 1);
+        assert_eq!(atlas.manifest.sprites.len(), sprites.len());
+
+        for page in &atlas.manifest.pages {
+            assert!(page.width.is_power_of_two() && page.height.is_power_of_two());
+            assert!(page.width <= 64 && page.height <= 64);
+        }
+
+        // No two padded sprites overlap, and each holds its own pixels
+        let padded = |s: &SpriteRect| Rect { x: s.x - 1, y: s.y - 1, width: s.width + 2, height: s.height + 2 };
+        for (i, a) in atlas.manifest.sprites.iter().enumerate() {
+            for b in &atlas.manifest.sprites[i + 1..] {
+                assert!(a.page != b.page || !padded(a).intersects(&padded(b)), "{} overlaps {}", a.name, b.name);
+            }
+            let (_, _, source) = sprites.iter().find(|(name, _, _)| name == &a.name).unwrap();
+            let page = &atlas.pages[a.page as usize];
+            assert_eq!(page.get_pixel(a.x, a.y), source.get_pixel(0, 0));
+            // Edge pixels extend into the padding
+            assert_eq!(page.get_pixel(a.x - 1, a.y - 1), source.get_pixel(0, 0));
+            let expected = if a.rotated { source.dimensions() == (a.height, a.width) } else { source.dimensions() == (a.width, a.height) };
+            assert!(expected);
+        }
+
+        // Same input, same atlas; manifests round-trip through both encodings
+        let again = pack_atlas(&sprites, &settings).unwrap();
+        assert_eq!(again.manifest, atlas.manifest);
+        assert_eq!(AtlasManifest::from_json(&atlas.manifest.to_json().unwrap()).unwrap(), atlas.manifest);
+        assert_eq!(AtlasManifest::from_bincode(&atlas.manifest.to_bincode().unwrap()).unwrap(), atlas.manifest);
+
+        let too_big = vec![("huge".to_string(), "h".to_string(), sprite(100, 10, 0))];
+        assert!(pack_atlas(&too_big, &settings).is_err());
+
+        // Power-of-two pages never round up past a max_size that is not one
+        let settings = AtlasSettings { max_size: 100, padding: 0, ..settings };
+        let wide = vec![("wide".to_string(), "w".to_string(), sprite(70, 10, 0))];
+        assert!(pack_atlas(&wide, &settings).is_err());
+        let atlas = pack_atlas(&sprites, &settings).unwrap();
+        assert!(atlas.manifest.pages.iter().all(|page| page.width <= 64 && page.height <= 64));
+    }
+}
@@ -1,44 +1,446 @@
-use crate::ResourceMetadata;
-use crate::ResourceType;
-use anyhow::Result;
-use std::time::{SystemTime, UNIX_EPOCH};
+use super::{
+    apply_gain, downmix, encode_flac16, encode_wav16, normalization_gain_db, parse_audio_header, quantize_i16,
+    resample_sinc, waveform_image, AudioHeader, AudioTargetFormat, AudioTranscodeSettings, ExternalFile,
+    ImportSettings, TextureProcessor, ThumbnailOptions, WaveformOptions,
+};
+use crate::{AudioProperties, ResourceMetadata, ResourceProperties, ResourceType, METADATA_SCHEMA_VERSION};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Serialize;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{CodecParameters, DecoderOptions};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::{FormatOptions, FormatReader};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use tracing::info;
 
+/// Length of the windows compared when looking for diverging waveform regions.
+const DIFF_WINDOW_SECS: f64 = 0.01;
+
+/// Level reported for silence, so level deltas stay finite.
+const SILENCE_DB: f64 = -120.0;
+
+/// Interleaved PCM samples normalized to `[-1.0, 1.0]`.
+#[derive(Debug, Clone)]
+pub struct DecodedAudio {
+    pub codec: String,
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub bits_per_sample: Option<u32>,
+    pub samples: Vec<f32>,
+}
+
+impl DecodedAudio {
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels.max(1) as usize
+    }
+
+    pub fn duration_secs(&self) -> f64 {
+        self.frames() as f64 / self.sample_rate.max(1) as f64
+    }
+
+    pub fn rms(&self) -> f64 {
+        if self.samples.is_empty() {
+            return 0.0;
+        }
+        let sum: f64 = self.samples.iter().map(|&s| (s as f64) * (s as f64)).sum();
+        (sum / self.samples.len() as f64).sqrt()
+    }
+
+    pub fn peak(&self) -> f64 {
+        self.samples.iter().fold(0.0f64, |peak, &s| peak.max(s.abs() as f64))
+    }
+
+    /// Averages all channels into one, frame by frame.
+    pub fn to_mono(&self) -> Vec<f32> {
+        let channels = self.channels.max(1) as usize;
+        self.samples
+            .chunks_exact(channels)
+            .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AudioInfo {
+    pub codec: String,
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub bits_per_sample: Option<u32>,
+    pub duration_secs: f64,
+    pub rms_db: f64,
+    pub peak_db: f64,
+}
+
+impl AudioInfo {
+    fn from_decoded(audio: &DecodedAudio) -> Self {
+        Self {
+            codec: audio.codec.clone(),
+            sample_rate: audio.sample_rate,
+            channels: audio.channels,
+            bits_per_sample: audio.bits_per_sample,
+            duration_secs: audio.duration_secs(),
+            rms_db: to_db(audio.rms()),
+            peak_db: to_db(audio.peak()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DivergentRange {
+    pub start_secs: f64,
+    pub end_secs: f64,
+    pub max_difference: f32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AudioDiff {
+    pub old: AudioInfo,
+    pub new: AudioInfo,
+    /// Linear amplitude above which two waveforms are considered to diverge.
+    pub threshold: f32,
+    pub divergent_ranges: Vec<DivergentRange>,
+}
+
+impl AudioDiff {
+    pub fn format_changed(&self) -> bool {
+        self.old.codec != self.new.codec
+            || self.old.sample_rate != self.new.sample_rate
+            || self.old.channels != self.new.channels
+            || self.old.bits_per_sample != self.new.bits_per_sample
+    }
+
+    pub fn duration_delta_secs(&self) -> f64 {
+        self.new.duration_secs - self.old.duration_secs
+    }
+
+    pub fn rms_delta_db(&self) -> f64 {
+        self.new.rms_db - self.old.rms_db
+    }
+
+    pub fn peak_delta_db(&self) -> f64 {
+        self.new.peak_db - self.old.peak_db
+    }
+}
+
+fn to_db(amplitude: f64) -> f64 {
+    (20.0 * amplitude.log10()).max(SILENCE_DB)
+}
+
+/// Linear resampling, which is plenty for locating where two waveforms differ.
+fn resample(samples: &[f32], from_rate: u32, to_rate: u32) -> Vec<f32> {
+    if from_rate == to_rate || samples.is_empty() {
+        return samples.to_vec();
+    }
+
+    let ratio = from_rate as f64 / to_rate as f64;
+    let len = (samples.len() as f64 / ratio).round() as usize;
+    (0..len)
+        .map(|i| {
+            let pos = i as f64 * ratio;
+            let index = pos.floor() as usize;
+            let frac = (pos - index as f64) as f32;
+            let a = samples[index.min(samples.len() - 1)];
+            let b = samples[(index + 1).min(samples.len() - 1)];
+            a + (b - a) * frac
+        })
+        .collect()
+}
+
+#[derive(Default)]
 pub struct AudioProcessor;
 
 impl AudioProcessor {
     pub fn new() -> Self {
         Self
     }
+
+    /// Whether the data starts with a header of one of the containers we decode.
+    pub fn is_audio(data: &[u8]) -> bool {
+        (data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WAVE")
+            || data.starts_with(b"OggS")
+            || data.starts_with(b"fLaC")
+            || data.starts_with(b"ID3")
+            || (data.len() >= 2 && data[0] == 0xFF && data[1] & 0xE0 == 0xE0)
+    }
+
+    /// Opens the container and returns its default track's id and parameters.
+    fn open(data: &[u8]) -> Result<(Box<dyn FormatReader>, u32, CodecParameters)> {
+        let source = MediaSourceStream::new(Box::new(std::io::Cursor::new(data.to_vec())), Default::default());
+        let probed = symphonia::default::get_probe()
+            .format(&Hint::new(), source, &FormatOptions::default(), &MetadataOptions::default())
+            .with_context(|| "Unrecognized audio format")?;
+
+        let track = probed
+            .format
+            .default_track()
+            .ok_or_else(|| anyhow!("Audio file has no tracks"))?;
+        let track_id = track.id;
+        let params = track.codec_params.clone();
+
+        Ok((probed.format, track_id, params))
+    }
+
+    fn codec_name(params: &CodecParameters) -> String {
+        symphonia::default::get_codecs()
+            .get_codec(params.codec)
+            .map_or("unknown", |descriptor| descriptor.short_name)
+            .to_string()
+    }
+
+    pub fn parse_header(&self, data: &[u8]) -> Result<AudioHeader> {
+        parse_audio_header(data)
+    }
+
+    /// Format, duration and loop points from the file headers.
+    pub fn get_properties(&self, data: &[u8]) -> Result<AudioProperties> {
+        let header = self.parse_header(data)?;
+
+        Ok(AudioProperties {
+            duration_secs: header.duration_secs(),
+            codec: header.codec,
+            sample_rate: header.sample_rate,
+            channels: header.channels,
+            bits_per_sample: header.bits_per_sample,
+            loop_points: header.loop_points,
+        })
+    }
+
+    /// Decodes the default track of any supported container to `f32` PCM.
+    pub fn decode(&self, data: &[u8]) -> Result<DecodedAudio> {
+        let (mut format, track_id, params) = Self::open(data)?;
+
+        let mut decoder = symphonia::default::get_codecs()
+            .make(&params, &DecoderOptions::default())
+            .with_context(|| "Unsupported audio codec")?;
+        let codec = Self::codec_name(&params);
+
+        let mut samples = Vec::new();
+        let mut sample_rate = params.sample_rate;
+        let mut channels = params.channels.map(|c| c.count() as u16);
+
+        loop {
+            let packet = match format.next_packet() {
+                Ok(packet) => packet,
+                Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            };
+            if packet.track_id() != track_id {
+                continue;
+            }
+
+            let buffer = match decoder.decode(&packet) {
+                Ok(buffer) => buffer,
+                // A corrupt packet only affects its own frames
+                Err(SymphoniaError::DecodeError(_)) => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            let spec = *buffer.spec();
+            sample_rate.get_or_insert(spec.rate);
+            channels.get_or_insert(spec.channels.count() as u16);
+
+            let mut interleaved = SampleBuffer::<f32>::new(buffer.capacity() as u64, spec);
+            interleaved.copy_interleaved_ref(buffer);
+            samples.extend_from_slice(interleaved.samples());
+        }
+
+        Ok(DecodedAudio {
+            codec,
+            sample_rate: sample_rate.ok_or_else(|| anyhow!("Unknown sample rate"))?,
+            channels: channels.ok_or_else(|| anyhow!("Unknown channel count"))?,
+            bits_per_sample: params.bits_per_sample,
+            samples,
+        })
+    }
+
+    /// Decodes the audio and re-encodes it as `settings` ask: mixed down,
+    /// resampled, normalized to the loudness target and written out as 16-bit
+    /// WAV or FLAC. Loop points are not carried over.
+    pub fn transcode(&self, data: &[u8], settings: &AudioTranscodeSettings) -> Result<Vec<u8>> {
+        let Some(format) = settings.format else {
+            bail!("Audio import settings need a format");
+        };
+        if format == AudioTargetFormat::Vorbis {
+            bail!("Ogg Vorbis encoding is not supported yet, use wav16 or flac");
+        }
+        if settings.sample_rate == Some(0) {
+            bail!("Audio import settings have a sample_rate of 0");
+        }
+        let mut audio = self.decode(data)?;
+
+        if let Some(channels) = settings.channels {
+            audio = downmix(&audio, channels)?;
+        }
+        if let Some(rate) = settings.sample_rate {
+            audio = resample_sinc(&audio, rate);
+        }
+        if let Some(target) = settings.loudness_lufs {
+            match normalization_gain_db(&audio, target, settings.max_peak_db()) {
+                Some(gain) => {
+                    info!("Applying {:.2} dB gain for a {:.1} LUFS target", gain, target);
+                    apply_gain(&mut audio, gain);
+                }
+                None => info!("Audio is silent, skipping loudness normalization"),
+            }
+        }
+
+        let samples = quantize_i16(&audio.samples);
+        match format {
+            AudioTargetFormat::Wav16 => Ok(encode_wav16(&samples, audio.sample_rate, audio.channels)),
+            AudioTargetFormat::Flac => encode_flac16(&samples, audio.sample_rate, audio.channels),
+            AudioTargetFormat::Vorbis => unreachable!("rejected above"),
+        }
+    }
+
+    /// Decodes both files and reports format, level and waveform differences.
+    ///
+    /// Waveforms are mixed down to mono and the new one is resampled to the old
+    /// sample rate, so files that only differ in encoding still line up.
+    pub fn audio_diff(&self, old: &[u8], new: &[u8], threshold: f32) -> Result<AudioDiff> {
+        let old = self.decode(old).with_context(|| "Failed to decode old audio")?;
+        let new = self.decode(new).with_context(|| "Failed to decode new audio")?;
+
+        let old_mono = old.to_mono();
+        let new_mono = resample(&new.to_mono(), new.sample_rate, old.sample_rate);
+
+        let window = ((old.sample_rate as f64 * DIFF_WINDOW_SECS) as usize).max(1);
+        let len = old_mono.len().max(new_mono.len());
+        let mut divergent_ranges: Vec<DivergentRange> = Vec::new();
+
+        for start in (0..len).step_by(window) {
+            let end = (start + window).min(len);
+            let max_difference = (start..end)
+                .map(|i| {
+                    let a = old_mono.get(i).copied().unwrap_or(0.0);
+                    let b = new_mono.get(i).copied().unwrap_or(0.0);
+                    (a - b).abs()
+                })
+                .fold(0.0f32, f32::max);
+
+            if max_difference <= threshold {
+                continue;
+            }
+
+            let start_secs = start as f64 / old.sample_rate as f64;
+            let end_secs = end as f64 / old.sample_rate as f64;
+            match divergent_ranges.last_mut() {
+                Some(range) if range.end_secs == start_secs => {
+                    range.end_secs = end_secs;
+                    range.max_difference = range.max_difference.max(max_difference);
+                }
+                _ => divergent_ranges.push(DivergentRange { start_secs, end_secs, max_difference }),
+            }
+        }
+
+        Ok(AudioDiff {
+            old: AudioInfo::from_decoded(&old),
+            new: AudioInfo::from_decoded(&new),
+            threshold,
+            divergent_ranges,
+        })
+    }
 }
 
 impl super::FormatProcessor for AudioProcessor {
+    fn name(&self) -> &'static str {
+        "audio"
+    }
+
+    fn resource_type(&self) -> ResourceType {
+        ResourceType::Audio
+    }
+
+    fn extensions(&self) -> &[&'static str] {
+        &["wav", "flac", "ogg", "oga", "opus", "mp3"]
+    }
+
+    fn mime_types(&self) -> &[&'static str] {
+        &["audio/wav", "audio/x-wav", "audio/flac", "audio/ogg", "audio/opus", "audio/mpeg"]
+    }
+
+    fn matches_magic(&self, data: &[u8]) -> bool {
+        Self::is_audio(data)
+    }
+
+    // 2: fixed resampling and surround downmix weights
+    fn version(&self) -> u32 {
+        2
+    }
+
     fn process(&self, data: &[u8]) -> Result<Vec<u8>> {
-        // Basic audio validation - check for common headers
-        if data.len() >= 4 {
-            let header = &data[0..4];
-            if header == b"RIFF" || header == b"OggS" || header == b"fLaC" || &header[0..3] == b"ID3" {
-                return Ok(data.to_vec());
-            }
-        }
-        
-        // If not recognized, assume it's valid
+        // Refuse anything whose headers we cannot read
+        self.parse_header(data)?;
         Ok(data.to_vec())
     }
 
+    fn process_with(&self, data: &[u8], settings: &ImportSettings, _files: &[ExternalFile]) -> Result<Vec<u8>> {
+        match &settings.audio {
+            Some(audio) => self.transcode(data, audio),
+            None => self.process(data),
+        }
+    }
+
+    fn thumbnail(&self, data: &[u8], options: &ThumbnailOptions, _files: &[ExternalFile]) -> Result<Option<Vec<u8>>> {
+        // A waveform half as high as it is wide, split between the channels
+        let audio = self.decode(data)?;
+        let waveform = WaveformOptions {
+            width: options.size,
+            channel_height: (options.size / 2 / audio.channels.max(1) as u32).max(2),
+        };
+        Ok(Some(TextureProcessor::encode_png(waveform_image(&audio, &waveform)?)?))
+    }
+
     fn get_metadata(&self, data: &[u8]) -> Result<ResourceMetadata> {
+        let properties = self.get_properties(data)?;
+
         Ok(ResourceMetadata {
+            schema_version: METADATA_SCHEMA_VERSION,
             hash: blake3::hash(data).to_string(),
             resource_type: ResourceType::Audio,
             size: data.len() as u64,
-            create_at: SystemTime::now()
-                .duration_since(UNIX_EPOCH)?
-                .as_secs() as i64,
-            dependencies: vec!["audio:raw".to_string()],
+            dependencies: Vec::new(),
+            properties: ResourceProperties::Audio(properties),
         })
     }
 
     fn validate(&self, data: &[u8]) -> Result<bool> {
-        // Simple validation - check minimum size
-        Ok(data.len() > 44) // WAV header size
+        // Report why a file is rejected rather than just that it is
+        self.parse_header(data).map(|_| true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audio_diff() {
+        let rate = 8000;
+        let tone: Vec<i16> = (0..rate)
+            .map(|i| ((i as f32 * 440.0 * std::f32::consts::TAU / rate as f32).sin() * 16000.0) as i16)
+            .collect();
+        let mut muted = tone.clone();
+        // Silence 0.5s..0.75s
+        muted[4000..6000].iter_mut().for_each(|s| *s = 0);
+
+        let processor = AudioProcessor::new();
+        let diff = processor.audio_diff(&encode_wav16(&tone, rate, 1), &encode_wav16(&muted, rate, 1), 0.01).unwrap();
+
+        assert!(!diff.format_changed());
+        assert_eq!(diff.old.bits_per_sample, Some(16));
+        assert!((diff.old.duration_secs - 1.0).abs() < 1e-9);
+        assert!(diff.rms_delta_db() < 0.0);
+        assert_eq!(diff.divergent_ranges.len(), 1);
+        assert!((diff.divergent_ranges[0].start_secs - 0.5).abs() < 1e-9);
+        assert!((diff.divergent_ranges[0].end_secs - 0.75).abs() < 1e-9);
+
+        // Silence sits at the floor rather than at -inf
+        let silence = encode_wav16(&vec![0; rate as usize], rate, 1);
+        let diff = processor.audio_diff(&encode_wav16(&tone, rate, 1), &silence, 0.01).unwrap();
+        assert_eq!((diff.new.rms_db, diff.new.peak_db), (SILENCE_DB, SILENCE_DB));
+        assert!(diff.rms_delta_db().is_finite() && diff.peak_delta_db().is_finite());
+        assert_eq!(processor.audio_diff(&silence, &silence, 0.01).unwrap().rms_delta_db(), 0.0);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,382 @@
+use anyhow::{bail, Result};
+
+/// Samples per FLAC frame; the reference encoder's default at most levels.
+const FLAC_BLOCK_SIZE: usize = 4096;
+const FLAC_MAX_PARTITION_ORDER: u32 = 6;
+/// Rice parameters 0-14; 15 is the escape code.
+const FLAC_MAX_RICE_PARAM: u32 = 14;
+const BITS_PER_SAMPLE: u32 = 16;
+
+/// A 16-bit PCM WAV file from interleaved samples.
+pub fn encode_wav16(samples: &[i16], sample_rate: u32, channels: u16) -> Vec<u8> {
+    let block_align = channels as u32 * 2;
+    let data_len = (samples.len() * 2) as u32;
+
+    let mut wav = Vec::with_capacity(44 + data_len as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&channels.to_le_bytes());
+    wav.extend_from_slice(&sample_rate.to_le_bytes());
+    wav.extend_from_slice(&(sample_rate * block_align).to_le_bytes());
+    wav.extend_from_slice(&(block_align as u16).to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+    for sample in samples {
+        wav.extend_from_slice(&sample.to_le_bytes());
+    }
+    wav
+}
+
+/// A 16-bit FLAC file from interleaved samples, using fixed predictors with
+/// partitioned Rice residuals and stereo decorrelation. The output depends
+/// only on the input, so re-encoding is reproducible.
+pub fn encode_flac16(samples: &[i16], sample_rate: u32, channels: u16) -> Result<Vec<u8>> {
+    if !(1..=8).contains(&channels) {
+        bail!("FLAC supports 1 to 8 channels, not {}", channels);
+    }
+    if sample_rate == 0 || sample_rate >= 1 << 20 {
+        bail!("Sample rate {} cannot be stored in FLAC", sample_rate);
+    }
+    // A FLAC stream needs at least one frame to be readable
+    if samples.len() < channels as usize {
+        bail!("Cannot encode FLAC without any samples");
+    }
+
+    let channels = channels as usize;
+    let frames = samples.len() / channels;
+
+    let mut out = BitWriter::new();
+    out.bytes.extend_from_slice(b"fLaC");
+    // Last-block flag, STREAMINFO type and its length
+    out.write(1, 1);
+    out.write(0, 7);
+    out.write(34, 24);
+    out.write(FLAC_BLOCK_SIZE as u64, 16);
+    out.write(FLAC_BLOCK_SIZE as u64, 16);
+    // Frame sizes and MD5 are optional; zero means unknown
+    out.write(0, 24);
+    out.write(0, 24);
+    out.write(sample_rate as u64, 20);
+    out.write(channels as u64 - 1, 3);
+    out.write(BITS_PER_SAMPLE as u64 - 1, 5);
+    out.write(frames as u64, 36);
+    out.bytes.extend_from_slice(&[0; 16]);
+
+    for (number, block) in samples.chunks(FLAC_BLOCK_SIZE * channels).enumerate() {
+        let planar: Vec<Vec<i32>> = (0..channels)
+            .map(|c| block.iter().skip(c).step_by(channels).map(|&s| s as i32).collect())
+            .collect();
+        write_frame(&mut out.bytes, number as u64, &planar);
+    }
+
+    Ok(out.bytes)
+}
+
+fn write_frame(out: &mut Vec<u8>, number: u64, planar: &[Vec<i32>]) {
+    let block_size = planar[0].len();
+    let bps = BITS_PER_SAMPLE;
+
+    // Pick the cheapest channel assignment; side channels need one extra bit
+    let (assignment, channels): (u64, Vec<(Vec<i32>, u32)>) = if let [left, right] = planar {
+        let side: Vec<i32> = left.iter().zip(right).map(|(l, r)| l - r).collect();
+        let mid: Vec<i32> = left.iter().zip(right).map(|(l, r)| (l + r) >> 1).collect();
+        let cost = |samples: &[i32], bps| plan_subframe(samples, bps).bits(samples.len(), bps);
+        let (l, r, s, m) = (cost(left, bps), cost(right, bps), cost(&side, bps + 1), cost(&mid, bps));
+
+        [(0b0001, l + r), (0b1000, l + s), (0b1001, s + r), (0b1010, m + s)]
+            .into_iter()
+            .min_by_key(|&(_, bits)| bits)
+            .map(|(assignment, _)| {
+                let channels = match assignment {
+                    0b0001 => vec![(left.clone(), bps), (right.clone(), bps)],
+                    0b1000 => vec![(left.clone(), bps), (side.clone(), bps + 1)],
+                    0b1001 => vec![(side.clone(), bps + 1), (right.clone(), bps)],
+                    _ => vec![(mid.clone(), bps), (side.clone(), bps + 1)],
+                };
+                (assignment, channels)
+            })
+            .unwrap()
+    } else {
+        (planar.len() as u64 - 1, planar.iter().map(|c| (c.clone(), bps)).collect())
+    };
+
+    let mut frame = BitWriter::new();
+    // Sync code with fixed-size blocking
+    frame.write(0xFFF8, 16);
+    // Block size as a 16-bit value at the end of the header, rate from STREAMINFO
+    frame.write(0b0111, 4);
+    frame.write(0b0000, 4);
+    frame.write(assignment, 4);
+    frame.write(0b100, 3);
+    frame.write(0, 1);
+    write_utf8_number(&mut frame, number);
+    frame.write(block_size as u64 - 1, 16);
+    let crc = crc8(&frame.bytes);
+    frame.write(crc as u64, 8);
+
+    for (samples, bps) in &channels {
+        plan_subframe(samples, *bps).write(&mut frame, samples, *bps);
+    }
+
+    frame.align();
+    let crc = crc16(&frame.bytes);
+    frame.write(crc as u64, 16);
+    out.extend_from_slice(&frame.bytes);
+}
+
+/// Frame numbers use the same variable-length coding as UTF-8.
+fn write_utf8_number(out: &mut BitWriter, n: u64) {
+    if n < 0x80 {
+        out.write(n, 8);
+        return;
+    }
+    // Each continuation byte carries six bits
+    let continuation = [0x800u64, 0x10000, 0x200000, 0x4000000]
+        .iter()
+        .position(|&limit| n < limit)
+        .map_or(5, |i| i + 1);
+    let lead_marker = (0xFF00u64 >> (continuation + 1)) & 0xFF;
+    out.write(lead_marker | (n >> (6 * continuation)), 8);
+    for i in (0..continuation).rev() {
+        out.write(0x80 | ((n >> (6 * i)) & 0x3F), 8);
+    }
+}
+
+enum Subframe {
+    Constant,
+    Verbatim,
+    Fixed {
+        order: usize,
+        partition_order: u32,
+        params: Vec<u32>,
+        residual_bits: u64,
+    },
+}
+
+impl Subframe {
+    fn bits(&self, len: usize, bps: u32) -> u64 {
+        match self {
+            Subframe::Constant => 8 + bps as u64,
+            Subframe::Verbatim => 8 + len as u64 * bps as u64,
+            Subframe::Fixed { order, residual_bits, .. } => 8 + *order as u64 * bps as u64 + residual_bits,
+        }
+    }
+
+    fn write(&self, out: &mut BitWriter, samples: &[i32], bps: u32) {
+        match self {
+            Subframe::Constant => {
+                out.write(0b0000_0000, 8);
+                out.write_signed(samples[0], bps);
+            }
+            Subframe::Verbatim => {
+                out.write(0b0000_0010, 8);
+                for &sample in samples {
+                    out.write_signed(sample, bps);
+                }
+            }
+            Subframe::Fixed { order, partition_order, params, .. } => {
+                out.write((0b001000 | *order as u64) << 1, 8);
+                for &sample in &samples[..*order] {
+                    out.write_signed(sample, bps);
+                }
+
+                // Rice coding with 4-bit parameters
+                out.write(0b00, 2);
+                out.write(*partition_order as u64, 4);
+                let residual = fixed_residual(samples, *order);
+                let partition_len = samples.len() >> partition_order;
+                let mut start = 0;
+                for (i, &k) in params.iter().enumerate() {
+                    let end = (i + 1) * partition_len - order;
+                    out.write(k as u64, 4);
+                    for &r in &residual[start..end] {
+                        let folded = zigzag(r);
+                        out.write_unary(folded >> k);
+                        out.write(folded, k);
+                    }
+                    start = end;
+                }
+            }
+        }
+    }
+}
+
+/// Chooses the subframe type, fixed predictor order and Rice partitioning
+/// with the smallest encoding.
+fn plan_subframe(samples: &[i32], bps: u32) -> Subframe {
+    if samples.iter().all(|&s| s == samples[0]) {
+        return Subframe::Constant;
+    }
+
+    let mut best = Subframe::Verbatim;
+    let mut best_bits = best.bits(samples.len(), bps);
+
+    for order in 0..=4.min(samples.len() - 1) {
+        let residual = fixed_residual(samples, order);
+        let folded: Vec<u64> = residual.iter().map(|&r| zigzag(r)).collect();
+
+        for partition_order in 0..=FLAC_MAX_PARTITION_ORDER {
+            let partitions = 1usize << partition_order;
+            if !samples.len().is_multiple_of(partitions) || samples.len() / partitions <= order {
+                break;
+            }
+
+            let partition_len = samples.len() / partitions;
+            let mut params = Vec::with_capacity(partitions);
+            let mut residual_bits = 2 + 4;
+            let mut start = 0;
+            for i in 0..partitions {
+                let end = (i + 1) * partition_len - order;
+                let (k, bits) = best_rice_param(&folded[start..end]);
+                params.push(k);
+                residual_bits += 4 + bits;
+                start = end;
+            }
+
+            let candidate = Subframe::Fixed { order, partition_order, params, residual_bits };
+            let bits = candidate.bits(samples.len(), bps);
+            if bits < best_bits {
+                best = candidate;
+                best_bits = bits;
+            }
+        }
+    }
+
+    best
+}
+
+/// The Rice parameter closest to optimal for the partition, and its cost.
+fn best_rice_param(folded: &[u64]) -> (u32, u64) {
+    let cost = |k: u32| folded.iter().map(|&u| (u >> k) + 1 + k as u64).sum::<u64>();
+
+    // The optimum sits near log2 of the mean; check its neighbours exactly
+    let mean = folded.iter().sum::<u64>() / folded.len().max(1) as u64;
+    let estimate = (64 - mean.leading_zeros()).min(FLAC_MAX_RICE_PARAM);
+    (estimate.saturating_sub(1)..=(estimate + 1).min(FLAC_MAX_RICE_PARAM))
+        .map(|k| (k, cost(k)))
+        .min_by_key(|&(_, bits)| bits)
+        .unwrap()
+}
+
+/// Prediction error of the FLAC fixed polynomial predictor of `order`.
+fn fixed_residual(samples: &[i32], order: usize) -> Vec<i64> {
+    let mut residual: Vec<i64> = samples.iter().map(|&s| s as i64).collect();
+    for _ in 0..order {
+        for i in (1..residual.len()).rev() {
+            residual[i] -= residual[i - 1];
+        }
+    }
+    residual.split_off(order)
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |mut crc, &byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |mut crc, &byte| {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+/// Big-endian bit packing, as FLAC frames are laid out.
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self { bytes: Vec::new(), acc: 0, bits: 0 }
+    }
+
+    /// Appends the low `n` bits of `value`, `n` at most 56.
+    fn write(&mut self, value: u64, n: u32) {
+        if n == 0 {
+            return;
+        }
+        self.acc = (self.acc << n) | (value & ((1u64 << n) - 1));
+        self.bits += n;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.bytes.push((self.acc >> self.bits) as u8);
+        }
+        self.acc &= (1u64 << self.bits) - 1;
+    }
+
+    fn write_signed(&mut self, value: i32, n: u32) {
+        self.write(value as i64 as u64, n);
+    }
+
+    /// `n` zero bits followed by a one.
+    fn write_unary(&mut self, mut n: u64) {
+        while n >= 32 {
+            self.write(0, 32);
+            n -= 32;
+        }
+        self.write(1, n as u32 + 1);
+    }
+
+    fn align(&mut self) {
+        if self.bits > 0 {
+            self.write(0, 8 - self.bits);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::AudioProcessor;
+
+    #[test]
+    fn test_flac_roundtrip() {
+        let frames = FLAC_BLOCK_SIZE * 2 + 1000;
+        let samples: Vec<i16> = (0..frames)
+            .flat_map(|i| {
+                let t = i as f64 / 44100.0;
+                let left = (12000.0 * (2.0 * std::f64::consts::PI * 440.0 * t).sin()) as i16;
+                // Sprinkle in noise so the predictors cannot fit exactly
+                let noise = ((i * 7919) % 201) as i16 - 100;
+                [left, left / 2 + noise]
+            })
+            .collect();
+
+        let flac = encode_flac16(&samples, 44100, 2).unwrap();
+        assert!(flac.len() < samples.len() * 2);
+
+        let processor = AudioProcessor::new();
+        let header = processor.parse_header(&flac).unwrap();
+        assert_eq!(header.total_frames, frames as u64);
+
+        let decoded = processor.decode(&flac).unwrap();
+        let roundtrip: Vec<i16> = decoded.samples.iter().map(|&s| (s * 32768.0).round() as i16).collect();
+        assert_eq!(roundtrip, samples);
+
+        // Constant and silent input takes the constant subframe path
+        let silence = encode_flac16(&[0; 64], 8000, 1).unwrap();
+        assert_eq!(processor.decode(&silence).unwrap().samples, vec![0.0; 64]);
+
+        let error = encode_flac16(&[], 8000, 1).unwrap_err();
+        assert!(error.to_string().contains("without any samples"), "{}", error);
+    }
+}
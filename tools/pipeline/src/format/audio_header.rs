@@ -0,0 +1,702 @@
+use crate::LoopPoint;
+use anyhow::{anyhow, bail, ensure, Result};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AudioContainer {
+    Wav,
+    Flac,
+    OggVorbis,
+    OggOpus,
+    Mp3,
+}
+
+/// Stream parameters read from a file's headers without decoding any audio.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AudioHeader {
+    pub container: AudioContainer,
+    pub codec: String,
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub bits_per_sample: Option<u32>,
+    pub total_frames: u64,
+    pub loop_points: Vec<LoopPoint>,
+}
+
+impl AudioHeader {
+    pub fn duration_secs(&self) -> f64 {
+        self.total_frames as f64 / self.sample_rate.max(1) as f64
+    }
+}
+
+/// Bounds-checked little helper for walking binary headers.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    what: &'static str,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], what: &'static str) -> Self {
+        Self { data, pos: 0, what }
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.remaining() {
+            bail!(
+                "Truncated {}: needed {} bytes at offset {}, only {} left",
+                self.what,
+                len,
+                self.pos,
+                self.remaining(),
+            );
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16_le(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into()?))
+    }
+
+    fn u32_le(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into()?))
+    }
+
+    fn u64_le(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into()?))
+    }
+
+    fn u24_be(&mut self) -> Result<u32> {
+        let b = self.bytes(3)?;
+        Ok(u32::from_be_bytes([0, b[0], b[1], b[2]]))
+    }
+}
+
+/// Parses the headers of a WAV, FLAC, Ogg Vorbis, Ogg Opus or MP3 file.
+pub fn parse_audio_header(data: &[u8]) -> Result<AudioHeader> {
+    if data.starts_with(b"RIFF") {
+        parse_wav(data)
+    } else if data.starts_with(b"fLaC") {
+        parse_flac(data)
+    } else if data.starts_with(b"OggS") {
+        parse_ogg(data)
+    } else if data.starts_with(b"ID3") || (data.len() >= 2 && data[0] == 0xFF && data[1] & 0xE0 == 0xE0) {
+        parse_mp3(data)
+    } else {
+        bail!("Unsupported audio format: no WAV, FLAC, Ogg or MP3 signature")
+    }
+}
+
+fn parse_wav(data: &[u8]) -> Result<AudioHeader> {
+    let mut reader = Reader::new(data, "WAV header");
+    reader.bytes(4)?;
+    let riff_len = reader.u32_le()? as usize;
+    ensure!(reader.bytes(4)? == b"WAVE", "Unsupported RIFF file: form type is not WAVE");
+    ensure!(
+        riff_len + 8 <= data.len(),
+        "Truncated WAV file: RIFF header declares {} bytes, file has {}",
+        riff_len + 8,
+        data.len(),
+    );
+
+    let mut format = None;
+    let mut data_len = None;
+    let mut loop_points = Vec::new();
+
+    while reader.remaining() >= 8 {
+        let id = reader.bytes(4)?;
+        let len = reader.u32_le()? as usize;
+        if len > reader.remaining() {
+            bail!(
+                "Truncated WAV file: '{}' chunk declares {} bytes, only {} left",
+                String::from_utf8_lossy(id),
+                len,
+                reader.remaining(),
+            );
+        }
+        let mut chunk = Reader::new(reader.bytes(len)?, "WAV chunk");
+        // Chunks are padded to even sizes
+        if len % 2 == 1 && reader.remaining() > 0 {
+            reader.bytes(1)?;
+        }
+
+        match id {
+            b"fmt " => {
+                let mut tag = chunk.u16_le()?;
+                let channels = chunk.u16_le()?;
+                let sample_rate = chunk.u32_le()?;
+                chunk.u32_le()?;
+                let block_align = chunk.u16_le()?;
+                let bits = chunk.u16_le()?;
+                if tag == 0xFFFE {
+                    // WAVE_FORMAT_EXTENSIBLE keeps the real tag in the sub-format GUID
+                    chunk.bytes(8)?;
+                    tag = chunk.u16_le()?;
+                }
+                format = Some((tag, channels, sample_rate, block_align, bits));
+            }
+            b"data" => data_len = Some(len),
+            b"smpl" => {
+                chunk.bytes(28)?;
+                let count = chunk.u32_le()?;
+                chunk.u32_le()?;
+                for _ in 0..count {
+                    chunk.bytes(8)?;
+                    let start = chunk.u32_le()? as u64;
+                    // Loop ends in `smpl` are inclusive
+                    let end = chunk.u32_le()? as u64 + 1;
+                    chunk.bytes(8)?;
+                    loop_points.push(LoopPoint { start, end });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let (tag, channels, sample_rate, block_align, bits) =
+        format.ok_or_else(|| anyhow!("Invalid WAV file: missing 'fmt ' chunk"))?;
+    let data_len = data_len.ok_or_else(|| anyhow!("Invalid WAV file: missing 'data' chunk"))?;
+
+    let codec = match tag {
+        0x0001 => "pcm",
+        0x0003 => "pcm_float",
+        0x0006 => "alaw",
+        0x0007 => "mulaw",
+        0x0002 | 0x0011 => "adpcm",
+        other => bail!("Unsupported WAV encoding: format tag {:#06x}", other),
+    };
+    ensure!(channels > 0, "Invalid WAV file: zero channels");
+    ensure!(sample_rate > 0, "Invalid WAV file: zero sample rate");
+    ensure!(block_align > 0, "Invalid WAV file: zero block alignment");
+
+    let total_frames = if codec == "adpcm" {
+        // Compressed blocks hold a codec-specific number of frames, so derive
+        // the length from the bit rate instead
+        data_len as u64 * 8 / (bits.max(1) as u64 * channels as u64)
+    } else {
+        (data_len / block_align as usize) as u64
+    };
+
+    Ok(AudioHeader {
+        container: AudioContainer::Wav,
+        codec: codec.to_string(),
+        sample_rate,
+        channels,
+        bits_per_sample: Some(bits as u32),
+        total_frames,
+        loop_points,
+    })
+}
+
+/// Reads `LOOPSTART`, `LOOPEND` and `LOOPLENGTH` tags from a Vorbis comment
+/// block, as written by most game audio tools.
+fn parse_vorbis_comment_loops(block: &[u8], total_frames: u64) -> Result<Vec<LoopPoint>> {
+    let mut reader = Reader::new(block, "Vorbis comment");
+    let vendor_len = reader.u32_le()? as usize;
+    reader.bytes(vendor_len)?;
+
+    let (mut start, mut end, mut length) = (None, None, None);
+    for _ in 0..reader.u32_le()? {
+        let len = reader.u32_le()? as usize;
+        let comment = String::from_utf8_lossy(reader.bytes(len)?);
+        let Some((key, value)) = comment.split_once('=') else {
+            continue;
+        };
+        let value = value.trim().parse::<u64>().ok();
+        match key.to_ascii_uppercase().as_str() {
+            "LOOPSTART" => start = value,
+            "LOOPEND" => end = value,
+            "LOOPLENGTH" => length = value,
+            _ => {}
+        }
+    }
+
+    Ok(start
+        .map(|start| LoopPoint {
+            start,
+            end: end.or(length.map(|l| start + l)).unwrap_or(total_frames),
+        })
+        .into_iter()
+        .collect())
+}
+
+fn parse_flac(data: &[u8]) -> Result<AudioHeader> {
+    let mut reader = Reader::new(data, "FLAC metadata");
+    reader.bytes(4)?;
+
+    let mut stream_info = None;
+    let mut comment = None;
+    loop {
+        let header = reader.u8()?;
+        let block_type = header & 0x7F;
+        let len = reader.u24_be()? as usize;
+        let block = reader.bytes(len)?;
+
+        match block_type {
+            0 => {
+                ensure!(len >= 18, "Invalid FLAC STREAMINFO: block is {} bytes", len);
+                stream_info = Some(block);
+            }
+            4 => comment = Some(block),
+            127 => bail!("Invalid FLAC metadata block type 127"),
+            _ => {}
+        }
+        if stream_info.is_none() {
+            bail!("Invalid FLAC file: first metadata block is not STREAMINFO");
+        }
+        if header & 0x80 != 0 {
+            break;
+        }
+    }
+
+    // STREAMINFO packs 20 bits of sample rate, 3 of channels - 1, 5 of
+    // bits per sample - 1 and 36 of total samples after the size fields
+    let info = stream_info.unwrap_or_default();
+    let packed = u64::from_be_bytes(info[10..18].try_into()?);
+    let sample_rate = (packed >> 44) as u32;
+    let channels = ((packed >> 41) & 0x7) as u16 + 1;
+    let bits = ((packed >> 36) & 0x1F) as u32 + 1;
+    let total_frames = packed & 0xF_FFFF_FFFF;
+    ensure!(sample_rate > 0, "Invalid FLAC STREAMINFO: zero sample rate");
+    ensure!(reader.remaining() > 0, "Truncated FLAC file: no audio frames after metadata");
+
+    let loop_points = match comment {
+        Some(block) => parse_vorbis_comment_loops(block, total_frames)?,
+        None => Vec::new(),
+    };
+
+    Ok(AudioHeader {
+        container: AudioContainer::Flac,
+        codec: "flac".to_string(),
+        sample_rate,
+        channels,
+        bits_per_sample: Some(bits),
+        total_frames,
+        loop_points,
+    })
+}
+
+struct OggPage<'a> {
+    granule: u64,
+    serial: u32,
+    segments: &'a [u8],
+    body: &'a [u8],
+}
+
+fn read_ogg_page<'a>(reader: &mut Reader<'a>) -> Result<OggPage<'a>> {
+    ensure!(reader.bytes(4)? == b"OggS", "Invalid Ogg file: missing page capture pattern at offset {}", reader.pos - 4);
+    ensure!(reader.u8()? == 0, "Unsupported Ogg stream structure version");
+    reader.u8()?;
+    let granule = reader.u64_le()?;
+    let serial = reader.u32_le()?;
+    reader.bytes(8)?;
+    let count = reader.u8()? as usize;
+    let segments = reader.bytes(count)?;
+    let body_len = segments.iter().map(|&s| s as usize).sum();
+    let body = reader.bytes(body_len)?;
+    Ok(OggPage { granule, serial, segments, body })
+}
+
+fn parse_ogg(data: &[u8]) -> Result<AudioHeader> {
+    let mut reader = Reader::new(data, "Ogg page");
+
+    // Reassemble the first two packets of the first logical stream: the
+    // identification header and the comment header
+    let mut serial = None;
+    let mut packets: Vec<Vec<u8>> = vec![Vec::new()];
+    while packets.len() <= 2 && reader.remaining() > 0 {
+        let page = read_ogg_page(&mut reader)?;
+        if *serial.get_or_insert(page.serial) != page.serial {
+            continue;
+        }
+        let mut offset = 0;
+        for &segment in page.segments {
+            packets.last_mut().unwrap().extend_from_slice(&page.body[offset..offset + segment as usize]);
+            offset += segment as usize;
+            if segment < 255 {
+                packets.push(Vec::new());
+            }
+        }
+    }
+    ensure!(packets.len() > 2, "Truncated Ogg file: stream ends inside its headers");
+
+    // The last page's granule position gives the stream length
+    let serial = serial.unwrap_or_default();
+    let mut last_granule = 0;
+    while reader.remaining() > 0 {
+        let page = read_ogg_page(&mut reader)?;
+        if page.serial == serial && page.granule != u64::MAX {
+            last_granule = page.granule;
+        }
+    }
+
+    let ident = &packets[0];
+    if ident.starts_with(b"\x01vorbis") {
+        let mut header = Reader::new(&ident[7..], "Vorbis identification header");
+        ensure!(header.u32_le()? == 0, "Unsupported Vorbis version");
+        let channels = header.u8()? as u16;
+        let sample_rate = header.u32_le()?;
+        ensure!(channels > 0 && sample_rate > 0, "Invalid Vorbis identification header");
+
+        let comment = &packets[1];
+        ensure!(comment.starts_with(b"\x03vorbis"), "Invalid Ogg Vorbis file: missing comment header");
+
+        Ok(AudioHeader {
+            container: AudioContainer::OggVorbis,
+            codec: "vorbis".to_string(),
+            sample_rate,
+            channels,
+            bits_per_sample: None,
+            total_frames: last_granule,
+            loop_points: parse_vorbis_comment_loops(&comment[7..], last_granule)?,
+        })
+    } else if ident.starts_with(b"OpusHead") {
+        let mut header = Reader::new(&ident[8..], "Opus identification header");
+        header.u8()?;
+        let channels = header.u8()? as u16;
+        let pre_skip = header.u16_le()? as u64;
+        ensure!(channels > 0, "Invalid Opus identification header: zero channels");
+
+        let comment = &packets[1];
+        ensure!(comment.starts_with(b"OpusTags"), "Invalid Ogg Opus file: missing OpusTags header");
+
+        // Opus always decodes at 48 kHz; granule positions include the pre-skip
+        let total_frames = last_granule.saturating_sub(pre_skip);
+        Ok(AudioHeader {
+            container: AudioContainer::OggOpus,
+            codec: "opus".to_string(),
+            sample_rate: 48_000,
+            channels,
+            bits_per_sample: None,
+            total_frames,
+            loop_points: parse_vorbis_comment_loops(&comment[8..], total_frames)?,
+        })
+    } else {
+        bail!("Unsupported Ogg codec: only Vorbis and Opus streams are recognized")
+    }
+}
+
+struct Mp3Frame {
+    sample_rate: u32,
+    channels: u16,
+    samples: u32,
+    len: usize,
+    layer: u8,
+}
+
+fn parse_mp3_frame(header: &[u8]) -> Option<Mp3Frame> {
+    const BITRATES_V1: [[u32; 15]; 3] = [
+        [0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448],
+        [0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384],
+        [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320],
+    ];
+    const BITRATES_V2: [[u32; 15]; 3] = [
+        [0, 32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256],
+        [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+        [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+    ];
+
+    if header.len() < 4 || header[0] != 0xFF || header[1] & 0xE0 != 0xE0 {
+        return None;
+    }
+
+    let version = (header[1] >> 3) & 0x3; // 0: MPEG 2.5, 2: MPEG 2, 3: MPEG 1
+    let layer = match (header[1] >> 1) & 0x3 {
+        3 => 1,
+        2 => 2,
+        1 => 3,
+        _ => return None,
+    };
+    let bitrate_index = (header[2] >> 4) as usize;
+    let rate_index = ((header[2] >> 2) & 0x3) as usize;
+    if version == 1 || bitrate_index == 0 || bitrate_index == 15 || rate_index == 3 {
+        return None;
+    }
+
+    let base_rate = [44_100, 48_000, 32_000][rate_index];
+    let sample_rate = match version {
+        3 => base_rate,
+        2 => base_rate / 2,
+        _ => base_rate / 4,
+    };
+    let table = if version == 3 { &BITRATES_V1 } else { &BITRATES_V2 };
+    let bitrate = table[layer as usize - 1][bitrate_index] * 1000;
+    let padding = ((header[2] >> 1) & 0x1) as u32;
+    let channels = if header[3] >> 6 == 3 { 1 } else { 2 };
+
+    let (samples, len) = match layer {
+        1 => (384, (12 * bitrate / sample_rate + padding) * 4),
+        2 => (1152, 144 * bitrate / sample_rate + padding),
+        _ if version == 3 => (1152, 144 * bitrate / sample_rate + padding),
+        _ => (576, 72 * bitrate / sample_rate + padding),
+    };
+
+    Some(Mp3Frame {
+        sample_rate,
+        channels,
+        samples,
+        len: len as usize,
+        layer,
+    })
+}
+
+/// Whether a Layer III frame holds a Xing, Info or VBRI header instead of
+/// audio. The first two follow the side information, the last 32 bytes in.
+fn is_vbr_info_frame(frame: &[u8], info: &Mp3Frame) -> bool {
+    if info.layer != 3 {
+        return false;
+    }
+    let mpeg1 = info.sample_rate >= 32_000;
+    let side_info = match (mpeg1, info.channels) {
+        (true, 1) => 17,
+        (true, _) => 32,
+        (false, 1) => 9,
+        (false, _) => 17,
+    };
+    let at = |offset: usize, tag: &[u8]| frame.get(offset..offset + tag.len()) == Some(tag);
+    at(4 + side_info, b"Xing") || at(4 + side_info, b"Info") || at(36, b"VBRI")
+}
+
+fn parse_mp3(data: &[u8]) -> Result<AudioHeader> {
+    let mut pos = 0;
+    if data.starts_with(b"ID3") {
+        ensure!(data.len() >= 10, "Truncated ID3v2 tag");
+        // Tag sizes are syncsafe: 7 bits per byte
+        let size = data[6..10].iter().fold(0usize, |size, &b| (size << 7) | (b & 0x7F) as usize);
+        let footer = if data[5] & 0x10 != 0 { 10 } else { 0 };
+        pos = 10 + size + footer;
+        ensure!(pos < data.len(), "Truncated MP3 file: no audio after ID3v2 tag");
+    }
+
+    let first = parse_mp3_frame(&data[pos..])
+        .ok_or_else(|| anyhow!("Invalid MP3 file: no MPEG audio frame at offset {}", pos))?;
+
+    let mut total_frames = 0u64;
+    let mut frames = 0;
+    while pos < data.len() {
+        // APEv2, Lyrics3 and ID3v1 tags may follow the last frame
+        if [&b"TAG"[..], b"APETAGEX", b"LYRICSBEGIN"].iter().any(|tag| data[pos..].starts_with(tag)) {
+            break;
+        }
+        let frame = parse_mp3_frame(&data[pos..])
+            .ok_or_else(|| anyhow!("Invalid MP3 file: lost frame sync at offset {}", pos))?;
+        ensure!(
+            pos + frame.len <= data.len(),
+            "Truncated MP3 file: frame at offset {} needs {} bytes, only {} left",
+            pos,
+            frame.len,
+            data.len() - pos,
+        );
+        if frames > 0 || !is_vbr_info_frame(&data[pos..pos + frame.len], &frame) {
+            total_frames += frame.samples as u64;
+        }
+        frames += 1;
+        pos += frame.len;
+    }
+    ensure!(frames > 0, "Invalid MP3 file: no complete frames");
+
+    Ok(AudioHeader {
+        container: AudioContainer::Mp3,
+        codec: format!("mp{}", first.layer),
+        sample_rate: first.sample_rate,
+        channels: first.channels,
+        bits_per_sample: None,
+        total_frames,
+        loop_points: Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(body);
+        chunk
+    }
+
+    fn wav(frames: u32, loops: &[(u32, u32)]) -> Vec<u8> {
+        let mut fmt = Vec::new();
+        for value in [1u16, 2] {
+            fmt.extend_from_slice(&value.to_le_bytes());
+        }
+        fmt.extend_from_slice(&44_100u32.to_le_bytes());
+        fmt.extend_from_slice(&(44_100u32 * 4).to_le_bytes());
+        fmt.extend_from_slice(&4u16.to_le_bytes());
+        fmt.extend_from_slice(&16u16.to_le_bytes());
+
+        let mut smpl = vec![0u8; 28];
+        smpl.extend_from_slice(&(loops.len() as u32).to_le_bytes());
+        smpl.extend_from_slice(&0u32.to_le_bytes());
+        for &(start, end) in loops {
+            smpl.extend_from_slice(&[0; 8]);
+            smpl.extend_from_slice(&start.to_le_bytes());
+            smpl.extend_from_slice(&end.to_le_bytes());
+            smpl.extend_from_slice(&[0; 8]);
+        }
+
+        let mut body = b"WAVE".to_vec();
+        body.extend(chunk(b"fmt ", &fmt));
+        body.extend(chunk(b"smpl", &smpl));
+        body.extend(chunk(b"data", &vec![0; frames as usize * 4]));
+        chunk(b"RIFF", &body)
+    }
+
+    #[test]
+    fn test_parse_wav_header() {
+        let header = parse_audio_header(&wav(22_050, &[(100, 199)])).unwrap();
+        assert_eq!(header.container, AudioContainer::Wav);
+        assert_eq!((header.sample_rate, header.channels, header.bits_per_sample), (44_100, 2, Some(16)));
+        assert_eq!(header.total_frames, 22_050);
+        assert!((header.duration_secs() - 0.5).abs() < 1e-9);
+        assert_eq!(header.loop_points, vec![LoopPoint { start: 100, end: 200 }]);
+
+        let mut truncated = wav(22_050, &[]);
+        truncated.truncate(truncated.len() - 10);
+        let error = parse_audio_header(&truncated).unwrap_err().to_string();
+        assert!(error.starts_with("Truncated WAV file"), "{}", error);
+
+        assert!(parse_audio_header(b"not audio at all").is_err());
+    }
+
+    #[test]
+    fn test_parse_flac_header() {
+        let mut flac = b"fLaC".to_vec();
+        flac.push(0x80); // last block, STREAMINFO
+        flac.extend_from_slice(&[0, 0, 34]);
+        flac.extend_from_slice(&[0; 10]);
+        // 48000 Hz, 2 channels, 24 bits, 96000 samples
+        let packed: u64 = (48_000 << 44) | (1 << 41) | (23 << 36) | 96_000;
+        flac.extend_from_slice(&packed.to_be_bytes());
+        flac.extend_from_slice(&[0; 16]);
+        flac.extend_from_slice(&[0xFF, 0xF8]);
+
+        let header = parse_audio_header(&flac).unwrap();
+        assert_eq!((header.sample_rate, header.channels, header.bits_per_sample), (48_000, 2, Some(24)));
+        assert!((header.duration_secs() - 2.0).abs() < 1e-9);
+    }
+
+    fn ogg_page(granule: u64, packets: &[&[u8]]) -> Vec<u8> {
+        let mut segments = Vec::new();
+        let mut body = Vec::new();
+        for packet in packets {
+            segments.extend(std::iter::repeat_n(255u8, packet.len() / 255));
+            segments.push((packet.len() % 255) as u8);
+            body.extend_from_slice(packet);
+        }
+
+        let mut page = b"OggS\0\0".to_vec();
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&[1, 0, 0, 0]);
+        page.extend_from_slice(&[0; 8]);
+        page.push(segments.len() as u8);
+        page.extend(segments);
+        page.extend(body);
+        page
+    }
+
+    #[test]
+    fn test_parse_ogg_vorbis_header() {
+        let mut ident = b"\x01vorbis".to_vec();
+        ident.extend_from_slice(&0u32.to_le_bytes());
+        ident.push(2);
+        ident.extend_from_slice(&44_100u32.to_le_bytes());
+        ident.extend_from_slice(&[0; 14]);
+
+        let mut comment = b"\x03vorbis".to_vec();
+        comment.extend_from_slice(&4u32.to_le_bytes());
+        comment.extend_from_slice(b"test");
+        comment.extend_from_slice(&2u32.to_le_bytes());
+        for tag in [&b"LOOPSTART=1000"[..], b"LOOPLENGTH=500"] {
+            comment.extend_from_slice(&(tag.len() as u32).to_le_bytes());
+            comment.extend_from_slice(tag);
+        }
+
+        let mut ogg = ogg_page(0, &[&ident]);
+        ogg.extend(ogg_page(0, &[&comment, b"\x05vorbis setup"]));
+        ogg.extend(ogg_page(88_200, &[b"audio"]));
+
+        let header = parse_audio_header(&ogg).unwrap();
+        assert_eq!(header.container, AudioContainer::OggVorbis);
+        assert_eq!((header.sample_rate, header.channels), (44_100, 2));
+        assert!((header.duration_secs() - 2.0).abs() < 1e-9);
+        assert_eq!(header.loop_points, vec![LoopPoint { start: 1000, end: 1500 }]);
+
+        let error = parse_audio_header(&ogg[..ogg.len() - 3]).unwrap_err().to_string();
+        assert!(error.starts_with("Truncated Ogg page"), "{}", error);
+    }
+
+    #[test]
+    fn test_parse_ogg_opus_header() {
+        let mut ident = b"OpusHead\x01\x02".to_vec();
+        ident.extend_from_slice(&312u16.to_le_bytes());
+        ident.extend_from_slice(&44_100u32.to_le_bytes());
+        ident.extend_from_slice(&[0, 0, 0]);
+
+        let mut comment = b"OpusTags".to_vec();
+        comment.extend_from_slice(&4u32.to_le_bytes());
+        comment.extend_from_slice(b"test");
+        comment.extend_from_slice(&1u32.to_le_bytes());
+        comment.extend_from_slice(&(b"LOOPSTART=4800".len() as u32).to_le_bytes());
+        comment.extend_from_slice(b"LOOPSTART=4800");
+
+        let mut ogg = ogg_page(0, &[&ident]);
+        ogg.extend(ogg_page(0, &[&comment]));
+        ogg.extend(ogg_page(96_312, &[b"audio"]));
+
+        // Always 48 kHz, without the pre-skip
+        let header = parse_audio_header(&ogg).unwrap();
+        assert_eq!((header.container, header.codec.as_str()), (AudioContainer::OggOpus, "opus"));
+        assert_eq!((header.sample_rate, header.channels, header.total_frames), (48_000, 2, 96_000));
+        assert_eq!(header.loop_points, vec![LoopPoint { start: 4800, end: 96_000 }]);
+    }
+
+    #[test]
+    fn test_parse_mp3_header() {
+        // MPEG-1 Layer III, 128 kbps, 44.1 kHz, joint stereo: 417 bytes a frame
+        let frame = |body: &[u8]| {
+            let mut frame = vec![0xFF, 0xFB, 0x90, 0x40];
+            frame.extend_from_slice(body);
+            frame.resize(417, 0);
+            frame
+        };
+        let mut info = vec![0; 32];
+        info.extend_from_slice(b"Info");
+
+        let mut mp3 = b"ID3\x04\x00\x00\x00\x00\x00\x04TIT2".to_vec();
+        mp3.extend(frame(&info));
+        (0..10).for_each(|_| mp3.extend(frame(&[])));
+        let audio_end = mp3.len();
+
+        let header = parse_audio_header(&mp3).unwrap();
+        assert_eq!((header.container, header.codec.as_str()), (AudioContainer::Mp3, "mp3"));
+        assert_eq!((header.sample_rate, header.channels), (44_100, 2));
+        // The Info frame carries no audio
+        assert_eq!(header.total_frames, 10 * 1152);
+
+        // Trailing tags end the scan
+        for tags in [&b"APETAGEX\xd0\x07\x00\x00"[..], b"LYRICSBEGININD00002"] {
+            mp3.truncate(audio_end);
+            mp3.extend_from_slice(tags);
+            mp3.extend_from_slice(b"TAG");
+            mp3.resize(mp3.len() + 125, 0);
+            assert_eq!(parse_audio_header(&mp3).unwrap().total_frames, 10 * 1152);
+        }
+
+        mp3.truncate(audio_end - 100);
+        let error = parse_audio_header(&mp3).unwrap_err().to_string();
+        assert!(error.starts_with("Truncated MP3 file"), "{}", error);
+    }
+}
@@ -0,0 +1,240 @@
+use super::{AudioProcessor, DecodedAudio, TextureProcessor};
+use anyhow::{bail, Result};
+use image::{Rgb, RgbImage};
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
+const BACKGROUND: Rgb<u8> = Rgb([24, 24, 28]);
+const CENTRE_LINE: Rgb<u8> = Rgb([60, 60, 68]);
+const PEAK_COLOR: Rgb<u8> = Rgb([70, 130, 200]);
+const RMS_COLOR: Rgb<u8> = Rgb([140, 200, 255]);
+
+/// Quietest level shown in the spectrogram; anything below is black.
+const SPECTROGRAM_FLOOR_DB: f64 = -100.0;
+
+/// Kinds of derived objects linked to a source audio object.
+pub const WAVEFORM_PREVIEW: &str = "waveform";
+pub const SPECTROGRAM_PREVIEW: &str = "spectrogram";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct WaveformOptions {
+    pub width: u32,
+    /// Height of each channel's lane.
+    pub channel_height: u32,
+}
+
+impl Default for WaveformOptions {
+    fn default() -> Self {
+        Self { width: 1024, channel_height: 128 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SpectrogramOptions {
+    pub width: u32,
+    pub height: u32,
+    /// FFT length in samples; must be a power of two.
+    pub fft_size: usize,
+}
+
+impl Default for SpectrogramOptions {
+    fn default() -> Self {
+        Self { width: 1024, height: 256, fft_size: 2048 }
+    }
+}
+
+impl AudioProcessor {
+    /// A PNG with one lane per channel showing each column's peak range and,
+    /// brighter, its RMS level.
+    pub fn render_waveform(&self, data: &[u8], options: &WaveformOptions) -> Result<Vec<u8>> {
+        let audio = self.decode(data)?;
+        TextureProcessor::encode_png(waveform_image(&audio, options)?)
+    }
+
+    /// A PNG of the mono mixdown's short-time spectrum, time running left to
+    /// right and frequency upwards on a linear scale.
+    pub fn render_spectrogram(&self, data: &[u8], options: &SpectrogramOptions) -> Result<Vec<u8>> {
+        let audio = self.decode(data)?;
+        TextureProcessor::encode_png(spectrogram_image(&audio, options)?)
+    }
+}
+
+pub fn waveform_image(audio: &DecodedAudio, options: &WaveformOptions) -> Result<RgbImage> {
+    if options.width == 0 || options.channel_height < 2 {
+        bail!("Waveform must be at least 1 pixel wide and 2 pixels high per channel");
+    }
+
+    let channels = audio.channels.max(1) as usize;
+    let lane = options.channel_height;
+    let mut image = RgbImage::from_pixel(options.width, lane * channels as u32, BACKGROUND);
+    let frames = audio.frames();
+    let half = (lane - 1) as f32 / 2.0;
+    let to_row = |top: u32, value: f32| top + (half - value.clamp(-1.0, 1.0) * half).round() as u32;
+
+    for c in 0..channels {
+        let top = c as u32 * lane;
+        for x in 0..options.width {
+            image.put_pixel(x, to_row(top, 0.0), CENTRE_LINE);
+
+            let start = frames * x as usize / options.width as usize;
+            let end = (frames * (x as usize + 1) / options.width as usize).max(start + 1).min(frames);
+            if start >= end {
+                continue;
+            }
+
+            let column = (start..end).map(|frame| audio.samples[frame * channels + c]);
+            let (min, max, energy) = column.fold((f32::MAX, f32::MIN, 0.0f64), |(min, max, energy), s| {
+                (min.min(s), max.max(s), energy + (s as f64) * (s as f64))
+            });
+            let rms = (energy / (end - start) as f64).sqrt() as f32;
+
+            for y in to_row(top, max)..=to_row(top, min) {
+                image.put_pixel(x, y, PEAK_COLOR);
+            }
+            for y in to_row(top, rms.min(max))..=to_row(top, (-rms).max(min)) {
+                image.put_pixel(x, y, RMS_COLOR);
+            }
+        }
+    }
+
+    Ok(image)
+}
+
+pub fn spectrogram_image(audio: &DecodedAudio, options: &SpectrogramOptions) -> Result<RgbImage> {
+    if options.width == 0 || options.height == 0 {
+        bail!("Spectrogram dimensions must be non-zero");
+    }
+    if !options.fft_size.is_power_of_two() || options.fft_size < 16 {
+        bail!("FFT size must be a power of two of at least 16, not {}", options.fft_size);
+    }
+
+    let mono = audio.to_mono();
+    let n = options.fft_size;
+    let bins = n / 2;
+    let window: Vec<f64> = (0..n).map(|i| 0.5 - 0.5 * (2.0 * PI * i as f64 / n as f64).cos()).collect();
+    // Hann window coherent gain, so a full-scale sine reads 0 dB
+    let scale = 2.0 / window.iter().sum::<f64>();
+
+    let mut image = RgbImage::from_pixel(options.width, options.height, Rgb([0, 0, 0]));
+    let mut re = vec![0.0; n];
+    let mut im = vec![0.0; n];
+
+    for x in 0..options.width {
+        // Centre each transform on its column
+        let centre = (mono.len() as f64 * (x as f64 + 0.5) / options.width as f64) as isize;
+        for (i, (re, im)) in re.iter_mut().zip(im.iter_mut()).enumerate() {
+            let index = centre - (n / 2) as isize + i as isize;
+            let sample = usize::try_from(index).ok().and_then(|i| mono.get(i)).copied().unwrap_or(0.0);
+            *re = sample as f64 * window[i];
+            *im = 0.0;
+        }
+        fft(&mut re, &mut im);
+
+        for y in 0..options.height {
+            // Every bin a pixel row covers; take the loudest
+            let row = (options.height - 1 - y) as usize;
+            let first = row * bins / options.height as usize;
+            let last = ((row + 1) * bins / options.height as usize).max(first + 1);
+            let magnitude = (first..last)
+                .map(|k| (re[k] * re[k] + im[k] * im[k]).sqrt() * scale)
+                .fold(0.0, f64::max);
+
+            let db = 20.0 * magnitude.max(1e-12).log10();
+            let level = ((db - SPECTROGRAM_FLOOR_DB) / -SPECTROGRAM_FLOOR_DB).clamp(0.0, 1.0);
+            image.put_pixel(x, y, heat_color(level));
+        }
+    }
+
+    Ok(image)
+}
+
+/// Black through purple and orange to pale yellow.
+fn heat_color(level: f64) -> Rgb<u8> {
+    const STOPS: [[f64; 3]; 5] = [
+        [0.0, 0.0, 0.0],
+        [80.0, 20.0, 120.0],
+        [200.0, 50.0, 80.0],
+        [250.0, 150.0, 30.0],
+        [255.0, 250.0, 200.0],
+    ];
+    let position = level * (STOPS.len() - 1) as f64;
+    let i = (position as usize).min(STOPS.len() - 2);
+    let t = position - i as f64;
+    let channel = |c: usize| (STOPS[i][c] + (STOPS[i + 1][c] - STOPS[i][c]) * t).round() as u8;
+    Rgb([channel(0), channel(1), channel(2)])
+}
+
+/// In-place iterative radix-2 FFT; `re.len()` must be a power of two.
+fn fft(re: &mut [f64], im: &mut [f64]) {
+    let n = re.len();
+
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f64;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (w_re, w_im) = ((angle * k as f64).cos(), (angle * k as f64).sin());
+                let (a, b) = (start + k, start + k + len / 2);
+                let t_re = re[b] * w_re - im[b] * w_im;
+                let t_im = re[b] * w_im + im[b] * w_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_previews() {
+        let rate = 8000;
+        let samples: Vec<f32> = (0..rate)
+            .flat_map(|i| {
+                let s = (2.0 * PI * 1000.0 * i as f64 / rate as f64).sin() as f32;
+                [s * 0.5, 0.0]
+            })
+            .collect();
+        let audio = DecodedAudio {
+            codec: "pcm_f32le".to_string(),
+            sample_rate: rate,
+            channels: 2,
+            bits_per_sample: None,
+            samples,
+        };
+
+        let waveform = waveform_image(&audio, &WaveformOptions { width: 64, channel_height: 33 }).unwrap();
+        assert_eq!(waveform.dimensions(), (64, 66));
+        // The tone peaks a quarter of the lane above centre; the silent
+        // channel is a flat line
+        assert_eq!(waveform.get_pixel(10, 8), &PEAK_COLOR);
+        assert_eq!(waveform.get_pixel(10, 16), &RMS_COLOR);
+        assert_eq!(waveform.get_pixel(10, 33 + 8), &BACKGROUND);
+        assert_eq!(waveform.get_pixel(10, 33 + 16), &RMS_COLOR);
+
+        let options = SpectrogramOptions { width: 16, height: 64, fft_size: 256 };
+        let spectrogram = spectrogram_image(&audio, &options).unwrap();
+        // 1 kHz is a quarter of the 4 kHz Nyquist band
+        let brightest = (0..64).max_by_key(|&y| spectrogram.get_pixel(8, y).0.iter().map(|&c| c as u32).sum::<u32>());
+        assert_eq!(brightest, Some(64 - 1 - 16));
+    }
+}
@@ -0,0 +1,378 @@
+use super::DecodedAudio;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
+/// Zero crossings of the resampling kernel on each side of its centre.
+const RESAMPLE_LOBES: usize = 16;
+
+/// EBU R128 gating block length and hop.
+const LOUDNESS_BLOCK_SECS: f64 = 0.4;
+const LOUDNESS_STEP_SECS: f64 = 0.1;
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+const RELATIVE_GATE_LU: f64 = -10.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioTargetFormat {
+    /// 16-bit PCM WAV.
+    Wav16,
+    /// 16-bit FLAC.
+    Flac,
+    /// Ogg Vorbis. Recognized so that settings asking for it fail with a
+    /// clear error; there is no Vorbis encoder yet.
+    Vorbis,
+}
+
+/// Per-asset or per-directory audio import settings. Fields left unset keep
+/// the source's value, or take the value of a fallback section merged in
+/// with [`or`](Self::or). Transcoding needs a format from one of them.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AudioTranscodeSettings {
+    #[serde(default)]
+    pub format: Option<AudioTargetFormat>,
+    #[serde(default)]
+    pub sample_rate: Option<u32>,
+    /// 1 or 2; sources with more channels are folded down.
+    #[serde(default)]
+    pub channels: Option<u16>,
+    /// Integrated loudness target in LUFS, e.g. -23 for EBU R128 or -16 for
+    /// streaming.
+    #[serde(default)]
+    pub loudness_lufs: Option<f64>,
+    /// Ceiling for the sample peak after gain, in dBFS; -1 by default.
+    #[serde(default)]
+    pub max_peak_db: Option<f64>,
+}
+
+impl AudioTranscodeSettings {
+    pub fn new(format: AudioTargetFormat) -> Self {
+        Self { format: Some(format), ..Default::default() }
+    }
+
+    /// Fills fields missing from `self` with those from `fallback`.
+    pub fn or(self, fallback: AudioTranscodeSettings) -> AudioTranscodeSettings {
+        AudioTranscodeSettings {
+            format: self.format.or(fallback.format),
+            sample_rate: self.sample_rate.or(fallback.sample_rate),
+            channels: self.channels.or(fallback.channels),
+            loudness_lufs: self.loudness_lufs.or(fallback.loudness_lufs),
+            max_peak_db: self.max_peak_db.or(fallback.max_peak_db),
+        }
+    }
+
+    pub fn max_peak_db(&self) -> f64 {
+        self.max_peak_db.unwrap_or(-1.0)
+    }
+}
+
+/// Mixes interleaved audio to one or two channels. Stereo folds follow
+/// ITU-R BS.775: centre and surrounds at -3 dB, LFE dropped.
+pub fn downmix(audio: &DecodedAudio, channels: u16) -> Result<DecodedAudio> {
+    if channels == audio.channels {
+        return Ok(audio.clone());
+    }
+    if !(1..=2).contains(&channels) {
+        bail!("Can only mix down to 1 or 2 channels, not {}", channels);
+    }
+
+    let source = audio.channels.max(1) as usize;
+    let mut samples = Vec::with_capacity(audio.frames() * channels as usize);
+    let centre = std::f32::consts::FRAC_1_SQRT_2;
+
+    for frame in audio.samples.chunks_exact(source) {
+        let (left, right) = match frame {
+            [mono] => (*mono, *mono),
+            [l, r] => (*l, *r),
+            [l, r, c] => (l + centre * c, r + centre * c),
+            [l, r, ls, rs] => (l + centre * ls, r + centre * rs),
+            [l, r, c, _lfe, ls, rs, ..] => (l + centre * (c + ls), r + centre * (c + rs)),
+            [l, r, c, ..] => (l + centre * c, r + centre * c),
+            [] => unreachable!(),
+        };
+        if channels == 1 {
+            samples.push((left + right) * 0.5);
+        } else {
+            samples.extend([left, right]);
+        }
+    }
+
+    Ok(DecodedAudio {
+        channels,
+        samples,
+        ..audio.clone()
+    })
+}
+
+/// Band-limited resampling with a Blackman-windowed sinc kernel, applied to
+/// each channel. `rate` must be above 0.
+pub fn resample_sinc(audio: &DecodedAudio, rate: u32) -> DecodedAudio {
+    if rate == audio.sample_rate || audio.frames() == 0 {
+        return DecodedAudio { sample_rate: rate, ..audio.clone() };
+    }
+
+    let channels = audio.channels.max(1) as usize;
+    let in_frames = audio.frames();
+    let ratio = rate as f64 / audio.sample_rate as f64;
+    let out_frames = (in_frames as f64 * ratio).round() as usize;
+    // Lower the cutoff below the new Nyquist frequency when downsampling
+    let cutoff = ratio.min(1.0);
+    let half_width = RESAMPLE_LOBES as f64 / cutoff;
+
+    let mut samples = vec![0.0f32; out_frames * channels];
+    for (i, frame) in samples.chunks_exact_mut(channels).enumerate() {
+        let centre = i as f64 / ratio;
+        let first = (centre - half_width).ceil().max(0.0) as usize;
+        let last = ((centre + half_width).floor() as usize).min(in_frames - 1);
+
+        let mut acc = vec![0.0f64; channels];
+        let mut norm = 0.0;
+        for j in first..=last {
+            let x = j as f64 - centre;
+            let weight = sinc(x * cutoff) * blackman(x / half_width);
+            norm += weight;
+            for (c, acc) in acc.iter_mut().enumerate() {
+                *acc += audio.samples[j * channels + c] as f64 * weight;
+            }
+        }
+        // Normalizing keeps DC gain at 1, also near the edges
+        for (out, acc) in frame.iter_mut().zip(acc) {
+            *out = if norm != 0.0 { (acc / norm) as f32 } else { 0.0 };
+        }
+    }
+
+    DecodedAudio {
+        sample_rate: rate,
+        samples,
+        ..audio.clone()
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-9 { 1.0 } else { (PI * x).sin() / (PI * x) }
+}
+
+/// Blackman window over `[-1, 1]`.
+fn blackman(x: f64) -> f64 {
+    if x.abs() > 1.0 {
+        return 0.0;
+    }
+    let t = (x + 1.0) * 0.5;
+    0.42 - 0.5 * (2.0 * PI * t).cos() + 0.08 * (4.0 * PI * t).cos()
+}
+
+#[derive(Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+}
+
+impl Biquad {
+    fn run(&self, input: impl Iterator<Item = f64>) -> Vec<f64> {
+        let (mut x1, mut x2, mut y1, mut y2) = (0.0, 0.0, 0.0, 0.0);
+        input
+            .map(|x| {
+                let y = self.b[0] * x + self.b[1] * x1 + self.b[2] * x2 - self.a[0] * y1 - self.a[1] * y2;
+                (x2, x1, y2, y1) = (x1, x, y1, y);
+                y
+            })
+            .collect()
+    }
+}
+
+/// The two ITU-R BS.1770 K-weighting stages for any sample rate.
+fn k_weighting(rate: u32) -> [Biquad; 2] {
+    let fs = rate as f64;
+
+    let (f0, gain_db, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (PI * f0 / fs).tan();
+    let vh = 10f64.powf(gain_db / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    };
+
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (PI * f0 / fs).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let highpass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    };
+
+    [shelf, highpass]
+}
+
+/// BS.1770 channel weights, assuming the usual L R C LFE Ls Rs order, or
+/// L R Ls Rs for four channels.
+fn channel_weight(channels: u16, channel: usize) -> f64 {
+    match (channels, channel) {
+        (_, 0..=1) => 1.0,
+        (4, _) => 1.41,
+        (6.., 3) => 0.0,
+        (_, 2) => 1.0,
+        _ => 1.41,
+    }
+}
+
+/// Gated integrated loudness in LUFS per EBU R128, or `None` when the
+/// programme is entirely below the absolute gate.
+pub fn integrated_loudness(audio: &DecodedAudio) -> Option<f64> {
+    let channels = audio.channels.max(1) as usize;
+    let frames = audio.frames();
+    if frames == 0 {
+        return None;
+    }
+
+    let [shelf, highpass] = k_weighting(audio.sample_rate);
+    let weighted: Vec<Vec<f64>> = (0..channels)
+        .map(|c| {
+            let channel = audio.samples.iter().skip(c).step_by(channels).map(|&s| s as f64);
+            highpass.run(shelf.run(channel).into_iter())
+        })
+        .collect();
+
+    let block = ((LOUDNESS_BLOCK_SECS * audio.sample_rate as f64) as usize).clamp(1, frames);
+    let step = ((LOUDNESS_STEP_SECS * audio.sample_rate as f64) as usize).max(1);
+
+    // Weighted mean square of every gating block
+    let powers: Vec<f64> = (0..=frames - block)
+        .step_by(step)
+        .map(|start| {
+            weighted
+                .iter()
+                .enumerate()
+                .map(|(c, samples)| {
+                    let energy: f64 = samples[start..start + block].iter().map(|s| s * s).sum();
+                    channel_weight(audio.channels, c) * energy / block as f64
+                })
+                .sum()
+        })
+        .collect();
+
+    let loudness = |power: f64| -0.691 + 10.0 * power.log10();
+    let gated_mean = |threshold: f64| {
+        let gated: Vec<f64> = powers.iter().copied().filter(|&p| loudness(p) > threshold).collect();
+        (!gated.is_empty()).then(|| gated.iter().sum::<f64>() / gated.len() as f64)
+    };
+
+    let relative_gate = loudness(gated_mean(ABSOLUTE_GATE_LUFS)?) + RELATIVE_GATE_LU;
+    gated_mean(relative_gate.max(ABSOLUTE_GATE_LUFS)).map(loudness)
+}
+
+/// Gain in dB that brings the audio to `target_lufs` without pushing its
+/// sample peak over `max_peak_db`.
+pub fn normalization_gain_db(audio: &DecodedAudio, target_lufs: f64, max_peak_db: f64) -> Option<f64> {
+    let measured = integrated_loudness(audio)?;
+    let gain = target_lufs - measured;
+
+    let peak = audio.peak();
+    if peak > 0.0 {
+        let headroom = max_peak_db - 20.0 * peak.log10();
+        if gain > headroom {
+            tracing::warn!(
+                "Limiting loudness gain from {:.1} dB to {:.1} dB to stay under {:.1} dBFS peak",
+                gain,
+                headroom,
+                max_peak_db
+            );
+            return Some(headroom);
+        }
+    }
+    Some(gain)
+}
+
+pub fn apply_gain(audio: &mut DecodedAudio, gain_db: f64) {
+    let gain = 10f64.powf(gain_db / 20.0) as f32;
+    audio.samples.iter_mut().for_each(|s| *s *= gain);
+}
+
+/// Rounds `[-1.0, 1.0]` samples to 16-bit integers.
+pub fn quantize_i16(samples: &[f32]) -> Vec<i16> {
+    samples
+        .iter()
+        .map(|&s| (s * 32767.0).round().clamp(-32768.0, 32767.0) as i16)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{encode_wav16, AudioProcessor};
+
+    fn sine(rate: u32, channels: u16, freq: f64, amplitude: f64, secs: f64) -> DecodedAudio {
+        let frames = (rate as f64 * secs) as usize;
+        let samples = (0..frames)
+            .flat_map(|i| {
+                let s = (amplitude * (2.0 * PI * freq * i as f64 / rate as f64).sin()) as f32;
+                std::iter::repeat_n(s, channels as usize)
+            })
+            .collect();
+        DecodedAudio {
+            codec: "pcm_f32le".to_string(),
+            sample_rate: rate,
+            channels,
+            bits_per_sample: None,
+            samples,
+        }
+    }
+
+    #[test]
+    fn test_loudness_and_resampling() {
+        // BS.1770 calibration: a 0 dBFS 1 kHz sine in one channel reads -3.01 LUFS
+        let tone = sine(48000, 1, 1000.0, 1.0, 5.0);
+        let loudness = integrated_loudness(&tone).unwrap();
+        assert!((loudness + 3.01).abs() < 0.05, "measured {loudness}");
+
+        let mut quiet = sine(48000, 2, 1000.0, 0.05, 5.0);
+        let gain = normalization_gain_db(&quiet, -23.0, -1.0).unwrap();
+        apply_gain(&mut quiet, gain);
+        assert!((integrated_loudness(&quiet).unwrap() + 23.0).abs() < 0.05);
+
+        // Peak ceiling wins over the loudness target
+        let gain = normalization_gain_db(&tone, 0.0, -1.0).unwrap();
+        assert!((gain + 1.0).abs() < 1e-6);
+
+        let silence = sine(48000, 1, 1000.0, 0.0, 1.0);
+        assert!(integrated_loudness(&silence).is_none());
+
+        let resampled = resample_sinc(&sine(44100, 2, 440.0, 0.5, 1.0), 22050);
+        assert_eq!(resampled.frames(), 22050);
+        assert!((resampled.peak() - 0.5).abs() < 0.01);
+
+        let mono = downmix(&resampled, 1).unwrap();
+        assert_eq!(mono.channels, 1);
+        assert_eq!(mono.frames(), 22050);
+    }
+
+    #[test]
+    fn test_edge_cases() {
+        // Less than one frame resamples to nothing rather than underflowing
+        let partial = DecodedAudio { samples: vec![0.5], ..sine(44100, 2, 440.0, 0.5, 0.0) };
+        assert_eq!(resample_sinc(&partial, 22050).frames(), 0);
+
+        let wav = encode_wav16(&[0, 1000, -1000, 0], 8000, 1);
+        let settings =
+            AudioTranscodeSettings { sample_rate: Some(0), ..AudioTranscodeSettings::new(AudioTargetFormat::Wav16) };
+        let error = AudioProcessor::new().transcode(&wav, &settings).unwrap_err();
+        assert!(error.to_string().contains("sample_rate"), "{}", error);
+        let vorbis: AudioTranscodeSettings = serde_json::from_str(r#"{"format": "vorbis"}"#).unwrap();
+        let error = AudioProcessor::new().transcode(&wav, &vorbis).unwrap_err();
+        assert!(error.to_string().contains("Vorbis"), "{}", error);
+        // An empty source fails rather than writing a FLAC that cannot be read back
+        let empty = encode_wav16(&[], 8000, 1);
+        let error = AudioProcessor::new().transcode(&empty, &AudioTranscodeSettings::new(AudioTargetFormat::Flac)).unwrap_err();
+        assert!(error.to_string().contains("without any samples"), "{}", error);
+
+        // Quad surrounds weigh 1.41, so the same tone reads 1.5 LU louder there
+        let tone = sine(48000, 1, 1000.0, 0.5, 2.0);
+        let quad = |channel: usize| {
+            let frame = |s: f32| -> [f32; 4] { std::array::from_fn(|c| if c == channel { s } else { 0.0 }) };
+            DecodedAudio { channels: 4, samples: tone.samples.iter().flat_map(|&s| frame(s)).collect(), ..tone.clone() }
+        };
+        let difference = integrated_loudness(&quad(2)).unwrap() - integrated_loudness(&quad(0)).unwrap();
+        assert!((difference - 10.0 * 1.41f64.log10()).abs() < 0.01, "{}", difference);
+    }
+}
@@ -0,0 +1,862 @@
+use anyhow::{bail, Result};
+use image::{Rgba32FImage, RgbaImage};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// BC7 mode 6 interpolation weights for 4-bit indices, out of 64.
+const BC7_WEIGHTS: [u32; 16] = [0, 4, 9, 13, 17, 21, 26, 30, 34, 38, 43, 47, 51, 55, 60, 64];
+
+/// Least-squares passes refining endpoints after the initial line fit.
+const REFINE_ITERATIONS: usize = 2;
+
+/// Bit pattern of the largest finite half float, the top of BC6H's unsigned
+/// range.
+const HALF_MAX_BITS: f32 = 31743.0;
+
+/// Pixel encodings a cooked texture can use. The BC formats work on 4x4
+/// blocks; edge blocks of images whose sides are not multiples of four
+/// repeat the last row or column.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockFormat {
+    /// Uncompressed 8-bit RGBA.
+    #[default]
+    Rgba8,
+    /// RGB with 1-bit alpha, 4 bits per pixel.
+    Bc1,
+    /// RGB with smooth alpha, 8 bits per pixel.
+    Bc3,
+    /// One channel, 4 bits per pixel, for masks and roughness maps.
+    Bc4,
+    /// Two channels, 8 bits per pixel, for tangent-space normal maps.
+    Bc5,
+    /// High-quality RGBA, 8 bits per pixel.
+    Bc7,
+    /// Uncompressed half-float RGBA for HDR textures.
+    Rgba16f,
+    /// Unsigned half-float RGB for HDR textures, 8 bits per pixel.
+    Bc6h,
+}
+
+impl BlockFormat {
+    pub const ALL: [BlockFormat; 8] = [
+        BlockFormat::Rgba8,
+        BlockFormat::Bc1,
+        BlockFormat::Bc3,
+        BlockFormat::Bc4,
+        BlockFormat::Bc5,
+        BlockFormat::Bc7,
+        BlockFormat::Rgba16f,
+        BlockFormat::Bc6h,
+    ];
+
+    /// Side of a block in pixels.
+    pub fn block_dimension(self) -> u32 {
+        match self {
+            BlockFormat::Rgba8 | BlockFormat::Rgba16f => 1,
+            _ => 4,
+        }
+    }
+
+    pub fn block_bytes(self) -> usize {
+        match self {
+            BlockFormat::Rgba8 => 4,
+            BlockFormat::Bc1 | BlockFormat::Bc4 | BlockFormat::Rgba16f => 8,
+            BlockFormat::Bc3 | BlockFormat::Bc5 | BlockFormat::Bc7 | BlockFormat::Bc6h => 16,
+        }
+    }
+
+    /// Whether the format stores values beyond 0 to 1.
+    pub fn is_float(self) -> bool {
+        matches!(self, BlockFormat::Rgba16f | BlockFormat::Bc6h)
+    }
+
+    /// Whether GPUs can decode the format from sRGB to linear on sampling.
+    pub fn supports_srgb(self) -> bool {
+        matches!(self, BlockFormat::Rgba8 | BlockFormat::Bc1 | BlockFormat::Bc3 | BlockFormat::Bc7)
+    }
+
+    /// Bytes needed for an image of the given size.
+    pub fn level_size(self, width: u32, height: u32) -> usize {
+        let blocks = |side: u32| side.div_ceil(self.block_dimension()) as usize;
+        blocks(width) * blocks(height) * self.block_bytes()
+    }
+}
+
+type Block = [[u8; 4]; 16];
+type FloatBlock = [[f32; 4]; 16];
+
+/// Encodes the image in `format`, block rows in parallel. The output only
+/// depends on the pixels, never on scheduling. Float formats take the bytes
+/// as values from 0 to 1.
+pub fn compress(image: &RgbaImage, format: BlockFormat) -> Vec<u8> {
+    let encode: fn(&Block) -> Vec<u8> = match format {
+        BlockFormat::Rgba8 => return image.as_raw().clone(),
+        BlockFormat::Rgba16f | BlockFormat::Bc6h => {
+            let pixels = image.as_raw().iter().map(|&c| c as f32 / 255.0).collect();
+            let float = Rgba32FImage::from_raw(image.width(), image.height(), pixels).unwrap();
+            return compress_float(&float, format);
+        }
+        BlockFormat::Bc1 => |block| encode_bc1(block, true).to_vec(),
+        BlockFormat::Bc3 => |block| {
+            let alpha = encode_bc4(&channel(block, 3));
+            [alpha, encode_bc1(block, false)].concat()
+        },
+        BlockFormat::Bc4 => |block| encode_bc4(&channel(block, 0)).to_vec(),
+        BlockFormat::Bc5 => |block| [encode_bc4(&channel(block, 0)), encode_bc4(&channel(block, 1))].concat(),
+        BlockFormat::Bc7 => |block| encode_bc7(block).to_vec(),
+    };
+
+    let (width, height) = image.dimensions();
+    encode_blocks(width, height, |bx, by| encode(&fetch_block(image, bx, by)))
+}
+
+/// Encodes linear float pixels in `format`. 8-bit formats clamp to 0 to 1;
+/// BC6H drops alpha and clamps negative values to zero.
+pub fn compress_float(image: &Rgba32FImage, format: BlockFormat) -> Vec<u8> {
+    match format {
+        BlockFormat::Rgba16f => image
+            .as_raw()
+            .iter()
+            .flat_map(|&c| half::f16::from_f32(c).to_le_bytes())
+            .collect(),
+        BlockFormat::Bc6h => {
+            let (width, height) = image.dimensions();
+            encode_blocks(width, height, |bx, by| encode_bc6h(&fetch_float_block(image, bx, by)).to_vec())
+        }
+        _ => {
+            let bytes = image.as_raw().iter().map(|&c| (c.clamp(0.0, 1.0) * 255.0).round() as u8).collect();
+            compress(&RgbaImage::from_raw(image.width(), image.height(), bytes).unwrap(), format)
+        }
+    }
+}
+
+fn encode_blocks(width: u32, height: u32, encode: impl Fn(u32, u32) -> Vec<u8> + Sync) -> Vec<u8> {
+    let blocks_x = width.div_ceil(4);
+    (0..height.div_ceil(4))
+        .into_par_iter()
+        .map(|by| (0..blocks_x).flat_map(|bx| encode(bx, by)).collect::<Vec<u8>>())
+        .collect::<Vec<_>>()
+        .concat()
+}
+
+fn fetch_block(image: &RgbaImage, bx: u32, by: u32) -> Block {
+    let (width, height) = image.dimensions();
+    let mut block = [[0; 4]; 16];
+    for (i, pixel) in block.iter_mut().enumerate() {
+        let x = (bx * 4 + i as u32 % 4).min(width - 1);
+        let y = (by * 4 + i as u32 / 4).min(height - 1);
+        *pixel = image.get_pixel(x, y).0;
+    }
+    block
+}
+
+fn fetch_float_block(image: &Rgba32FImage, bx: u32, by: u32) -> FloatBlock {
+    let (width, height) = image.dimensions();
+    let mut block = [[0.0; 4]; 16];
+    for (i, pixel) in block.iter_mut().enumerate() {
+        let x = (bx * 4 + i as u32 % 4).min(width - 1);
+        let y = (by * 4 + i as u32 / 4).min(height - 1);
+        *pixel = image.get_pixel(x, y).0;
+    }
+    block
+}
+
+fn channel(block: &Block, c: usize) -> [u8; 16] {
+    block.map(|pixel| pixel[c])
+}
+
+/// The line through the points' mean along their principal axis, clipped to
+/// where the points project onto it.
+fn fit_line<const N: usize>(points: &[[f32; N]]) -> ([f32; N], [f32; N]) {
+    let count = points.len().max(1) as f32;
+    let mut mean = [0.0; N];
+    for point in points {
+        for c in 0..N {
+            mean[c] += point[c] / count;
+        }
+    }
+
+    let mut covariance = [[0.0f32; N]; N];
+    for point in points {
+        for i in 0..N {
+            for j in 0..N {
+                covariance[i][j] += (point[i] - mean[i]) * (point[j] - mean[j]);
+            }
+        }
+    }
+
+    // Power iteration, seeded with the bounding box diagonal
+    let mut axis = [0.0f32; N];
+    for c in 0..N {
+        let (min, max) = points.iter().fold((f32::MAX, f32::MIN), |(lo, hi), p| (lo.min(p[c]), hi.max(p[c])));
+        axis[c] = max - min;
+    }
+    for _ in 0..8 {
+        let mut next = [0.0; N];
+        for i in 0..N {
+            next[i] = (0..N).map(|j| covariance[i][j] * axis[j]).sum();
+        }
+        let norm = next.iter().map(|v| v * v).sum::<f32>().sqrt();
+        if norm < 1e-6 {
+            return (mean, mean);
+        }
+        axis = next.map(|v| v / norm);
+    }
+
+    let project = |p: &[f32; N]| (0..N).map(|c| (p[c] - mean[c]) * axis[c]).sum::<f32>();
+    let (low, high) = points
+        .iter()
+        .map(project)
+        .fold((f32::MAX, f32::MIN), |(lo, hi), t| (lo.min(t), hi.max(t)));
+    let along = |t: f32| std::array::from_fn(|c| (mean[c] + axis[c] * t).clamp(0.0, 255.0));
+    (along(high), along(low))
+}
+
+/// Endpoints minimizing the squared error of `points` reconstructed as
+/// `(1 - w) * a + w * b` with the given weights.
+fn least_squares<const N: usize>(points: &[[f32; N]], weights: &[f32]) -> Option<([f32; N], [f32; N])> {
+    let (mut aa, mut ab, mut bb) = (0.0, 0.0, 0.0);
+    let (mut ax, mut bx) = ([0.0; N], [0.0; N]);
+    for (point, &w) in points.iter().zip(weights) {
+        let v = 1.0 - w;
+        aa += v * v;
+        ab += v * w;
+        bb += w * w;
+        for c in 0..N {
+            ax[c] += v * point[c];
+            bx[c] += w * point[c];
+        }
+    }
+
+    let det = aa * bb - ab * ab;
+    if det.abs() < 1e-6 {
+        return None;
+    }
+    let a = std::array::from_fn(|c| ((bb * ax[c] - ab * bx[c]) / det).clamp(0.0, 255.0));
+    let b = std::array::from_fn(|c| ((aa * bx[c] - ab * ax[c]) / det).clamp(0.0, 255.0));
+    Some((a, b))
+}
+
+fn squared_error<const N: usize>(a: &[f32; N], b: &[f32; N]) -> f32 {
+    (0..N).map(|c| (a[c] - b[c]) * (a[c] - b[c])).sum()
+}
+
+fn nearest<const N: usize>(palette: &[[f32; N]], point: &[f32; N]) -> (usize, f32) {
+    palette
+        .iter()
+        .map(|entry| squared_error(entry, point))
+        .enumerate()
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .unwrap()
+}
+
+fn to_565(color: &[f32; 3]) -> u16 {
+    let r = (color[0] * 31.0 / 255.0).round() as u16;
+    let g = (color[1] * 63.0 / 255.0).round() as u16;
+    let b = (color[2] * 31.0 / 255.0).round() as u16;
+    (r << 11) | (g << 5) | b
+}
+
+fn from_565(color: u16) -> [f32; 3] {
+    let (r, g, b) = ((color >> 11) & 31, (color >> 5) & 63, color & 31);
+    [((r << 3) | (r >> 2)) as f32, ((g << 2) | (g >> 4)) as f32, ((b << 3) | (b >> 2)) as f32]
+}
+
+struct Bc1Fit {
+    c0: u16,
+    c1: u16,
+    indices: [u8; 16],
+    error: f32,
+}
+
+/// Quantizes the endpoints and picks each pixel's palette entry. Blocks with
+/// transparent pixels use the three-colour mode, whose fourth entry is
+/// transparent black.
+fn fit_bc1(colors: &[[f32; 3]; 16], transparent: &[bool; 16], e0: &[f32; 3], e1: &[f32; 3]) -> Bc1Fit {
+    let (mut c0, mut c1) = (to_565(e0), to_565(e1));
+    let three_color = transparent.iter().any(|&t| t);
+    if three_color == (c0 > c1) {
+        std::mem::swap(&mut c0, &mut c1);
+    }
+
+    let (a, b) = (from_565(c0), from_565(c1));
+    let mix = |wa: f32, wb: f32, d: f32| std::array::from_fn::<f32, 3, _>(|c| (wa * a[c] + wb * b[c]) / d);
+    let palette = if three_color {
+        vec![a, b, mix(1.0, 1.0, 2.0)]
+    } else {
+        vec![a, b, mix(2.0, 1.0, 3.0), mix(1.0, 2.0, 3.0)]
+    };
+
+    let mut indices = [0; 16];
+    let mut error = 0.0;
+    for i in 0..16 {
+        if transparent[i] {
+            indices[i] = 3;
+            continue;
+        }
+        let (index, e) = nearest(&palette, &colors[i]);
+        indices[i] = index as u8;
+        error += e;
+    }
+
+    Bc1Fit { c0, c1, indices, error }
+}
+
+fn encode_bc1(block: &Block, allow_transparent: bool) -> [u8; 8] {
+    let colors: [[f32; 3]; 16] = block.map(|p| [p[0] as f32, p[1] as f32, p[2] as f32]);
+    let transparent: [bool; 16] = block.map(|p| allow_transparent && p[3] < 128);
+
+    let opaque: Vec<[f32; 3]> = (0..16).filter(|&i| !transparent[i]).map(|i| colors[i]).collect();
+    let (e0, e1) = fit_line(&opaque);
+    let mut best = fit_bc1(&colors, &transparent, &e0, &e1);
+
+    let three_color = transparent.iter().any(|&t| t);
+    for _ in 0..REFINE_ITERATIONS {
+        let weight = |index: u8| match (three_color, index) {
+            (_, 0) => 0.0,
+            (_, 1) => 1.0,
+            (true, _) => 0.5,
+            (false, 2) => 1.0 / 3.0,
+            (false, _) => 2.0 / 3.0,
+        };
+        let weights: Vec<f32> = (0..16).filter(|&i| !transparent[i]).map(|i| weight(best.indices[i])).collect();
+
+        // Index order may have been swapped, so fit against the stored order
+        let Some((a, b)) = least_squares(&opaque, &weights) else { break };
+        let candidate = fit_bc1(&colors, &transparent, &a, &b);
+        if candidate.error >= best.error {
+            break;
+        }
+        best = candidate;
+    }
+
+    let indices = best.indices.iter().enumerate().fold(0u32, |bits, (i, &index)| bits | (index as u32) << (2 * i));
+    let mut out = [0; 8];
+    out[0..2].copy_from_slice(&best.c0.to_le_bytes());
+    out[2..4].copy_from_slice(&best.c1.to_le_bytes());
+    out[4..8].copy_from_slice(&indices.to_le_bytes());
+    out
+}
+
+/// Both BC4 palette modes: eight interpolated values, or six plus explicit
+/// 0 and 255 when the block also holds those extremes.
+fn bc4_palette(a0: u8, a1: u8) -> [f32; 8] {
+    let (a, b) = (a0 as f32, a1 as f32);
+    let mut palette = [a, b, 0.0, 0.0, 0.0, 0.0, 0.0, 255.0];
+    if a0 > a1 {
+        for (i, value) in palette.iter_mut().enumerate().skip(2) {
+            *value = ((8 - i) as f32 * a + (i - 1) as f32 * b) / 7.0;
+        }
+    } else {
+        for (i, value) in palette.iter_mut().enumerate().take(6).skip(2) {
+            *value = ((6 - i) as f32 * a + (i - 1) as f32 * b) / 5.0;
+        }
+    }
+    palette
+}
+
+fn encode_bc4(values: &[u8; 16]) -> [u8; 8] {
+    let min = *values.iter().min().unwrap();
+    let max = *values.iter().max().unwrap();
+    let inner = values.iter().filter(|&&v| v != 0 && v != 255);
+    let inner_min = inner.clone().min().copied().unwrap_or(min);
+    let inner_max = inner.max().copied().unwrap_or(max);
+
+    let mut candidates = vec![(max, min)];
+    if inner_min != min || inner_max != max {
+        candidates.push((inner_min, inner_max));
+    }
+
+    let (a0, a1, indices) = candidates
+        .into_iter()
+        .map(|(a0, a1)| {
+            let palette = bc4_palette(a0, a1).map(|v| [v]);
+            let mut indices = [0u8; 16];
+            let mut error = 0.0;
+            for (i, &v) in values.iter().enumerate() {
+                let (index, e) = nearest(&palette, &[v as f32]);
+                indices[i] = index as u8;
+                error += e;
+            }
+            (a0, a1, indices, error)
+        })
+        .min_by(|a, b| a.3.total_cmp(&b.3))
+        .map(|(a0, a1, indices, _)| (a0, a1, indices))
+        .unwrap();
+
+    let bits = indices.iter().enumerate().fold(0u64, |bits, (i, &index)| bits | (index as u64) << (3 * i));
+    let mut out = [0; 8];
+    out[0] = a0;
+    out[1] = a1;
+    out[2..8].copy_from_slice(&bits.to_le_bytes()[..6]);
+    out
+}
+
+struct Bc7Fit {
+    endpoints: [[u8; 4]; 2],
+    pbits: [u8; 2],
+    indices: [u8; 16],
+    error: f32,
+}
+
+fn bc7_unquantize(endpoint: &[u8; 4], pbit: u8) -> [f32; 4] {
+    endpoint.map(|c| ((c << 1) | pbit) as f32)
+}
+
+/// Quantizes both endpoints to 7 bits plus a shared p-bit, trying every
+/// p-bit pair, and picks each pixel's index.
+fn fit_bc7(pixels: &[[f32; 4]; 16], e0: &[f32; 4], e1: &[f32; 4]) -> Bc7Fit {
+    let quantize = |e: &[f32; 4], pbit: u8| e.map(|v| ((v - pbit as f32) / 2.0).round().clamp(0.0, 127.0) as u8);
+
+    let mut best: Option<Bc7Fit> = None;
+    for pbits in [[0, 0], [0, 1], [1, 0], [1, 1]] {
+        let endpoints = [quantize(e0, pbits[0]), quantize(e1, pbits[1])];
+        let (a, b) = (bc7_unquantize(&endpoints[0], pbits[0]), bc7_unquantize(&endpoints[1], pbits[1]));
+        let palette: Vec<[f32; 4]> = BC7_WEIGHTS
+            .iter()
+            .map(|&w| std::array::from_fn(|c| (((64 - w) * a[c] as u32 + w * b[c] as u32 + 32) >> 6) as f32))
+            .collect();
+
+        let mut indices = [0; 16];
+        let mut error = 0.0;
+        for (i, pixel) in pixels.iter().enumerate() {
+            let (index, e) = nearest(&palette, pixel);
+            indices[i] = index as u8;
+            error += e;
+        }
+
+        if best.as_ref().is_none_or(|best| error < best.error) {
+            best = Some(Bc7Fit { endpoints, pbits, indices, error });
+        }
+    }
+    best.unwrap()
+}
+
+/// BC7 mode 6: one subset, 7-bit RGBA endpoints with p-bits and 4-bit
+/// indices.
+fn encode_bc7(block: &Block) -> [u8; 16] {
+    let pixels: [[f32; 4]; 16] = block.map(|p| p.map(|c| c as f32));
+    let (e0, e1) = fit_line(&pixels);
+    let mut best = fit_bc7(&pixels, &e0, &e1);
+
+    for _ in 0..REFINE_ITERATIONS {
+        let weights: Vec<f32> = best.indices.iter().map(|&i| BC7_WEIGHTS[i as usize] as f32 / 64.0).collect();
+        let Some((a, b)) = least_squares(&pixels, &weights) else { break };
+        let candidate = fit_bc7(&pixels, &a, &b);
+        if candidate.error >= best.error {
+            break;
+        }
+        best = candidate;
+    }
+
+    // The first pixel's index is stored without its top bit
+    if best.indices[0] >= 8 {
+        best.endpoints.swap(0, 1);
+        best.pbits.swap(0, 1);
+        best.indices = best.indices.map(|i| 15 - i);
+    }
+
+    let mut bits = 0u128;
+    let mut position = 0;
+    let mut put = |value: u128, count: u32| {
+        bits |= value << position;
+        position += count;
+    };
+
+    put(1 << 6, 7);
+    for c in 0..4 {
+        put(best.endpoints[0][c] as u128, 7);
+        put(best.endpoints[1][c] as u128, 7);
+    }
+    put(best.pbits[0] as u128, 1);
+    put(best.pbits[1] as u128, 1);
+    for (i, &index) in best.indices.iter().enumerate() {
+        put(index as u128, if i == 0 { 3 } else { 4 });
+    }
+
+    bits.to_le_bytes()
+}
+
+/// Half-float bit pattern of a non-negative value, as a float. BC6H
+/// interpolates these patterns, which are close to logarithmic.
+fn half_bits(value: f32) -> f32 {
+    let value = if value.is_nan() { 0.0 } else { value.clamp(0.0, 65504.0) };
+    half::f16::from_f32(value).to_bits() as f32
+}
+
+/// 10-bit unsigned endpoint widened to 16 bits, as BC6H decoders do.
+fn bc6h_unquantize(value: u16) -> u32 {
+    match value {
+        0 => 0,
+        1023 => 0xFFFF,
+        _ => ((value as u32) << 16 | 0x8000) >> 10,
+    }
+}
+
+struct Bc6hFit {
+    endpoints: [[u16; 3]; 2],
+    indices: [u8; 16],
+    error: f32,
+}
+
+/// Quantizes both endpoints to 10 bits and picks each pixel's index. Points
+/// are half-float bit patterns scaled to 0 to 255, so the line fitting
+/// helpers apply unchanged.
+fn fit_bc6h(pixels: &[[f32; 3]; 16], e0: &[f32; 3], e1: &[f32; 3]) -> Bc6hFit {
+    let quantize = |e: &[f32; 3]| e.map(|v| (v / 255.0 * 1023.0).round().clamp(0.0, 1023.0) as u16);
+    let endpoints = [quantize(e0), quantize(e1)];
+    let (a, b) = (endpoints[0].map(bc6h_unquantize), endpoints[1].map(bc6h_unquantize));
+    let palette: Vec<[f32; 3]> = BC7_WEIGHTS
+        .iter()
+        .map(|&w| {
+            std::array::from_fn(|c| {
+                let interpolated = ((64 - w) * a[c] + w * b[c] + 32) >> 6;
+                ((interpolated * 31) >> 6) as f32 * 255.0 / HALF_MAX_BITS
+            })
+        })
+        .collect();
+
+    let mut indices = [0; 16];
+    let mut error = 0.0;
+    for (i, pixel) in pixels.iter().enumerate() {
+        let (index, e) = nearest(&palette, pixel);
+        indices[i] = index as u8;
+        error += e;
+    }
+    Bc6hFit { endpoints, indices, error }
+}
+
+/// BC6H mode 11: one region, unsigned 10-bit RGB endpoints without deltas
+/// and 4-bit indices.
+fn encode_bc6h(block: &FloatBlock) -> [u8; 16] {
+    let pixels: [[f32; 3]; 16] = block.map(|p| std::array::from_fn(|c| half_bits(p[c]) * 255.0 / HALF_MAX_BITS));
+    let (e0, e1) = fit_line(&pixels);
+    let mut best = fit_bc6h(&pixels, &e0, &e1);
+
+    for _ in 0..REFINE_ITERATIONS {
+        let weights: Vec<f32> = best.indices.iter().map(|&i| BC7_WEIGHTS[i as usize] as f32 / 64.0).collect();
+        let Some((a, b)) = least_squares(&pixels, &weights) else { break };
+        let candidate = fit_bc6h(&pixels, &a, &b);
+        if candidate.error >= best.error {
+            break;
+        }
+        best = candidate;
+    }
+
+    // The first pixel's index is stored without its top bit
+    if best.indices[0] >= 8 {
+        best.endpoints.swap(0, 1);
+        best.indices = best.indices.map(|i| 15 - i);
+    }
+
+    let mut bits = 0u128;
+    let mut position = 0;
+    let mut put = |value: u128, count: u32| {
+        bits |= value << position;
+        position += count;
+    };
+
+    put(0b00011, 5);
+    for endpoint in best.endpoints {
+        for c in endpoint {
+            put(c as u128, 10);
+        }
+    }
+    for (i, &index) in best.indices.iter().enumerate() {
+        put(index as u128, if i == 0 { 3 } else { 4 });
+    }
+
+    bits.to_le_bytes()
+}
+
+/// A BC7 or BC6H block in a mode other than the one [`compress`] writes,
+/// as other encoders produce. Decoding fails with it rather than showing
+/// wrong pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnsupportedBlockMode {
+    pub format: BlockFormat,
+    /// The BC7 mode, or the BC6H mode bits.
+    pub mode: u8,
+}
+
+impl fmt::Display for UnsupportedBlockMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.format {
+            BlockFormat::Bc6h => write!(f, "BC6H mode bits {:#07b} are not supported", self.mode),
+            format => write!(f, "{:?} mode {} is not supported", format, self.mode),
+        }
+    }
+}
+
+impl std::error::Error for UnsupportedBlockMode {}
+
+/// Decodes a level of `format` data. BC7 supports only mode 6 and BC6H only
+/// mode 11, the modes [`compress`] writes; blocks in others fail with
+/// [`UnsupportedBlockMode`]. Float formats are clamped to 0 to 1.
+pub fn decompress(data: &[u8], format: BlockFormat, width: u32, height: u32) -> Result<RgbaImage> {
+    if format.is_float() {
+        let float = decompress_float(data, format, width, height)?;
+        let bytes = float.as_raw().iter().map(|&c| (c.clamp(0.0, 1.0) * 255.0).round() as u8).collect();
+        return Ok(RgbaImage::from_raw(width, height, bytes).unwrap());
+    }
+    check_level_size(data, format, width, height)?;
+    if format == BlockFormat::Rgba8 {
+        return Ok(RgbaImage::from_raw(width, height, data[..format.level_size(width, height)].to_vec()).unwrap());
+    }
+
+    let mut image = RgbaImage::new(width, height);
+    for_each_block(data, format, width, |block, x, y| {
+        let pixels = match format {
+            BlockFormat::Bc1 => decode_bc1(block, false),
+            BlockFormat::Bc3 => {
+                let alpha = decode_bc4(&block[..8]);
+                let mut colors = decode_bc1(&block[8..], true);
+                colors.iter_mut().zip(alpha).for_each(|(pixel, a)| pixel[3] = a);
+                colors
+            }
+            BlockFormat::Bc4 => decode_bc4(block).map(|r| [r, 0, 0, 255]),
+            BlockFormat::Bc5 => {
+                let (r, g) = (decode_bc4(&block[..8]), decode_bc4(&block[8..]));
+                std::array::from_fn(|i| [r[i], g[i], 0, 255])
+            }
+            _ => decode_bc7(block)?,
+        };
+        put_block(&mut image, x, y, &pixels);
+        Ok(())
+    })?;
+    Ok(image)
+}
+
+/// Float counterpart of [`decompress`]; 8-bit formats map to 0 to 1.
+pub fn decompress_float(data: &[u8], format: BlockFormat, width: u32, height: u32) -> Result<Rgba32FImage> {
+    check_level_size(data, format, width, height)?;
+    match format {
+        BlockFormat::Rgba16f => {
+            let values = data[..format.level_size(width, height)]
+                .chunks_exact(2)
+                .map(|c| half::f16::from_le_bytes([c[0], c[1]]).to_f32())
+                .collect();
+            Ok(Rgba32FImage::from_raw(width, height, values).unwrap())
+        }
+        BlockFormat::Bc6h => {
+            let mut image = Rgba32FImage::new(width, height);
+            for_each_block(data, format, width, |block, x, y| {
+                let pixels = decode_bc6h(block)?;
+                put_block(&mut image, x, y, &pixels);
+                Ok(())
+            })?;
+            Ok(image)
+        }
+        _ => {
+            let image = decompress(data, format, width, height)?;
+            let values = image.as_raw().iter().map(|&c| c as f32 / 255.0).collect();
+            Ok(Rgba32FImage::from_raw(width, height, values).unwrap())
+        }
+    }
+}
+
+fn check_level_size(data: &[u8], format: BlockFormat, width: u32, height: u32) -> Result<()> {
+    if data.len() < format.level_size(width, height) {
+        bail!("{:?} data for {}x{} is truncated", format, width, height);
+    }
+    Ok(())
+}
+
+/// Calls `decode` with each 4x4 block and its top-left pixel.
+fn for_each_block(
+    data: &[u8],
+    format: BlockFormat,
+    width: u32,
+    mut decode: impl FnMut(&[u8], u32, u32) -> Result<()>,
+) -> Result<()> {
+    let blocks_x = width.div_ceil(4) as usize;
+    for (i, block) in data.chunks_exact(format.block_bytes()).enumerate() {
+        let (bx, by) = ((i % blocks_x) as u32, (i / blocks_x) as u32);
+        decode(block, bx * 4, by * 4)?;
+    }
+    Ok(())
+}
+
+/// Writes a decoded block, dropping the pixels past the image's edges.
+fn put_block<P: image::Pixel>(image: &mut image::ImageBuffer<P, Vec<P::Subpixel>>, x: u32, y: u32, pixels: &[[P::Subpixel; 4]; 16]) {
+    for (i, pixel) in pixels.iter().enumerate() {
+        let (px, py) = (x + i as u32 % 4, y + i as u32 / 4);
+        if px < image.width() && py < image.height() {
+            image.put_pixel(px, py, *P::from_slice(pixel));
+        }
+    }
+}
+
+fn decode_bc1(block: &[u8], four_color_only: bool) -> [[u8; 4]; 16] {
+    let c0 = u16::from_le_bytes([block[0], block[1]]);
+    let c1 = u16::from_le_bytes([block[2], block[3]]);
+    let (a, b) = (from_565(c0), from_565(c1));
+    let mix = |wa: f32, wb: f32, d: f32| -> [u8; 4] {
+        let c: [f32; 3] = std::array::from_fn(|i| (wa * a[i] + wb * b[i]) / d);
+        [c[0].round() as u8, c[1].round() as u8, c[2].round() as u8, 255]
+    };
+    let palette = if c0 > c1 || four_color_only {
+        [mix(1.0, 0.0, 1.0), mix(0.0, 1.0, 1.0), mix(2.0, 1.0, 3.0), mix(1.0, 2.0, 3.0)]
+    } else {
+        [mix(1.0, 0.0, 1.0), mix(0.0, 1.0, 1.0), mix(1.0, 1.0, 2.0), [0, 0, 0, 0]]
+    };
+    let indices = u32::from_le_bytes(block[4..8].try_into().unwrap());
+    std::array::from_fn(|i| palette[(indices >> (2 * i) & 3) as usize])
+}
+
+fn decode_bc4(block: &[u8]) -> [u8; 16] {
+    let palette = bc4_palette(block[0], block[1]);
+    let mut bits = [0u8; 8];
+    bits[..6].copy_from_slice(&block[2..8]);
+    let bits = u64::from_le_bytes(bits);
+    std::array::from_fn(|i| palette[(bits >> (3 * i) & 7) as usize].round() as u8)
+}
+
+/// Index bits of a one-subset block with 4-bit indices, the first stored
+/// without its top bit, starting at bit 65.
+fn one_subset_index(bits: u128, pixel: usize) -> usize {
+    let field = |offset: u32, count: u32| ((bits >> offset) & ((1 << count) - 1)) as usize;
+    if pixel == 0 { field(65, 3) } else { field(68 + 4 * (pixel as u32 - 1), 4) }
+}
+
+fn decode_bc7(block: &[u8]) -> Result<[[u8; 4]; 16]> {
+    let bits = u128::from_le_bytes(block.try_into().unwrap());
+    if bits & 0x7F != 1 << 6 {
+        return Err(UnsupportedBlockMode { format: BlockFormat::Bc7, mode: bits.trailing_zeros().min(8) as u8 }.into());
+    }
+    let field = |offset: u32, count: u32| ((bits >> offset) & ((1 << count) - 1)) as u32;
+    let p = [field(63, 1), field(64, 1)];
+    let endpoint = |e: usize| -> [u32; 4] { std::array::from_fn(|c| (field(7 + 14 * c as u32 + 7 * e as u32, 7) << 1) | p[e]) };
+    let (a, b) = (endpoint(0), endpoint(1));
+    Ok(std::array::from_fn(|i| {
+        let w = BC7_WEIGHTS[one_subset_index(bits, i)];
+        std::array::from_fn(|c| (((64 - w) * a[c] + w * b[c] + 32) >> 6) as u8)
+    }))
+}
+
+fn decode_bc6h(block: &[u8]) -> Result<[[f32; 4]; 16]> {
+    let bits = u128::from_le_bytes(block.try_into().unwrap());
+    if bits & 0x1F != 0b00011 {
+        return Err(UnsupportedBlockMode { format: BlockFormat::Bc6h, mode: (bits & 0x1F) as u8 }.into());
+    }
+    let field = |offset: u32, count: u32| ((bits >> offset) & ((1 << count) - 1)) as u16;
+    let endpoint = |e: u32| -> [u32; 3] { std::array::from_fn(|c| bc6h_unquantize(field(5 + 30 * e + 10 * c as u32, 10))) };
+    let (a, b) = (endpoint(0), endpoint(1));
+    Ok(std::array::from_fn(|i| {
+        let w = BC7_WEIGHTS[one_subset_index(bits, i)];
+        let channel = |c: usize| {
+            let half_bits = ((((64 - w) * a[c] + w * b[c] + 32) >> 6) * 31) >> 6;
+            half::f16::from_bits(half_bits as u16).to_f32()
+        };
+        [channel(0), channel(1), channel(2), 1.0]
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn psnr(a: &[u8], b: &[u8]) -> f64 {
+        let mse = a.iter().zip(b).map(|(&x, &y)| (x as f64 - y as f64).powi(2)).sum::<f64>() / a.len() as f64;
+        10.0 * (255.0 * 255.0 / mse.max(1e-10)).log10()
+    }
+
+    #[test]
+    fn test_block_compression_quality() {
+        // Smooth gradients with a hard edge, and a non-multiple-of-4 size
+        let image = RgbaImage::from_fn(70, 37, |x, y| {
+            let edge = if x > 40 { 90 } else { 0 };
+            image::Rgba([(x * 3 + edge) as u8, (y * 6) as u8, ((x + y) * 2) as u8, (255 - y * 4) as u8])
+        });
+
+        let channels = |image: &RgbaImage, take: &[usize]| -> Vec<u8> {
+            image.pixels().flat_map(|p| take.iter().map(|&c| p.0[c]).collect::<Vec<_>>()).collect()
+        };
+
+        for (format, take, min_psnr) in [
+            (BlockFormat::Bc1, &[0, 1, 2][..], 30.0),
+            (BlockFormat::Bc3, &[0, 1, 2, 3][..], 30.0),
+            (BlockFormat::Bc4, &[0][..], 40.0),
+            (BlockFormat::Bc5, &[0, 1][..], 40.0),
+            (BlockFormat::Bc7, &[0, 1, 2, 3][..], 38.0),
+        ] {
+            let opaque = if format == BlockFormat::Bc1 {
+                RgbaImage::from_fn(70, 37, |x, y| {
+                    let mut p = *image.get_pixel(x, y);
+                    p.0[3] = 255;
+                    p
+                })
+            } else {
+                image.clone()
+            };
+            let data = compress(&opaque, format);
+            assert_eq!(data.len(), format.level_size(70, 37));
+            // Deterministic regardless of thread scheduling
+            assert_eq!(data, compress(&opaque, format));
+
+            let decoded = decompress(&data, format, 70, 37).unwrap();
+            let quality = psnr(&channels(&opaque, take), &channels(&decoded, take));
+            assert!(quality > min_psnr, "{:?} PSNR {:.1} dB", format, quality);
+        }
+
+        // BC1 keeps cut-out transparency
+        let cutout = RgbaImage::from_fn(8, 8, |x, _| image::Rgba([200, 30, 30, if x < 4 { 0 } else { 255 }]));
+        let decoded = decompress(&compress(&cutout, BlockFormat::Bc1), BlockFormat::Bc1, 8, 8).unwrap();
+        assert_eq!(decoded.get_pixel(1, 1).0[3], 0);
+        assert_eq!(decoded.get_pixel(5, 1).0, [198, 28, 33, 255]);
+    }
+
+    #[test]
+    fn test_bc6h_quality() {
+        // A sky-like ramp spanning about twenty stops, well past 1.0, with a
+        // tint that drifts across the image
+        let image = Rgba32FImage::from_fn(33, 18, |x, y| {
+            let v = ((x + y) as f32 / 2.5 - 8.0).exp2();
+            let tint = 0.5 + x as f32 / 64.0;
+            image::Rgba([v, v * tint, v * (1.2 - tint * 0.5), 1.0])
+        });
+
+        let data = compress_float(&image, BlockFormat::Bc6h);
+        assert_eq!(data.len(), BlockFormat::Bc6h.level_size(33, 18));
+        let decoded = decompress_float(&data, BlockFormat::Bc6h, 33, 18).unwrap();
+        let (mut worst, mut total) = (0.0f32, 0.0f32);
+        for (pixel, decoded) in image.pixels().zip(decoded.pixels()) {
+            for c in 0..3 {
+                let error = (decoded[c] - pixel[c]).abs() / pixel[c];
+                worst = worst.max(error);
+                total += error;
+            }
+        }
+        let mean = total / (33.0 * 18.0 * 3.0);
+        assert!(mean < 0.03 && worst < 0.15, "relative error mean {} worst {}", mean, worst);
+
+        let halves = compress_float(&image, BlockFormat::Rgba16f);
+        assert_eq!(halves.len(), BlockFormat::Rgba16f.level_size(33, 18));
+        let decoded = decompress_float(&halves, BlockFormat::Rgba16f, 33, 18).unwrap();
+        assert_eq!(decoded.get_pixel(0, 0)[0], 1.0 / 256.0);
+        assert!(decoded.pixels().zip(image.pixels()).all(|(a, b)| (0..4).all(|c| (a[c] - b[c]).abs() <= b[c] / 1024.0)));
+    }
+
+    #[test]
+    fn test_unsupported_modes() {
+        use crate::format::{FormatProcessor, TextureContainer, TextureProcessor, ThumbnailOptions};
+
+        // A BC7 mode 1 block and a BC6H mode 1 block, as other encoders write
+        let (mut bc7, mut bc6h) = ([0u8; 16], [0u8; 16]);
+        (bc7[0], bc6h[0]) = (0b10, 0b00);
+        let error = decompress(&bc7, BlockFormat::Bc7, 4, 4).unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&UnsupportedBlockMode { format: BlockFormat::Bc7, mode: 1 }));
+        let error = decompress_float(&bc6h, BlockFormat::Bc6h, 4, 4).unwrap_err();
+        assert_eq!(error.to_string(), "BC6H mode bits 0b00000 are not supported");
+
+        // Shown as no thumbnail rather than a failed one
+        let processor = TextureProcessor::new();
+        for (format, block) in [(BlockFormat::Bc7, bc7), (BlockFormat::Bc6h, bc6h)] {
+            let ktx2 = TextureContainer::Ktx2.write(format, false, false, 4, 4, &[block.to_vec()]);
+            assert_eq!(processor.thumbnail(&ktx2, &ThumbnailOptions::default(), &[]).unwrap(), None);
+            assert!(processor.get_properties(&ktx2).unwrap().perceptual_hashes.is_none());
+        }
+    }
+}
@@ -0,0 +1,25 @@
+use crate::ResourceType;
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+/// A file a resource refers to from outside its own data, such as a glTF
+/// model's `.bin` buffer or texture image.
+#[derive(Debug, Clone)]
+pub struct ExternalFile {
+    /// The reference as written in the resource.
+    pub uri: String,
+    pub path: PathBuf,
+    pub data: Vec<u8>,
+    /// What the file is stored as; [`ResourceType::Binary`] when unknown.
+    pub resource_type: ResourceType,
+}
+
+/// Finds the files a resource depends on, so they can be stored alongside
+/// it and linked in the dependency graph. Processors whose formats refer to
+/// other files implement this and return themselves from
+/// [`FormatProcessor::dependency_extractor`](super::FormatProcessor::dependency_extractor).
+pub trait DependencyExtractor {
+    /// Files `data` refers to, resolved against `base_dir`, the directory of
+    /// the asset. A referenced file that is missing is an error.
+    fn extract_dependencies(&self, data: &[u8], base_dir: Option<&Path>) -> Result<Vec<ExternalFile>>;
+}
@@ -0,0 +1,254 @@
+use super::{linear_to_srgb, srgb_to_linear, TextureProcessor};
+use crate::{ColorSpace, DynamicRange};
+use anyhow::{bail, Context, Result};
+use image::{DynamicImage, ImageFormat, Rgba, Rgba32FImage, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+
+/// Kind of the derived object holding an HDR texture's tonemapped preview.
+pub const TONEMAPPED_PREVIEW: &str = "tonemapped";
+
+/// Scene value that auto exposure maps the average luminance to.
+const MIDDLE_GREY: f32 = 0.18;
+
+/// One layer of an HDR image, as linear floats with straight alpha.
+#[derive(Debug, Clone)]
+pub struct HdrLayer {
+    /// `None` for the unnamed main layer.
+    pub name: Option<String>,
+    pub image: Rgba32FImage,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct TonemapOptions {
+    /// Exposure adjustment in stops. Without one the average luminance is
+    /// exposed as middle grey.
+    pub exposure: Option<f32>,
+}
+
+/// Whether the data is a Radiance HDR or OpenEXR image.
+pub fn is_hdr(data: &[u8]) -> bool {
+    matches!(image::guess_format(data), Ok(ImageFormat::Hdr | ImageFormat::OpenExr))
+}
+
+/// Every layer of a Radiance HDR or OpenEXR image at full float precision.
+pub fn decode_hdr_layers(data: &[u8]) -> Result<Vec<HdrLayer>> {
+    match image::guess_format(data) {
+        Ok(ImageFormat::OpenExr) => decode_exr_layers(data),
+        Ok(ImageFormat::Hdr) => {
+            let image = image::load_from_memory_with_format(data, ImageFormat::Hdr)
+                .with_context(|| "Failed to load Radiance HDR image")?;
+            Ok(vec![HdrLayer { name: None, image: image.to_rgba32f() }])
+        }
+        _ => bail!("Not a Radiance HDR or OpenEXR image"),
+    }
+}
+
+/// A channel's name without its layer prefix, and its samples row by row.
+type ExrChannel = (String, Vec<f32>);
+
+/// Reads all parts of an OpenEXR file. Channels named like `diffuse.R`
+/// form a layer of their own, as multi-layer renders write them. Layers
+/// with a single channel or only luminance become grey.
+fn decode_exr_layers(data: &[u8]) -> Result<Vec<HdrLayer>> {
+    use exr::prelude::{ReadChannels, ReadLayers};
+
+    let image = exr::prelude::read()
+        .no_deep_data()
+        .largest_resolution_level()
+        .all_channels()
+        .all_layers()
+        .all_attributes()
+        .from_buffered(Cursor::new(data))
+        .with_context(|| "Failed to load OpenEXR image")?;
+
+    let mut layers = Vec::new();
+    for part in &image.layer_data {
+        let part_name = part.attributes.layer_name.as_ref().map(|name| name.to_string());
+        let mut groups: Vec<(Option<String>, Vec<ExrChannel>)> = Vec::new();
+        for channel in &part.channel_data.list {
+            let full_name = channel.name.to_string();
+            let (prefix, short) = match full_name.rsplit_once('.') {
+                Some((prefix, short)) => (Some(prefix.to_string()), short.to_string()),
+                None => (None, full_name),
+            };
+            let name = match (&part_name, prefix) {
+                (Some(part), Some(prefix)) => Some(format!("{}.{}", part, prefix)),
+                (part, prefix) => part.clone().or(prefix),
+            };
+
+            let values = channel.sample_data.values_as_f32().collect();
+            match groups.iter_mut().find(|(group, _)| *group == name) {
+                Some((_, channels)) => channels.push((short, values)),
+                None => groups.push((name, vec![(short, values)])),
+            }
+        }
+
+        let width = part.size.width();
+        for (name, channels) in groups {
+            let find = |wanted: &str| {
+                channels.iter().find(|(channel, _)| channel.eq_ignore_ascii_case(wanted)).map(|(_, v)| v)
+            };
+            let rgb = match (find("R"), find("G"), find("B")) {
+                (Some(r), Some(g), Some(b)) => [r, g, b],
+                _ => match find("Y").or_else(|| (channels.len() == 1).then(|| &channels[0].1)) {
+                    Some(y) => [y, y, y],
+                    None => continue,
+                },
+            };
+            let alpha = find("A");
+
+            let image = Rgba32FImage::from_fn(width as u32, part.size.height() as u32, |x, y| {
+                let i = y as usize * width + x as usize;
+                Rgba([rgb[0][i], rgb[1][i], rgb[2][i], alpha.map_or(1.0, |a| a[i])])
+            });
+            layers.push(HdrLayer { name, image });
+        }
+    }
+
+    if layers.is_empty() {
+        bail!("OpenEXR image has no colour channels");
+    }
+    Ok(layers)
+}
+
+/// Linear float pixels of a texture: the named layer, or the first, of an
+/// HDR image, or an 8-bit image decoded from `color_space`.
+pub fn decode_linear(data: &[u8], layer: Option<&str>, color_space: ColorSpace) -> Result<Rgba32FImage> {
+    if !is_hdr(data) {
+        let mut image = image::load_from_memory(data)
+            .with_context(|| "Failed to load image")?
+            .to_rgba32f();
+        if color_space == ColorSpace::Srgb {
+            image.pixels_mut().for_each(|p| (0..3).for_each(|c| p[c] = srgb_to_linear(p[c])));
+        }
+        return Ok(image);
+    }
+
+    let layers = decode_hdr_layers(data)?;
+    match layer {
+        None => Ok(layers.into_iter().next().unwrap().image),
+        Some(wanted) => match layers.into_iter().find(|l| l.name.as_deref() == Some(wanted)) {
+            Some(found) => Ok(found.image),
+            None => bail!("Image has no layer named {:?}", wanted),
+        },
+    }
+}
+
+fn luminance(p: &Rgba<f32>) -> f32 {
+    0.2126 * p[0] + 0.7152 * p[1] + 0.0722 * p[2]
+}
+
+/// Rec. 709 luminance statistics over the pixels with positive, finite
+/// luminance. All zero for an image without any.
+pub fn dynamic_range(image: &Rgba32FImage) -> DynamicRange {
+    let (mut min, mut max, mut log_sum, mut count) = (f32::MAX, 0.0f32, 0.0f64, 0usize);
+    for l in image.pixels().map(luminance).filter(|l| l.is_finite() && *l > 0.0) {
+        min = min.min(l);
+        max = max.max(l);
+        log_sum += (l as f64).ln();
+        count += 1;
+    }
+
+    if count == 0 {
+        return DynamicRange { min_luminance: 0.0, max_luminance: 0.0, average_luminance: 0.0, stops: 0.0 };
+    }
+    DynamicRange {
+        min_luminance: min,
+        max_luminance: max,
+        average_luminance: (log_sum / count as f64).exp() as f32,
+        stops: (max / min).log2(),
+    }
+}
+
+/// Maps scene values to display sRGB with the ACES filmic curve as fitted
+/// by Narkowicz.
+pub fn tonemap(image: &Rgba32FImage, options: &TonemapOptions) -> RgbaImage {
+    let scale = match options.exposure {
+        Some(stops) => stops.exp2(),
+        None => match dynamic_range(image).average_luminance {
+            average if average > 0.0 => MIDDLE_GREY / average,
+            _ => 1.0,
+        },
+    };
+    let aces = |x: f32| ((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)).clamp(0.0, 1.0);
+    let to_byte = |v: f32| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+
+    RgbaImage::from_fn(image.width(), image.height(), |x, y| {
+        let p = image.get_pixel(x, y);
+        let c = |i: usize| to_byte(linear_to_srgb(aces(p[i].max(0.0) * scale)));
+        Rgba([c(0), c(1), c(2), to_byte(p[3])])
+    })
+}
+
+/// Single-layer OpenEXR with 32-bit float channels.
+pub fn encode_exr(image: &Rgba32FImage) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    DynamicImage::ImageRgba32F(image.clone())
+        .write_to(&mut Cursor::new(&mut buffer), ImageFormat::OpenExr)
+        .with_context(|| "Failed to encode OpenEXR image")?;
+    Ok(buffer)
+}
+
+impl TextureProcessor {
+    /// PNG of the first layer of an HDR image, tonemapped for review.
+    pub fn render_tonemapped(&self, data: &[u8], options: &TonemapOptions) -> Result<Vec<u8>> {
+        let image = decode_linear(data, None, ColorSpace::Linear)?;
+        Self::encode_png(tonemap(&image, options))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exr_layers_and_tonemapping() {
+        use exr::prelude::{
+            Encoding, Image, ImageAttributes, IntegerBounds, Layer, LayerAttributes, SpecificChannels, Vec2, WritableImage,
+        };
+
+        let size = Vec2(8, 4);
+        let sky = Layer::new(
+            size,
+            LayerAttributes::named("sky"),
+            Encoding::FAST_LOSSLESS,
+            SpecificChannels::rgb(|p: Vec2<usize>| (p.x() as f32 * 100.0 + 0.5, 2.0f32, 0.25f32)),
+        );
+        let ground = Layer::new(
+            size,
+            LayerAttributes::named("ground"),
+            Encoding::FAST_LOSSLESS,
+            SpecificChannels::rgba(|_| (0.1f32, 0.2f32, 0.3f32, 0.5f32)),
+        );
+        let mut data = Vec::new();
+        Image::empty(ImageAttributes::new(IntegerBounds::from_dimensions(size)))
+            .with_layer(sky)
+            .with_layer(ground)
+            .write()
+            .to_buffered(Cursor::new(&mut data))
+            .unwrap();
+
+        assert!(is_hdr(&data));
+        let layers = decode_hdr_layers(&data).unwrap();
+        let names: Vec<_> = layers.iter().map(|l| l.name.as_deref()).collect();
+        assert_eq!(names, vec![Some("sky"), Some("ground")]);
+        assert_eq!(layers[0].image.get_pixel(7, 0).0, [700.5, 2.0, 0.25, 1.0]);
+
+        let ground = decode_linear(&data, Some("ground"), ColorSpace::Linear).unwrap();
+        assert_eq!(ground.get_pixel(0, 0).0, [0.1, 0.2, 0.3, 0.5]);
+        assert!(decode_linear(&data, Some("water"), ColorSpace::Linear).is_err());
+
+        let range = dynamic_range(&layers[0].image);
+        assert!(range.max_luminance > 150.0 && range.stops > 5.0, "{:?}", range);
+
+        // Values far above 1 still ramp up instead of clipping early
+        let preview = tonemap(&layers[0].image, &TonemapOptions { exposure: Some(-8.0) });
+        let reds: Vec<u8> = (0..8).map(|x| preview.get_pixel(x, 0)[0]).collect();
+        assert!(reds.windows(2).all(|w| w[0] < w[1]), "{:?}", reds);
+
+        // Single-layer EXR written through the image crate round-trips floats
+        let round_trip = decode_hdr_layers(&encode_exr(&ground).unwrap()).unwrap();
+        assert_eq!(round_trip[0].image, ground);
+    }
+}
@@ -0,0 +1,997 @@
+use super::model_pack::{document_json, push_view, relayout_views, set_buffer, view_references, write_glb};
+use super::{ExternalFile, LoadedModel, Model3DProcessor};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+
+/// Entries of the post-transform vertex cache the triangle order targets.
+const CACHE_SIZE: usize = 16;
+
+const BYTE: u64 = 5120;
+const UNSIGNED_BYTE: u64 = 5121;
+const SHORT: u64 = 5122;
+const UNSIGNED_SHORT: u64 = 5123;
+const UNSIGNED_INT: u64 = 5125;
+const FLOAT: u64 = 5126;
+
+const ARRAY_BUFFER: u64 = 34962;
+const ELEMENT_ARRAY_BUFFER: u64 = 34963;
+
+const MESH_QUANTIZATION: &str = "KHR_mesh_quantization";
+
+/// Extensions that refer to accessors or materials from places stripping
+/// does not look.
+const STRIP_BLOCKING_EXTENSIONS: [&str; 2] = ["EXT_mesh_gpu_instancing", "KHR_materials_variants"];
+
+/// Which steps of [`Model3DProcessor::optimize`] to run; all by default.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MeshOptimizeSettings {
+    /// Merge vertices whose attributes are identical bit for bit, dropping
+    /// the triangles that collapse.
+    #[serde(default = "enabled")]
+    pub weld: bool,
+    /// Order triangles for the vertex cache and then front to back in
+    /// clusters against overdraw, and vertices by first use.
+    #[serde(default = "enabled")]
+    pub reorder: bool,
+    /// Store positions, normals and tangents as integers under
+    /// `KHR_mesh_quantization`, and texture coordinates in 0 to 1 as
+    /// normalized shorts.
+    #[serde(default = "enabled")]
+    pub quantize: bool,
+    /// Drop accessors and materials nothing refers to.
+    #[serde(default = "enabled")]
+    pub strip_unused: bool,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+impl Default for MeshOptimizeSettings {
+    fn default() -> Self {
+        Self { weld: true, reorder: true, quantize: true, strip_unused: true }
+    }
+}
+
+/// What an optimization pass changed. Vertex, triangle and cache figures
+/// cover the primitives that were rewritten.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MeshOptimizationReport {
+    pub original_size: usize,
+    pub optimized_size: usize,
+    pub original_vertices: usize,
+    pub optimized_vertices: usize,
+    pub original_triangles: usize,
+    pub optimized_triangles: usize,
+    /// Average vertex cache misses per triangle.
+    pub original_acmr: f32,
+    pub optimized_acmr: f32,
+    pub stripped_accessors: usize,
+    pub stripped_materials: usize,
+    /// Primitives left as they were: points, lines, sparse or matrix
+    /// attributes.
+    pub skipped_primitives: usize,
+}
+
+impl MeshOptimizationReport {
+    /// Share of the original size saved, from 0 to 1.
+    pub fn savings(&self) -> f32 {
+        match self.original_size {
+            0 => 0.0,
+            original => 1.0 - self.optimized_size as f32 / original as f32,
+        }
+    }
+}
+
+impl fmt::Display for MeshOptimizationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} -> {} bytes ({:.1}% saved), {} -> {} vertices, {} -> {} triangles, ACMR {:.2} -> {:.2}",
+            self.original_size,
+            self.optimized_size,
+            self.savings() * 100.0,
+            self.original_vertices,
+            self.optimized_vertices,
+            self.original_triangles,
+            self.optimized_triangles,
+            self.original_acmr,
+            self.optimized_acmr,
+        )?;
+        if self.stripped_accessors + self.stripped_materials > 0 {
+            write!(f, ", stripped {} accessors and {} materials", self.stripped_accessors, self.stripped_materials)?;
+        }
+        if self.skipped_primitives > 0 {
+            write!(f, ", {} primitives skipped", self.skipped_primitives)?;
+        }
+        Ok(())
+    }
+}
+
+/// A vertex attribute of a primitive, its elements packed back to back.
+struct Stream {
+    /// The morph target it belongs to, if any.
+    target: Option<usize>,
+    name: String,
+    component_type: u64,
+    kind: String,
+    normalized: bool,
+    element_size: usize,
+    data: Vec<u8>,
+}
+
+impl Stream {
+    fn element(&self, vertex: usize) -> &[u8] {
+        &self.data[vertex * self.element_size..(vertex + 1) * self.element_size]
+    }
+
+    /// Keeps `vertices`, in that order.
+    fn select(&mut self, vertices: &[usize]) {
+        self.data = vertices.iter().flat_map(|&v| self.element(v).to_vec()).collect();
+    }
+
+    fn floats(&self) -> Option<Vec<f32>> {
+        (self.component_type == FLOAT)
+            .then(|| self.data.chunks_exact(4).map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]])).collect())
+    }
+}
+
+/// A triangle-list primitive read out of its accessors.
+struct Primitive {
+    mesh: usize,
+    index: usize,
+    streams: Vec<Stream>,
+    vertex_count: usize,
+    indices: Vec<u32>,
+}
+
+impl Primitive {
+    fn positions(&self) -> Option<Vec<[f32; 3]>> {
+        let stream = self.streams.iter().find(|s| s.target.is_none() && s.name == "POSITION" && s.kind == "VEC3")?;
+        Some(stream.floats()?.chunks_exact(3).map(|p| [p[0], p[1], p[2]]).collect())
+    }
+
+    /// Merges vertices with identical attributes, keeping the first of each.
+    fn weld(&mut self) {
+        let mut unique: HashMap<Vec<u8>, u32> = HashMap::new();
+        let mut kept = Vec::new();
+        let remap: Vec<u32> = (0..self.vertex_count)
+            .map(|v| {
+                let key: Vec<u8> = self.streams.iter().flat_map(|s| s.element(v).to_vec()).collect();
+                *unique.entry(key).or_insert_with(|| {
+                    kept.push(v);
+                    kept.len() as u32 - 1
+                })
+            })
+            .collect();
+
+        self.streams.iter_mut().for_each(|stream| stream.select(&kept));
+        self.vertex_count = kept.len();
+        self.indices.iter_mut().for_each(|i| *i = remap[*i as usize]);
+        let triangles: Vec<[u32; 3]> = self.indices.chunks_exact(3).map(|t| [t[0], t[1], t[2]]).collect();
+        self.indices = triangles.into_iter().filter(|t| t[0] != t[1] && t[1] != t[2] && t[0] != t[2]).flatten().collect();
+    }
+
+    /// Numbers vertices in the order the triangles first use them, dropping
+    /// the unused ones.
+    fn reorder_vertices(&mut self) {
+        let mut remap = vec![u32::MAX; self.vertex_count];
+        let mut order = Vec::new();
+        for index in self.indices.iter_mut() {
+            if remap[*index as usize] == u32::MAX {
+                remap[*index as usize] = order.len() as u32;
+                order.push(*index as usize);
+            }
+            *index = remap[*index as usize];
+        }
+        self.streams.iter_mut().for_each(|stream| stream.select(&order));
+        self.vertex_count = order.len();
+    }
+}
+
+impl Model3DProcessor {
+    /// Rewrites a model's triangle meshes for size and rendering speed and
+    /// returns it as a GLB. Buffers in separate files are taken from
+    /// `files` and embedded. The same input and settings always give the
+    /// same bytes.
+    pub fn optimize(
+        &self,
+        data: &[u8],
+        files: &[ExternalFile],
+        settings: &MeshOptimizeSettings,
+    ) -> Result<(Vec<u8>, MeshOptimizationReport)> {
+        let model = self
+            .load_with_files(data, None, files)
+            .with_context(|| "Mesh optimization needs the model's buffers embedded or among its files")?;
+        let mut json = document_json(data)?;
+        let mut report = MeshOptimizationReport { original_size: data.len(), ..Default::default() };
+
+        let mut primitives = Vec::new();
+        let mut incomplete_meshes = HashSet::new();
+        for mesh in model.document.meshes() {
+            for primitive in mesh.primitives() {
+                match read_primitive(&json, &model, &primitive, mesh.index()) {
+                    Some(primitive) => primitives.push(primitive),
+                    None => {
+                        report.skipped_primitives += 1;
+                        incomplete_meshes.insert(mesh.index());
+                    }
+                }
+            }
+        }
+
+        let (mut misses_before, mut misses_after) = (0, 0);
+        for primitive in &mut primitives {
+            report.original_vertices += primitive.vertex_count;
+            report.original_triangles += primitive.indices.len() / 3;
+            misses_before += cache_misses(&primitive.indices);
+
+            if settings.weld {
+                primitive.weld();
+            }
+            if settings.reorder {
+                let (indices, clusters) = tipsify(&primitive.indices, primitive.vertex_count);
+                primitive.indices = match primitive.positions() {
+                    Some(positions) => sort_clusters(&indices, &clusters, &positions),
+                    None => indices,
+                };
+                primitive.reorder_vertices();
+            }
+
+            report.optimized_vertices += primitive.vertex_count;
+            report.optimized_triangles += primitive.indices.len() / 3;
+            misses_after += cache_misses(&primitive.indices);
+        }
+        report.original_acmr = misses_before as f32 / report.original_triangles.max(1) as f32;
+        report.optimized_acmr = misses_after as f32 / report.optimized_triangles.max(1) as f32;
+
+        // Positions are quantized over each mesh's bounds, undone by a node
+        let dequantize: BTreeMap<usize, ([f32; 3], f32)> = match settings.quantize {
+            true => position_quantization(&json, &primitives, &incomplete_meshes),
+            false => BTreeMap::new(),
+        };
+        let mut pending = Vec::new();
+        let mut uses_extension = false;
+        for primitive in &primitives {
+            let mut accessors = Vec::new();
+            for stream in &primitive.streams {
+                let encoded = encode_stream(stream, settings.quantize, dequantize.get(&primitive.mesh));
+                uses_extension |= encoded.extension;
+                accessors.push(push_accessor(&mut json, &mut pending, encoded, primitive.vertex_count));
+            }
+            let indices = encode_indices(&primitive.indices, primitive.vertex_count);
+            let indices = push_accessor(&mut json, &mut pending, indices, primitive.indices.len());
+
+            let source = &mut json["meshes"][primitive.mesh]["primitives"][primitive.index];
+            source["indices"] = json!(indices);
+            for (stream, accessor) in primitive.streams.iter().zip(accessors) {
+                match stream.target {
+                    Some(t) => source["targets"][t][&stream.name] = json!(accessor),
+                    None => source["attributes"][&stream.name] = json!(accessor),
+                }
+            }
+        }
+        for (&mesh, &(offset, scale)) in &dequantize {
+            add_dequantization_nodes(&mut json, mesh, offset, scale);
+        }
+        if uses_extension {
+            for list in ["extensionsUsed", "extensionsRequired"] {
+                let extensions = json[list].as_array().cloned().unwrap_or_default();
+                if !extensions.iter().any(|e| e == MESH_QUANTIZATION) {
+                    json[list] = Value::Array(extensions.into_iter().chain([json!(MESH_QUANTIZATION)]).collect());
+                }
+            }
+        }
+
+        let used = json.get("extensionsUsed").and_then(Value::as_array).cloned().unwrap_or_default();
+        let strip = settings.strip_unused && !used.iter().any(|e| STRIP_BLOCKING_EXTENSIONS.iter().any(|b| e == b));
+        if strip {
+            let remap = strip_unused(&mut json, "accessors", accessor_references);
+            report.stripped_accessors = remap.iter().filter(|i| i.is_none()).count();
+            for (accessor, _, _, _) in &mut pending {
+                *accessor = remap[*accessor].context("A rewritten accessor went unused")?;
+            }
+            let remap = strip_unused(&mut json, "materials", material_references);
+            report.stripped_materials = remap.iter().filter(|i| i.is_none()).count();
+        }
+
+        // Views of the replaced accessors go, and the new data follows
+        let view_count = json["bufferViews"].as_array().map_or(0, Vec::len) as u64;
+        let referenced: HashSet<u64> = view_references(&mut json).into_iter().filter_map(|v| v.as_u64()).collect();
+        let unreferenced: Vec<u64> = (0..view_count).filter(|v| !referenced.contains(v)).collect();
+        let mut bin = relayout_views(&mut json, &model, &unreferenced)?;
+        place_pending(&mut json, &mut bin, pending);
+        set_buffer(&mut json, &bin, None);
+
+        let glb = write_glb(&serde_json::to_vec(&json)?, &bin);
+        report.optimized_size = glb.len();
+        Ok((glb, report))
+    }
+}
+
+/// Reads a triangle-list primitive whose attributes can all be rewritten.
+fn read_primitive(json: &Value, model: &LoadedModel, primitive: &gltf::Primitive, mesh: usize) -> Option<Primitive> {
+    if primitive.mode() != gltf::mesh::Mode::Triangles {
+        return None;
+    }
+    let source = &json["meshes"][mesh]["primitives"][primitive.index()];
+    let mut streams = Vec::new();
+    let targets = source["targets"].as_array().into_iter().flatten().enumerate().map(|(t, attributes)| (Some(t), attributes));
+    for (target, attributes) in std::iter::once((None, &source["attributes"])).chain(targets) {
+        for (name, accessor) in attributes.as_object()? {
+            streams.push(read_stream(json, model, accessor.as_u64()? as usize, target, name)?);
+        }
+    }
+
+    let vertex_count = streams.first()?.data.len() / streams.first()?.element_size;
+    if streams.iter().any(|s| s.data.len() != vertex_count * s.element_size) {
+        return None;
+    }
+    let reader = primitive.reader(|buffer| model.buffers.get(buffer.index()).map(|data| &data[..]));
+    let mut indices: Vec<u32> = match reader.read_indices() {
+        Some(indices) => indices.into_u32().collect(),
+        None if source.get("indices").is_some() => return None,
+        None => (0..vertex_count as u32).collect(),
+    };
+    if indices.iter().any(|&i| i as usize >= vertex_count) {
+        return None;
+    }
+    indices.truncate(indices.len() / 3 * 3);
+
+    Some(Primitive { mesh, index: primitive.index(), streams, vertex_count, indices })
+}
+
+fn read_stream(json: &Value, model: &LoadedModel, index: usize, target: Option<usize>, name: &str) -> Option<Stream> {
+    let accessor = model.document.accessors().nth(index)?;
+    let source = &json["accessors"][index];
+    let kind = source["type"].as_str()?;
+    if accessor.sparse().is_some() || component_count(kind).is_none() {
+        return None;
+    }
+    let view = accessor.view()?;
+    let element_size = accessor.size();
+    let stride = view.stride().unwrap_or(element_size);
+    let buffer = model.buffers.get(view.buffer().index())?;
+    let start = view.offset() + accessor.offset();
+
+    let mut data = Vec::with_capacity(accessor.count() * element_size);
+    for i in 0..accessor.count() {
+        data.extend_from_slice(buffer.get(start + i * stride..start + i * stride + element_size)?);
+    }
+    Some(Stream {
+        target,
+        name: name.to_string(),
+        component_type: source["componentType"].as_u64()?,
+        kind: kind.to_string(),
+        normalized: source["normalized"].as_bool().unwrap_or(false),
+        element_size,
+        data,
+    })
+}
+
+fn component_count(kind: &str) -> Option<usize> {
+    match kind {
+        "SCALAR" => Some(1),
+        "VEC2" => Some(2),
+        "VEC3" => Some(3),
+        "VEC4" => Some(4),
+        _ => None,
+    }
+}
+
+fn component_size(component_type: u64) -> usize {
+    match component_type {
+        BYTE | UNSIGNED_BYTE => 1,
+        SHORT | UNSIGNED_SHORT => 2,
+        _ => 4,
+    }
+}
+
+/// Misses in a FIFO vertex cache drawing `indices` in order.
+fn cache_misses(indices: &[u32]) -> usize {
+    let mut cache = VecDeque::with_capacity(CACHE_SIZE);
+    let mut misses = 0;
+    for &index in indices {
+        if !cache.contains(&index) {
+            misses += 1;
+            if cache.len() == CACHE_SIZE {
+                cache.pop_front();
+            }
+            cache.push_back(index);
+        }
+    }
+    misses
+}
+
+/// Orders triangles for the vertex cache with Tipsify (Sander, Nehab and
+/// Barczak 2007): fan around a vertex still in the cache, else restart from
+/// a recent vertex. Returns the indices and the first triangle of each run
+/// between restarts, which share no cache state and can be reordered.
+pub(super) fn tipsify(indices: &[u32], vertex_count: usize) -> (Vec<u32>, Vec<usize>) {
+    let triangle_count = indices.len() / 3;
+    let mut offsets = vec![0usize; vertex_count + 1];
+    indices.iter().for_each(|&v| offsets[v as usize + 1] += 1);
+    (0..vertex_count).for_each(|v| offsets[v + 1] += offsets[v]);
+    let mut adjacency = vec![0usize; indices.len()];
+    let mut fill = offsets.clone();
+    for (t, triangle) in indices.chunks_exact(3).enumerate() {
+        for &v in triangle {
+            adjacency[fill[v as usize]] = t;
+            fill[v as usize] += 1;
+        }
+    }
+
+    let mut live: Vec<usize> = (0..vertex_count).map(|v| offsets[v + 1] - offsets[v]).collect();
+    let mut cache_time = vec![0usize; vertex_count];
+    let mut time = CACHE_SIZE + 1;
+    let mut emitted = vec![false; triangle_count];
+    let mut dead_end = Vec::new();
+    let mut cursor = 0;
+    let mut output = Vec::with_capacity(indices.len());
+    let mut clusters = Vec::new();
+
+    let mut next_dead_end = |dead_end: &mut Vec<u32>, live: &[usize]| {
+        while let Some(v) = dead_end.pop() {
+            if live[v as usize] > 0 {
+                return Some(v as usize);
+            }
+        }
+        while cursor < vertex_count {
+            cursor += 1;
+            if live[cursor - 1] > 0 {
+                return Some(cursor - 1);
+            }
+        }
+        None
+    };
+
+    clusters.push(0);
+    let mut fanning = next_dead_end(&mut dead_end, &live);
+    while let Some(f) = fanning {
+        let mut candidates = Vec::new();
+        for &t in &adjacency[offsets[f]..offsets[f + 1]] {
+            if emitted[t] {
+                continue;
+            }
+            emitted[t] = true;
+            for &v in &indices[t * 3..t * 3 + 3] {
+                output.push(v);
+                dead_end.push(v);
+                candidates.push(v as usize);
+                live[v as usize] -= 1;
+                if time - cache_time[v as usize] > CACHE_SIZE {
+                    cache_time[v as usize] = time;
+                    time += 1;
+                }
+            }
+        }
+
+        // The candidate longest in the cache that its remaining triangles
+        // will not push out
+        let mut best = None;
+        let mut best_priority = -1isize;
+        for &v in &candidates {
+            if live[v] == 0 {
+                continue;
+            }
+            let age = time - cache_time[v];
+            let priority = if age + 2 * live[v] <= CACHE_SIZE { age as isize } else { 0 };
+            if priority > best_priority {
+                best = Some(v);
+                best_priority = priority;
+            }
+        }
+        fanning = match best {
+            Some(v) => Some(v),
+            None => {
+                clusters.push(output.len() / 3);
+                next_dead_end(&mut dead_end, &live)
+            }
+        };
+    }
+    clusters.retain(|&start| start < triangle_count);
+    clusters.dedup();
+    (output, clusters)
+}
+
+/// Draws clusters facing away from the mesh centre first, as they tend to
+/// be in front, so fewer pixels are shaded twice.
+fn sort_clusters(indices: &[u32], clusters: &[usize], positions: &[[f32; 3]]) -> Vec<u32> {
+    let triangle_count = indices.len() / 3;
+    let mut centre = [0.0f64; 3];
+    let mut total_area = 0.0f64;
+    let mut stats = Vec::with_capacity(clusters.len());
+    for (c, &start) in clusters.iter().enumerate() {
+        let end = clusters.get(c + 1).copied().unwrap_or(triangle_count);
+        let (mut weighted, mut normal, mut area) = ([0.0f64; 3], [0.0f64; 3], 0.0f64);
+        for triangle in indices[start * 3..end * 3].chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|i| positions[triangle[i] as usize].map(f64::from));
+            let n = cross(sub(b, a), sub(c, a));
+            let twice_area = dot(n, n).sqrt();
+            for i in 0..3 {
+                weighted[i] += (a[i] + b[i] + c[i]) / 3.0 * twice_area;
+                normal[i] += n[i];
+            }
+            area += twice_area;
+        }
+        (0..3).for_each(|i| centre[i] += weighted[i]);
+        total_area += area;
+        stats.push((start..end, weighted, normal, area));
+    }
+    if total_area > 0.0 {
+        centre = centre.map(|c| c / total_area);
+    }
+
+    let mut keyed: Vec<(f64, std::ops::Range<usize>)> = stats
+        .into_iter()
+        .map(|(range, weighted, normal, area)| {
+            let length = dot(normal, normal).sqrt();
+            if area == 0.0 || length == 0.0 {
+                return (0.0, range);
+            }
+            let offset = [0, 1, 2].map(|i| weighted[i] / area - centre[i]);
+            (dot(offset, normal) / length, range)
+        })
+        .collect();
+    keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+    keyed.into_iter().flat_map(|(_, range)| indices[range.start * 3..range.end * 3].to_vec()).collect()
+}
+
+fn sub(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+/// Offset and uniform scale mapping 16-bit positions back onto each mesh
+/// that can have its positions quantized: fully rewritten, without morph
+/// targets, and placed only by plain nodes whose transform can be extended.
+/// A uniform scale leaves normals pointing the right way. Keyed by mesh
+/// index so the dequantization nodes are added in mesh order.
+fn position_quantization(json: &Value, primitives: &[Primitive], incomplete: &HashSet<usize>) -> BTreeMap<usize, ([f32; 3], f32)> {
+    let nodes = json["nodes"].as_array().cloned().unwrap_or_default();
+    let mut bounds: BTreeMap<usize, ([f32; 3], [f32; 3])> = BTreeMap::new();
+    let mut excluded: HashSet<usize> = incomplete.clone();
+    for primitive in primitives {
+        let positions = match primitive.streams.iter().any(|s| s.target.is_some()) {
+            true => None,
+            false => primitive.positions(),
+        };
+        let Some(positions) = positions else {
+            excluded.insert(primitive.mesh);
+            continue;
+        };
+        let entry = bounds.entry(primitive.mesh).or_insert(([f32::MAX; 3], [f32::MIN; 3]));
+        for p in positions {
+            entry.0 = std::array::from_fn(|i| entry.0[i].min(p[i]));
+            entry.1 = std::array::from_fn(|i| entry.1[i].max(p[i]));
+        }
+    }
+
+    bounds
+        .into_iter()
+        .filter(|(mesh, _)| !excluded.contains(mesh))
+        .filter(|(mesh, _)| {
+            let users: Vec<&Value> = nodes.iter().filter(|n| n["mesh"].as_u64() == Some(*mesh as u64)).collect();
+            !users.is_empty() && users.iter().all(|n| n.get("skin").is_none() && n.get("extensions").is_none())
+        })
+        .filter(|(_, (min, max))| min.iter().chain(max).all(|c| c.is_finite()))
+        .map(|(mesh, (min, max))| {
+            let extent = (0..3).map(|i| max[i] - min[i]).fold(0.0f32, f32::max);
+            (mesh, (min, if extent > 0.0 { extent / 65535.0 } else { 1.0 }))
+        })
+        .collect()
+}
+
+/// Moves `mesh` off each node placing it onto a new child carrying the
+/// dequantization transform, so the node's children and animations are
+/// unaffected.
+fn add_dequantization_nodes(json: &mut Value, mesh: usize, offset: [f32; 3], scale: f32) {
+    let Some(nodes) = json["nodes"].as_array_mut() else {
+        return;
+    };
+    let users: Vec<usize> = (0..nodes.len()).filter(|&n| nodes[n]["mesh"].as_u64() == Some(mesh as u64)).collect();
+    for user in users {
+        let child = nodes.len();
+        nodes.push(json!({"mesh": mesh, "translation": offset, "scale": [scale, scale, scale]}));
+        let node = nodes[user].as_object_mut().expect("nodes placing a mesh are objects");
+        node.remove("mesh");
+        let mut children = node.get("children").and_then(Value::as_array).cloned().unwrap_or_default();
+        children.push(json!(child));
+        node.insert("children".to_string(), Value::Array(children));
+    }
+}
+
+/// Accessors waiting for buffer views: the accessor's index, its data, the
+/// view's stride if it needs one and the view's target.
+pub(super) type Pending = Vec<(usize, Vec<u8>, Option<usize>, u64)>;
+
+/// An accessor's new contents and JSON, before it has a buffer view.
+pub(super) struct Encoded {
+    accessor: Value,
+    data: Vec<u8>,
+    element_size: usize,
+    target: u64,
+    /// Whether it needs `KHR_mesh_quantization`.
+    extension: bool,
+}
+
+/// Re-encodes a stream tightly, quantizing what the settings allow.
+fn encode_stream(stream: &Stream, quantize: bool, dequantize: Option<&([f32; 3], f32)>) -> Encoded {
+    let floats = match quantize && stream.target.is_none() {
+        true => stream.floats(),
+        false => None,
+    };
+    let quantized = floats.and_then(|floats| match (stream.name.as_str(), stream.kind.as_str()) {
+        ("POSITION", "VEC3") => dequantize.map(|&(offset, scale)| {
+            let data = floats.chunks_exact(3).flat_map(|p| {
+                (0..3).flat_map(move |i| (((p[i] - offset[i]) / scale).round().clamp(0.0, 65535.0) as u16).to_le_bytes())
+            });
+            (UNSIGNED_SHORT, false, data.collect::<Vec<u8>>(), true)
+        }),
+        ("NORMAL", "VEC3") | ("TANGENT", "VEC4") => {
+            let data = floats.iter().map(|&c| (c.clamp(-1.0, 1.0) * 127.0).round() as i8 as u8).collect();
+            Some((BYTE, true, data, true))
+        }
+        (name, "VEC2") if name.starts_with("TEXCOORD_") && floats.iter().all(|c| (0.0..=1.0).contains(c)) => {
+            let data = floats.iter().flat_map(|&c| ((c * 65535.0).round() as u16).to_le_bytes()).collect();
+            Some((UNSIGNED_SHORT, true, data, false))
+        }
+        _ => None,
+    });
+
+    let (component_type, normalized, data, extension) =
+        quantized.unwrap_or((stream.component_type, stream.normalized, stream.data.clone(), false));
+    let element_size = component_count(&stream.kind).unwrap_or(1) * component_size(component_type);
+    let mut accessor = json!({"componentType": component_type, "type": stream.kind});
+    if normalized {
+        accessor["normalized"] = json!(true);
+    }
+    // Positions must declare their bounds
+    if stream.name == "POSITION" {
+        let (min, max) = bounds(&data, component_type, component_count(&stream.kind).unwrap_or(1));
+        accessor["min"] = Value::Array(min);
+        accessor["max"] = Value::Array(max);
+    }
+    Encoded { accessor, data, element_size, target: ARRAY_BUFFER, extension }
+}
+
+/// Per-component minimum and maximum of `data` as stored.
+fn bounds(data: &[u8], component_type: u64, components: usize) -> (Vec<Value>, Vec<Value>) {
+    let size = component_size(component_type);
+    let values: Vec<f64> = data
+        .chunks_exact(size)
+        .map(|c| match component_type {
+            BYTE => c[0] as i8 as f64,
+            UNSIGNED_BYTE => c[0] as f64,
+            SHORT => i16::from_le_bytes([c[0], c[1]]) as f64,
+            UNSIGNED_SHORT => u16::from_le_bytes([c[0], c[1]]) as f64,
+            UNSIGNED_INT => u32::from_le_bytes([c[0], c[1], c[2], c[3]]) as f64,
+            _ => f32::from_le_bytes([c[0], c[1], c[2], c[3]]) as f64,
+        })
+        .collect();
+    let to_json = |v: f64| match component_type {
+        FLOAT => json!(v as f32),
+        _ => json!(v as i64),
+    };
+    (0..components)
+        .map(|i| {
+            let column = values.iter().skip(i).step_by(components);
+            let min = column.clone().copied().fold(f64::INFINITY, f64::min);
+            let max = column.copied().fold(f64::NEG_INFINITY, f64::max);
+            (to_json(if min.is_finite() { min } else { 0.0 }), to_json(if max.is_finite() { max } else { 0.0 }))
+        })
+        .unzip()
+}
+
+/// 16-bit indices when every vertex fits below the restart value, else 32.
+pub(super) fn encode_indices(indices: &[u32], vertex_count: usize) -> Encoded {
+    let (component_type, data): (u64, Vec<u8>) = match vertex_count <= u16::MAX as usize {
+        true => (UNSIGNED_SHORT, indices.iter().flat_map(|&i| (i as u16).to_le_bytes()).collect()),
+        false => (UNSIGNED_INT, indices.iter().flat_map(|&i| i.to_le_bytes()).collect()),
+    };
+    Encoded {
+        accessor: json!({"componentType": component_type, "type": "SCALAR"}),
+        element_size: component_size(component_type),
+        data,
+        target: ELEMENT_ARRAY_BUFFER,
+        extension: false,
+    }
+}
+
+/// Plain floats as a vertex attribute of `kind`, such as `VEC3`.
+pub(super) fn encode_floats<const N: usize>(values: &[[f32; N]], kind: &str) -> Encoded {
+    Encoded {
+        accessor: json!({"componentType": FLOAT, "type": kind}),
+        data: values.iter().flatten().flat_map(|c| c.to_le_bytes()).collect(),
+        element_size: N * 4,
+        target: ARRAY_BUFFER,
+        extension: false,
+    }
+}
+
+/// Appends the accessor, leaving its data, padded so every vertex starts
+/// four-byte aligned, to be placed in a buffer view once the old views are
+/// laid out. Returns the accessor's index.
+pub(super) fn push_accessor(json: &mut Value, pending: &mut Pending, encoded: Encoded, count: usize) -> usize {
+    let mut accessor = encoded.accessor;
+    accessor["count"] = json!(count);
+    let accessors = json["accessors"].as_array().cloned().unwrap_or_default();
+    let index = accessors.len();
+    json["accessors"] = Value::Array(accessors.into_iter().chain([accessor]).collect());
+
+    let stride = encoded.element_size.next_multiple_of(4);
+    let (data, stride) = match encoded.target == ARRAY_BUFFER && stride != encoded.element_size {
+        true => {
+            let padded = encoded.data.chunks_exact(encoded.element_size).flat_map(|element| {
+                element.iter().copied().chain(std::iter::repeat_n(0, stride - encoded.element_size))
+            });
+            (padded.collect(), Some(stride))
+        }
+        false => (encoded.data, None),
+    };
+    pending.push((index, data, stride, encoded.target));
+    index
+}
+
+/// Gives each pending accessor a buffer view at the end of `bin`.
+pub(super) fn place_pending(json: &mut Value, bin: &mut Vec<u8>, pending: Pending) {
+    for (accessor, bytes, stride, target) in pending {
+        let view = push_view(json, bin, &bytes);
+        if let Some(stride) = stride {
+            json["bufferViews"][view]["byteStride"] = json!(stride);
+        }
+        json["bufferViews"][view]["target"] = json!(target);
+        json["accessors"][accessor]["bufferView"] = json!(view);
+    }
+}
+
+/// Removes the items of `key` that nothing refers to and renumbers the
+/// references. Returns each old index's new one.
+fn strip_unused(json: &mut Value, key: &str, references: fn(&mut Value) -> Vec<&mut Value>) -> Vec<Option<usize>> {
+    let count = json[key].as_array().map_or(0, Vec::len);
+    let used: HashSet<u64> = references(json).into_iter().filter_map(|r| r.as_u64()).collect();
+    let mut remap = Vec::with_capacity(count);
+    let mut next = 0;
+    for i in 0..count {
+        match used.contains(&(i as u64)) {
+            true => {
+                remap.push(Some(next));
+                next += 1;
+            }
+            false => remap.push(None),
+        }
+    }
+    if count == 0 {
+        return remap;
+    }
+
+    for reference in references(json) {
+        if let Some(Some(index)) = reference.as_u64().and_then(|i| remap.get(i as usize)) {
+            *reference = json!(index);
+        }
+    }
+    let items = json[key].as_array().cloned().unwrap_or_default();
+    let kept: Vec<Value> = items.into_iter().zip(&remap).filter(|(_, new)| new.is_some()).map(|(item, _)| item).collect();
+    match (kept.is_empty(), json.as_object_mut()) {
+        (true, Some(fields)) => {
+            fields.remove(key);
+        }
+        _ => json[key] = Value::Array(kept),
+    }
+    remap
+}
+
+fn items(value: &mut Value) -> impl Iterator<Item = &mut Value> {
+    value.as_array_mut().into_iter().flatten()
+}
+
+fn members(value: &mut Value) -> impl Iterator<Item = &mut Value> {
+    value.as_object_mut().into_iter().flat_map(|object| object.values_mut())
+}
+
+fn primitives(meshes: &mut Value) -> impl Iterator<Item = &mut Value> {
+    items(meshes).filter_map(|mesh| mesh.get_mut("primitives")).flat_map(items)
+}
+
+/// Every accessor index the core glTF schema refers to.
+fn accessor_references(json: &mut Value) -> Vec<&mut Value> {
+    let mut references = Vec::new();
+    for (key, value) in json.as_object_mut().into_iter().flatten() {
+        match key.as_str() {
+            "meshes" => {
+                for primitive in primitives(value) {
+                    for (field, value) in primitive.as_object_mut().into_iter().flatten() {
+                        match field.as_str() {
+                            "attributes" => references.extend(members(value)),
+                            "indices" => references.push(value),
+                            "targets" => references.extend(items(value).flat_map(members)),
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            "skins" => references.extend(items(value).filter_map(|skin| skin.get_mut("inverseBindMatrices"))),
+            "animations" => {
+                for sampler in items(value).filter_map(|animation| animation.get_mut("samplers")).flat_map(items) {
+                    for (field, value) in sampler.as_object_mut().into_iter().flatten() {
+                        if field == "input" || field == "output" {
+                            references.push(value);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    references
+}
+
+fn material_references(json: &mut Value) -> Vec<&mut Value> {
+    match json.get_mut("meshes") {
+        Some(meshes) => primitives(meshes).filter_map(|primitive| primitive.get_mut("material")).collect(),
+        None => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::model_loader::data_uri;
+
+    /// Triangles of the first mesh as world-space corner positions, rounded
+    /// to a thousandth so quantized copies compare equal.
+    fn world_triangles(data: &[u8]) -> Vec<[[i32; 3]; 3]> {
+        let model = Model3DProcessor::new().load(data, None).unwrap();
+        let node = model.document.nodes().find(|node| node.mesh().is_some()).unwrap();
+        let ([tx, ty, tz], _, [s, _, _]) = node.transform().decomposed();
+        let primitive = node.mesh().unwrap().primitives().next().unwrap();
+        let positions = crate::format::read_positions(&primitive, &model.buffers).unwrap();
+        let reader = primitive.reader(|buffer| model.buffers.get(buffer.index()).map(|data| &data[..]));
+        let indices: Vec<u32> = match reader.read_indices() {
+            Some(indices) => indices.into_u32().collect(),
+            None => (0..positions.len() as u32).collect(),
+        };
+
+        let corner = |i: u32| {
+            let p = positions[i as usize];
+            [tx + p[0] * s, ty + p[1] * s, tz + p[2] * s].map(|c| (c * 1000.0).round() as i32)
+        };
+        let mut triangles: Vec<[[i32; 3]; 3]> = indices
+            .chunks_exact(3)
+            .map(|t| {
+                // Rotate so the smallest corner leads, keeping the winding
+                let corners = [corner(t[0]), corner(t[1]), corner(t[2])];
+                let first = (0..3).min_by_key(|&i| corners[i]).unwrap();
+                [corners[first], corners[(first + 1) % 3], corners[(first + 2) % 3]]
+            })
+            .collect();
+        triangles.sort();
+        triangles
+    }
+
+    #[test]
+    fn test_optimize_grid() {
+        // An 8x8 grid of quads as an unindexed, shuffled triangle soup
+        let mut corners = Vec::new();
+        for y in 0..8 {
+            for x in 0..8 {
+                let [a, b, c, d] = [(x, y), (x + 1, y), (x + 1, y + 1), (x, y + 1)];
+                corners.push([a, b, c]);
+                corners.push([a, c, d]);
+            }
+        }
+        let mut seed = 7u32;
+        for i in (1..corners.len()).rev() {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            corners.swap(i, (seed >> 16) as usize % (i + 1));
+        }
+        let vertices: Vec<(i32, i32)> = corners.iter().flatten().copied().collect();
+        let mut buffer = Vec::new();
+        for &(x, y) in &vertices {
+            [x as f32 * 0.5 - 2.0, y as f32 * 0.5, 1.0].iter().for_each(|c| buffer.extend(c.to_le_bytes()));
+        }
+        for _ in &vertices {
+            [0.0f32, 0.0, 1.0].iter().for_each(|c| buffer.extend(c.to_le_bytes()));
+        }
+        for &(x, y) in &vertices {
+            [x as f32 / 8.0, y as f32 / 8.0].iter().for_each(|c| buffer.extend(c.to_le_bytes()));
+        }
+        let n = vertices.len();
+        let gltf = json!({
+            "asset": {"version": "2.0"},
+            "scene": 0,
+            "scenes": [{"nodes": [0]}],
+            "nodes": [{"mesh": 0, "name": "grid"}],
+            "meshes": [{"primitives": [{"attributes": {"POSITION": 0, "NORMAL": 1, "TEXCOORD_0": 2}, "material": 1}]}],
+            "materials": [{"name": "unused"}, {"name": "ground"}],
+            "buffers": [{"byteLength": buffer.len(), "uri": data_uri(&buffer)}],
+            "bufferViews": [
+                {"buffer": 0, "byteLength": n * 12},
+                {"buffer": 0, "byteOffset": n * 12, "byteLength": n * 12},
+                {"buffer": 0, "byteOffset": n * 24, "byteLength": n * 8}
+            ],
+            "accessors": [
+                {"bufferView": 0, "componentType": FLOAT, "count": n, "type": "VEC3", "min": [-2, 0, 1], "max": [2, 4, 1]},
+                {"bufferView": 1, "componentType": FLOAT, "count": n, "type": "VEC3"},
+                {"bufferView": 2, "componentType": FLOAT, "count": n, "type": "VEC2"},
+                {"bufferView": 1, "componentType": FLOAT, "count": 1, "type": "SCALAR"}
+            ]
+        });
+        let gltf = serde_json::to_vec(&gltf).unwrap();
+
+        let processor = Model3DProcessor::new();
+        let (optimized, report) = processor.optimize(&gltf, &[], &MeshOptimizeSettings::default()).unwrap();
+        assert_eq!((report.original_vertices, report.optimized_vertices), (384, 81));
+        assert_eq!((report.original_triangles, report.optimized_triangles), (128, 128));
+        assert!(report.optimized_acmr < 1.0 && report.original_acmr > 2.0, "{}", report);
+        assert_eq!((report.stripped_accessors, report.stripped_materials), (4, 1));
+        assert!(report.savings() > 0.5, "{}", report);
+
+        // Same triangles, now quantized and welded
+        assert_eq!(world_triangles(&optimized), world_triangles(&gltf));
+        let json = document_json(&optimized).unwrap();
+        assert_eq!(json["extensionsRequired"], json!([MESH_QUANTIZATION]));
+        assert_eq!(json["materials"], json!([{"name": "ground"}]));
+        assert_eq!(json["meshes"][0]["primitives"][0]["material"], 0);
+        assert_eq!(json["nodes"][0]["children"], json!([1]));
+        let primitive = &json["meshes"][0]["primitives"][0];
+        let accessor = |name: &str| &json["accessors"][primitive["attributes"][name].as_u64().unwrap() as usize];
+        assert_eq!(accessor("POSITION")["componentType"], UNSIGNED_SHORT);
+        assert_eq!(accessor("NORMAL")["componentType"], BYTE);
+        assert_eq!(accessor("TEXCOORD_0")["componentType"], UNSIGNED_SHORT);
+
+        // Deterministic, and without quantization the floats stay
+        assert_eq!(processor.optimize(&gltf, &[], &MeshOptimizeSettings::default()).unwrap().0, optimized);
+        let settings = MeshOptimizeSettings { quantize: false, ..Default::default() };
+        let (unquantized, _) = processor.optimize(&gltf, &[], &settings).unwrap();
+        assert_eq!(world_triangles(&unquantized), world_triangles(&gltf));
+        assert!(document_json(&unquantized).unwrap().get("extensionsUsed").is_none());
+    }
+
+    #[test]
+    fn test_optimize_many_meshes() {
+        // Eight meshes of one triangle each, at different sizes
+        let mut buffer = Vec::new();
+        for mesh in 0..8 {
+            let size = mesh as f32 + 1.0;
+            for c in [0.0f32, 0.0, 0.0, size, 0.0, 0.0, 0.0, size, 0.0] {
+                buffer.extend(c.to_le_bytes());
+            }
+        }
+        let gltf = json!({
+            "asset": {"version": "2.0"},
+            "scene": 0,
+            "scenes": [{"nodes": (0..8).collect::<Vec<_>>()}],
+            "nodes": (0..8).map(|m| json!({"mesh": m})).collect::<Vec<_>>(),
+            "meshes": (0..8).map(|m| json!({"primitives": [{"attributes": {"POSITION": m}}]})).collect::<Vec<_>>(),
+            "buffers": [{"byteLength": buffer.len(), "uri": data_uri(&buffer)}],
+            "bufferViews": (0..8).map(|m| json!({"buffer": 0, "byteOffset": m * 36, "byteLength": 36})).collect::<Vec<_>>(),
+            "accessors": (0..8)
+                .map(|m| json!({"bufferView": m, "componentType": FLOAT, "count": 3, "type": "VEC3",
+                    "min": [0, 0, 0], "max": [m + 1, m + 1, 0]}))
+                .collect::<Vec<_>>()
+        });
+        let gltf = serde_json::to_vec(&gltf).unwrap();
+
+        let processor = Model3DProcessor::new();
+        let (optimized, _) = processor.optimize(&gltf, &[], &MeshOptimizeSettings::default()).unwrap();
+        for _ in 0..4 {
+            assert_eq!(processor.optimize(&gltf, &[], &MeshOptimizeSettings::default()).unwrap().0, optimized);
+        }
+        let json = document_json(&optimized).unwrap();
+        for mesh in 0..8 {
+            assert_eq!(json["nodes"][mesh]["children"], json!([8 + mesh]));
+            assert_eq!(json["nodes"][8 + mesh]["mesh"], mesh);
+        }
+    }
+}
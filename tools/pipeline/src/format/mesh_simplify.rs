@@ -0,0 +1,653 @@
+use super::mesh_optimize::{encode_indices, place_pending, push_accessor, tipsify};
+use super::model_pack::{document_json, relayout_views, set_buffer, write_glb};
+use super::{read_positions, ExternalFile, Model3DProcessor};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fmt;
+
+const MSFT_LOD: &str = "MSFT_lod";
+
+/// How much more moving a vertex off a mesh border or seam costs than off
+/// the surface, so outlines and texture seams hold their shape.
+const BORDER_WEIGHT: f64 = 10.0;
+
+/// How many times its target a level may keep before the report flags it.
+const MISSED_TARGET_MARGIN: f32 = 1.5;
+
+/// Triangle counts of a model and of each level of detail made from it.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LodReport {
+    pub ratios: Vec<f32>,
+    /// Triangles of the full model, then of each level.
+    pub triangles: Vec<usize>,
+}
+
+impl LodReport {
+    /// Ratios of the levels that kept well over their share of triangles,
+    /// as when seams or borders hold most of a mesh in place.
+    pub fn missed_targets(&self) -> Vec<f32> {
+        let full = self.triangles.first().copied().unwrap_or(0) as f32;
+        self.ratios
+            .iter()
+            .zip(self.triangles.iter().skip(1))
+            .filter(|&(ratio, &triangles)| triangles as f32 > (full * ratio * MISSED_TARGET_MARGIN).ceil())
+            .map(|(&ratio, _)| ratio)
+            .collect()
+    }
+}
+
+impl fmt::Display for LodReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} triangles", self.triangles.first().copied().unwrap_or(0))?;
+        for (ratio, triangles) in self.ratios.iter().zip(self.triangles.iter().skip(1)) {
+            write!(f, ", {} at {}%", triangles, ratio * 100.0)?;
+        }
+        Ok(())
+    }
+}
+
+/// Symmetric 4x4 error quadric, as its ten distinct coefficients.
+#[derive(Debug, Clone, Copy, Default)]
+struct Quadric([f64; 10]);
+
+impl Quadric {
+    /// Squared distance to the plane `n·p + d = 0`, times `weight`.
+    fn plane(n: [f64; 3], d: f64, weight: f64) -> Self {
+        let [a, b, c] = n;
+        Self([a * a, a * b, a * c, a * d, b * b, b * c, b * d, c * c, c * d, d * d].map(|q| q * weight))
+    }
+
+    fn add(&mut self, other: &Quadric) {
+        (0..10).for_each(|i| self.0[i] += other.0[i]);
+    }
+
+    fn error(&self, [x, y, z]: [f64; 3]) -> f64 {
+        let q = &self.0;
+        q[0] * x * x + 2.0 * q[1] * x * y + 2.0 * q[2] * x * z + 2.0 * q[3] * x
+            + q[4] * y * y + 2.0 * q[5] * y * z + 2.0 * q[6] * y
+            + q[7] * z * z + 2.0 * q[8] * z
+            + q[9]
+    }
+}
+
+/// Collapsing vertex `from` onto `to`, at the error it would add.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Collapse {
+    cost: f64,
+    from: u32,
+    to: u32,
+}
+
+impl Eq for Collapse {}
+
+impl Ord for Collapse {
+    /// Reversed, so the heap pops the cheapest collapse, ties going to the
+    /// lowest vertices.
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.cost.total_cmp(&self.cost).then(other.from.cmp(&self.from)).then(other.to.cmp(&self.to))
+    }
+}
+
+impl PartialOrd for Collapse {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Reduces a triangle list to at most `target_triangles` by collapsing
+/// vertices onto neighbours, cheapest first by quadric error (Garland and
+/// Heckbert 1997). Vertices are never moved or made, so the result indexes
+/// the same vertex data.
+///
+/// Vertices sharing a position collapse as one, so split vertices along
+/// texture seams and hard edges go together: each copy moves to the copy
+/// of the target it shared a triangle with. Vertices on borders and seams
+/// only slide along them, and a collapse that would leave a copy without
+/// a counterpart is skipped. Stops early when nothing more can collapse
+/// without flipping a triangle.
+pub fn simplify(positions: &[[f32; 3]], indices: &[u32], target_triangles: usize) -> Vec<u32> {
+    let points: Vec<[f64; 3]> = positions.iter().map(|p| p.map(f64::from)).collect();
+    let mut first_at: HashMap<[u32; 3], u32> = HashMap::new();
+    let welded: Vec<u32> = (0..positions.len() as u32)
+        .map(|v| *first_at.entry(positions[v as usize].map(f32::to_bits)).or_insert(v))
+        .collect();
+
+    // Triangles over the welded vertices, and the vertex each corner uses
+    let mut corners: Vec<[u32; 3]> = indices.chunks_exact(3).map(|t| [t[0], t[1], t[2]]).collect();
+    let mut triangles: Vec<[u32; 3]> = corners.iter().map(|t| t.map(|v| welded[v as usize])).collect();
+    let mut alive: Vec<bool> = triangles.iter().map(|t| t[0] != t[1] && t[1] != t[2] && t[0] != t[2]).collect();
+    if triangles.len() <= target_triangles {
+        return indices.to_vec();
+    }
+    let mut remaining = alive.iter().filter(|&&alive| alive).count();
+
+    let mut incident = vec![Vec::new(); points.len()];
+    let mut quadrics = vec![Quadric::default(); points.len()];
+    let mut edges: HashMap<(u32, u32), usize> = HashMap::new();
+    let mut split_edges: HashMap<(u32, u32), usize> = HashMap::new();
+    for (t, triangle) in triangles.iter().enumerate() {
+        if !alive[t] {
+            continue;
+        }
+        triangle.iter().for_each(|&v| incident[v as usize].push(t));
+        for (a, b) in [(0, 1), (1, 2), (2, 0)] {
+            *edges.entry(edge_key(triangle[a], triangle[b])).or_default() += 1;
+            *split_edges.entry(edge_key(corners[t][a], corners[t][b])).or_default() += 1;
+        }
+        let [a, b, c] = triangle.map(|v| points[v as usize]);
+        let normal = cross(sub(b, a), sub(c, a));
+        let length = dot(normal, normal).sqrt();
+        if length > 0.0 {
+            let n = normal.map(|x| x / length);
+            let quadric = Quadric::plane(n, -dot(n, a), length / 2.0);
+            triangle.iter().for_each(|&v| quadrics[v as usize].add(&quadric));
+        }
+    }
+
+    // Planes through border and seam edges, perpendicular to their triangle
+    let mut border_edges = HashSet::new();
+    let mut on_border = vec![false; points.len()];
+    for (t, triangle) in triangles.iter().enumerate() {
+        if !alive[t] {
+            continue;
+        }
+        let [pa, pb, pc] = triangle.map(|v| points[v as usize]);
+        let face = cross(sub(pb, pa), sub(pc, pa));
+        for (a, b) in [(0, 1), (1, 2), (2, 0)] {
+            let seam = split_edges[&edge_key(corners[t][a], corners[t][b])] == 1;
+            let (a, b) = (triangle[a], triangle[b]);
+            if edges[&edge_key(a, b)] != 1 && !seam {
+                continue;
+            }
+            border_edges.insert(edge_key(a, b));
+            on_border[a as usize] = true;
+            on_border[b as usize] = true;
+            let edge = sub(points[b as usize], points[a as usize]);
+            let normal = cross(edge, face);
+            let length = dot(normal, normal).sqrt();
+            if length > 0.0 {
+                let n = normal.map(|x| x / length);
+                let quadric = Quadric::plane(n, -dot(n, points[a as usize]), BORDER_WEIGHT * dot(edge, edge));
+                quadrics[a as usize].add(&quadric);
+                quadrics[b as usize].add(&quadric);
+            }
+        }
+    }
+
+    let allowed = |from: u32, to: u32, border_edges: &HashSet<(u32, u32)>| {
+        !on_border[from as usize] || border_edges.contains(&edge_key(from, to))
+    };
+    let cost = |from: u32, to: u32, quadrics: &[Quadric]| {
+        let mut quadric = quadrics[from as usize];
+        quadric.add(&quadrics[to as usize]);
+        quadric.error(points[to as usize]).max(0.0)
+    };
+
+    let mut heap = BinaryHeap::new();
+    for (t, triangle) in triangles.iter().enumerate() {
+        if !alive[t] {
+            continue;
+        }
+        for (a, b) in [(0, 1), (1, 2), (2, 0), (1, 0), (2, 1), (0, 2)] {
+            let (from, to) = (triangle[a], triangle[b]);
+            if allowed(from, to, &border_edges) {
+                heap.push(Collapse { cost: cost(from, to, &quadrics), from, to });
+            }
+        }
+    }
+
+    let mut removed = vec![false; points.len()];
+    while remaining > target_triangles {
+        let Some(candidate) = heap.pop() else {
+            break;
+        };
+        let Collapse { from, to, .. } = candidate;
+        if removed[from as usize] || removed[to as usize] || !allowed(from, to, &border_edges) {
+            continue;
+        }
+        let around: Vec<usize> = incident[from as usize].iter().copied().filter(|&t| alive[t]).collect();
+        if !around.iter().any(|&t| triangles[t].contains(&to)) {
+            continue;
+        }
+        // Costs only grow as quadrics merge; requeue outdated ones
+        let current = cost(from, to, &quadrics);
+        if current > candidate.cost * (1.0 + 1e-9) + 1e-12 {
+            heap.push(Collapse { cost: current, from, to });
+            continue;
+        }
+        if around.iter().any(|&t| !triangles[t].contains(&to) && flips(&triangles[t], from, to, &points)) {
+            continue;
+        }
+        let Some(copies) = matching_copies(&around, &triangles, &corners, from, to) else {
+            continue;
+        };
+
+        removed[from as usize] = true;
+        for &t in &around {
+            if triangles[t].contains(&to) {
+                alive[t] = false;
+                remaining -= 1;
+            } else {
+                for corner in 0..3 {
+                    if triangles[t][corner] == from {
+                        triangles[t][corner] = to;
+                        corners[t][corner] = copies[&corners[t][corner]];
+                    }
+                }
+                incident[to as usize].push(t);
+            }
+        }
+        incident[from as usize].clear();
+        incident[to as usize].retain(|&t| alive[t]);
+        let merged = quadrics[from as usize];
+        quadrics[to as usize].add(&merged);
+
+        let moved: Vec<(u32, u32)> = border_edges.iter().copied().filter(|&(a, b)| a == from || b == from).collect();
+        for (a, b) in moved {
+            border_edges.remove(&(a, b));
+            let other = if a == from { b } else { a };
+            if other != to {
+                border_edges.insert(edge_key(other, to));
+            }
+        }
+
+        let mut neighbours: Vec<u32> =
+            incident[to as usize].iter().flat_map(|&t| triangles[t]).filter(|&v| v != to).collect();
+        neighbours.sort_unstable();
+        neighbours.dedup();
+        for v in neighbours {
+            for (from, to) in [(to, v), (v, to)] {
+                if allowed(from, to, &border_edges) {
+                    heap.push(Collapse { cost: cost(from, to, &quadrics), from, to });
+                }
+            }
+        }
+    }
+
+    corners.into_iter().zip(alive).filter(|(_, alive)| *alive).flat_map(|(t, _)| t).collect()
+}
+
+/// For each copy of `from` among the triangles `around` it, the copy of
+/// `to` it shares a collapsing triangle with, or `None` when a copy has no
+/// single counterpart.
+fn matching_copies(
+    around: &[usize],
+    triangles: &[[u32; 3]],
+    corners: &[[u32; 3]],
+    from: u32,
+    to: u32,
+) -> Option<HashMap<u32, u32>> {
+    let mut copies = HashMap::new();
+    for &t in around.iter().filter(|&&t| triangles[t].contains(&to)) {
+        let copy = |v: u32| (0..3).find(|&c| triangles[t][c] == v).map(|c| corners[t][c]);
+        let (source, target) = (copy(from)?, copy(to)?);
+        if *copies.entry(source).or_insert(target) != target {
+            return None;
+        }
+    }
+    let every_copy = around
+        .iter()
+        .flat_map(|&t| (0..3).filter(move |&c| triangles[t][c] == from).map(move |c| corners[t][c]))
+        .all(|copy| copies.contains_key(&copy));
+    every_copy.then_some(copies)
+}
+
+fn edge_key(a: u32, b: u32) -> (u32, u32) {
+    (a.min(b), a.max(b))
+}
+
+/// Whether moving `from` to `to` turns the triangle over or flattens it.
+fn flips(triangle: &[u32; 3], from: u32, to: u32, points: &[[f64; 3]]) -> bool {
+    let normal = |t: [u32; 3]| {
+        let [a, b, c] = t.map(|v| points[v as usize]);
+        cross(sub(b, a), sub(c, a))
+    };
+    let before = normal(*triangle);
+    let after = normal(triangle.map(|v| if v == from { to } else { v }));
+    let scale = (dot(before, before) * dot(after, after)).sqrt();
+    scale == 0.0 || dot(before, after) <= 1e-3 * scale
+}
+
+fn sub(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+impl Model3DProcessor {
+    /// Adds a level of detail per ratio of the triangle count, largest
+    /// first, and returns the model as a GLB. Each level is a copy of every
+    /// mesh whose triangle primitives get simplified index buffers over the
+    /// original vertices, on a node listed in its source node's `MSFT_lod`
+    /// extension. Buffers in separate files are taken from `files` and
+    /// embedded.
+    pub fn generate_lods(&self, data: &[u8], files: &[ExternalFile], ratios: &[f32]) -> Result<(Vec<u8>, LodReport)> {
+        if let Some(ratio) = ratios.iter().find(|r| !(**r > 0.0 && **r < 1.0)) {
+            bail!("LOD ratio {} is not between 0 and 1", ratio);
+        }
+        let mut ratios = ratios.to_vec();
+        ratios.sort_by(|a, b| b.total_cmp(a));
+        ratios.dedup();
+
+        let model = self
+            .load_with_files(data, None, files)
+            .with_context(|| "LOD generation needs the model's buffers embedded or among its files")?;
+        let mut json = document_json(data)?;
+        let mut report = LodReport { ratios: ratios.clone(), triangles: vec![0; ratios.len() + 1] };
+        let mut pending = Vec::new();
+
+        // Each level of each mesh, as new meshes sharing the vertex accessors
+        let mut lod_meshes: Vec<Vec<usize>> = Vec::new();
+        for mesh in model.document.meshes() {
+            let mut levels: Vec<Value> = vec![json["meshes"][mesh.index()].clone(); ratios.len()];
+            for primitive in mesh.primitives() {
+                if primitive.mode() != gltf::mesh::Mode::Triangles {
+                    continue;
+                }
+                let Some(positions) = read_positions(&primitive, &model.buffers) else {
+                    continue;
+                };
+                let reader = primitive.reader(|buffer| model.buffers.get(buffer.index()).map(|data| &data[..]));
+                let mut indices: Vec<u32> = match reader.read_indices() {
+                    Some(indices) => indices.into_u32().collect(),
+                    None => (0..positions.len() as u32).collect(),
+                };
+                indices.truncate(indices.len() / 3 * 3);
+                if indices.iter().any(|&i| i as usize >= positions.len()) {
+                    bail!("Mesh {} has an index out of range", mesh.index());
+                }
+                weld_identical(&primitive, &model.buffers, &mut indices, positions.len());
+                report.triangles[0] += indices.len() / 3;
+
+                for (level, &ratio) in ratios.iter().enumerate() {
+                    let target = (indices.len() as f32 / 3.0 * ratio).round() as usize;
+                    let simplified = simplify(&positions, &indices, target.max(1));
+                    let (simplified, _) = tipsify(&simplified, positions.len());
+                    report.triangles[level + 1] += simplified.len() / 3;
+                    let encoded = encode_indices(&simplified, positions.len());
+                    let accessor = push_accessor(&mut json, &mut pending, encoded, simplified.len());
+                    levels[level]["primitives"][primitive.index()]["indices"] = json!(accessor);
+                }
+            }
+
+            let meshes = json["meshes"].as_array_mut().context("Model has meshes but no mesh list")?;
+            let first = meshes.len();
+            for (level, mut lod) in levels.into_iter().enumerate() {
+                if let Some(name) = lod["name"].as_str() {
+                    lod["name"] = json!(format!("{} LOD{}", name, level + 1));
+                }
+                meshes.push(lod);
+            }
+            lod_meshes.push((first..first + ratios.len()).collect());
+        }
+
+        if !ratios.is_empty() {
+            add_lod_nodes(&mut json, &lod_meshes);
+        }
+        let mut bin = relayout_views(&mut json, &model, &[])?;
+        place_pending(&mut json, &mut bin, pending);
+        set_buffer(&mut json, &bin, None);
+        Ok((write_glb(&serde_json::to_vec(&json)?, &bin), report))
+    }
+}
+
+/// Points each index at the first vertex equal to it in every attribute and
+/// morph target, so meshes exported without shared vertices simplify as if
+/// they had them. Leaves the indices alone when an attribute is sparse or
+/// not in a buffer view.
+fn weld_identical(primitive: &gltf::Primitive, buffers: &[Vec<u8>], indices: &mut [u32], count: usize) {
+    let targets = primitive.morph_targets().flat_map(|t| [t.positions(), t.normals(), t.tangents()]);
+    let accessors = primitive.attributes().map(|(_, accessor)| accessor).chain(targets.flatten());
+    let mut keys = vec![Vec::new(); count];
+    for accessor in accessors {
+        let Some(view) = accessor.view().filter(|_| accessor.sparse().is_none()) else {
+            return;
+        };
+        let Some(buffer) = buffers.get(view.buffer().index()) else {
+            return;
+        };
+        let size = accessor.size();
+        let stride = view.stride().unwrap_or(size);
+        let start = view.offset() + accessor.offset();
+        for (v, key) in keys.iter_mut().enumerate() {
+            let Some(bytes) = buffer.get(start + v * stride..start + v * stride + size) else {
+                return;
+            };
+            key.extend_from_slice(bytes);
+        }
+    }
+    let mut first: HashMap<&[u8], u32> = HashMap::new();
+    let welded: Vec<u32> = keys.iter().enumerate().map(|(v, key)| *first.entry(key).or_insert(v as u32)).collect();
+    indices.iter_mut().for_each(|i| *i = welded[*i as usize]);
+}
+
+/// Gives every node placing a mesh an `MSFT_lod` list of new nodes, outside
+/// the scene, placing its levels. Nodes with children or animations keep
+/// them but hand the mesh to a new child, as a level stands in for its
+/// node and everything under it.
+fn add_lod_nodes(json: &mut Value, lod_meshes: &[Vec<usize>]) {
+    let animated: HashSet<u64> = json["animations"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .flat_map(|animation| animation["channels"].as_array().into_iter().flatten())
+        .filter_map(|channel| channel["target"]["node"].as_u64())
+        .collect();
+    let Some(nodes) = json["nodes"].as_array_mut() else {
+        return;
+    };
+
+    for node in 0..nodes.len() {
+        let Some(mesh) = nodes[node]["mesh"].as_u64().map(|m| m as usize) else {
+            continue;
+        };
+        let mut host = node;
+        if nodes[node].get("children").is_some() || animated.contains(&(node as u64)) {
+            host = nodes.len();
+            let source = nodes[node].as_object_mut().expect("nodes placing a mesh are objects");
+            let mut child = serde_json::Map::new();
+            for field in ["mesh", "skin", "weights"] {
+                if let Some(value) = source.remove(field) {
+                    child.insert(field.to_string(), value);
+                }
+            }
+            let mut children = source.get("children").and_then(Value::as_array).cloned().unwrap_or_default();
+            children.push(json!(host));
+            source.insert("children".to_string(), Value::Array(children));
+            nodes.push(Value::Object(child));
+        }
+
+        let mut ids = Vec::new();
+        for &lod in &lod_meshes[mesh] {
+            let mut level = serde_json::Map::new();
+            for field in ["skin", "weights", "matrix", "translation", "rotation", "scale"] {
+                if let Some(value) = nodes[host].get(field) {
+                    level.insert(field.to_string(), value.clone());
+                }
+            }
+            level.insert("mesh".to_string(), json!(lod));
+            ids.push(nodes.len());
+            nodes.push(Value::Object(level));
+        }
+        nodes[host]["extensions"][MSFT_LOD] = json!({"ids": ids});
+    }
+
+    let mut used = json["extensionsUsed"].as_array().cloned().unwrap_or_default();
+    if !used.iter().any(|e| e == MSFT_LOD) {
+        used.push(json!(MSFT_LOD));
+        json["extensionsUsed"] = Value::Array(used);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::model_loader::data_uri;
+    use crate::format::{FormatRegistry, ImportSettings, ModelImportSettings};
+
+    /// A UV sphere with its poles welded, so only the simplifier decides what
+    /// goes. With `split_seam`, the vertices along its texture seam come
+    /// twice, as they would with UVs.
+    fn sphere(rings: u32, segments: u32, split_seam: bool) -> (Vec<[f32; 3]>, Vec<u32>) {
+        let mut positions = vec![[0.0, 1.0, 0.0]];
+        for ring in 1..rings {
+            let theta = std::f32::consts::PI * ring as f32 / rings as f32;
+            for segment in 0..segments {
+                let phi = std::f32::consts::TAU * segment as f32 / segments as f32;
+                positions.push([theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin()]);
+            }
+        }
+        let seam = positions.len() as u32;
+        if split_seam {
+            let column: Vec<[f32; 3]> =
+                (1..rings).map(|ring| positions[1 + ((ring - 1) * segments) as usize]).collect();
+            positions.extend(column);
+        }
+        positions.push([0.0, -1.0, 0.0]);
+        let bottom = positions.len() as u32 - 1;
+        let at = |ring: u32, segment: u32| match ring {
+            0 => 0,
+            r if r == rings => bottom,
+            r if split_seam && segment == segments => seam + r - 1,
+            r => 1 + (r - 1) * segments + segment % segments,
+        };
+        let mut indices = Vec::new();
+        for ring in 0..rings {
+            for segment in 0..segments {
+                let [a, b, c, d] = [at(ring, segment), at(ring + 1, segment), at(ring + 1, segment + 1), at(ring, segment + 1)];
+                for triangle in [[a, c, b], [a, d, c]] {
+                    if triangle[0] != triangle[1] && triangle[1] != triangle[2] && triangle[0] != triangle[2] {
+                        indices.extend(triangle);
+                    }
+                }
+            }
+        }
+        (positions, indices)
+    }
+
+    #[test]
+    fn test_simplify_and_embed_lods() {
+        let (positions, indices) = sphere(24, 32, false);
+        let triangles = indices.len() / 3;
+        let half = simplify(&positions, &indices, triangles / 2);
+        assert!(half.len() / 3 <= triangles / 2 && half.len() / 3 > triangles / 3, "{}", half.len() / 3);
+        // Still closed and facing outwards
+        for t in half.chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|i| positions[t[i] as usize].map(f64::from));
+            let centre = [0, 1, 2].map(|i| a[i] + b[i] + c[i]);
+            assert!(dot(cross(sub(b, a), sub(c, a)), centre) > 0.0);
+        }
+        let mut edges: HashMap<(u32, u32), usize> = HashMap::new();
+        for t in half.chunks_exact(3) {
+            for (a, b) in [(t[0], t[1]), (t[1], t[2]), (t[2], t[0])] {
+                *edges.entry(edge_key(a, b)).or_default() += 1;
+            }
+        }
+        assert!(edges.values().all(|&count| count == 2));
+
+        // Split seam vertices collapse together, each keeping to its side
+        let (split, split_indices) = sphere(24, 32, true);
+        let copies = split.len() as u32 - 24..split.len() as u32 - 1;
+        let half = simplify(&split, &split_indices, triangles / 2);
+        assert!(half.len() / 3 <= triangles / 2 && half.len() / 3 > triangles / 3, "{}", half.len() / 3);
+        let on_seam = |v: u32| split[v as usize][2] == 0.0 && split[v as usize][0] > 0.0;
+        for t in half.chunks_exact(3) {
+            let sides: HashSet<bool> = t.iter().filter(|&&v| on_seam(v)).map(|v| copies.contains(v)).collect();
+            assert!(sides.len() <= 1, "{:?}", t);
+        }
+
+        // A flat grid keeps its outline
+        let grid: Vec<[f32; 3]> = (0..100).map(|i| [(i % 10) as f32, (i / 10) as f32, 0.0]).collect();
+        let grid_indices: Vec<u32> = (0..81)
+            .flat_map(|q| {
+                let v = q / 9 * 10 + q % 9;
+                [v, v + 1, v + 11, v, v + 11, v + 10]
+            })
+            .collect();
+        let reduced = simplify(&grid, &grid_indices, 20);
+        assert!(reduced.len() / 3 <= 20, "{}", reduced.len() / 3);
+        let area: f32 = reduced
+            .chunks_exact(3)
+            .map(|t| {
+                let [a, b, c] = [0, 1, 2].map(|i| grid[t[i] as usize]);
+                ((b[0] - a[0]) * (c[1] - a[1]) - (b[1] - a[1]) * (c[0] - a[0])) / 2.0
+            })
+            .sum();
+        assert!((area - 81.0).abs() < 1e-3, "{}", area);
+
+        // Embedded in a model as MSFT_lod levels over the same vertices
+        let mut buffer: Vec<u8> = positions.iter().flatten().flat_map(|c| c.to_le_bytes()).collect();
+        let index_offset = buffer.len();
+        buffer.extend(indices.iter().flat_map(|&i| (i as u16).to_le_bytes()));
+        let gltf = serde_json::to_vec(&json!({
+            "asset": {"version": "2.0"},
+            "scene": 0,
+            "scenes": [{"nodes": [0]}],
+            "nodes": [{"mesh": 0, "translation": [0, 2, 0]}],
+            "meshes": [{"name": "ball", "primitives": [{"attributes": {"POSITION": 0}, "indices": 1}]}],
+            "buffers": [{"byteLength": buffer.len(), "uri": data_uri(&buffer)}],
+            "bufferViews": [
+                {"buffer": 0, "byteLength": index_offset},
+                {"buffer": 0, "byteOffset": index_offset, "byteLength": indices.len() * 2}
+            ],
+            "accessors": [
+                {"bufferView": 0, "componentType": 5126, "count": positions.len(), "type": "VEC3", "min": [-1, -1, -1], "max": [1, 1, 1]},
+                {"bufferView": 1, "componentType": 5123, "count": indices.len(), "type": "SCALAR"}
+            ]
+        }))
+        .unwrap();
+
+        let processor = Model3DProcessor::new();
+        let (glb, report) = processor.generate_lods(&gltf, &[], &[0.25, 0.5]).unwrap();
+        assert_eq!(report.ratios, vec![0.5, 0.25]);
+        assert_eq!(report.triangles[0], triangles);
+        assert!(report.triangles[1] <= triangles / 2 && report.triangles[2] <= triangles / 4, "{}", report);
+        assert_eq!(processor.generate_lods(&gltf, &[], &[0.5, 0.25]).unwrap().0, glb);
+
+        let json = document_json(&glb).unwrap();
+        assert_eq!(json["extensionsUsed"], json!([MSFT_LOD]));
+        assert_eq!(json["nodes"][0]["extensions"][MSFT_LOD]["ids"], json!([1, 2]));
+        assert_eq!(json["nodes"][2], json!({"mesh": 2, "translation": [0, 2, 0]}));
+        assert_eq!(json["meshes"][1]["name"], "ball LOD1");
+        assert_eq!(json["meshes"][2]["primitives"][0]["attributes"]["POSITION"], 0);
+        assert!(processor.get_properties(&glb).is_ok());
+        assert!(processor.generate_lods(&gltf, &[], &[1.5]).is_err());
+
+        // Without indices every triangle has vertices of its own until welded
+        let corners: Vec<u8> =
+            indices.iter().flat_map(|&i| positions[i as usize]).flat_map(|c| c.to_le_bytes()).collect();
+        let mut unindexed = document_json(&gltf).unwrap();
+        unindexed["buffers"][0] = json!({"byteLength": corners.len(), "uri": data_uri(&corners)});
+        unindexed["bufferViews"] = json!([{"buffer": 0, "byteLength": corners.len()}]);
+        unindexed["accessors"] = json!([
+            {"bufferView": 0, "componentType": 5126, "count": indices.len(), "type": "VEC3", "min": [-1, -1, -1], "max": [1, 1, 1]}
+        ]);
+        unindexed["meshes"][0]["primitives"][0].as_object_mut().unwrap().remove("indices");
+        let (_, report) = processor.generate_lods(&serde_json::to_vec(&unindexed).unwrap(), &[], &[0.5]).unwrap();
+        assert!(report.triangles[1] <= triangles / 2 && report.missed_targets().is_empty(), "{}", report);
+        assert_eq!(LodReport { ratios: vec![0.5], triangles: vec![100, 98] }.missed_targets(), vec![0.5]);
+
+        // A .gltf whose buffer is a file of its own gets LODs on import too
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("ball.bin"), &buffer).unwrap();
+        let mut separate = document_json(&gltf).unwrap();
+        separate["buffers"][0]["uri"] = json!("ball.bin");
+        let model = ModelImportSettings { lods: Some(vec![0.5]), ..Default::default() };
+        let settings = ImportSettings { model: Some(model), ..Default::default() };
+        let path = dir.path().join("ball.gltf");
+        let imported = FormatRegistry::with_defaults()
+            .import(Some(&path), &serde_json::to_vec(&separate).unwrap(), None, &settings)
+            .unwrap();
+        assert_eq!(document_json(&imported.data).unwrap()["nodes"][0]["extensions"][MSFT_LOD]["ids"], json!([1]));
+        assert_eq!(imported.metadata.dependencies, vec![blake3::hash(&buffer).to_string()]);
+    }
+}
@@ -0,0 +1,350 @@
+use crate::ColorSpace;
+use image::{Rgba, Rgba32FImage, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+/// Steps of the binary search for the alpha scale that restores coverage.
+const COVERAGE_SEARCH_STEPS: usize = 20;
+const MAX_ALPHA_SCALE: f32 = 16.0;
+
+/// Whether colour channels are stored multiplied by alpha.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlphaMode {
+    #[default]
+    Straight,
+    Premultiplied,
+}
+
+/// How a texture's pixel values should be treated when filtering mip levels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MipOptions {
+    pub color_space: ColorSpace,
+    /// Alpha mode of the output; input is always straight alpha.
+    pub alpha_mode: AlphaMode,
+    /// Alpha-test threshold of cutout textures. Each level's alpha is scaled
+    /// so the same fraction of pixels passes the test as in the full image,
+    /// which keeps foliage and fences from thinning out with distance.
+    pub alpha_cutoff: Option<f32>,
+    /// Tangent-space normals in RGB, renormalized after filtering.
+    pub normal_map: bool,
+}
+
+impl Default for MipOptions {
+    fn default() -> Self {
+        Self {
+            color_space: ColorSpace::Srgb,
+            alpha_mode: AlphaMode::Straight,
+            alpha_cutoff: None,
+            normal_map: false,
+        }
+    }
+}
+
+/// Number of levels from `width` x `height` down to 1x1.
+pub fn mip_level_count(width: u32, height: u32) -> u32 {
+    32 - width.max(height).max(1).leading_zeros()
+}
+
+/// Pixels as linear, alpha-premultiplied floats while filtering.
+struct LinearImage {
+    width: u32,
+    height: u32,
+    pixels: Vec<[f32; 4]>,
+}
+
+pub(crate) fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+pub(crate) fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 { c * 12.92 } else { 1.055 * c.powf(1.0 / 2.4) - 0.055 }
+}
+
+/// Source pixels covered by each destination pixel along one axis, with the
+/// fraction of each that falls inside it. Odd sizes split the middle pixel.
+fn box_weights(source: u32, destination: u32) -> Vec<Vec<(usize, f32)>> {
+    let scale = source as f32 / destination as f32;
+    (0..destination)
+        .map(|d| {
+            let (start, end) = (d as f32 * scale, (d + 1) as f32 * scale);
+            (start.floor() as usize..(end.ceil() as usize).min(source as usize))
+                .map(|s| {
+                    let covered = end.min(s as f32 + 1.0) - start.max(s as f32);
+                    (s, covered / scale)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+impl LinearImage {
+    fn from_rgba(image: &RgbaImage, options: &MipOptions) -> Self {
+        let srgb = options.color_space == ColorSpace::Srgb && !options.normal_map;
+        let premultiply = !options.normal_map;
+        let pixels = image
+            .pixels()
+            .map(|p| {
+                let alpha = p[3] as f32 / 255.0;
+                let channel = |c: u8| {
+                    let value = c as f32 / 255.0;
+                    let value = if srgb { srgb_to_linear(value) } else { value };
+                    if premultiply { value * alpha } else { value }
+                };
+                [channel(p[0]), channel(p[1]), channel(p[2]), alpha]
+            })
+            .collect();
+        Self { width: image.width(), height: image.height(), pixels }
+    }
+
+    /// Float pixels are already linear and only need premultiplying.
+    fn from_rgba32f(image: &Rgba32FImage) -> Self {
+        let pixels = image.pixels().map(|&Rgba([r, g, b, a])| [r * a, g * a, b * a, a]).collect();
+        Self { width: image.width(), height: image.height(), pixels }
+    }
+
+    fn to_rgba32f(&self, alpha_mode: AlphaMode) -> Rgba32FImage {
+        let pixels = self
+            .pixels
+            .iter()
+            .flat_map(|&[r, g, b, a]| match alpha_mode {
+                AlphaMode::Premultiplied => [r, g, b, a],
+                AlphaMode::Straight if a > 0.0 => [r / a, g / a, b / a, a],
+                AlphaMode::Straight => [0.0; 4],
+            })
+            .collect();
+        Rgba32FImage::from_raw(self.width, self.height, pixels).unwrap()
+    }
+
+    /// Box-filters to half size, each axis clamped at 1.
+    fn downsample(&self) -> Self {
+        let (width, height) = ((self.width / 2).max(1), (self.height / 2).max(1));
+        let columns = box_weights(self.width, width);
+        let rows = box_weights(self.height, height);
+
+        let mut horizontal = vec![[0.0f32; 4]; (width * self.height) as usize];
+        for y in 0..self.height as usize {
+            for (x, taps) in columns.iter().enumerate() {
+                let out = &mut horizontal[y * width as usize + x];
+                for &(s, weight) in taps {
+                    let pixel = self.pixels[y * self.width as usize + s];
+                    (0..4).for_each(|c| out[c] += pixel[c] * weight);
+                }
+            }
+        }
+
+        let mut pixels = vec![[0.0f32; 4]; (width * height) as usize];
+        for (y, taps) in rows.iter().enumerate() {
+            for x in 0..width as usize {
+                let out = &mut pixels[y * width as usize + x];
+                for &(s, weight) in taps {
+                    let pixel = horizontal[s * width as usize + x];
+                    (0..4).for_each(|c| out[c] += pixel[c] * weight);
+                }
+            }
+        }
+
+        Self { width, height, pixels }
+    }
+
+    fn to_rgba(&self, options: &MipOptions, alpha_scale: f32) -> RgbaImage {
+        let srgb = options.color_space == ColorSpace::Srgb && !options.normal_map;
+        let premultiplied = options.alpha_mode == AlphaMode::Premultiplied && !options.normal_map;
+        let to_byte = |v: f32| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+
+        let pixels = self.pixels.iter().map(|&[r, g, b, a]| {
+            let alpha = (a * alpha_scale).clamp(0.0, 1.0);
+            let mut rgb = if options.normal_map {
+                let n = [r * 2.0 - 1.0, g * 2.0 - 1.0, b * 2.0 - 1.0];
+                let length = n.iter().map(|v| v * v).sum::<f32>().sqrt();
+                if length > 1e-6 { n.map(|v| (v / length + 1.0) / 2.0) } else { [r, g, b] }
+            } else if a > 0.0 {
+                let straight = [r / a, g / a, b / a];
+                if premultiplied { straight.map(|c| c * alpha) } else { straight }
+            } else {
+                [0.0; 3]
+            };
+            if srgb {
+                rgb = rgb.map(linear_to_srgb);
+            }
+            Rgba([to_byte(rgb[0]), to_byte(rgb[1]), to_byte(rgb[2]), to_byte(alpha)])
+        });
+
+        let mut image = RgbaImage::new(self.width, self.height);
+        for (out, pixel) in image.pixels_mut().zip(pixels) {
+            *out = pixel;
+        }
+        image
+    }
+
+    fn coverage(&self, cutoff: f32, scale: f32) -> f32 {
+        let passing = self.pixels.iter().filter(|p| (p[3] * scale).min(1.0) > cutoff).count();
+        passing as f32 / self.pixels.len() as f32
+    }
+
+    /// The alpha scale at which this level's alpha-test coverage is closest
+    /// to `target`. Alpha is left alone when nothing or everything passed
+    /// the test in the source, or when scaling would not change coverage.
+    fn alpha_scale_for_coverage(&self, cutoff: f32, target: f32) -> f32 {
+        if target <= 0.0 || target >= 1.0 {
+            return 1.0;
+        }
+        let (mut low, mut high) = (0.0, MAX_ALPHA_SCALE);
+        for _ in 0..COVERAGE_SEARCH_STEPS {
+            let mid = (low + high) / 2.0;
+            if self.coverage(cutoff, mid) < target {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        match self.coverage(cutoff, high) == self.coverage(cutoff, 1.0) {
+            true => 1.0,
+            false => high,
+        }
+    }
+}
+
+/// The image in the output alpha mode: unchanged for straight alpha,
+/// multiplied in linear light for premultiplied.
+pub fn apply_alpha_mode(image: &RgbaImage, options: &MipOptions) -> RgbaImage {
+    match options.alpha_mode {
+        AlphaMode::Straight => image.clone(),
+        AlphaMode::Premultiplied => LinearImage::from_rgba(image, options).to_rgba(options, 1.0),
+    }
+}
+
+/// Resamples to `width` x `height` with a Lanczos filter in linear light
+/// and premultiplied alpha, for capping texture resolution.
+pub fn resize_linear(image: &RgbaImage, width: u32, height: u32, options: &MipOptions) -> RgbaImage {
+    let straight = MipOptions { alpha_mode: AlphaMode::Straight, ..*options };
+    let linear = LinearImage::from_rgba(image, &straight);
+    let buffer = Rgba32FImage::from_fn(linear.width, linear.height, |x, y| {
+        Rgba(linear.pixels[(y * linear.width + x) as usize])
+    });
+
+    let resized = image::imageops::resize(&buffer, width, height, image::imageops::FilterType::Lanczos3);
+    let pixels = resized.pixels().map(|p| p.0.map(|c| c.max(0.0))).collect();
+    LinearImage { width, height, pixels }.to_rgba(&straight, 1.0)
+}
+
+/// The full mip chain, largest first. Level 0 is the image itself; each
+/// further level is box-filtered from the previous one in linear light, with
+/// premultiplied alpha so transparent pixels do not bleed their colour.
+pub fn build_mip_chain(image: &RgbaImage, options: &MipOptions) -> Vec<RgbaImage> {
+    let mut level = LinearImage::from_rgba(image, options);
+    let target_coverage = options.alpha_cutoff.map(|cutoff| level.coverage(cutoff, 1.0));
+
+    let mut chain = vec![apply_alpha_mode(image, options)];
+    while level.width > 1 || level.height > 1 {
+        level = level.downsample();
+        let alpha_scale = match (options.alpha_cutoff, target_coverage) {
+            (Some(cutoff), Some(target)) => level.alpha_scale_for_coverage(cutoff, target),
+            _ => 1.0,
+        };
+        chain.push(level.to_rgba(options, alpha_scale));
+    }
+    chain
+}
+
+/// Float counterpart of [`apply_alpha_mode`].
+pub fn apply_float_alpha_mode(image: &Rgba32FImage, alpha_mode: AlphaMode) -> Rgba32FImage {
+    match alpha_mode {
+        AlphaMode::Straight => image.clone(),
+        AlphaMode::Premultiplied => LinearImage::from_rgba32f(image).to_rgba32f(alpha_mode),
+    }
+}
+
+/// Float counterpart of [`resize_linear`] for HDR textures. Negative lobes
+/// of the filter around very bright pixels are clamped to zero.
+pub fn resize_float(image: &Rgba32FImage, width: u32, height: u32) -> Rgba32FImage {
+    let linear = LinearImage::from_rgba32f(image);
+    let buffer = linear.to_rgba32f(AlphaMode::Premultiplied);
+    let resized = image::imageops::resize(&buffer, width, height, image::imageops::FilterType::Lanczos3);
+    let pixels = resized.pixels().map(|p| p.0.map(|c| c.max(0.0))).collect();
+    LinearImage { width, height, pixels }.to_rgba32f(AlphaMode::Straight)
+}
+
+/// Float counterpart of [`build_mip_chain`] for HDR textures, keeping values
+/// above 1. Level 0 is the image itself, in the requested alpha mode.
+pub fn build_float_mip_chain(image: &Rgba32FImage, alpha_mode: AlphaMode) -> Vec<Rgba32FImage> {
+    let mut level = LinearImage::from_rgba32f(image);
+    let mut chain = vec![apply_float_alpha_mode(image, alpha_mode)];
+    while level.width > 1 || level.height > 1 {
+        level = level.downsample();
+        chain.push(level.to_rgba32f(alpha_mode));
+    }
+    chain
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mip_chain() {
+        // Non-square sizes clamp each axis at 1
+        let checker = RgbaImage::from_fn(8, 2, |x, y| {
+            let v = if (x + y) % 2 == 0 { 255 } else { 0 };
+            Rgba([v, v, v, 255])
+        });
+        let chain = build_mip_chain(&checker, &MipOptions::default());
+        let sizes: Vec<_> = chain.iter().map(|level| level.dimensions()).collect();
+        assert_eq!(sizes, vec![(8, 2), (4, 1), (2, 1), (1, 1)]);
+        assert_eq!(chain.len() as u32, mip_level_count(8, 2));
+        assert_eq!(chain[0], checker);
+
+        // Half-covered black and white averages to half the light, which
+        // is 188 in sRGB, not 128
+        assert_eq!(chain[1].get_pixel(0, 0).0, [188, 188, 188, 255]);
+        let linear = build_mip_chain(&checker, &MipOptions { color_space: ColorSpace::Linear, ..Default::default() });
+        assert_eq!(linear[1].get_pixel(0, 0).0, [128, 128, 128, 255]);
+
+        // Odd sizes weight the shared middle pixel by half
+        let odd = RgbaImage::from_fn(3, 1, |x, _| Rgba([[0, 0, 255][x as usize], 0, 0, 255]));
+        let linear_options = MipOptions { color_space: ColorSpace::Linear, ..Default::default() };
+        assert_eq!(build_mip_chain(&odd, &linear_options)[1].get_pixel(0, 0).0, [85, 0, 0, 255]);
+
+        // Noisy cutout alpha averages towards the middle, so without
+        // correction almost nothing would pass a 0.7 alpha test after a few levels
+        let cutout = RgbaImage::from_fn(64, 64, |x, y| {
+            let alpha = ((x * 7919 + y * 104729) ^ (x * y * 31)) % 256;
+            Rgba([40, 120, 40, alpha as u8])
+        });
+        let coverage = |image: &RgbaImage| {
+            image.pixels().filter(|p| p[3] as f32 / 255.0 > 0.7).count() as f32 / image.pixels().len() as f32
+        };
+        let uncorrected = build_mip_chain(&cutout, &MipOptions::default());
+        assert!(coverage(&uncorrected[3]) < coverage(&cutout) / 2.0);
+
+        let chain = build_mip_chain(&cutout, &MipOptions { alpha_cutoff: Some(0.7), ..Default::default() });
+        for level in &chain[1..5] {
+            assert!((coverage(level) - coverage(&cutout)).abs() < 0.1, "coverage {}", coverage(level));
+        }
+        // Colour stays that of the opaque pixels rather than fading to black
+        assert_eq!(&chain[3].get_pixel(0, 0).0[..3], &[40, 120, 40]);
+
+        // Opaque and never-passing alpha are kept as they are
+        let cutout_options = MipOptions { alpha_cutoff: Some(0.5), ..Default::default() };
+        let opaque = RgbaImage::from_pixel(8, 8, Rgba([200, 10, 10, 255]));
+        assert!(build_mip_chain(&opaque, &cutout_options).iter().all(|level| level.pixels().all(|p| p[3] == 255)));
+        let faint = RgbaImage::from_pixel(8, 8, Rgba([200, 10, 10, 100]));
+        assert!(build_mip_chain(&faint, &cutout_options).iter().all(|level| level.pixels().all(|p| p[3] == 100)));
+
+        // Premultiplied output scales colour by alpha in linear light
+        let half = RgbaImage::from_pixel(2, 2, Rgba([255, 255, 255, 128]));
+        let options = MipOptions { alpha_mode: AlphaMode::Premultiplied, ..Default::default() };
+        assert_eq!(build_mip_chain(&half, &options)[0].get_pixel(0, 0).0, [188, 188, 188, 128]);
+
+        // Float levels keep values above 1
+        let bright = Rgba32FImage::from_fn(2, 2, |x, _| Rgba([if x == 0 { 40.0 } else { 0.0 }, 1.0, 1.0, 1.0]));
+        let chain = build_float_mip_chain(&bright, AlphaMode::Straight);
+        assert_eq!(chain[1].get_pixel(0, 0).0, [20.0, 1.0, 1.0, 1.0]);
+
+        // Averaged normals are unit length again
+        let normals = RgbaImage::from_fn(2, 2, |x, _| if x == 0 { Rgba([255, 128, 128, 255]) } else { Rgba([128, 255, 128, 255]) });
+        let chain = build_mip_chain(&normals, &MipOptions { normal_map: true, ..Default::default() });
+        let n = chain[1].get_pixel(0, 0).0.map(|c| c as f32 / 255.0 * 2.0 - 1.0);
+        let length = (n[0] * n[0] + n[1] * n[1] + n[2] * n[2]).sqrt();
+        assert!((length - 1.0).abs() < 0.02, "length {length}");
+    }
+}
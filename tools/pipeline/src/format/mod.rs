@@ -1,16 +1,115 @@
 pub mod texture;
+pub mod block_compression;
+pub mod texture_container;
+pub mod mipmap;
+pub mod hdr;
+pub mod perceptual_hash;
+pub mod atlas;
 pub mod model3d;
+pub mod model_loader;
+pub mod model_preview;
+pub mod model_pack;
+pub mod model_stats;
+pub mod model_convert;
+pub mod model_obj;
+pub mod model_stl;
+pub mod model_ply;
+pub mod mesh_optimize;
+pub mod mesh_simplify;
 pub mod audio;
+pub mod audio_header;
+pub mod audio_transcode;
+pub mod audio_encode;
+pub mod audio_preview;
+pub mod registry;
+pub mod dependency_extraction;
+pub mod settings;
+pub mod thumbnail;
 
 pub use texture::*;
+pub use block_compression::*;
+pub use texture_container::*;
+pub use mipmap::*;
+pub use hdr::*;
+pub use perceptual_hash::*;
+pub use atlas::*;
 pub use model3d::*;
+pub use model_loader::*;
+pub use model_pack::*;
+pub use model_stats::*;
+pub use model_convert::*;
+pub use model_obj::*;
+pub use model_stl::*;
+pub use model_ply::*;
+pub use mesh_optimize::*;
+pub use mesh_simplify::*;
 pub use audio::*;
+pub use audio_header::*;
+pub use audio_transcode::*;
+pub use audio_encode::*;
+pub use audio_preview::*;
+pub use registry::*;
+pub use dependency_extraction::*;
+pub use settings::*;
+pub use thumbnail::*;
 
-use crate::ResourceMetadata;
+use crate::{ResourceMetadata, ResourceType};
 use anyhow::Result;
 
 pub trait FormatProcessor {
+    fn resource_type(&self) -> ResourceType;
+
+    /// Identifies the processor in import provenance.
+    fn name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
+
+    /// Lowercase file extensions, without the leading dot.
+    fn extensions(&self) -> &[&'static str] {
+        &[]
+    }
+
+    fn mime_types(&self) -> &[&'static str] {
+        &[]
+    }
+
+    /// Whether the data starts with a signature this processor recognizes.
+    fn matches_magic(&self, _data: &[u8]) -> bool {
+        false
+    }
+
+    /// Bumped whenever processing changes its output, so cached imports made
+    /// by an older version are redone.
+    fn version(&self) -> u32 {
+        1
+    }
+
     fn process(&self, data: &[u8]) -> Result<Vec<u8>>;
+
+    /// Processes `data` as the asset's import settings ask, along with the
+    /// files the dependency extractor found for it. Processors with no
+    /// settings of their own ignore them, and those that keep the references
+    /// process `data` alone.
+    fn process_with(&self, data: &[u8], _settings: &ImportSettings, _files: &[ExternalFile]) -> Result<Vec<u8>> {
+        self.process(data)
+    }
+
+    /// How to find the files this processor's resources refer to, for
+    /// formats that refer to any.
+    fn dependency_extractor(&self) -> Option<&dyn DependencyExtractor> {
+        None
+    }
+
+    /// A PNG preview at most `options.size` pixels on each side, or `None`
+    /// for resources the processor cannot picture. The resource's stored
+    /// dependencies are at hand in `files`, matched by the URIs it refers to
+    /// them by.
+    fn thumbnail(&self, _data: &[u8], _options: &ThumbnailOptions, _files: &[ExternalFile]) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
     fn get_metadata(&self, data: &[u8]) -> Result<ResourceMetadata>;
+    /// Returns `Ok(false)` for data the processor does not accept, or an error
+    /// when it can say why.
     fn validate(&self, data: &[u8]) -> Result<bool>;
 }
\ No newline at end of file
@@ -1,8 +1,237 @@
-use crate::ResourceMetadata;
-use crate::ResourceType;
-use anyhow::Result;
-use std::time::{SystemTime, UNIX_EPOCH};
+use crate::{Bounds, ModelProperties, ResourceMetadata, ResourceProperties, ResourceType, METADATA_SCHEMA_VERSION};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use tracing::{info, warn};
 
+#[derive(Debug, Clone, Serialize)]
+pub struct PropertyChange {
+    pub property: String,
+    pub old: String,
+    pub new: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum ElementChange {
+    Added {
+        index: usize,
+        name: Option<String>,
+    },
+    Removed {
+        index: usize,
+        name: Option<String>,
+    },
+    Renamed {
+        old_index: usize,
+        new_index: usize,
+        old_name: Option<String>,
+        new_name: Option<String>,
+        properties: Vec<PropertyChange>,
+    },
+    Modified {
+        old_index: usize,
+        new_index: usize,
+        name: Option<String>,
+        properties: Vec<PropertyChange>,
+    },
+}
+
+/// Structural differences between two glTF documents, grouped by element kind.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ModelDiff {
+    pub nodes: Vec<ElementChange>,
+    pub meshes: Vec<ElementChange>,
+    pub materials: Vec<ElementChange>,
+    pub animations: Vec<ElementChange>,
+    pub skins: Vec<ElementChange>,
+}
+
+impl ModelDiff {
+    pub fn is_empty(&self) -> bool {
+        self.categories().iter().all(|(_, changes)| changes.is_empty())
+    }
+
+    fn categories(&self) -> [(&'static str, &Vec<ElementChange>); 5] {
+        [
+            ("nodes", &self.nodes),
+            ("meshes", &self.meshes),
+            ("materials", &self.materials),
+            ("animations", &self.animations),
+            ("skins", &self.skins),
+        ]
+    }
+}
+
+impl fmt::Display for ModelDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "No structural changes");
+        }
+
+        let label = |name: &Option<String>, index: usize| match name {
+            Some(name) => format!("\"{}\" #{}", name, index),
+            None => format!("<unnamed> #{}", index),
+        };
+
+        for (category, changes) in self.categories() {
+            if changes.is_empty() {
+                continue;
+            }
+            writeln!(f, "{} ({} changed)", category, changes.len())?;
+
+            for (i, change) in changes.iter().enumerate() {
+                let last = i + 1 == changes.len();
+                let (branch, indent) = if last { ("└── ", "    ") } else { ("├── ", "│   ") };
+
+                let (line, properties): (String, &[PropertyChange]) = match change {
+                    ElementChange::Added { index, name } => (format!("+ {}", label(name, *index)), &[]),
+                    ElementChange::Removed { index, name } => (format!("- {}", label(name, *index)), &[]),
+                    ElementChange::Renamed { old_index, new_index, old_name, new_name, properties } => (
+                        format!("~ {} -> {}", label(old_name, *old_index), label(new_name, *new_index)),
+                        properties,
+                    ),
+                    ElementChange::Modified { new_index, name, properties, .. } => {
+                        (format!("* {}", label(name, *new_index)), properties)
+                    }
+                };
+                writeln!(f, "{}{}", branch, line)?;
+
+                for (j, property) in properties.iter().enumerate() {
+                    let leaf = if j + 1 == properties.len() { "└── " } else { "├── " };
+                    writeln!(f, "{}{}{}: {} -> {}", indent, leaf, property.property, property.old, property.new)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Name plus the comparable properties of one glTF element, in a fixed order
+/// per element kind.
+struct Element {
+    name: Option<String>,
+    properties: Vec<(&'static str, String)>,
+}
+
+fn format_floats(values: &[f32]) -> String {
+    let parts: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+    format!("[{}]", parts.join(", "))
+}
+
+fn format_ref(name: Option<&str>, index: usize) -> String {
+    match name {
+        Some(name) => name.to_string(),
+        None => format!("#{}", index),
+    }
+}
+
+/// Pairs elements by name first, then leftovers with identical properties as
+/// renames, then unnamed leftovers at the same index. Anything else is a
+/// removal plus an addition.
+fn diff_elements(old: &[Element], new: &[Element]) -> Vec<ElementChange> {
+    let mut matches: Vec<Option<usize>> = vec![None; old.len()];
+    let mut taken = vec![false; new.len()];
+
+    for (i, element) in old.iter().enumerate() {
+        let Some(name) = &element.name else {
+            continue;
+        };
+        if let Some(j) = (0..new.len()).find(|&j| !taken[j] && new[j].name.as_ref() == Some(name)) {
+            matches[i] = Some(j);
+            taken[j] = true;
+        }
+    }
+
+    // Same index first, so identical unnamed elements keep their places
+    for (i, matched) in matches.iter_mut().enumerate() {
+        if matched.is_some() {
+            continue;
+        }
+        let same = |j: &usize| !taken[*j] && new[*j].properties == old[i].properties;
+        if let Some(j) = Some(i).filter(|j| *j < new.len() && same(j)).or_else(|| (0..new.len()).find(same)) {
+            *matched = Some(j);
+            taken[j] = true;
+        }
+    }
+
+    for (i, matched) in matches.iter_mut().enumerate() {
+        if matched.is_none() && i < new.len() && !taken[i] && old[i].name.is_none() && new[i].name.is_none() {
+            *matched = Some(i);
+            taken[i] = true;
+        }
+    }
+
+    let mut changes = Vec::new();
+    for (i, matched) in matches.iter().enumerate() {
+        let Some(j) = *matched else {
+            changes.push(ElementChange::Removed { index: i, name: old[i].name.clone() });
+            continue;
+        };
+
+        let properties: Vec<PropertyChange> = old[i]
+            .properties
+            .iter()
+            .zip(&new[j].properties)
+            .filter(|(a, b)| a.1 != b.1)
+            .map(|(a, b)| PropertyChange {
+                property: a.0.to_string(),
+                old: a.1.clone(),
+                new: b.1.clone(),
+            })
+            .collect();
+
+        if old[i].name != new[j].name {
+            changes.push(ElementChange::Renamed {
+                old_index: i,
+                new_index: j,
+                old_name: old[i].name.clone(),
+                new_name: new[j].name.clone(),
+                properties,
+            });
+        } else if !properties.is_empty() {
+            changes.push(ElementChange::Modified {
+                old_index: i,
+                new_index: j,
+                name: new[j].name.clone(),
+                properties,
+            });
+        }
+    }
+
+    for (j, element) in new.iter().enumerate() {
+        if !taken[j] {
+            changes.push(ElementChange::Added { index: j, name: element.name.clone() });
+        }
+    }
+
+    changes
+}
+
+/// Per-asset model import settings. Without any the model is stored as is.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ModelImportSettings {
+    /// Optimize the meshes, storing the model as a GLB.
+    #[serde(default)]
+    pub optimize: Option<super::MeshOptimizeSettings>,
+    /// Levels of detail to add, as fractions of the triangle count, storing
+    /// the model as a GLB.
+    #[serde(default)]
+    pub lods: Option<Vec<f32>>,
+}
+
+impl ModelImportSettings {
+    /// Fills fields missing from `self` with those from `fallback`.
+    pub fn or(self, fallback: ModelImportSettings) -> ModelImportSettings {
+        ModelImportSettings {
+            optimize: self.optimize.or(fallback.optimize),
+            lods: self.lods.or(fallback.lods),
+        }
+    }
+}
+
+#[derive(Default)]
 pub struct Model3DProcessor;
 
 impl Model3DProcessor {
@@ -11,48 +240,324 @@ impl Model3DProcessor {
     }
 
     pub fn validate_gltf(&self, data: &[u8]) -> Result<bool> {
-        match gltf::Gltf::from_slice(data) {
+        match self.parse(data) {
             Ok(_) => Ok(true),
             Err(_) => Ok(false),
         }
     }
 
     pub fn get_scene_info(&self, data: &[u8]) -> Result<(usize, usize, usize)> {
-        let gltf = gltf::Gltf::from_slice(data)?;
+        let gltf = self.parse(data)?;
         let scene_count = gltf.scenes().count();
         let mesh_count = gltf.meshes().count();
         let node_count = gltf.nodes().count();
 
         Ok((scene_count, mesh_count, node_count))
     }
+
+    pub fn get_properties(&self, data: &[u8]) -> Result<ModelProperties> {
+        let gltf = self.parse(data)?;
+
+        let mut bounds: Option<Bounds> = None;
+        for primitive in gltf.meshes().flat_map(|mesh| mesh.primitives()) {
+            let Some(positions) = primitive.get(&gltf::Semantic::Positions) else {
+                continue;
+            };
+            let (Some(min), Some(max)) = (Self::vec3(positions.min()), Self::vec3(positions.max())) else {
+                continue;
+            };
+
+            bounds = Some(match bounds {
+                None => Bounds { min, max },
+                Some(b) => Bounds {
+                    min: std::array::from_fn(|i| b.min[i].min(min[i])),
+                    max: std::array::from_fn(|i| b.max[i].max(max[i])),
+                },
+            });
+        }
+
+        Ok(ModelProperties {
+            scenes: gltf.scenes().count() as u32,
+            meshes: gltf.meshes().count() as u32,
+            nodes: gltf.nodes().count() as u32,
+            materials: gltf.materials().count() as u32,
+            animations: gltf.animations().count() as u32,
+            skins: gltf.skins().count() as u32,
+            bounds,
+        })
+    }
+
+    fn vec3(value: Option<gltf::json::Value>) -> Option<[f32; 3]> {
+        let values = value?;
+        let values = values.as_array()?;
+        if values.len() != 3 {
+            return None;
+        }
+        Some([
+            values[0].as_f64()? as f32,
+            values[1].as_f64()? as f32,
+            values[2].as_f64()? as f32,
+        ])
+    }
+
+    /// Compares two glTF documents element by element rather than byte by byte.
+    pub fn structural_diff(&self, old: &[u8], new: &[u8]) -> Result<ModelDiff> {
+        let old = self.parse(old).with_context(|| "Failed to parse old model")?;
+        let new = self.parse(new).with_context(|| "Failed to parse new model")?;
+
+        Ok(ModelDiff {
+            nodes: diff_elements(&Self::node_elements(&old), &Self::node_elements(&new)),
+            meshes: diff_elements(&Self::mesh_elements(&old), &Self::mesh_elements(&new)),
+            materials: diff_elements(&Self::material_elements(&old), &Self::material_elements(&new)),
+            animations: diff_elements(&Self::animation_elements(&old), &Self::animation_elements(&new)),
+            skins: diff_elements(&Self::skin_elements(&old), &Self::skin_elements(&new)),
+        })
+    }
+
+    fn node_elements(gltf: &gltf::Gltf) -> Vec<Element> {
+        gltf.nodes()
+            .map(|node| {
+                let (translation, rotation, scale) = node.transform().decomposed();
+                Element {
+                    name: node.name().map(str::to_string),
+                    properties: vec![
+                        ("mesh", node.mesh().map_or("none".to_string(), |m| format_ref(m.name(), m.index()))),
+                        ("skin", node.skin().map_or("none".to_string(), |s| format_ref(s.name(), s.index()))),
+                        ("children", node.children().count().to_string()),
+                        ("translation", format_floats(&translation)),
+                        ("rotation", format_floats(&rotation)),
+                        ("scale", format_floats(&scale)),
+                    ],
+                }
+            })
+            .collect()
+    }
+
+    fn mesh_elements(gltf: &gltf::Gltf) -> Vec<Element> {
+        gltf.meshes()
+            .map(|mesh| {
+                let mut vertices = 0;
+                let mut indices = 0;
+                for primitive in mesh.primitives() {
+                    vertices += primitive.get(&gltf::Semantic::Positions).map_or(0, |a| a.count());
+                    indices += primitive.indices().map_or(0, |a| a.count());
+                }
+                Element {
+                    name: mesh.name().map(str::to_string),
+                    properties: vec![
+                        ("primitives", mesh.primitives().count().to_string()),
+                        ("vertices", vertices.to_string()),
+                        ("indices", indices.to_string()),
+                    ],
+                }
+            })
+            .collect()
+    }
+
+    fn material_elements(gltf: &gltf::Gltf) -> Vec<Element> {
+        gltf.materials()
+            .map(|material| {
+                let pbr = material.pbr_metallic_roughness();
+                Element {
+                    name: material.name().map(str::to_string),
+                    properties: vec![
+                        ("base_color_factor", format_floats(&pbr.base_color_factor())),
+                        ("base_color_texture", pbr.base_color_texture().is_some().to_string()),
+                        ("metallic_factor", pbr.metallic_factor().to_string()),
+                        ("roughness_factor", pbr.roughness_factor().to_string()),
+                        ("alpha_mode", format!("{:?}", material.alpha_mode())),
+                        ("double_sided", material.double_sided().to_string()),
+                    ],
+                }
+            })
+            .collect()
+    }
+
+    fn animation_elements(gltf: &gltf::Gltf) -> Vec<Element> {
+        gltf.animations()
+            .map(|animation| Element {
+                name: animation.name().map(str::to_string),
+                properties: vec![
+                    ("channels", animation.channels().count().to_string()),
+                    ("samplers", animation.samplers().count().to_string()),
+                ],
+            })
+            .collect()
+    }
+
+    fn skin_elements(gltf: &gltf::Gltf) -> Vec<Element> {
+        gltf.skins()
+            .map(|skin| Element {
+                name: skin.name().map(str::to_string),
+                properties: vec![
+                    ("joints", skin.joints().count().to_string()),
+                    ("skeleton", skin.skeleton().map_or("none".to_string(), |n| format_ref(n.name(), n.index()))),
+                ],
+            })
+            .collect()
+    }
 }
 
 impl super::FormatProcessor for Model3DProcessor {
+    fn name(&self) -> &'static str {
+        "model3d"
+    }
+
+    fn resource_type(&self) -> ResourceType {
+        ResourceType::Model3D
+    }
+
+    fn extensions(&self) -> &[&'static str] {
+        &["gltf", "glb"]
+    }
+
+    fn mime_types(&self) -> &[&'static str] {
+        &["model/gltf+json", "model/gltf-binary"]
+    }
+
+    fn matches_magic(&self, data: &[u8]) -> bool {
+        // Binary glTF has a header; JSON glTF has to be parsed to be told apart
+        // from other JSON documents.
+        data.starts_with(b"glTF")
+            || (data.trim_ascii_start().starts_with(b"{") && self.parse(data).is_ok())
+    }
+
+    // 2: deterministic quantization, and LODs that collapse split vertices
+    fn version(&self) -> u32 {
+        2
+    }
+
     fn process(&self, data: &[u8]) -> Result<Vec<u8>> {
-        // For now, just validate and return the same data
-        self.validate_gltf(data)?;
+        // Stored as is, but only once it parses as glTF
+        self.parse(data).with_context(|| "Invalid glTF model")?;
         Ok(data.to_vec())
     }
 
+    /// Optimizing and generating LODs embed the buffers, read from `files`
+    /// when they are separate; images stay where they are.
+    fn process_with(&self, data: &[u8], settings: &super::ImportSettings, files: &[super::ExternalFile]) -> Result<Vec<u8>> {
+        let Some(model) = settings.model.as_ref() else {
+            return self.process(data);
+        };
+        let mut data = match model.optimize {
+            Some(optimize) => {
+                let (optimized, report) = self.optimize(data, files, &optimize)?;
+                info!("Optimized meshes: {}", report);
+                optimized
+            }
+            None => self.process(data)?,
+        };
+        if let Some(lods) = model.lods.as_ref().filter(|lods| !lods.is_empty()) {
+            let (with_lods, report) = self.generate_lods(&data, files, lods)?;
+            info!("Generated LODs: {}", report);
+            for ratio in report.missed_targets() {
+                warn!("LOD at {}% kept far more triangles than asked for: {}", ratio * 100.0, report);
+            }
+            data = with_lods;
+        }
+        Ok(data)
+    }
+
+    fn dependency_extractor(&self) -> Option<&dyn super::DependencyExtractor> {
+        Some(self)
+    }
+
+    fn thumbnail(
+        &self,
+        data: &[u8],
+        options: &super::ThumbnailOptions,
+        files: &[super::ExternalFile],
+    ) -> Result<Option<Vec<u8>>> {
+        // Files that were not stored with the model are out of its reach
+        let gltf = self.parse(data)?;
+        let buffers = gltf.buffers().filter_map(|buffer| match buffer.source() {
+            gltf::buffer::Source::Uri(uri) => Some(uri),
+            gltf::buffer::Source::Bin => None,
+        });
+        let images = gltf.images().filter_map(|image| match image.source() {
+            gltf::image::Source::Uri { uri, .. } => Some(uri),
+            gltf::image::Source::View { .. } => None,
+        });
+        let mut uris = buffers.chain(images);
+        if uris.any(|uri| !uri.starts_with("data:") && !files.iter().any(|file| file.uri == uri)) {
+            return Ok(None);
+        }
+        Ok(Some(super::TextureProcessor::encode_png(self.render_view(data, files, options.size)?)?))
+    }
+
     fn get_metadata(&self, data: &[u8]) -> Result<ResourceMetadata> {
-        let (scenes, meshes, nodes) = self.get_scene_info(data)?;
+        let properties = self.get_properties(data)?;
 
         Ok(ResourceMetadata {
+            schema_version: METADATA_SCHEMA_VERSION,
             hash: blake3::hash(data).to_string(),
             resource_type: ResourceType::Model3D,
             size: data.len() as u64,
-            create_at: SystemTime::now()
-                .duration_since(UNIX_EPOCH)?
-                .as_secs() as i64,
-            dependencies: vec![
-                format!("gltf:scenes={}", scenes),
-                format!("gltf:meshes={}", meshes),
-                format!("gltf:nodes={}", nodes),
-            ],
+            dependencies: Vec::new(),
+            properties: ResourceProperties::Model3D(properties),
         })
     }
 
     fn validate(&self, data: &[u8]) -> Result<bool> {
         self.validate_gltf(data)
     }
-}
\ No newline at end of file
+}
+
+impl super::DependencyExtractor for Model3DProcessor {
+    /// External buffers, stored as binary resources, and images, stored as
+    /// textures.
+    fn extract_dependencies(&self, data: &[u8], base_dir: Option<&std::path::Path>) -> Result<Vec<super::ExternalFile>> {
+        Ok(self.load(data, base_dir)?.external)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::FormatProcessor;
+
+    #[test]
+    fn test_structural_diff() {
+        let old = br#"{
+            "asset": {"version": "2.0"},
+            "nodes": [
+                {"name": "Root", "children": [1]},
+                {"name": "Body", "translation": [0, 1, 0]},
+                {"name": "Hat"},
+                {"name": "Cape", "scale": [2, 2, 2]}
+            ],
+            "materials": [{"name": "Skin"}, {"pbrMetallicRoughness": {"roughnessFactor": 0.5}}]
+        }"#;
+        let new = br#"{
+            "asset": {"version": "2.0"},
+            "nodes": [
+                {"name": "Root", "children": [1]},
+                {"name": "Torso", "translation": [0, 1, 0]},
+                {"name": "Hat"},
+                {"name": "Shoes"}
+            ],
+            "materials": [{"name": "Skin"}, {"pbrMetallicRoughness": {"roughnessFactor": 0.8}}]
+        }"#;
+
+        let diff = Model3DProcessor::new().structural_diff(old, new).unwrap();
+        assert_eq!(diff.nodes.len(), 3);
+        match &diff.nodes[0] {
+            ElementChange::Renamed { new_name, properties, .. } => {
+                assert_eq!(new_name.as_deref(), Some("Torso"));
+                assert!(properties.is_empty());
+            }
+            other => panic!("unexpected change: {:?}", other),
+        }
+        // A different element in a removed one's place is not a rename
+        assert!(matches!(&diff.nodes[1], ElementChange::Removed { index: 3, .. }));
+        assert!(matches!(&diff.nodes[2], ElementChange::Added { index: 3, .. }));
+        assert!(diff.to_string().contains("+ \"Shoes\" #3"));
+        // Unnamed elements still pair up by index
+        assert!(matches!(&diff.materials[..], [ElementChange::Modified { old_index: 1, new_index: 1, .. }]));
+
+        // Processing keeps valid models and rejects invalid ones
+        assert_eq!(Model3DProcessor::new().process(old).unwrap(), old);
+        let error = Model3DProcessor::new().process(br#"{"nodes": []}"#).unwrap_err();
+        assert!(error.to_string().contains("Invalid glTF"));
+    }
+}
@@ -0,0 +1,338 @@
+use super::mesh_optimize::{encode_floats, encode_indices, place_pending, push_accessor};
+use super::model_pack::{push_view, set_buffer, write_glb, URI_ESCAPED};
+use super::{ExternalFile, TextureProcessor};
+use crate::ResourceType;
+use anyhow::{bail, Context, Result};
+use percent_encoding::{utf8_percent_encode, AsciiSet};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Faces meeting at a sharper angle keep a hard edge between them when
+/// normals are generated.
+const CREASE_ANGLE_DEGREES: f32 = 60.0;
+
+/// Characters escaped in the URIs of referenced images, which keep their
+/// directories.
+const PATH_ESCAPED: &AsciiSet = &URI_ESCAPED.remove(b'/');
+
+/// A mesh read from a format other than glTF, ready to become a one-node
+/// GLB with [`ConvertedMesh::into_glb`]. All primitives share the vertices.
+#[derive(Debug, Clone, Default)]
+pub struct ConvertedMesh {
+    pub name: Option<String>,
+    pub positions: Vec<[f32; 3]>,
+    /// Generated from the triangles when missing.
+    pub normals: Option<Vec<[f32; 3]>>,
+    /// With `v` pointing down the image, as glTF has it.
+    pub tex_coords: Option<Vec<[f32; 2]>>,
+    /// Linear RGBA.
+    pub colors: Option<Vec<[f32; 4]>>,
+    /// Triangle lists. A mesh without any becomes a point cloud.
+    pub primitives: Vec<ConvertedPrimitive>,
+    pub materials: Vec<ConvertedMaterial>,
+    /// Images the materials refer to by index.
+    pub images: Vec<ConvertedImage>,
+}
+
+/// An encoded image and the URI the source file named it by.
+#[derive(Debug, Clone, Default)]
+pub struct ConvertedImage {
+    pub uri: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ConvertedPrimitive {
+    pub material: Option<usize>,
+    pub indices: Vec<u32>,
+}
+
+/// A metallic-roughness material. Textures are indices into
+/// [`ConvertedMesh::images`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConvertedMaterial {
+    pub name: Option<String>,
+    /// Linear RGBA.
+    pub base_color: [f32; 4],
+    pub metallic: f32,
+    pub roughness: f32,
+    pub emissive: [f32; 3],
+    pub base_color_texture: Option<usize>,
+    pub normal_texture: Option<usize>,
+    pub emissive_texture: Option<usize>,
+}
+
+impl Default for ConvertedMaterial {
+    fn default() -> Self {
+        Self {
+            name: None,
+            base_color: [1.0; 4],
+            metallic: 0.0,
+            roughness: 1.0,
+            emissive: [0.0; 3],
+            base_color_texture: None,
+            normal_texture: None,
+            emissive_texture: None,
+        }
+    }
+}
+
+impl ConvertedMesh {
+    /// Fills in smooth normals, each the area-weighted sum of the faces
+    /// around its position that lie within the crease angle of the corner's
+    /// own face. Vertices split for texture seams still match, and vertices
+    /// on a crease are split so the edge stays hard.
+    pub fn generate_normals(&mut self) {
+        let key = |p: &[f32; 3]| p.map(f32::to_bits);
+        let mut faces = Vec::new();
+        let mut around: HashMap<[u32; 3], Vec<usize>> = HashMap::new();
+        for triangle in self.primitives.iter().flat_map(|p| p.indices.chunks_exact(3)) {
+            let [a, b, c] = [0, 1, 2].map(|i| self.positions[triangle[i] as usize]);
+            for corner in [a, b, c] {
+                let faces_here = around.entry(key(&corner)).or_default();
+                if faces_here.last() != Some(&faces.len()) {
+                    faces_here.push(faces.len());
+                }
+            }
+            faces.push(face_normal(a, b, c));
+        }
+        let units: Vec<Option<[f32; 3]>> = faces.iter().map(|&f| normalize(f)).collect();
+        let threshold = CREASE_ANGLE_DEGREES.to_radians().cos();
+
+        let mut normals: Vec<Option<[f32; 3]>> = vec![None; self.positions.len()];
+        let mut splits: HashMap<(u32, [u32; 3]), u32> = HashMap::new();
+        let mut primitives = std::mem::take(&mut self.primitives);
+        let mut face = 0;
+        for primitive in &mut primitives {
+            let corners = primitive.indices.len() / 3 * 3;
+            for (corner, vertex) in primitive.indices[..corners].iter_mut().enumerate() {
+                let own = units[face + corner / 3];
+                let sum = around[&key(&self.positions[*vertex as usize])]
+                    .iter()
+                    .filter(|&&other| own.zip(units[other]).is_none_or(|(a, b)| dot(a, b) >= threshold))
+                    .fold([0.0; 3], |sum, &other| std::array::from_fn(|i| sum[i] + faces[other][i]));
+                let normal = normalize(sum).unwrap_or([0.0, 0.0, 1.0]);
+                match normals[*vertex as usize] {
+                    None => normals[*vertex as usize] = Some(normal),
+                    Some(existing) if existing == normal => {}
+                    Some(_) => {
+                        let original = *vertex;
+                        *vertex = *splits.entry((original, normal.map(f32::to_bits))).or_insert_with(|| {
+                            normals.push(Some(normal));
+                            self.duplicate_vertex(original)
+                        });
+                    }
+                }
+            }
+            face += corners / 3;
+        }
+        self.primitives = primitives;
+        self.normals = Some(normals.into_iter().map(|n| n.unwrap_or([0.0, 0.0, 1.0])).collect());
+    }
+
+    /// Appends a copy of `vertex` without its normal, returning its index.
+    fn duplicate_vertex(&mut self, vertex: u32) -> u32 {
+        let v = vertex as usize;
+        self.positions.push(self.positions[v]);
+        if let Some(tex_coords) = &mut self.tex_coords {
+            tex_coords.push(tex_coords[v]);
+        }
+        if let Some(colors) = &mut self.colors {
+            colors.push(colors[v]);
+        }
+        self.positions.len() as u32 - 1
+    }
+
+    /// Writes the mesh as a GLB with its geometry in the binary chunk. PNG
+    /// and JPEG images are referred to by URI; the rest are embedded,
+    /// re-encoded as PNG.
+    pub fn into_glb(mut self) -> Result<Vec<u8>> {
+        let count = self.positions.len();
+        if count == 0 {
+            bail!("Mesh has no vertices");
+        }
+        let lengths = [
+            self.normals.as_ref().map(Vec::len),
+            self.tex_coords.as_ref().map(Vec::len),
+            self.colors.as_ref().map(Vec::len),
+        ];
+        if lengths.into_iter().flatten().any(|length| length != count) {
+            bail!("Mesh has {} positions but a different number of other attributes", count);
+        }
+        if let Some(index) = self.primitives.iter().flat_map(|p| &p.indices).find(|&&i| i as usize >= count) {
+            bail!("Mesh has index {} but only {} vertices", index, count);
+        }
+        let triangles = self.primitives.iter().any(|p| p.indices.len() >= 3);
+        if self.normals.is_none() && triangles {
+            self.generate_normals();
+        }
+        let count = self.positions.len();
+
+        let mut json = json!({
+            "asset": {"version": "2.0"},
+            "scene": 0,
+            "scenes": [{"nodes": [0]}],
+            "nodes": [{"mesh": 0}],
+        });
+        let mut pending = Vec::new();
+
+        let (min, max) = self.positions.iter().fold(([f32::MAX; 3], [f32::MIN; 3]), |(min, max), p| {
+            (std::array::from_fn(|i| min[i].min(p[i])), std::array::from_fn(|i| max[i].max(p[i])))
+        });
+        let mut attributes = serde_json::Map::new();
+        let position = push_accessor(&mut json, &mut pending, encode_floats(&self.positions, "VEC3"), count);
+        json["accessors"][position]["min"] = json!(min);
+        json["accessors"][position]["max"] = json!(max);
+        attributes.insert("POSITION".to_string(), json!(position));
+
+        let streams = [
+            ("NORMAL", self.normals.as_ref().map(|normals| encode_floats(normals, "VEC3"))),
+            ("TEXCOORD_0", self.tex_coords.as_ref().map(|tex_coords| encode_floats(tex_coords, "VEC2"))),
+            ("COLOR_0", self.colors.as_ref().map(|colors| encode_floats(colors, "VEC4"))),
+        ];
+        for (name, encoded) in streams {
+            if let Some(encoded) = encoded {
+                attributes.insert(name.to_string(), json!(push_accessor(&mut json, &mut pending, encoded, count)));
+            }
+        }
+
+        let mut primitives = Vec::new();
+        for primitive in self.primitives.iter().filter(|p| p.indices.len() >= 3) {
+            let indices = &primitive.indices[..primitive.indices.len() / 3 * 3];
+            let accessor = push_accessor(&mut json, &mut pending, encode_indices(indices, count), indices.len());
+            let mut value = json!({"attributes": attributes, "indices": accessor});
+            if let Some(material) = primitive.material {
+                value["material"] = json!(material);
+            }
+            primitives.push(value);
+        }
+        if primitives.is_empty() {
+            primitives.push(json!({"attributes": attributes, "mode": 0}));
+        }
+        json["meshes"] = json!([{"primitives": primitives}]);
+        if let Some(name) = &self.name {
+            json["meshes"][0]["name"] = json!(name);
+            json["nodes"][0]["name"] = json!(name);
+        }
+
+        let mut bin = Vec::new();
+        place_pending(&mut json, &mut bin, pending);
+        if !self.materials.is_empty() {
+            json["materials"] = Value::Array(self.materials.iter().map(material_json).collect());
+        }
+        if !self.images.is_empty() {
+            let mut images = Vec::new();
+            for (index, image) in self.images.iter().enumerate() {
+                let is_png = image.data.starts_with(b"\x89PNG");
+                let is_jpeg = image.data.starts_with(&[0xff, 0xd8, 0xff]);
+                // The image is stored on its own as a dependency, so refer to
+                // it rather than keeping a second copy in the GLB
+                if (is_png || is_jpeg) && is_relative_path(&image.uri) {
+                    images.push(json!({"uri": utf8_percent_encode(&image.uri, PATH_ESCAPED).to_string()}));
+                    continue;
+                }
+                let (mime_type, bytes) = match &image.data {
+                    png if is_png => ("image/png", png.clone()),
+                    jpeg if is_jpeg => ("image/jpeg", jpeg.clone()),
+                    other => {
+                        let decoded =
+                            image::load_from_memory(other).with_context(|| format!("Failed to decode image {}", index))?;
+                        ("image/png", TextureProcessor::encode_png(decoded)?)
+                    }
+                };
+                let view = push_view(&mut json, &mut bin, &bytes);
+                images.push(json!({"bufferView": view, "mimeType": mime_type}));
+            }
+            json["textures"] = Value::Array((0..images.len()).map(|source| json!({"source": source})).collect());
+            json["images"] = Value::Array(images);
+        }
+        set_buffer(&mut json, &bin, None);
+
+        Ok(write_glb(&serde_json::to_vec(&json)?, &bin))
+    }
+}
+
+/// Whether glTF can name the file by `path` relative to the model.
+fn is_relative_path(path: &str) -> bool {
+    !path.is_empty()
+        && !path.contains(['\\', ':'])
+        && !path.starts_with('/')
+        && path.split('/').all(|part| !part.is_empty() && part != "." && part != "..")
+}
+
+fn material_json(material: &ConvertedMaterial) -> Value {
+    let mut pbr = json!({
+        "baseColorFactor": material.base_color,
+        "metallicFactor": material.metallic,
+        "roughnessFactor": material.roughness,
+    });
+    if let Some(texture) = material.base_color_texture {
+        pbr["baseColorTexture"] = json!({"index": texture});
+    }
+    let mut value = json!({"pbrMetallicRoughness": pbr});
+    if let Some(name) = &material.name {
+        value["name"] = json!(name);
+    }
+    if material.emissive != [0.0; 3] || material.emissive_texture.is_some() {
+        // A texture alone would be scaled to black
+        let factor = match material.emissive {
+            [0.0, 0.0, 0.0] => [1.0; 3],
+            factor => factor,
+        };
+        value["emissiveFactor"] = json!(factor);
+    }
+    if let Some(texture) = material.emissive_texture {
+        value["emissiveTexture"] = json!({"index": texture});
+    }
+    if let Some(texture) = material.normal_texture {
+        value["normalTexture"] = json!({"index": texture});
+    }
+    if material.base_color[3] < 1.0 {
+        value["alphaMode"] = json!("BLEND");
+    }
+    value
+}
+
+/// Normal of a counter-clockwise triangle, as long as twice its area.
+pub(super) fn face_normal(a: [f32; 3], b: [f32; 3], c: [f32; 3]) -> [f32; 3] {
+    let (u, v) = ([b[0] - a[0], b[1] - a[1], b[2] - a[2]], [c[0] - a[0], c[1] - a[1], c[2] - a[2]]);
+    [u[1] * v[2] - u[2] * v[1], u[2] * v[0] - u[0] * v[2], u[0] * v[1] - u[1] * v[0]]
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+pub(super) fn normalize(v: [f32; 3]) -> Option<[f32; 3]> {
+    let length = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+    (length > 0.0 && length.is_finite()).then(|| v.map(|c| c / length))
+}
+
+/// Reads a file a converted format names relative to `base_dir`. Absolute
+/// paths, often left by the exporting machine, are looked for by file name
+/// in `base_dir` instead.
+pub(super) fn read_reference(
+    name: &str,
+    base_dir: Option<&Path>,
+    what: &str,
+    resource_type: ResourceType,
+) -> Result<ExternalFile> {
+    let Some(base_dir) = base_dir else {
+        bail!("{} refers to {}, which cannot be resolved without the model's path", what, name);
+    };
+    let relative = name.replace('\\', "/");
+    let absolute = relative.starts_with('/') || relative.as_bytes().get(1) == Some(&b':');
+    let path = match absolute {
+        true => base_dir.join(relative.rsplit('/').next().unwrap_or_default()),
+        false => base_dir.join(&relative),
+    };
+    let data = match std::fs::read(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            bail!("{} refers to missing file {}", what, path.display())
+        }
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    Ok(ExternalFile { uri: name.to_string(), path, data, resource_type })
+}
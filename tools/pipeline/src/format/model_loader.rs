@@ -0,0 +1,278 @@
+use super::{ExternalFile, Model3DProcessor};
+use crate::ResourceType;
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use std::path::Path;
+
+/// Required extensions handled here rather than by the glTF parser, which
+/// rejects every model requiring one.
+pub const HANDLED_EXTENSIONS: [&str; 1] = ["KHR_mesh_quantization"];
+
+/// A glTF document with everything it refers to loaded.
+pub struct LoadedModel {
+    pub document: gltf::Document,
+    /// Contents of each buffer, in document order.
+    pub buffers: Vec<Vec<u8>>,
+    /// Encoded file of each image, whether it lives in its own file, a data
+    /// URI or a buffer view.
+    pub images: Vec<Vec<u8>>,
+    /// The files the model refers to by relative URI, in document order,
+    /// buffers first.
+    pub external: Vec<ExternalFile>,
+}
+
+impl Model3DProcessor {
+    /// Parses and validates a `.gltf` or `.glb`, allowing it to require the
+    /// [`HANDLED_EXTENSIONS`].
+    pub fn parse(&self, data: &[u8]) -> gltf::Result<gltf::Gltf> {
+        use gltf::json::validation::{Error, Validate};
+
+        let gltf = gltf::Gltf::from_slice_without_validation(data)?;
+        let root = gltf.document.as_json();
+        let mut errors = Vec::new();
+        root.validate(root, gltf::json::Path::new, &mut |path, error| {
+            let path = path();
+            let handled = HANDLED_EXTENSIONS.iter().any(|e| path.as_str().ends_with(&format!("\"{}\"", e)));
+            if !(error == Error::Unsupported && handled) {
+                errors.push((path, error));
+            }
+        });
+        match errors.is_empty() {
+            true => Ok(gltf),
+            false => Err(gltf::Error::Validation(errors)),
+        }
+    }
+
+    /// Parses a `.gltf` or `.glb` and loads its buffers and images: the GLB
+    /// binary chunk, data URIs, and files at relative URIs resolved against
+    /// `base_dir`. Missing files, absolute or remote URIs, URIs leaving
+    /// `base_dir`, and buffers shorter than they declare are errors.
+    pub fn load(&self, data: &[u8], base_dir: Option<&Path>) -> Result<LoadedModel> {
+        self.load_with_files(data, base_dir, &[])
+    }
+
+    /// Like [`load`](Self::load), but takes the files at relative URIs from
+    /// `files` by URI where it can, as for a stored model whose files are
+    /// stored objects.
+    pub fn load_with_files(&self, data: &[u8], base_dir: Option<&Path>, files: &[ExternalFile]) -> Result<LoadedModel> {
+        let gltf = self.parse(data).with_context(|| "Failed to parse model")?;
+        let mut external = Vec::new();
+
+        let mut buffers = Vec::new();
+        for buffer in gltf.buffers() {
+            let what = format!("Buffer {}", buffer.index());
+            let contents = match buffer.source() {
+                gltf::buffer::Source::Bin => match &gltf.blob {
+                    Some(blob) => blob.clone(),
+                    None => bail!("{} refers to a binary chunk the file does not have", what),
+                },
+                gltf::buffer::Source::Uri(uri) => {
+                    load_uri(uri, base_dir, files, &what, ResourceType::Binary, &mut external)?
+                }
+            };
+            // The binary chunk may be padded to four bytes
+            if contents.len() < buffer.length() {
+                bail!("{} is {} bytes but declares {}", what, contents.len(), buffer.length());
+            }
+            buffers.push(contents);
+        }
+
+        let mut images = Vec::new();
+        for image in gltf.images() {
+            let what = format!("Image {}", image.index());
+            let contents = match image.source() {
+                gltf::image::Source::View { view, .. } => {
+                    let buffer = &buffers[view.buffer().index()];
+                    match buffer.get(view.offset()..view.offset() + view.length()) {
+                        Some(bytes) => bytes.to_vec(),
+                        None => bail!("{} lies outside buffer {}", what, view.buffer().index()),
+                    }
+                }
+                gltf::image::Source::Uri { uri, .. } => {
+                    load_uri(uri, base_dir, files, &what, ResourceType::Texture, &mut external)?
+                }
+            };
+            images.push(contents);
+        }
+
+        Ok(LoadedModel { document: gltf.document, buffers, images, external })
+    }
+}
+
+/// Positions of a primitive as floats, dequantizing the integer types
+/// `KHR_mesh_quantization` allows. Sparse positions must be floats.
+pub fn read_positions(primitive: &gltf::Primitive, buffers: &[Vec<u8>]) -> Option<Vec<[f32; 3]>> {
+    use gltf::accessor::DataType;
+
+    let accessor = primitive.get(&gltf::Semantic::Positions)?;
+    if accessor.data_type() == DataType::F32 || accessor.sparse().is_some() {
+        let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(|data| &data[..]));
+        return Some(reader.read_positions()?.collect());
+    }
+
+    let view = accessor.view()?;
+    let size = accessor.data_type().size();
+    let stride = view.stride().unwrap_or(accessor.size());
+    let start = view.offset() + accessor.offset();
+    let buffer = buffers.get(view.buffer().index())?;
+    let normalized = accessor.normalized();
+    (0..accessor.count())
+        .map(|i| {
+            let element = buffer.get(start + i * stride..start + i * stride + 3 * size)?;
+            Some(std::array::from_fn(|c| {
+                let b = &element[c * size..(c + 1) * size];
+                match (accessor.data_type(), normalized) {
+                    (DataType::I8, false) => b[0] as i8 as f32,
+                    (DataType::I8, true) => (b[0] as i8 as f32 / 127.0).max(-1.0),
+                    (DataType::U8, false) => b[0] as f32,
+                    (DataType::U8, true) => b[0] as f32 / 255.0,
+                    (DataType::I16, false) => i16::from_le_bytes([b[0], b[1]]) as f32,
+                    (DataType::I16, true) => (i16::from_le_bytes([b[0], b[1]]) as f32 / 32767.0).max(-1.0),
+                    (DataType::U16, false) => u16::from_le_bytes([b[0], b[1]]) as f32,
+                    (DataType::U16, true) => u16::from_le_bytes([b[0], b[1]]) as f32 / 65535.0,
+                    _ => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32,
+                }
+            }))
+        })
+        .collect()
+}
+
+/// Decodes a data URI or reads the file a relative URI names, from `files`
+/// or else `base_dir`, recording the latter in `external` as a
+/// `resource_type` file.
+fn load_uri(
+    uri: &str,
+    base_dir: Option<&Path>,
+    files: &[ExternalFile],
+    what: &str,
+    resource_type: ResourceType,
+    external: &mut Vec<ExternalFile>,
+) -> Result<Vec<u8>> {
+    if let Some(data) = uri.strip_prefix("data:") {
+        let Some((media_type, payload)) = data.split_once(',') else {
+            bail!("{} has a malformed data URI", what);
+        };
+        return match media_type.ends_with(";base64") {
+            true => base64::engine::general_purpose::STANDARD
+                .decode(payload)
+                .with_context(|| format!("{} has invalid base64 in its data URI", what)),
+            false => Ok(percent_encoding::percent_decode_str(payload).collect()),
+        };
+    }
+
+    if uri.contains("://") || uri.starts_with('/') || uri.starts_with('\\') {
+        bail!("{} refers to {}, which is not a relative URI", what, uri);
+    }
+    let relative = percent_encoding::percent_decode_str(uri)
+        .decode_utf8()
+        .with_context(|| format!("{} has a URI that is not UTF-8", what))?;
+    if Path::new(relative.as_ref()).components().any(|c| c == std::path::Component::ParentDir) {
+        bail!("{} refers to {}, which leaves the model's directory", what, uri);
+    }
+    if let Some(file) = files.iter().find(|file| file.uri == uri || file.uri == relative) {
+        external.push(file.clone());
+        return Ok(file.data.clone());
+    }
+    let Some(base_dir) = base_dir else {
+        bail!("{} refers to {}, which cannot be resolved without the model's path", what, uri);
+    };
+    let path = base_dir.join(relative.as_ref());
+    let data = match std::fs::read(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            bail!("{} refers to missing file {}", what, path.display())
+        }
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+
+    external.push(ExternalFile { uri: uri.to_string(), path, data: data.clone(), resource_type });
+    Ok(data)
+}
+
+/// A base64 data URI holding `data`, for building test models in memory.
+#[cfg(test)]
+pub(super) fn data_uri(data: &[u8]) -> String {
+    format!("data:application/octet-stream;base64,{}", base64::engine::general_purpose::STANDARD.encode(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{FormatRegistry, ImportSettings, TextureProcessor};
+
+    #[test]
+    fn test_external_references() {
+        let dir = tempfile::tempdir().unwrap();
+        let buffer: Vec<u8> = [[0.0f32, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]]
+            .iter()
+            .flat_map(|p| p.iter().flat_map(|c| c.to_le_bytes()))
+            .collect();
+        std::fs::create_dir(dir.path().join("data")).unwrap();
+        std::fs::write(dir.path().join("data/mesh data.bin"), &buffer).unwrap();
+        let albedo = TextureProcessor::encode_png(image::RgbaImage::new(4, 4)).unwrap();
+        std::fs::write(dir.path().join("albedo.png"), &albedo).unwrap();
+
+        let gltf = br#"{
+            "asset": {"version": "2.0"},
+            "buffers": [{"byteLength": 36, "uri": "data/mesh%20data.bin"}, {"byteLength": 3, "uri": "data:application/octet-stream;base64,AQID"}],
+            "images": [{"uri": "albedo.png"}, {"uri": "data:image/png,%89PNG"}]
+        }"#;
+        let processor = Model3DProcessor::new();
+        let model = processor.load(gltf, Some(dir.path())).unwrap();
+        assert_eq!(model.buffers, vec![buffer.clone(), vec![1, 2, 3]]);
+        assert_eq!(model.images[1], b"\x89PNG");
+        let uris: Vec<&str> = model.external.iter().map(|file| file.uri.as_str()).collect();
+        assert_eq!(uris, vec!["data/mesh%20data.bin", "albedo.png"]);
+        let types: Vec<&ResourceType> = model.external.iter().map(|file| &file.resource_type).collect();
+        assert_eq!(types, vec![&ResourceType::Binary, &ResourceType::Texture]);
+
+        // Without the model's path relative URIs cannot be resolved, unless
+        // the files are at hand
+        assert!(processor.load(gltf, None).is_err());
+        let reloaded = processor.load_with_files(gltf, None, &model.external).unwrap();
+        assert_eq!(reloaded.buffers, model.buffers);
+        assert_eq!(reloaded.external.len(), 2);
+        let escaping = br#"{"asset": {"version": "2.0"}, "images": [{"uri": "textures/../../secret.png"}]}"#;
+        let error = processor.load(escaping, Some(dir.path())).err().unwrap();
+        assert!(error.to_string().contains("leaves the model's directory"), "{}", error);
+
+        // The import records each external file as a dependency
+        let registry = FormatRegistry::with_defaults();
+        let path = dir.path().join("model.gltf");
+        let imported = registry.import(Some(&path), gltf, None, &ImportSettings::default()).unwrap();
+        assert_eq!(imported.external.len(), 2);
+        assert_eq!(imported.metadata.dependencies, vec![
+            blake3::hash(&buffer).to_string(),
+            blake3::hash(&albedo).to_string(),
+        ]);
+
+        // Each is stored byte for byte, so its hash is the one depended on
+        let texture = registry.import_dependency(&imported.external[1]).unwrap();
+        assert_eq!(texture.metadata.resource_type, ResourceType::Texture);
+        assert_eq!((texture.metadata.hash.as_str(), texture.data), (imported.metadata.dependencies[1].as_str(), albedo));
+        let bin = registry.import_dependency(&imported.external[0]).unwrap();
+        assert_eq!(bin.metadata.resource_type, ResourceType::Binary);
+
+        std::fs::remove_file(dir.path().join("albedo.png")).unwrap();
+        let error = registry.import(Some(&path), gltf, None, &ImportSettings::default()).unwrap_err();
+        assert!(error.to_string().contains("Image 0 refers to missing file"), "{}", error);
+
+        // GLB keeps its buffer in the binary chunk
+        let json = br#"{"asset":{"version":"2.0"},"buffers":[{"byteLength":36}]}"#;
+        let mut glb = Vec::new();
+        let json_len = json.len().next_multiple_of(4);
+        glb.extend_from_slice(b"glTF");
+        glb.extend_from_slice(&2u32.to_le_bytes());
+        glb.extend_from_slice(&(12 + 8 + json_len as u32 + 8 + 36).to_le_bytes());
+        glb.extend_from_slice(&(json_len as u32).to_le_bytes());
+        glb.extend_from_slice(b"JSON");
+        glb.extend_from_slice(json);
+        glb.resize(20 + json_len, b' ');
+        glb.extend_from_slice(&36u32.to_le_bytes());
+        glb.extend_from_slice(b"BIN\0");
+        glb.extend_from_slice(&buffer);
+        let model = processor.load(&glb, None).unwrap();
+        assert_eq!(model.buffers, vec![buffer]);
+        assert!(model.external.is_empty());
+    }
+}
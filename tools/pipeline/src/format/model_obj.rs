@@ -0,0 +1,473 @@
+use super::mipmap::srgb_to_linear;
+use super::model_convert::read_reference;
+use super::{
+    ConvertedImage, ConvertedMaterial, ConvertedMesh, ConvertedPrimitive, DependencyExtractor, ExternalFile, FormatProcessor, ImportSettings,
+    Model3DProcessor,
+};
+use crate::{ResourceMetadata, ResourceType};
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::path::Path;
+use tracing::warn;
+
+/// Converts Wavefront OBJ meshes to glTF, with their MTL materials mapped
+/// to metallic-roughness and the textures those name referred to.
+#[derive(Default)]
+pub struct ObjProcessor;
+
+/// An OBJ's geometry, before its materials are looked up.
+struct ObjMesh {
+    mesh: ConvertedMesh,
+    /// MTL files named by `mtllib`, in order.
+    libraries: Vec<String>,
+    /// Material named by `usemtl` for each of `mesh.materials`.
+    materials: Vec<String>,
+}
+
+/// A face corner's position, texture coordinate and normal indices.
+type Corner = (usize, Option<usize>, Option<usize>);
+
+/// A material as an MTL file describes it.
+#[derive(Debug, Default)]
+struct MtlMaterial {
+    name: String,
+    diffuse: Option<[f32; 3]>,
+    emissive: Option<[f32; 3]>,
+    dissolve: Option<f32>,
+    shininess: Option<f32>,
+    roughness: Option<f32>,
+    metallic: Option<f32>,
+    diffuse_map: Option<String>,
+    normal_map: Option<String>,
+    emissive_map: Option<String>,
+}
+
+impl ObjProcessor {
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// Parses an OBJ into a mesh, taking the MTL files it names and their
+    /// textures from `files` by reference. Materials not found keep only
+    /// their name.
+    pub fn convert(&self, data: &[u8], files: &[ExternalFile]) -> Result<ConvertedMesh> {
+        let ObjMesh { mut mesh, libraries, materials } = parse_obj(data)?;
+        let file = |uri: &str, resource_type: ResourceType| {
+            files.iter().find(|file| file.uri == uri && file.resource_type == resource_type)
+        };
+
+        let mut library = Vec::new();
+        for name in &libraries {
+            match file(name, ResourceType::Binary) {
+                Some(file) => library.extend(parse_mtl(&file.data)),
+                None => warn!("Material library {} was not provided", name),
+            }
+        }
+
+        let mut images: HashMap<String, usize> = HashMap::new();
+        for (index, name) in materials.iter().enumerate() {
+            let Some(found) = library.iter().find(|m| &m.name == name) else {
+                warn!("Material {} is not in any material library", name);
+                continue;
+            };
+            let mut texture = |map: &Option<String>| -> Option<usize> {
+                let uri = map.as_deref()?;
+                if let Some(&image) = images.get(uri) {
+                    return Some(image);
+                }
+                let Some(file) = file(uri, ResourceType::Texture) else {
+                    warn!("Texture {} of material {} was not provided", uri, name);
+                    return None;
+                };
+                mesh.images.push(ConvertedImage { uri: uri.to_string(), data: file.data.clone() });
+                images.insert(uri.to_string(), mesh.images.len() - 1);
+                Some(mesh.images.len() - 1)
+            };
+            let (base_color_texture, normal_texture, emissive_texture) =
+                (texture(&found.diffuse_map), texture(&found.normal_map), texture(&found.emissive_map));
+            mesh.materials[index] =
+                ConvertedMaterial { base_color_texture, normal_texture, emissive_texture, ..found.to_material() };
+        }
+        Ok(mesh)
+    }
+}
+
+impl MtlMaterial {
+    /// Diffuse colour and dissolve become the base colour, and the specular
+    /// exponent roughness unless the PBR extension's `Pr` and `Pm` are given.
+    fn to_material(&self) -> ConvertedMaterial {
+        let [r, g, b] = self.diffuse.unwrap_or([1.0; 3]).map(srgb_to_linear);
+        let roughness = match (self.roughness, self.shininess) {
+            (Some(roughness), _) => roughness,
+            (None, Some(shininess)) => (2.0 / (shininess.max(0.0) + 2.0)).sqrt(),
+            (None, None) => 1.0,
+        };
+        ConvertedMaterial {
+            name: Some(self.name.clone()),
+            base_color: [r, g, b, self.dissolve.unwrap_or(1.0).clamp(0.0, 1.0)],
+            metallic: self.metallic.unwrap_or(0.0).clamp(0.0, 1.0),
+            roughness: roughness.clamp(0.0, 1.0),
+            emissive: self.emissive.unwrap_or_default().map(srgb_to_linear),
+            ..Default::default()
+        }
+    }
+}
+
+fn parse_obj(data: &[u8]) -> Result<ObjMesh> {
+    let text = std::str::from_utf8(data).with_context(|| "OBJ is not UTF-8 text")?;
+    let mut positions: Vec<[f32; 3]> = Vec::new();
+    let mut colors: Vec<Option<[f32; 3]>> = Vec::new();
+    let mut tex_coords: Vec<[f32; 2]> = Vec::new();
+    let mut normals: Vec<[f32; 3]> = Vec::new();
+
+    let mut mesh = ConvertedMesh::default();
+    let mut libraries = Vec::new();
+    let mut materials: Vec<String> = Vec::new();
+    let mut vertices: HashMap<Corner, u32> = HashMap::new();
+    let mut corners: Vec<Corner> = Vec::new();
+    let mut primitive_of: HashMap<Option<String>, usize> = HashMap::new();
+    let mut current: Option<String> = None;
+    let mut faces = 0;
+
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        let (keyword, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        let floats = || -> Result<Vec<f32>> {
+            rest.split_whitespace()
+                .map(|t| t.parse::<f32>().with_context(|| format!("Line {}: {} is not a number", number + 1, t)))
+                .collect()
+        };
+        match keyword {
+            "v" => {
+                let v = floats()?;
+                if v.len() < 3 {
+                    bail!("Line {}: a vertex needs three coordinates", number + 1);
+                }
+                positions.push([v[0], v[1], v[2]]);
+                // Vertex colours follow the position, a weight never does
+                colors.push((v.len() >= 6).then(|| [v[3], v[4], v[5]]));
+            }
+            "vt" => {
+                let v = floats()?;
+                let u = v.first().copied().unwrap_or(0.0);
+                tex_coords.push([u, 1.0 - v.get(1).copied().unwrap_or(0.0)]);
+            }
+            "vn" => {
+                let v = floats()?;
+                if v.len() < 3 {
+                    bail!("Line {}: a normal needs three components", number + 1);
+                }
+                normals.push([v[0], v[1], v[2]]);
+            }
+            "f" => {
+                corners.clear();
+                for corner in rest.split_whitespace() {
+                    let mut parts = corner.split('/');
+                    let resolve = |part: Option<&str>, count: usize, what: &str| -> Result<Option<usize>> {
+                        let Some(part) = part.filter(|p| !p.is_empty()) else {
+                            return Ok(None);
+                        };
+                        let index: i64 = part
+                            .parse()
+                            .with_context(|| format!("Line {}: {} is not a {} index", number + 1, part, what))?;
+                        let resolved = match index {
+                            i if i > 0 => i - 1,
+                            i => count as i64 + i,
+                        };
+                        if index == 0 || resolved < 0 || resolved >= count as i64 {
+                            bail!("Line {}: {} index {} is out of range", number + 1, what, index);
+                        }
+                        Ok(Some(resolved as usize))
+                    };
+                    let Some(position) = resolve(parts.next(), positions.len(), "vertex")? else {
+                        bail!("Line {}: a face corner needs a vertex", number + 1);
+                    };
+                    let tex_coord = resolve(parts.next(), tex_coords.len(), "texture coordinate")?;
+                    let normal = resolve(parts.next(), normals.len(), "normal")?;
+                    corners.push((position, tex_coord, normal));
+                }
+                if corners.len() < 3 {
+                    continue;
+                }
+
+                let primitive = *primitive_of.entry(current.clone()).or_insert_with(|| {
+                    let material = current.as_ref().map(|name| {
+                        materials.push(name.clone());
+                        mesh.materials.push(ConvertedMaterial { name: Some(name.clone()), ..Default::default() });
+                        mesh.materials.len() - 1
+                    });
+                    mesh.primitives.push(ConvertedPrimitive { material, indices: Vec::new() });
+                    mesh.primitives.len() - 1
+                });
+                let indices: Vec<u32> = corners
+                    .iter()
+                    .map(|&corner| {
+                        let next = vertices.len() as u32;
+                        *vertices.entry(corner).or_insert(next)
+                    })
+                    .collect();
+                for i in 1..indices.len() - 1 {
+                    mesh.primitives[primitive].indices.extend([indices[0], indices[i], indices[i + 1]]);
+                }
+                faces += 1;
+            }
+            "usemtl" if !rest.is_empty() => current = Some(rest.to_string()),
+            "mtllib" => libraries.extend(library_names(rest)),
+            "o" if mesh.name.is_none() && !rest.is_empty() => mesh.name = Some(rest.to_string()),
+            _ => {}
+        }
+    }
+
+    // Without faces the vertices are a point cloud
+    let order: Vec<Corner> = match faces {
+        0 => (0..positions.len()).map(|p| (p, None, None)).collect(),
+        _ => {
+            let mut order = vec![(0, None, None); vertices.len()];
+            vertices.iter().for_each(|(&corner, &index)| order[index as usize] = corner);
+            order
+        }
+    };
+    mesh.positions = order.iter().map(|&(p, _, _)| positions[p]).collect();
+    if order.iter().any(|(_, t, _)| t.is_some()) {
+        mesh.tex_coords = Some(order.iter().map(|(_, t, _)| t.map_or([0.0; 2], |t| tex_coords[t])).collect());
+    }
+    if !order.is_empty() && order.iter().all(|(_, _, n)| n.is_some()) {
+        mesh.normals = Some(order.iter().map(|(_, _, n)| n.map_or([0.0; 3], |n| normals[n])).collect());
+    }
+    if order.iter().any(|&(p, _, _)| colors[p].is_some()) {
+        let linear = |&(p, _, _): &Corner| {
+            let [r, g, b] = colors[p].unwrap_or([1.0; 3]).map(srgb_to_linear);
+            [r, g, b, 1.0]
+        };
+        mesh.colors = Some(order.iter().map(linear).collect());
+    }
+    Ok(ObjMesh { mesh, libraries, materials })
+}
+
+/// Files a `mtllib` line names. Names may hold spaces, so the line is only
+/// split after each `.mtl`.
+fn library_names(rest: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut start = 0;
+    let lower = rest.to_ascii_lowercase();
+    for (end, _) in lower.match_indices(".mtl") {
+        let end = end + 4;
+        if end == rest.len() || rest[end..].starts_with(char::is_whitespace) {
+            names.push(rest[start..end].trim().to_string());
+            start = end;
+        }
+    }
+    if !rest[start..].trim().is_empty() {
+        names.push(rest[start..].trim().to_string());
+    }
+    names
+}
+
+fn parse_mtl(data: &[u8]) -> Vec<MtlMaterial> {
+    let text = String::from_utf8_lossy(data);
+    let mut materials: Vec<MtlMaterial> = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        let (keyword, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        if keyword == "newmtl" {
+            materials.push(MtlMaterial { name: rest.to_string(), ..Default::default() });
+            continue;
+        }
+        let Some(material) = materials.last_mut() else {
+            continue;
+        };
+        let floats: Vec<f32> = rest.split_whitespace().map_while(|t| t.parse().ok()).collect();
+        let color = || match floats[..] {
+            [r, g, b, ..] => Some([r, g, b]),
+            [v] => Some([v; 3]),
+            _ => None,
+        };
+        match keyword {
+            "Kd" => material.diffuse = color(),
+            "Ke" => material.emissive = color(),
+            "d" => material.dissolve = floats.first().copied(),
+            "Tr" => material.dissolve = floats.first().map(|t| 1.0 - t),
+            "Ns" => material.shininess = floats.first().copied(),
+            "Pr" => material.roughness = floats.first().copied(),
+            "Pm" => material.metallic = floats.first().copied(),
+            "map_Kd" => material.diffuse_map = map_name(rest),
+            "norm" | "map_Bump" | "map_bump" | "bump" => material.normal_map = map_name(rest),
+            "map_Ke" => material.emissive_map = map_name(rest),
+            _ => {}
+        }
+    }
+    materials
+}
+
+/// The file of a texture map statement, after its options.
+fn map_name(rest: &str) -> Option<String> {
+    let tokens: Vec<&str> = rest.split_whitespace().collect();
+    let mut i = 0;
+    while let Some(option) = tokens.get(i).filter(|t| t.starts_with('-')) {
+        i += 1;
+        let arguments = match *option {
+            "-o" | "-s" | "-t" => 3,
+            "-mm" => 2,
+            _ => 1,
+        };
+        // Offsets, scales and turbulence take up to three numbers
+        for _ in 0..arguments {
+            match tokens.get(i) {
+                Some(t) if arguments == 1 || t.parse::<f32>().is_ok() => i += 1,
+                _ => break,
+            }
+        }
+    }
+    let name = tokens[i.min(tokens.len())..].join(" ");
+    (!name.is_empty()).then_some(name)
+}
+
+impl FormatProcessor for ObjProcessor {
+    fn name(&self) -> &'static str {
+        "obj"
+    }
+
+    fn resource_type(&self) -> ResourceType {
+        ResourceType::Model3D
+    }
+
+    fn extensions(&self) -> &[&'static str] {
+        &["obj"]
+    }
+
+    fn mime_types(&self) -> &[&'static str] {
+        &["model/obj"]
+    }
+
+    // 2: stored textures are referred to and normals keep hard edges
+    fn version(&self) -> u32 {
+        2
+    }
+
+    fn process(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.process_with(data, &ImportSettings::default(), &[])
+    }
+
+    fn process_with(&self, data: &[u8], settings: &ImportSettings, files: &[ExternalFile]) -> Result<Vec<u8>> {
+        let glb = self.convert(data, files)?.into_glb()?;
+        Model3DProcessor::new().process_with(&glb, settings, files)
+    }
+
+    fn dependency_extractor(&self) -> Option<&dyn DependencyExtractor> {
+        Some(self)
+    }
+
+    /// Describes the converted model.
+    fn get_metadata(&self, data: &[u8]) -> Result<ResourceMetadata> {
+        Model3DProcessor::new().get_metadata(data)
+    }
+
+    fn validate(&self, data: &[u8]) -> Result<bool> {
+        parse_obj(data)?;
+        Ok(true)
+    }
+}
+
+impl DependencyExtractor for ObjProcessor {
+    /// The MTL files, stored as binary resources, and the textures they
+    /// name, resolved against each MTL's directory.
+    fn extract_dependencies(&self, data: &[u8], base_dir: Option<&Path>) -> Result<Vec<ExternalFile>> {
+        let mut files: Vec<ExternalFile> = Vec::new();
+        for name in parse_obj(data)?.libraries {
+            let library = read_reference(&name, base_dir, "Material library", ResourceType::Binary)?;
+            let library_dir = library.path.parent().map(Path::to_path_buf);
+            let materials = parse_mtl(&library.data);
+            files.push(library);
+            for material in materials {
+                for map in [&material.diffuse_map, &material.normal_map, &material.emissive_map].into_iter().flatten() {
+                    let what = format!("Material {}", material.name);
+                    let texture = read_reference(map, library_dir.as_deref(), &what, ResourceType::Texture)?;
+                    if !files.iter().any(|file| file.path == texture.path) {
+                        files.push(texture);
+                    }
+                }
+            }
+        }
+        Ok(files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{FormatRegistry, TextureProcessor};
+
+    #[test]
+    fn test_obj_import() {
+        let dir = tempfile::tempdir().unwrap();
+        let obj = b"# A quad and a triangle\n\
+            mtllib scene.mtl\n\
+            o Scene\n\
+            v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nv 0 0 1\n\
+            vt 0 0\nvt 1 0\nvt 1 1\nvt 0 1\n\
+            usemtl Wood\n\
+            f 1/1 2/2 3/3 4/4\n\
+            usemtl Glass\n\
+            f -5/1 -4/2 5/3\n";
+        std::fs::write(dir.path().join("scene.obj"), obj).unwrap();
+        std::fs::create_dir(dir.path().join("textures")).unwrap();
+        let bmp = {
+            let mut bytes = std::io::Cursor::new(Vec::new());
+            image::RgbaImage::new(2, 2).write_to(&mut bytes, image::ImageFormat::Bmp).unwrap();
+            bytes.into_inner()
+        };
+        std::fs::write(dir.path().join("textures/wood grain.bmp"), &bmp).unwrap();
+        let mtl = b"newmtl Wood\nKd 1 1 1\nNs 98\nmap_Kd -s 2 2 1 -bm 1 textures/wood grain.bmp\n\
+            newmtl Glass\nKd 0.5 0.5 0.5\nd 0.25\nPm 1\nPr 0.1\n";
+        std::fs::write(dir.path().join("scene.mtl"), mtl).unwrap();
+
+        let registry = FormatRegistry::with_defaults();
+        let path = dir.path().join("scene.obj");
+        let imported = registry.import(Some(&path), obj, None, &ImportSettings::default()).unwrap();
+        assert_eq!(imported.metadata.resource_type, ResourceType::Model3D);
+        assert_eq!(imported.metadata.dependencies, vec![blake3::hash(mtl).to_string(), blake3::hash(&bmp).to_string()]);
+        assert_eq!(imported.external[1].uri, "textures/wood grain.bmp");
+
+        let model = Model3DProcessor::new().load(&imported.data, None).unwrap();
+        let mesh = model.document.meshes().next().unwrap();
+        assert_eq!(mesh.name(), Some("Scene"));
+        let primitives: Vec<gltf::Primitive> = mesh.primitives().collect();
+        assert_eq!(primitives.len(), 2);
+        let reader = primitives[0].reader(|buffer| Some(&model.buffers[buffer.index()][..]));
+        assert_eq!(reader.read_indices().unwrap().into_u32().collect::<Vec<_>>(), vec![0, 1, 2, 0, 2, 3]);
+        let tex_coords: Vec<[f32; 2]> = reader.read_tex_coords(0).unwrap().into_f32().collect();
+        assert_eq!(tex_coords[3], [0.0, 0.0]);
+        let normals: Vec<[f32; 3]> = reader.read_normals().unwrap().collect();
+        assert_eq!(normals[2], [0.0, 0.0, 1.0]);
+
+        let wood = primitives[0].material();
+        assert_eq!(wood.name(), Some("Wood"));
+        assert!((wood.pbr_metallic_roughness().roughness_factor() - 0.1414).abs() < 1e-3);
+        assert!(wood.pbr_metallic_roughness().base_color_texture().is_some());
+        let glass = primitives[1].material();
+        assert_eq!(glass.alpha_mode(), gltf::material::AlphaMode::Blend);
+        assert_eq!(glass.pbr_metallic_roughness().metallic_factor(), 1.0);
+        assert!((glass.pbr_metallic_roughness().base_color_factor()[0] - 0.214).abs() < 1e-3);
+        // The BMP is embedded as a PNG
+        assert!(model.images[0].starts_with(b"\x89PNG"));
+        assert_eq!(TextureProcessor::new().get_metadata(&model.images[0]).unwrap().resource_type, ResourceType::Texture);
+
+        // A welded cube keeps its hard edges
+        let cube = b"v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nv 0 0 1\nv 1 0 1\nv 1 1 1\nv 0 1 1\n\
+            f 1 4 3 2\nf 5 6 7 8\nf 1 2 6 5\nf 3 4 8 7\nf 1 5 8 4\nf 2 3 7 6\n";
+        let imported = registry.import(Some(&dir.path().join("cube.obj")), cube, None, &ImportSettings::default()).unwrap();
+        let model = Model3DProcessor::new().load(&imported.data, None).unwrap();
+        let primitive = model.document.meshes().next().unwrap().primitives().next().unwrap();
+        let reader = primitive.reader(|buffer| Some(&model.buffers[buffer.index()][..]));
+        let normals: Vec<[f32; 3]> = reader.read_normals().unwrap().collect();
+        assert_eq!(normals.len(), 24);
+        assert!(normals.iter().all(|n| n.iter().filter(|c| c.abs() == 1.0).count() == 1));
+
+        std::fs::remove_file(dir.path().join("scene.mtl")).unwrap();
+        let error = registry.import(Some(&path), obj, None, &ImportSettings::default()).unwrap_err();
+        assert!(error.to_string().contains("Material library refers to missing file"), "{}", error);
+        assert!(registry.import(Some(&path), b"f 1 2 3\n", None, &ImportSettings::default()).is_err());
+    }
+}
@@ -0,0 +1,360 @@
+use super::{ExternalFile, LoadedModel, Model3DProcessor};
+use crate::ResourceType;
+use anyhow::{bail, Context, Result};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+
+/// Characters escaped in the file names of unpacked parts.
+pub(super) const URI_ESCAPED: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.');
+
+/// Extensions whose data lives in buffer views glTF itself does not refer
+/// to, which moving views around would break.
+const OPAQUE_BUFFER_EXTENSIONS: [&str; 2] = ["KHR_draco_mesh_compression", "EXT_meshopt_compression"];
+
+/// A self-contained GLB and the files it took in.
+#[derive(Debug, Clone)]
+pub struct PackedModel {
+    pub glb: Vec<u8>,
+    /// The external files now embedded in the GLB, as the source referred
+    /// to them.
+    pub external: Vec<ExternalFile>,
+}
+
+/// A `.gltf` and the files it refers to, their paths relative to it.
+#[derive(Debug, Clone)]
+pub struct UnpackedModel {
+    pub gltf: Vec<u8>,
+    /// One `.bin` with every buffer view, if there are any, then each image.
+    pub files: Vec<ExternalFile>,
+}
+
+impl Model3DProcessor {
+    /// Embeds every buffer and image of a `.gltf` or `.glb` in the binary
+    /// chunk of a single GLB. Buffer views are laid out one after another
+    /// in document order, followed by the images that were separate files
+    /// or data URIs; bytes no view covers are dropped.
+    pub fn pack_glb(&self, data: &[u8], base_dir: Option<&Path>) -> Result<PackedModel> {
+        let model = self.load(data, base_dir)?;
+        let mut json = document_json(data)?;
+        let mut bin = relayout_views(&mut json, &model, &[])?;
+
+        let images = json.get_mut("images").and_then(Value::as_array_mut).map(std::mem::take).unwrap_or_default();
+        let mut embedded = Vec::new();
+        for (i, mut image) in images.into_iter().enumerate() {
+            if let Some(uri) = image.as_object_mut().and_then(|image| image.remove("uri")) {
+                let contents = &model.images[i];
+                let mime_type = mime_type(uri.as_str().unwrap_or_default(), contents);
+                image["bufferView"] = json!(push_view(&mut json, &mut bin, contents));
+                image["mimeType"] = json!(mime_type);
+            }
+            embedded.push(image);
+        }
+        if !embedded.is_empty() {
+            json["images"] = Value::Array(embedded);
+        }
+        set_buffer(&mut json, &bin, None);
+
+        Ok(PackedModel { glb: write_glb(&serde_json::to_vec(&json)?, &bin), external: model.external })
+    }
+
+    /// Splits a `.glb`, or a `.gltf` with everything in data URIs, into a
+    /// `.gltf`, a `<name>.bin` holding its buffer views and an image file
+    /// per image named `<name>_<index>.<extension>`. Images are taken out of
+    /// the buffer so that models sharing a texture share its file.
+    pub fn unpack_glb(&self, data: &[u8], name: &str) -> Result<UnpackedModel> {
+        let model = self.load(data, None)?;
+        let mut json = document_json(data)?;
+
+        // Views that only hold images go with them
+        let mut image_views = Vec::new();
+        let mut files = Vec::new();
+        let images = json.get_mut("images").and_then(Value::as_array_mut).map(std::mem::take).unwrap_or_default();
+        let mut unpacked = Vec::new();
+        for (i, mut image) in images.into_iter().enumerate() {
+            let contents = &model.images[i];
+            let fields = image.as_object_mut().with_context(|| format!("Image {} is not an object", i))?;
+            let uri = fields.remove("uri");
+            let mime_type = fields.remove("mimeType");
+            image_views.extend(fields.remove("bufferView").and_then(|view| view.as_u64()));
+
+            let hint = mime_type.as_ref().or(uri.as_ref()).and_then(Value::as_str).unwrap_or_default();
+            let file_name = format!("{}_{}.{}", name, i, extension(hint, contents));
+            let uri = utf8_percent_encode(&file_name, URI_ESCAPED).to_string();
+            image["uri"] = json!(uri);
+            files.push(ExternalFile {
+                uri,
+                path: PathBuf::from(file_name),
+                data: contents.clone(),
+                resource_type: ResourceType::Texture,
+            });
+            unpacked.push(image);
+        }
+        if !unpacked.is_empty() {
+            json["images"] = Value::Array(unpacked);
+        }
+
+        let still_used = referenced_views(&json);
+        image_views.retain(|view| !still_used.contains(view));
+        let bin = relayout_views(&mut json, &model, &image_views)?;
+        if !bin.is_empty() {
+            let file_name = format!("{}.bin", name);
+            let uri = utf8_percent_encode(&file_name, URI_ESCAPED).to_string();
+            set_buffer(&mut json, &bin, Some(&uri));
+            files.insert(0, ExternalFile {
+                uri,
+                path: PathBuf::from(file_name),
+                data: bin,
+                resource_type: ResourceType::Binary,
+            });
+        } else {
+            set_buffer(&mut json, &bin, None);
+        }
+
+        Ok(UnpackedModel { gltf: serde_json::to_vec_pretty(&json)?, files })
+    }
+}
+
+/// The JSON of a `.gltf`, or of a `.glb`'s JSON chunk.
+pub(super) fn document_json(data: &[u8]) -> Result<Value> {
+    let json = match data.starts_with(b"glTF") {
+        true => gltf::Glb::from_slice(data).with_context(|| "Failed to parse GLB")?.json.into_owned(),
+        false => data.to_vec(),
+    };
+    let json: Value = serde_json::from_slice(&json).with_context(|| "Failed to parse model JSON")?;
+    let used = json.get("extensionsUsed").and_then(Value::as_array).into_iter().flatten();
+    if let Some(extension) = used.filter_map(Value::as_str).find(|e| OPAQUE_BUFFER_EXTENSIONS.contains(e)) {
+        bail!("Models using {} cannot be repacked", extension);
+    }
+    Ok(json)
+}
+
+/// Copies each buffer view but those in `dropped` into one new buffer,
+/// four-byte aligned, and renumbers the views and the references to them.
+pub(super) fn relayout_views(json: &mut Value, model: &LoadedModel, dropped: &[u64]) -> Result<Vec<u8>> {
+    let views = json.get_mut("bufferViews").and_then(Value::as_array_mut).map(std::mem::take).unwrap_or_default();
+    let mut bin = Vec::new();
+    let mut kept = Vec::new();
+    let mut renumbered = Vec::with_capacity(views.len());
+    for (i, mut view) in views.into_iter().enumerate() {
+        if dropped.contains(&(i as u64)) {
+            renumbered.push(None);
+            continue;
+        }
+        let gltf_view = model.document.views().nth(i).with_context(|| format!("Buffer view {} is missing", i))?;
+        let buffer = &model.buffers[gltf_view.buffer().index()];
+        let Some(bytes) = buffer.get(gltf_view.offset()..gltf_view.offset() + gltf_view.length()) else {
+            bail!("Buffer view {} lies outside buffer {}", i, gltf_view.buffer().index());
+        };
+
+        renumbered.push(Some(kept.len()));
+        bin.resize(bin.len().next_multiple_of(4), 0);
+        view["buffer"] = json!(0);
+        view["byteOffset"] = json!(bin.len());
+        bin.extend_from_slice(bytes);
+        kept.push(view);
+    }
+
+    match (kept.is_empty(), json.as_object_mut()) {
+        (false, _) => json["bufferViews"] = Value::Array(kept),
+        (true, Some(fields)) => {
+            fields.remove("bufferViews");
+        }
+        (true, None) => {}
+    }
+    for reference in view_references(json) {
+        if let Some(Some(index)) = reference.as_u64().and_then(|i| renumbered.get(i as usize)) {
+            *reference = json!(index);
+        }
+    }
+    Ok(bin)
+}
+
+/// Appends `bytes` as a new buffer view, returning its index.
+pub(super) fn push_view(json: &mut Value, bin: &mut Vec<u8>, bytes: &[u8]) -> usize {
+    bin.resize(bin.len().next_multiple_of(4), 0);
+    let view = json!({"buffer": 0, "byteOffset": bin.len(), "byteLength": bytes.len()});
+    bin.extend_from_slice(bytes);
+    match json.get_mut("bufferViews").and_then(Value::as_array_mut) {
+        Some(views) => {
+            views.push(view);
+            views.len() - 1
+        }
+        None => {
+            json["bufferViews"] = json!([view]);
+            0
+        }
+    }
+}
+
+/// Makes `bin` the only buffer, in the binary chunk or at `uri`.
+pub(super) fn set_buffer(json: &mut Value, bin: &[u8], uri: Option<&str>) {
+    let Some(fields) = json.as_object_mut() else {
+        return;
+    };
+    if bin.is_empty() {
+        fields.remove("buffers");
+        return;
+    }
+    let mut buffer = json!({"byteLength": bin.len()});
+    if let Some(uri) = uri {
+        buffer["uri"] = json!(uri);
+    }
+    fields.insert("buffers".to_string(), json!([buffer]));
+}
+
+/// Every buffer view index the core glTF schema refers to.
+pub(super) fn view_references(json: &mut Value) -> Vec<&mut Value> {
+    let mut references = Vec::new();
+    let Some(fields) = json.as_object_mut() else {
+        return references;
+    };
+    for (key, value) in fields.iter_mut() {
+        let Some(items) = value.as_array_mut().filter(|_| key == "accessors" || key == "images") else {
+            continue;
+        };
+        for item in items.iter_mut().filter_map(Value::as_object_mut) {
+            let mut sparse = None;
+            for (field, value) in item.iter_mut() {
+                match field.as_str() {
+                    "bufferView" => references.push(value),
+                    "sparse" => sparse = Some(value),
+                    _ => {}
+                }
+            }
+            if let Some(sparse) = sparse.and_then(Value::as_object_mut) {
+                for (field, part) in sparse.iter_mut() {
+                    if field == "indices" || field == "values" {
+                        references.extend(part.get_mut("bufferView"));
+                    }
+                }
+            }
+        }
+    }
+    references
+}
+
+fn referenced_views(json: &Value) -> Vec<u64> {
+    let mut json = json.clone();
+    view_references(&mut json).into_iter().filter_map(|reference| reference.as_u64()).collect()
+}
+
+/// The media type of an image, from its contents or else its file name.
+fn mime_type(uri: &str, contents: &[u8]) -> &'static str {
+    match extension(uri, contents) {
+        "png" => "image/png",
+        "jpg" => "image/jpeg",
+        "webp" => "image/webp",
+        "ktx2" => "image/ktx2",
+        "dds" => "image/vnd-ms.dds",
+        _ => "application/octet-stream",
+    }
+}
+
+/// The file extension of an image, from its contents or else its media
+/// type or file name.
+fn extension(hint: &str, contents: &[u8]) -> &'static str {
+    let hint = hint.to_ascii_lowercase();
+    match contents {
+        [0x89, b'P', b'N', b'G', ..] => "png",
+        [0xFF, 0xD8, ..] => "jpg",
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => "webp",
+        [0xAB, b'K', b'T', b'X', b' ', b'2', b'0', ..] => "ktx2",
+        [b'D', b'D', b'S', b' ', ..] => "dds",
+        _ if hint.ends_with("png") => "png",
+        _ if hint.ends_with("jpeg") || hint.ends_with("jpg") => "jpg",
+        _ => "bin",
+    }
+}
+
+/// A GLB of a JSON chunk padded with spaces and, if `bin` is not empty, a
+/// binary chunk padded with zeros.
+pub(super) fn write_glb(json: &[u8], bin: &[u8]) -> Vec<u8> {
+    let json_length = json.len().next_multiple_of(4);
+    let bin_length = bin.len().next_multiple_of(4);
+    let total = 12 + 8 + json_length + if bin.is_empty() { 0 } else { 8 + bin_length };
+
+    let mut glb = Vec::with_capacity(total);
+    glb.extend_from_slice(b"glTF");
+    glb.extend_from_slice(&2u32.to_le_bytes());
+    glb.extend_from_slice(&(total as u32).to_le_bytes());
+    glb.extend_from_slice(&(json_length as u32).to_le_bytes());
+    glb.extend_from_slice(b"JSON");
+    glb.extend_from_slice(json);
+    glb.resize(20 + json_length, b' ');
+    if !bin.is_empty() {
+        glb.extend_from_slice(&(bin_length as u32).to_le_bytes());
+        glb.extend_from_slice(b"BIN\0");
+        glb.extend_from_slice(bin);
+        glb.resize(total, 0);
+    }
+    glb
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::TextureProcessor;
+
+    fn positions(processor: &Model3DProcessor, data: &[u8], base_dir: Option<&Path>) -> Vec<[f32; 3]> {
+        let model = processor.load(data, base_dir).unwrap();
+        let mesh = model.document.meshes().next().unwrap();
+        let primitive = mesh.primitives().next().unwrap();
+        let reader = primitive.reader(|buffer| model.buffers.get(buffer.index()).map(|data| &data[..]));
+        reader.read_positions().unwrap().collect()
+    }
+
+    #[test]
+    fn test_pack_and_unpack() {
+        let dir = tempfile::tempdir().unwrap();
+        let triangle = [[0.0f32, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]];
+        // Two bytes of padding the packed buffer need not keep
+        let mut buffer = vec![7u8, 7];
+        buffer.extend(triangle.iter().flat_map(|p| p.iter().flat_map(|c| c.to_le_bytes())));
+        let albedo = TextureProcessor::encode_png(image::RgbaImage::new(4, 4)).unwrap();
+        std::fs::write(dir.path().join("mesh.bin"), &buffer).unwrap();
+        std::fs::write(dir.path().join("albedo.png"), &albedo).unwrap();
+        let gltf = br#"{
+            "asset": {"version": "2.0"},
+            "buffers": [{"byteLength": 38, "uri": "mesh.bin"}],
+            "bufferViews": [{"buffer": 0, "byteOffset": 2, "byteLength": 36}],
+            "accessors": [{"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3", "min": [0, 0, 0], "max": [1, 1, 0]}],
+            "meshes": [{"primitives": [{"attributes": {"POSITION": 0}}]}],
+            "images": [{"uri": "albedo.png"}]
+        }"#;
+
+        let processor = Model3DProcessor::new();
+        let packed = processor.pack_glb(gltf, Some(dir.path())).unwrap();
+        assert_eq!(packed.external.len(), 2);
+        let model = processor.load(&packed.glb, None).unwrap();
+        assert!(model.external.is_empty());
+        assert_eq!(model.images, vec![albedo.clone()]);
+        let image = model.document.images().next().unwrap();
+        assert!(matches!(
+            image.source(),
+            gltf::image::Source::View { view, mime_type: "image/png" } if view.index() == 1,
+        ));
+        assert_eq!(positions(&processor, &packed.glb, None), triangle);
+
+        let unpacked = processor.unpack_glb(&packed.glb, "hero mesh").unwrap();
+        let uris: Vec<&str> = unpacked.files.iter().map(|file| file.uri.as_str()).collect();
+        assert_eq!(uris, vec!["hero%20mesh.bin", "hero%20mesh_0.png"]);
+        // The image left the buffer with its view
+        assert_eq!(unpacked.files[0].data.len(), 36);
+        assert_eq!(unpacked.files[1].data, albedo);
+        assert_eq!(unpacked.files[1].resource_type, ResourceType::Texture);
+
+        let out = dir.path().join("out");
+        std::fs::create_dir(&out).unwrap();
+        for file in &unpacked.files {
+            std::fs::write(out.join(&file.path), &file.data).unwrap();
+        }
+        assert_eq!(positions(&processor, &unpacked.gltf, Some(&out)), triangle);
+
+        // Packing the parts again gives the same GLB
+        assert_eq!(processor.pack_glb(&unpacked.gltf, Some(&out)).unwrap().glb, packed.glb);
+
+        let draco = br#"{"asset": {"version": "2.0"}, "extensionsUsed": ["KHR_draco_mesh_compression"]}"#;
+        assert!(processor.unpack_glb(draco, "draco").is_err());
+    }
+}
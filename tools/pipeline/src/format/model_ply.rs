@@ -0,0 +1,504 @@
+use super::mipmap::srgb_to_linear;
+use super::model_convert::read_reference;
+use super::{
+    ConvertedImage, ConvertedMaterial, ConvertedMesh, ConvertedPrimitive, DependencyExtractor, ExternalFile, FormatProcessor, ImportSettings,
+    Model3DProcessor,
+};
+use crate::{ResourceMetadata, ResourceType};
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::path::Path;
+use tracing::warn;
+
+/// Converts PLY meshes and point clouds, ASCII or binary, to glTF. Vertex
+/// colours become `COLOR_0`, and the textures MeshLab-style `TextureFile`
+/// comments name become materials.
+#[derive(Default)]
+pub struct PlyProcessor;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl Scalar {
+    fn parse(name: &str) -> Result<Self> {
+        Ok(match name {
+            "char" | "int8" => Scalar::I8,
+            "uchar" | "uint8" => Scalar::U8,
+            "short" | "int16" => Scalar::I16,
+            "ushort" | "uint16" => Scalar::U16,
+            "int" | "int32" => Scalar::I32,
+            "uint" | "uint32" => Scalar::U32,
+            "float" | "float32" => Scalar::F32,
+            "double" | "float64" => Scalar::F64,
+            other => bail!("Unknown PLY property type {}", other),
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Scalar::I8 | Scalar::U8 => 1,
+            Scalar::I16 | Scalar::U16 => 2,
+            Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+            Scalar::F64 => 8,
+        }
+    }
+
+    /// The value integer colours are divided by to reach 0 to 1.
+    fn full_scale(self) -> f64 {
+        match self {
+            Scalar::I8 => 127.0,
+            Scalar::U8 => 255.0,
+            Scalar::I16 => 32767.0,
+            Scalar::U16 => 65535.0,
+            Scalar::I32 => i32::MAX as f64,
+            Scalar::U32 => u32::MAX as f64,
+            Scalar::F32 | Scalar::F64 => 1.0,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Property {
+    name: String,
+    kind: Scalar,
+    /// Type of the length prefix of a list property.
+    list: Option<Scalar>,
+}
+
+#[derive(Debug)]
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+#[derive(Debug)]
+struct Header {
+    encoding: Encoding,
+    elements: Vec<Element>,
+    textures: Vec<String>,
+    /// Offset of the first element.
+    body: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    Ascii,
+    LittleEndian,
+    BigEndian,
+}
+
+enum Body<'a> {
+    Ascii(std::str::SplitAsciiWhitespace<'a>),
+    Binary { data: &'a [u8], at: usize, big_endian: bool },
+}
+
+impl Body<'_> {
+    fn read(&mut self, kind: Scalar) -> Result<f64> {
+        match self {
+            Body::Ascii(tokens) => {
+                let token = tokens.next().context("PLY ends before all its elements")?;
+                token.parse().with_context(|| format!("{} is not a number", token))
+            }
+            Body::Binary { data, at, big_endian } => {
+                let bytes = data.get(*at..*at + kind.size()).context("PLY ends before all its elements")?;
+                *at += kind.size();
+                let mut buffer = [0u8; 8];
+                buffer[..bytes.len()].copy_from_slice(bytes);
+                if *big_endian {
+                    buffer[..bytes.len()].reverse();
+                }
+                Ok(match kind {
+                    Scalar::I8 => buffer[0] as i8 as f64,
+                    Scalar::U8 => buffer[0] as f64,
+                    Scalar::I16 => i16::from_le_bytes([buffer[0], buffer[1]]) as f64,
+                    Scalar::U16 => u16::from_le_bytes([buffer[0], buffer[1]]) as f64,
+                    Scalar::I32 => i32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as f64,
+                    Scalar::U32 => u32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as f64,
+                    Scalar::F32 => f32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as f64,
+                    Scalar::F64 => f64::from_le_bytes(buffer),
+                })
+            }
+        }
+    }
+}
+
+/// Values of one property across an element: scalars in one column, and
+/// a list for each row of a list property.
+enum Column {
+    Scalar(Vec<f64>),
+    List(Vec<Vec<f64>>),
+}
+
+impl Column {
+    fn value(&self, row: usize) -> f64 {
+        match self {
+            Column::Scalar(values) => values[row],
+            Column::List(lists) => lists[row].first().copied().unwrap_or_default(),
+        }
+    }
+
+    fn list(&self, row: usize) -> &[f64] {
+        match self {
+            Column::Scalar(values) => std::slice::from_ref(&values[row]),
+            Column::List(lists) => &lists[row],
+        }
+    }
+}
+
+impl PlyProcessor {
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// Parses a PLY into a mesh, taking the textures its comments name from
+    /// `files` by reference. Without faces the vertices become a point cloud.
+    pub fn convert(&self, data: &[u8], files: &[ExternalFile]) -> Result<ConvertedMesh> {
+        let header = parse_header(data)?;
+        let mut body = match header.encoding {
+            Encoding::Ascii => Body::Ascii(
+                std::str::from_utf8(&data[header.body..])
+                    .with_context(|| "ASCII PLY body is not text")?
+                    .split_ascii_whitespace(),
+            ),
+            encoding => Body::Binary { data, at: header.body, big_endian: encoding == Encoding::BigEndian },
+        };
+
+        let mut vertices: Option<(&Element, Vec<Column>)> = None;
+        let mut faces: Option<(&Element, Vec<Column>)> = None;
+        for element in &header.elements {
+            let reserve = element.count.min(1 << 20);
+            let mut columns: Vec<Column> = element
+                .properties
+                .iter()
+                .map(|property| match property.list {
+                    Some(_) => Column::List(Vec::with_capacity(reserve)),
+                    None => Column::Scalar(Vec::with_capacity(reserve)),
+                })
+                .collect();
+            for _ in 0..element.count {
+                for (property, column) in element.properties.iter().zip(&mut columns) {
+                    match (column, property.list) {
+                        (Column::Scalar(values), None) => values.push(body.read(property.kind)?),
+                        (Column::List(lists), Some(length)) => {
+                            let length = body.read(length)? as usize;
+                            lists.push((0..length).map(|_| body.read(property.kind)).collect::<Result<_>>()?);
+                        }
+                        _ => unreachable!("columns are made from their properties"),
+                    }
+                }
+            }
+            match element.name.as_str() {
+                "vertex" => vertices = Some((element, columns)),
+                "face" => faces = Some((element, columns)),
+                _ => {}
+            }
+        }
+        let Some((vertex, values)) = vertices else {
+            bail!("PLY has no vertex element");
+        };
+
+        let column = |element: &Element, names: &[&str]| element.properties.iter().position(|p| names.contains(&p.name.as_str()));
+        let columns = |names: [&[&str]; 3]| -> Option<[usize; 3]> {
+            Some([column(vertex, names[0])?, column(vertex, names[1])?, column(vertex, names[2])?])
+        };
+        let Some(position) = columns([&["x"], &["y"], &["z"]]) else {
+            bail!("PLY vertices have no x, y and z");
+        };
+        let normal = columns([&["nx"], &["ny"], &["nz"]]);
+        let tex_coord = column(vertex, &["u", "s", "texture_u", "texture_s"])
+            .zip(column(vertex, &["v", "t", "texture_v", "texture_t"]));
+        let color = columns([&["red", "diffuse_red"], &["green", "diffuse_green"], &["blue", "diffuse_blue"]]);
+        let alpha = column(vertex, &["alpha", "diffuse_alpha"]);
+
+        let value = |row: usize, column: usize| values[column].value(row);
+        let rows = 0..vertex.count;
+        let mut mesh = ConvertedMesh {
+            positions: rows.clone().map(|row| position.map(|c| value(row, c) as f32)).collect(),
+            normals: normal.map(|normal| rows.clone().map(|row| normal.map(|c| value(row, c) as f32)).collect()),
+            tex_coords: tex_coord.map(|(u, v)| {
+                rows.clone().map(|row| [value(row, u) as f32, 1.0 - value(row, v) as f32]).collect()
+            }),
+            colors: color.map(|color| {
+                let scale = |c: usize| vertex.properties[c].kind.full_scale();
+                rows.clone()
+                    .map(|row| {
+                        let [r, g, b] = color.map(|c| srgb_to_linear((value(row, c) / scale(c)) as f32));
+                        let a = alpha.map_or(1.0, |a| (value(row, a) / scale(a)) as f32);
+                        [r, g, b, a]
+                    })
+                    .collect()
+            }),
+            ..Default::default()
+        };
+
+        for uri in &header.textures {
+            let mut material = ConvertedMaterial { name: Some(uri.clone()), ..Default::default() };
+            match files.iter().find(|file| &file.uri == uri && file.resource_type == ResourceType::Texture) {
+                Some(file) => {
+                    mesh.images.push(ConvertedImage { uri: uri.to_string(), data: file.data.clone() });
+                    material.base_color_texture = Some(mesh.images.len() - 1);
+                }
+                None => warn!("Texture {} was not provided", uri),
+            }
+            mesh.materials.push(material);
+        }
+
+        let Some((face, values)) = faces.filter(|(face, _)| face.count > 0) else {
+            return Ok(mesh);
+        };
+        let Some(indices) = column(face, &["vertex_indices", "vertex_index"]) else {
+            bail!("PLY faces have no vertex_indices");
+        };
+        let corner_tex_coords = column(face, &["texcoord"]);
+        let texture = column(face, &["texnumber"]);
+
+        // Per-corner texture coordinates split the vertices they disagree on
+        let vertex_count = mesh.positions.len();
+        let mut source = None;
+        if corner_tex_coords.is_some() {
+            let mut original = std::mem::take(&mut mesh);
+            mesh.tex_coords = Some(Vec::new());
+            mesh.normals = original.normals.as_ref().map(|_| Vec::new());
+            mesh.colors = original.colors.as_ref().map(|_| Vec::new());
+            mesh.materials = std::mem::take(&mut original.materials);
+            mesh.images = std::mem::take(&mut original.images);
+            source = Some(original);
+        }
+        let mut split: HashMap<(u32, [u32; 2]), u32> = HashMap::new();
+
+        let mut primitive_of: HashMap<Option<usize>, usize> = HashMap::new();
+        for row in 0..face.count {
+            let corners = values[indices].list(row);
+            if let Some(&bad) = corners.iter().find(|&&i| i < 0.0 || i as usize >= vertex_count) {
+                bail!("Face {} refers to vertex {} of {}", row, bad, vertex_count);
+            }
+            let mut corners: Vec<u32> = corners.iter().map(|&i| i as u32).collect();
+            if let (Some(column), Some(source)) = (corner_tex_coords, &source) {
+                let uvs = values[column].list(row);
+                for (corner, vertex) in corners.iter_mut().enumerate() {
+                    let uv = [
+                        uvs.get(corner * 2).copied().unwrap_or_default() as f32,
+                        1.0 - uvs.get(corner * 2 + 1).copied().unwrap_or_default() as f32,
+                    ];
+                    let next = mesh.positions.len() as u32;
+                    let original = *vertex as usize;
+                    *vertex = *split.entry((*vertex, uv.map(f32::to_bits))).or_insert_with(|| {
+                        mesh.positions.push(source.positions[original]);
+                        mesh.tex_coords.as_mut().expect("split meshes have coordinates").push(uv);
+                        if let (Some(normals), Some(from)) = (mesh.normals.as_mut(), source.normals.as_ref()) {
+                            normals.push(from[original]);
+                        }
+                        if let (Some(colors), Some(from)) = (mesh.colors.as_mut(), source.colors.as_ref()) {
+                            colors.push(from[original]);
+                        }
+                        next
+                    });
+                }
+            }
+            if corners.len() < 3 {
+                continue;
+            }
+
+            let material = match texture {
+                Some(column) => Some(values[column].value(row) as usize).filter(|&t| t < mesh.materials.len()),
+                None => (!mesh.materials.is_empty()).then_some(0),
+            };
+            let primitive = *primitive_of.entry(material).or_insert_with(|| {
+                mesh.primitives.push(ConvertedPrimitive { material, indices: Vec::new() });
+                mesh.primitives.len() - 1
+            });
+            for i in 1..corners.len() - 1 {
+                mesh.primitives[primitive].indices.extend([corners[0], corners[i], corners[i + 1]]);
+            }
+        }
+        Ok(mesh)
+    }
+}
+
+fn parse_header(data: &[u8]) -> Result<Header> {
+    if !data.starts_with(b"ply") {
+        bail!("Not a PLY file");
+    }
+    let end = data
+        .windows(10)
+        .position(|w| w == b"end_header")
+        .context("PLY header has no end_header")?;
+    let body = match data[end..].iter().position(|&b| b == b'\n') {
+        Some(newline) => end + newline + 1,
+        None => data.len(),
+    };
+    let text = std::str::from_utf8(&data[..end]).with_context(|| "PLY header is not text")?;
+
+    let mut encoding = None;
+    let mut elements: Vec<Element> = Vec::new();
+    let mut textures = Vec::new();
+    for line in text.lines().skip(1) {
+        let mut tokens = line.split_whitespace();
+        match tokens.next() {
+            Some("format") => {
+                encoding = Some(match tokens.next() {
+                    Some("ascii") => Encoding::Ascii,
+                    Some("binary_little_endian") => Encoding::LittleEndian,
+                    Some("binary_big_endian") => Encoding::BigEndian,
+                    other => bail!("Unknown PLY format {}", other.unwrap_or_default()),
+                })
+            }
+            Some("element") => {
+                let (Some(name), Some(count)) = (tokens.next(), tokens.next()) else {
+                    bail!("Malformed PLY element: {}", line);
+                };
+                let count = count.parse().with_context(|| format!("Malformed PLY element: {}", line))?;
+                elements.push(Element { name: name.to_string(), count, properties: Vec::new() });
+            }
+            Some("property") => {
+                let Some(element) = elements.last_mut() else {
+                    bail!("PLY property outside an element: {}", line);
+                };
+                let tokens: Vec<&str> = tokens.collect();
+                let property = match tokens[..] {
+                    ["list", length, kind, name] => {
+                        Property { name: name.to_string(), kind: Scalar::parse(kind)?, list: Some(Scalar::parse(length)?) }
+                    }
+                    [kind, name] => Property { name: name.to_string(), kind: Scalar::parse(kind)?, list: None },
+                    _ => bail!("Malformed PLY property: {}", line),
+                };
+                element.properties.push(property);
+            }
+            Some("comment") => {
+                let comment = line.trim_start().trim_start_matches("comment").trim();
+                if let Some(name) = comment.strip_prefix("TextureFile") {
+                    textures.push(name.trim().to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+    let Some(encoding) = encoding else {
+        bail!("PLY header has no format");
+    };
+    Ok(Header { encoding, elements, textures, body })
+}
+
+impl FormatProcessor for PlyProcessor {
+    fn name(&self) -> &'static str {
+        "ply"
+    }
+
+    fn resource_type(&self) -> ResourceType {
+        ResourceType::Model3D
+    }
+
+    fn extensions(&self) -> &[&'static str] {
+        &["ply"]
+    }
+
+    fn matches_magic(&self, data: &[u8]) -> bool {
+        data.starts_with(b"ply\n") || data.starts_with(b"ply\r\n")
+    }
+
+    // 2: stored textures are referred to and normals keep hard edges
+    fn version(&self) -> u32 {
+        2
+    }
+
+    fn process(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.process_with(data, &ImportSettings::default(), &[])
+    }
+
+    fn process_with(&self, data: &[u8], settings: &ImportSettings, files: &[ExternalFile]) -> Result<Vec<u8>> {
+        let glb = self.convert(data, files)?.into_glb()?;
+        Model3DProcessor::new().process_with(&glb, settings, files)
+    }
+
+    fn dependency_extractor(&self) -> Option<&dyn DependencyExtractor> {
+        Some(self)
+    }
+
+    /// Describes the converted model.
+    fn get_metadata(&self, data: &[u8]) -> Result<ResourceMetadata> {
+        Model3DProcessor::new().get_metadata(data)
+    }
+
+    fn validate(&self, data: &[u8]) -> Result<bool> {
+        parse_header(data)?;
+        Ok(true)
+    }
+}
+
+impl DependencyExtractor for PlyProcessor {
+    /// Textures named by `TextureFile` comments.
+    fn extract_dependencies(&self, data: &[u8], base_dir: Option<&Path>) -> Result<Vec<ExternalFile>> {
+        parse_header(data)?
+            .textures
+            .iter()
+            .map(|name| read_reference(name, base_dir, "PLY", ResourceType::Texture))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{FormatRegistry, TextureProcessor};
+
+    #[test]
+    fn test_ply_import() {
+        // A binary quad with vertex colours and a textured ASCII triangle
+        let mut binary = b"ply\nformat binary_big_endian 1.0\ncomment made by hand\n\
+            element vertex 4\nproperty float x\nproperty float y\nproperty float z\n\
+            property uchar red\nproperty uchar green\nproperty uchar blue\n\
+            element face 1\nproperty list uchar int vertex_indices\nend_header\n"
+            .to_vec();
+        for (position, color) in [([0.0f32, 0.0, 0.0], [255u8, 0, 0]), ([1.0, 0.0, 0.0], [0, 255, 0]), ([1.0, 1.0, 0.0], [0, 0, 255]), ([0.0, 1.0, 0.0], [255, 255, 255])] {
+            binary.extend(position.iter().flat_map(|c| c.to_be_bytes()));
+            binary.extend(color);
+        }
+        binary.push(4);
+        binary.extend([0i32, 1, 2, 3].iter().flat_map(|i| i.to_be_bytes()));
+
+        let processor = PlyProcessor::new();
+        let quad = processor.convert(&binary, &[]).unwrap();
+        assert_eq!(quad.positions[2], [1.0, 1.0, 0.0]);
+        assert_eq!(quad.colors.as_ref().unwrap()[1], [0.0, 1.0, 0.0, 1.0]);
+        assert_eq!(quad.primitives[0].indices, vec![0, 1, 2, 0, 2, 3]);
+        assert!(quad.normals.is_none());
+
+        let dir = tempfile::tempdir().unwrap();
+        let png = TextureProcessor::encode_png(image::RgbaImage::new(2, 2)).unwrap();
+        std::fs::write(dir.path().join("scan.png"), &png).unwrap();
+        let ascii = b"ply\r\nformat ascii 1.0\r\ncomment TextureFile scan.png\r\n\
+            element vertex 3\r\nproperty double x\r\nproperty double y\r\nproperty double z\r\n\
+            element face 1\r\nproperty list uchar int vertex_indices\r\nproperty list uchar float texcoord\r\n\
+            end_header\r\n0 0 0\r\n1 0 0\r\n0 1 0\r\n3 0 1 2 6 0 0 1 0 0 1\r\n";
+        let registry = FormatRegistry::with_defaults();
+        assert_eq!(registry.detect(None, ascii).unwrap().name(), "ply");
+        let path = dir.path().join("scan.ply");
+        let imported = registry.import(Some(&path), ascii, None, &ImportSettings::default()).unwrap();
+        assert_eq!(imported.metadata.dependencies, vec![blake3::hash(&png).to_string()]);
+        let stats = Model3DProcessor::new().stats(&imported.data).unwrap();
+        assert_eq!((stats.vertices, stats.triangles), (3, 1));
+        assert_eq!(stats.textures[0].materials, vec![0]);
+        // The texture is stored once, as the dependency, and only referred to
+        let gltf = gltf::Gltf::from_slice(&imported.data).unwrap();
+        assert!(matches!(gltf.images().next().unwrap().source(), gltf::image::Source::Uri { uri: "scan.png", .. }));
+        let model = Model3DProcessor::new().load_with_files(&imported.data, None, &imported.external).unwrap();
+        assert_eq!(model.images[0], png);
+
+        // Vertices alone are a point cloud
+        let cloud = b"ply\nformat ascii 1.0\nelement vertex 2\nproperty float x\nproperty float y\nproperty float z\nend_header\n0 0 0\n1 1 1\n";
+        let imported = registry.import(None, cloud, None, &ImportSettings::default()).unwrap();
+        let stats = Model3DProcessor::new().stats(&imported.data).unwrap();
+        assert_eq!((stats.vertices, stats.triangles), (2, 0));
+        assert!(processor.convert(&binary[..binary.len() - 4], &[]).is_err());
+    }
+}
@@ -0,0 +1,212 @@
+use super::{linear_to_srgb, read_positions, ExternalFile, LoadedModel, Model3DProcessor};
+use anyhow::{bail, Result};
+use image::{Rgba, RgbaImage};
+
+/// Samples per pixel along each axis, averaged down for smooth edges.
+const SUPERSAMPLING: u32 = 2;
+
+/// Share of the image the model's bounding sphere spans.
+const FILL: f32 = 0.9;
+
+const AMBIENT: f32 = 0.25;
+
+/// A world-space triangle and its material's linear base colour.
+struct Triangle {
+    corners: [[f32; 3]; 3],
+    color: [f32; 4],
+}
+
+impl Model3DProcessor {
+    /// Rasterizes the default scene, or the first, on the CPU: an
+    /// orthographic three-quarter view from above, fitted to the model,
+    /// with flat Lambert shading of each material's base colour factor on a
+    /// transparent background. Buffers must be in a GLB's binary chunk,
+    /// data URIs or `files`, as the stored model has no path to resolve
+    /// files against.
+    pub fn render_view(&self, data: &[u8], files: &[ExternalFile], size: u32) -> Result<RgbaImage> {
+        if size == 0 {
+            bail!("Model view must be at least 1 pixel wide");
+        }
+        let triangles = self.scene_triangles(data, files)?;
+        let samples = size * SUPERSAMPLING;
+        let mut color = vec![[0.0f32; 4]; (samples * samples) as usize];
+        let mut depth = vec![f32::MIN; color.len()];
+
+        let Some((centre, radius)) = bounding_sphere(&triangles) else {
+            return Ok(RgbaImage::new(size, size));
+        };
+        // Camera looking down 30 degrees from the front-right, the light
+        // coming from over its left shoulder
+        let (yaw, pitch) = (45f32.to_radians(), 30f32.to_radians());
+        let forward = [pitch.cos() * yaw.sin(), pitch.sin(), pitch.cos() * yaw.cos()];
+        let right = normalize([forward[2], 0.0, -forward[0]]);
+        let up = cross(forward, right);
+        let light = normalize([-0.4, 0.8, 0.6]);
+        let scale = samples as f32 * FILL / (2.0 * radius);
+
+        let project = |p: [f32; 3]| -> [f32; 3] {
+            let v = sub(p, centre);
+            [
+                samples as f32 / 2.0 + dot(v, right) * scale,
+                samples as f32 / 2.0 - dot(v, up) * scale,
+                dot(v, forward),
+            ]
+        };
+
+        for triangle in &triangles {
+            let [a, b, c] = triangle.corners;
+            let mut normal = normalize(cross(sub(b, a), sub(c, a)));
+            if dot(normal, forward) < 0.0 {
+                normal = normal.map(|n| -n);
+            }
+            let shade = AMBIENT + (1.0 - AMBIENT) * dot(normal, light).max(0.0);
+            let shaded = [
+                linear_to_srgb(triangle.color[0] * shade),
+                linear_to_srgb(triangle.color[1] * shade),
+                linear_to_srgb(triangle.color[2] * shade),
+                triangle.color[3],
+            ];
+
+            let [p0, p1, p2] = triangle.corners.map(project);
+            let area = edge(p0, p1, p2);
+            if area.abs() < f32::EPSILON {
+                continue;
+            }
+            let min_x = p0[0].min(p1[0]).min(p2[0]).floor().max(0.0) as u32;
+            let min_y = p0[1].min(p1[1]).min(p2[1]).floor().max(0.0) as u32;
+            let max_x = (p0[0].max(p1[0]).max(p2[0]).ceil() as u32).min(samples);
+            let max_y = (p0[1].max(p1[1]).max(p2[1]).ceil() as u32).min(samples);
+
+            for y in min_y..max_y {
+                for x in min_x..max_x {
+                    let p = [x as f32 + 0.5, y as f32 + 0.5, 0.0];
+                    let w = [edge(p1, p2, p) / area, edge(p2, p0, p) / area, edge(p0, p1, p) / area];
+                    if w.iter().any(|&w| w < 0.0) {
+                        continue;
+                    }
+                    let z = w[0] * p0[2] + w[1] * p1[2] + w[2] * p2[2];
+                    let i = (y * samples + x) as usize;
+                    if z > depth[i] {
+                        depth[i] = z;
+                        color[i] = shaded;
+                    }
+                }
+            }
+        }
+
+        Ok(RgbaImage::from_fn(size, size, |x, y| {
+            // Average with premultiplied alpha so the background does not
+            // darken the edges
+            let mut sum = [0.0f32; 4];
+            for sy in 0..SUPERSAMPLING {
+                for sx in 0..SUPERSAMPLING {
+                    let c = color[((y * SUPERSAMPLING + sy) * samples + x * SUPERSAMPLING + sx) as usize];
+                    (0..3).for_each(|i| sum[i] += c[i] * c[3]);
+                    sum[3] += c[3];
+                }
+            }
+            let alpha = sum[3] / (SUPERSAMPLING * SUPERSAMPLING) as f32;
+            let to_byte = |v: f32| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+            if sum[3] == 0.0 {
+                return Rgba([0, 0, 0, 0]);
+            }
+            Rgba([to_byte(sum[0] / sum[3]), to_byte(sum[1] / sum[3]), to_byte(sum[2] / sum[3]), to_byte(alpha)])
+        }))
+    }
+
+    /// Triangles of every mesh in the scene, transformed by their nodes.
+    fn scene_triangles(&self, data: &[u8], files: &[ExternalFile]) -> Result<Vec<Triangle>> {
+        let LoadedModel { document: gltf, buffers, .. } = self.load_with_files(data, None, files)?;
+
+        let mut triangles = Vec::new();
+        let mut nodes: Vec<(gltf::Node, [[f32; 4]; 4])> = match gltf.default_scene().or_else(|| gltf.scenes().next()) {
+            Some(scene) => scene.nodes().map(|node| (node, IDENTITY)).collect(),
+            None => Vec::new(),
+        };
+        while let Some((node, parent)) = nodes.pop() {
+            let transform = multiply(parent, node.transform().matrix());
+            nodes.extend(node.children().map(|child| (child, transform)));
+            let Some(mesh) = node.mesh() else {
+                continue;
+            };
+
+            for primitive in mesh.primitives() {
+                let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(|data| &data[..]));
+                let Some(positions) = read_positions(&primitive, &buffers) else {
+                    continue;
+                };
+                let positions: Vec<[f32; 3]> = positions.into_iter().map(|p| transform_point(transform, p)).collect();
+                let indices: Vec<u32> = match reader.read_indices() {
+                    Some(indices) => indices.into_u32().collect(),
+                    None => (0..positions.len() as u32).collect(),
+                };
+                let color = primitive.material().pbr_metallic_roughness().base_color_factor();
+
+                let faces: Vec<[u32; 3]> = match primitive.mode() {
+                    gltf::mesh::Mode::Triangles => indices.chunks_exact(3).map(|t| [t[0], t[1], t[2]]).collect(),
+                    gltf::mesh::Mode::TriangleStrip => (2..indices.len())
+                        .map(|i| match i % 2 {
+                            0 => [indices[i - 2], indices[i - 1], indices[i]],
+                            _ => [indices[i - 1], indices[i - 2], indices[i]],
+                        })
+                        .collect(),
+                    gltf::mesh::Mode::TriangleFan => {
+                        (2..indices.len()).map(|i| [indices[0], indices[i - 1], indices[i]]).collect()
+                    }
+                    _ => continue,
+                };
+                for face in faces {
+                    let [Some(a), Some(b), Some(c)] = face.map(|i| positions.get(i as usize).copied()) else {
+                        bail!("Mesh {} has an index out of range", mesh.index());
+                    };
+                    triangles.push(Triangle { corners: [a, b, c], color });
+                }
+            }
+        }
+        Ok(triangles)
+    }
+}
+
+pub(super) const IDENTITY: [[f32; 4]; 4] = [[1.0, 0.0, 0.0, 0.0], [0.0, 1.0, 0.0, 0.0], [0.0, 0.0, 1.0, 0.0], [0.0, 0.0, 0.0, 1.0]];
+
+/// Product of two column-major matrices.
+pub(super) fn multiply(a: [[f32; 4]; 4], b: [[f32; 4]; 4]) -> [[f32; 4]; 4] {
+    std::array::from_fn(|col| std::array::from_fn(|row| (0..4).map(|k| a[k][row] * b[col][k]).sum()))
+}
+
+pub(super) fn transform_point(m: [[f32; 4]; 4], p: [f32; 3]) -> [f32; 3] {
+    std::array::from_fn(|row| m[0][row] * p[0] + m[1][row] * p[1] + m[2][row] * p[2] + m[3][row])
+}
+
+/// Centre of the triangles' bounding box and the radius around it that
+/// holds them all.
+fn bounding_sphere(triangles: &[Triangle]) -> Option<([f32; 3], f32)> {
+    let points = || triangles.iter().flat_map(|t| t.corners);
+    let min = points().reduce(|a, b| std::array::from_fn(|i| a[i].min(b[i])))?;
+    let max = points().reduce(|a, b| std::array::from_fn(|i| a[i].max(b[i])))?;
+    let centre = std::array::from_fn(|i| (min[i] + max[i]) / 2.0);
+    let radius = points().map(|p| dot(sub(p, centre), sub(p, centre)).sqrt()).fold(0.0f32, f32::max);
+    Some((centre, radius.max(f32::EPSILON)))
+}
+
+fn sub(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+fn normalize(v: [f32; 3]) -> [f32; 3] {
+    let length = dot(v, v).sqrt();
+    if length == 0.0 { v } else { v.map(|c| c / length) }
+}
+
+/// Twice the signed area of the screen-space triangle `a`, `b`, `p`.
+fn edge(a: [f32; 3], b: [f32; 3], p: [f32; 3]) -> f32 {
+    (b[0] - a[0]) * (p[1] - a[1]) - (b[1] - a[1]) * (p[0] - a[0])
+}
@@ -0,0 +1,459 @@
+use super::model_preview::{multiply, transform_point, IDENTITY};
+use super::Model3DProcessor;
+use crate::Bounds;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fmt;
+
+/// What a glTF model is made of, for `pipeline inspect`. Everything comes
+/// from the document itself, so models with external files need not have
+/// them at hand.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ModelStats {
+    pub scenes: usize,
+    pub nodes: usize,
+    pub vertices: usize,
+    pub triangles: usize,
+    /// World-space box around every mesh instance in the default scene, or
+    /// the first. Skinning and morph targets are not applied.
+    pub bounds: Option<Bounds>,
+    pub meshes: Vec<MeshStats>,
+    pub materials: Vec<MaterialStats>,
+    pub textures: Vec<TextureStats>,
+    pub skins: Vec<SkinStats>,
+    pub animations: Vec<AnimationStats>,
+    pub extensions_used: Vec<String>,
+    pub extensions_required: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MeshStats {
+    pub index: usize,
+    pub name: Option<String>,
+    pub primitives: usize,
+    pub vertices: usize,
+    pub triangles: usize,
+    /// Nodes placing the mesh in the scene.
+    pub instances: usize,
+    /// World-space box around the instances; none when nothing places it.
+    pub bounds: Option<Bounds>,
+    /// Materials of the primitives; none stands for the default material.
+    pub materials: Vec<Option<usize>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MaterialStats {
+    pub index: usize,
+    pub name: Option<String>,
+    pub alpha_mode: String,
+    pub double_sided: bool,
+    pub textures: Vec<TextureSlot>,
+    /// Meshes with a primitive using the material.
+    pub meshes: Vec<usize>,
+}
+
+/// A texture a material samples, by the property it feeds.
+#[derive(Debug, Clone, Serialize)]
+pub struct TextureSlot {
+    pub slot: String,
+    pub texture: usize,
+    pub tex_coord: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TextureStats {
+    pub index: usize,
+    pub name: Option<String>,
+    pub image: usize,
+    /// The image's URI, or where in a buffer it lives.
+    pub source: String,
+    pub mime_type: Option<String>,
+    pub materials: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SkinStats {
+    pub index: usize,
+    pub name: Option<String>,
+    pub joints: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AnimationStats {
+    pub index: usize,
+    pub name: Option<String>,
+    /// Time of the last keyframe, in seconds.
+    pub duration: f32,
+    pub channels: Vec<ChannelStats>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChannelStats {
+    pub node: usize,
+    /// `translation`, `rotation`, `scale` or `weights`.
+    pub path: String,
+    pub interpolation: String,
+}
+
+impl fmt::Display for ModelStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = |name: &Option<String>, index: usize| match name {
+            Some(name) => format!("{:?} #{}", name, index),
+            None => format!("#{}", index),
+        };
+
+        writeln!(f, "{} scenes, {} nodes, {} vertices, {} triangles", self.scenes, self.nodes, self.vertices, self.triangles)?;
+        if let Some(bounds) = &self.bounds {
+            writeln!(f, "Bounds: {}", format_bounds(bounds))?;
+        }
+        if !self.extensions_used.is_empty() {
+            writeln!(f, "Extensions: {}", self.extensions_used.join(", "))?;
+        }
+        if !self.extensions_required.is_empty() {
+            writeln!(f, "Required extensions: {}", self.extensions_required.join(", "))?;
+        }
+
+        writeln!(f, "Meshes: {}", self.meshes.len())?;
+        for mesh in &self.meshes {
+            write!(
+                f,
+                "  {}: {} primitives, {} vertices, {} triangles, {} instances",
+                name(&mesh.name, mesh.index),
+                mesh.primitives,
+                mesh.vertices,
+                mesh.triangles,
+                mesh.instances,
+            )?;
+            match &mesh.bounds {
+                Some(bounds) => writeln!(f, ", {}", format_bounds(bounds))?,
+                None => writeln!(f)?,
+            }
+        }
+        writeln!(f, "Materials: {}", self.materials.len())?;
+        for material in &self.materials {
+            let slots: Vec<String> = material.textures.iter().map(|t| format!("{} #{}", t.slot, t.texture)).collect();
+            writeln!(
+                f,
+                "  {}: {}{}, used by {} meshes{}",
+                name(&material.name, material.index),
+                material.alpha_mode.to_lowercase(),
+                if material.double_sided { ", double sided" } else { "" },
+                material.meshes.len(),
+                if slots.is_empty() { String::new() } else { format!(", textures: {}", slots.join(", ")) },
+            )?;
+        }
+        writeln!(f, "Textures: {}", self.textures.len())?;
+        for texture in &self.textures {
+            writeln!(
+                f,
+                "  {}: image #{} ({}), used by {} materials",
+                name(&texture.name, texture.index),
+                texture.image,
+                texture.source,
+                texture.materials.len(),
+            )?;
+        }
+        writeln!(f, "Skins: {}", self.skins.len())?;
+        for skin in &self.skins {
+            writeln!(f, "  {}: {} joints", name(&skin.name, skin.index), skin.joints)?;
+        }
+        writeln!(f, "Animations: {}", self.animations.len())?;
+        for animation in &self.animations {
+            writeln!(
+                f,
+                "  {}: {:.3}s, {} channels",
+                name(&animation.name, animation.index),
+                animation.duration,
+                animation.channels.len(),
+            )?;
+            for channel in &animation.channels {
+                writeln!(f, "    node #{} {} ({})", channel.node, channel.path, channel.interpolation.to_lowercase())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn format_bounds(bounds: &Bounds) -> String {
+    let format = |v: [f32; 3]| format!("[{:.3}, {:.3}, {:.3}]", v[0], v[1], v[2]);
+    format!("{} to {}", format(bounds.min), format(bounds.max))
+}
+
+impl Model3DProcessor {
+    /// Counts, bounds and usage of everything in a `.gltf` or `.glb`.
+    pub fn stats(&self, data: &[u8]) -> Result<ModelStats> {
+        let gltf = self.parse(data).with_context(|| "Failed to parse model")?;
+
+        let mut meshes: Vec<MeshStats> = gltf
+            .meshes()
+            .map(|mesh| {
+                let primitives: Vec<gltf::Primitive> = mesh.primitives().collect();
+                MeshStats {
+                    index: mesh.index(),
+                    name: mesh.name().map(str::to_string),
+                    primitives: primitives.len(),
+                    vertices: primitives.iter().map(vertex_count).sum(),
+                    triangles: primitives.iter().map(triangle_count).sum(),
+                    instances: 0,
+                    bounds: None,
+                    materials: primitives.iter().map(|p| p.material().index()).collect(),
+                }
+            })
+            .collect();
+
+        let mut nodes: Vec<(gltf::Node, [[f32; 4]; 4])> = match gltf.default_scene().or_else(|| gltf.scenes().next()) {
+            Some(scene) => scene.nodes().map(|node| (node, IDENTITY)).collect(),
+            None => Vec::new(),
+        };
+        while let Some((node, parent)) = nodes.pop() {
+            let transform = multiply(parent, node.transform().matrix());
+            nodes.extend(node.children().map(|child| (child, transform)));
+            let Some(mesh) = node.mesh() else {
+                continue;
+            };
+            let stats = &mut meshes[mesh.index()];
+            stats.instances += 1;
+            for primitive in mesh.primitives() {
+                if let Some(local) = position_bounds(&primitive) {
+                    stats.bounds = Some(union(stats.bounds, transform_bounds(transform, &local)));
+                }
+            }
+        }
+
+        let mut materials: Vec<MaterialStats> = gltf
+            .materials()
+            .map(|material| {
+                let pbr = material.pbr_metallic_roughness();
+                let slots = [
+                    ("base_color", pbr.base_color_texture().map(|t| (t.texture().index(), t.tex_coord()))),
+                    ("metallic_roughness", pbr.metallic_roughness_texture().map(|t| (t.texture().index(), t.tex_coord()))),
+                    ("normal", material.normal_texture().map(|t| (t.texture().index(), t.tex_coord()))),
+                    ("occlusion", material.occlusion_texture().map(|t| (t.texture().index(), t.tex_coord()))),
+                    ("emissive", material.emissive_texture().map(|t| (t.texture().index(), t.tex_coord()))),
+                ];
+                MaterialStats {
+                    index: material.index().unwrap_or_default(),
+                    name: material.name().map(str::to_string),
+                    alpha_mode: format!("{:?}", material.alpha_mode()),
+                    double_sided: material.double_sided(),
+                    textures: slots
+                        .into_iter()
+                        .filter_map(|(slot, texture)| {
+                            texture.map(|(texture, tex_coord)| TextureSlot { slot: slot.to_string(), texture, tex_coord })
+                        })
+                        .collect(),
+                    meshes: Vec::new(),
+                }
+            })
+            .collect();
+        for mesh in &meshes {
+            for material in mesh.materials.iter().flatten() {
+                let users = &mut materials[*material].meshes;
+                if !users.contains(&mesh.index) {
+                    users.push(mesh.index);
+                }
+            }
+        }
+
+        let textures = gltf
+            .textures()
+            .map(|texture| {
+                let image = texture.source();
+                let (source, mime_type) = match image.source() {
+                    gltf::image::Source::Uri { uri, mime_type } if uri.starts_with("data:") => {
+                        let media_type = uri["data:".len()..].split([';', ',']).next().filter(|m| !m.is_empty());
+                        ("data URI".to_string(), mime_type.or(media_type).map(str::to_string))
+                    }
+                    gltf::image::Source::Uri { uri, mime_type } => (uri.to_string(), mime_type.map(str::to_string)),
+                    gltf::image::Source::View { view, mime_type } => {
+                        (format!("buffer view #{}", view.index()), Some(mime_type.to_string()))
+                    }
+                };
+                let mut users: Vec<usize> = materials
+                    .iter()
+                    .filter(|material| material.textures.iter().any(|slot| slot.texture == texture.index()))
+                    .map(|material| material.index)
+                    .collect();
+                users.dedup();
+                TextureStats {
+                    index: texture.index(),
+                    name: texture.name().map(str::to_string),
+                    image: image.index(),
+                    source,
+                    mime_type,
+                    materials: users,
+                }
+            })
+            .collect();
+
+        let skins = gltf
+            .skins()
+            .map(|skin| SkinStats {
+                index: skin.index(),
+                name: skin.name().map(str::to_string),
+                joints: skin.joints().count(),
+            })
+            .collect();
+
+        let animations = gltf
+            .animations()
+            .map(|animation| AnimationStats {
+                index: animation.index(),
+                name: animation.name().map(str::to_string),
+                duration: animation
+                    .samplers()
+                    .filter_map(|sampler| sampler.input().max()?.as_array()?.first()?.as_f64())
+                    .fold(0.0, |duration, end| duration.max(end as f32)),
+                channels: animation
+                    .channels()
+                    .map(|channel| ChannelStats {
+                        node: channel.target().node().index(),
+                        path: match channel.target().property() {
+                            gltf::animation::Property::Translation => "translation",
+                            gltf::animation::Property::Rotation => "rotation",
+                            gltf::animation::Property::Scale => "scale",
+                            gltf::animation::Property::MorphTargetWeights => "weights",
+                        }
+                        .to_string(),
+                        interpolation: format!("{:?}", channel.sampler().interpolation()),
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        Ok(ModelStats {
+            scenes: gltf.scenes().count(),
+            nodes: gltf.nodes().count(),
+            vertices: meshes.iter().map(|mesh| mesh.vertices).sum(),
+            triangles: meshes.iter().map(|mesh| mesh.triangles).sum(),
+            bounds: meshes.iter().filter_map(|mesh| mesh.bounds).reduce(|a, b| union(Some(a), b)),
+            meshes,
+            materials,
+            textures,
+            skins,
+            animations,
+            extensions_used: gltf.extensions_used().map(str::to_string).collect(),
+            extensions_required: gltf.extensions_required().map(str::to_string).collect(),
+        })
+    }
+}
+
+fn vertex_count(primitive: &gltf::Primitive) -> usize {
+    primitive.get(&gltf::Semantic::Positions).map_or(0, |positions| positions.count())
+}
+
+fn triangle_count(primitive: &gltf::Primitive) -> usize {
+    let elements = primitive.indices().map_or_else(|| vertex_count(primitive), |indices| indices.count());
+    match primitive.mode() {
+        gltf::mesh::Mode::Triangles => elements / 3,
+        gltf::mesh::Mode::TriangleStrip | gltf::mesh::Mode::TriangleFan => elements.saturating_sub(2),
+        _ => 0,
+    }
+}
+
+/// The POSITION accessor's declared bounds, scaled as `read_positions`
+/// reads normalized integers.
+fn position_bounds(primitive: &gltf::Primitive) -> Option<Bounds> {
+    use gltf::accessor::DataType;
+
+    let accessor = primitive.get(&gltf::Semantic::Positions)?;
+    let vec3 = |value: gltf::json::Value| -> Option<[f32; 3]> {
+        let values = value.as_array()?;
+        let scale = match (accessor.normalized(), accessor.data_type()) {
+            (true, DataType::I8) => 127.0,
+            (true, DataType::U8) => 255.0,
+            (true, DataType::I16) => 32767.0,
+            (true, DataType::U16) => 65535.0,
+            _ => 1.0,
+        };
+        let floor = if accessor.normalized() { -1.0 } else { f64::MIN };
+        Some([values.first()?.as_f64()?, values.get(1)?.as_f64()?, values.get(2)?.as_f64()?]
+            .map(|v| (v / scale).max(floor) as f32))
+    };
+    Some(Bounds { min: vec3(accessor.min()?)?, max: vec3(accessor.max()?)? })
+}
+
+/// Box around the eight corners of `bounds` once transformed.
+fn transform_bounds(transform: [[f32; 4]; 4], bounds: &Bounds) -> Bounds {
+    (0..8)
+        .map(|corner| {
+            let p = std::array::from_fn(|axis| if corner >> axis & 1 == 0 { bounds.min[axis] } else { bounds.max[axis] });
+            let p = transform_point(transform, p);
+            Bounds { min: p, max: p }
+        })
+        .reduce(|a, b| union(Some(a), b))
+        .expect("a box has corners")
+}
+
+fn union(a: Option<Bounds>, b: Bounds) -> Bounds {
+    match a {
+        None => b,
+        Some(a) => Bounds {
+            min: std::array::from_fn(|i| a.min[i].min(b.min[i])),
+            max: std::array::from_fn(|i| a.max[i].max(b.max[i])),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_model_stats() {
+        let gltf = br#"{
+            "asset": {"version": "2.0"},
+            "extensionsUsed": ["KHR_materials_unlit"],
+            "scene": 0,
+            "scenes": [{"nodes": [0, 1, 2]}],
+            "nodes": [
+                {"name": "Left", "mesh": 0, "translation": [-2, 0, 0]},
+                {"name": "Right", "mesh": 0, "translation": [2, 0, 0], "scale": [1, 3, 1]},
+                {"name": "Body", "mesh": 1, "skin": 0, "children": [3]},
+                {"name": "Bone"}
+            ],
+            "meshes": [
+                {"name": "Box", "primitives": [{"attributes": {"POSITION": 0}, "indices": 1, "material": 0}]},
+                {"name": "Strip", "primitives": [{"attributes": {"POSITION": 2}, "mode": 5, "material": 0}, {"attributes": {"POSITION": 2}, "mode": 1}]}
+            ],
+            "materials": [{"name": "Paint", "alphaMode": "MASK", "pbrMetallicRoughness": {"baseColorTexture": {"index": 0}}, "normalTexture": {"index": 0, "texCoord": 1}}],
+            "textures": [{"source": 0}],
+            "images": [{"uri": "paint.png"}],
+            "skins": [{"joints": [2, 3]}],
+            "animations": [{
+                "name": "Wave",
+                "channels": [{"sampler": 0, "target": {"node": 3, "path": "rotation"}}],
+                "samplers": [{"input": 3, "output": 4, "interpolation": "STEP"}]
+            }],
+            "buffers": [{"byteLength": 1024, "uri": "missing.bin"}],
+            "bufferViews": [{"buffer": 0, "byteLength": 1024}],
+            "accessors": [
+                {"bufferView": 0, "componentType": 5126, "count": 8, "type": "VEC3", "min": [-1, -1, -1], "max": [1, 1, 1]},
+                {"bufferView": 0, "componentType": 5123, "count": 36, "type": "SCALAR"},
+                {"bufferView": 0, "componentType": 5126, "count": 6, "type": "VEC3", "min": [0, 0, 0], "max": [1, 5, 0]},
+                {"bufferView": 0, "componentType": 5126, "count": 3, "type": "SCALAR", "min": [0], "max": [1.5]},
+                {"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC4"}
+            ]
+        }"#;
+
+        let stats = Model3DProcessor::new().stats(gltf).unwrap();
+        assert_eq!((stats.vertices, stats.triangles), (20, 16));
+        assert_eq!((stats.meshes[0].triangles, stats.meshes[0].instances), (12, 2));
+        assert_eq!(stats.meshes[0].bounds, Some(Bounds { min: [-3.0, -3.0, -1.0], max: [3.0, 3.0, 1.0] }));
+        assert_eq!((stats.meshes[1].vertices, stats.meshes[1].triangles), (12, 4));
+        assert_eq!(stats.bounds, Some(Bounds { min: [-3.0, -3.0, -1.0], max: [3.0, 5.0, 1.0] }));
+        assert_eq!(stats.meshes[1].materials, vec![Some(0), None]);
+        assert_eq!(stats.materials[0].meshes, vec![0, 1]);
+        let slots: Vec<(&str, u32)> = stats.materials[0].textures.iter().map(|t| (t.slot.as_str(), t.tex_coord)).collect();
+        assert_eq!(slots, vec![("base_color", 0), ("normal", 1)]);
+        assert_eq!((stats.textures[0].source.as_str(), &stats.textures[0].materials), ("paint.png", &vec![0]));
+        assert_eq!(stats.skins[0].joints, 2);
+        assert_eq!(stats.animations[0].duration, 1.5);
+        assert_eq!(stats.animations[0].channels[0].path, "rotation");
+        assert_eq!(stats.extensions_used, vec!["KHR_materials_unlit"]);
+
+        let text = stats.to_string();
+        assert!(text.contains("\"Box\" #0: 1 primitives, 8 vertices, 12 triangles, 2 instances"), "{}", text);
+        assert!(text.contains("node #3 rotation (step)"), "{}", text);
+    }
+}
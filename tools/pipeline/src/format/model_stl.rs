@@ -0,0 +1,193 @@
+use super::model_convert::{face_normal, normalize};
+use super::{ConvertedMesh, ConvertedPrimitive, ExternalFile, FormatProcessor, ImportSettings, Model3DProcessor};
+use crate::{ResourceMetadata, ResourceType};
+use anyhow::{bail, Context, Result};
+
+/// Converts STL meshes, ASCII or binary, to glTF. Facets keep their own
+/// vertices so they stay flat shaded; units and axes are kept as they are.
+#[derive(Default)]
+pub struct StlProcessor;
+
+impl StlProcessor {
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// Parses an STL into a mesh with one normal per facet, taken from its
+    /// winding and falling back to the stored normal for degenerate facets.
+    pub fn convert(&self, data: &[u8]) -> Result<ConvertedMesh> {
+        let (name, facets) = match binary_facet_count(data) {
+            Some(count) => (None, parse_binary(data, count)),
+            None => parse_ascii(data)?,
+        };
+        if facets.is_empty() {
+            bail!("STL has no facets");
+        }
+
+        let mut mesh = ConvertedMesh { name, ..Default::default() };
+        let mut normals = Vec::with_capacity(facets.len() * 3);
+        for (stored, corners) in &facets {
+            let normal = normalize(face_normal(corners[0], corners[1], corners[2]))
+                .or_else(|| normalize(*stored))
+                .unwrap_or([0.0, 0.0, 1.0]);
+            mesh.positions.extend(corners);
+            normals.extend([normal; 3]);
+        }
+        mesh.normals = Some(normals);
+        mesh.primitives = vec![ConvertedPrimitive { material: None, indices: (0..facets.len() as u32 * 3).collect() }];
+        Ok(mesh)
+    }
+}
+
+type Facet = ([f32; 3], [[f32; 3]; 3]);
+
+/// The facet count of a binary STL whose size matches it. ASCII files are
+/// told apart by size, as binary headers may start with `solid` too.
+fn binary_facet_count(data: &[u8]) -> Option<usize> {
+    let count = u32::from_le_bytes(data.get(80..84)?.try_into().ok()?) as usize;
+    (data.len() == 84 + count * 50).then_some(count)
+}
+
+fn parse_binary(data: &[u8], count: usize) -> Vec<Facet> {
+    let float = |at: usize| f32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]);
+    let vector = |at: usize| [float(at), float(at + 4), float(at + 8)];
+    (0..count)
+        .map(|facet| {
+            let at = 84 + facet * 50;
+            (vector(at), [vector(at + 12), vector(at + 24), vector(at + 36)])
+        })
+        .collect()
+}
+
+fn parse_ascii(data: &[u8]) -> Result<(Option<String>, Vec<Facet>)> {
+    let text = std::str::from_utf8(data).with_context(|| "STL is neither binary nor ASCII")?;
+    let first = text.lines().next().unwrap_or_default().trim();
+    let Some(name) = first.strip_prefix("solid") else {
+        bail!("ASCII STL does not start with solid");
+    };
+    let name = Some(name.trim().to_string()).filter(|name| !name.is_empty());
+
+    let mut facets = Vec::new();
+    let mut normal = [0.0; 3];
+    let mut corners = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let mut tokens = line.split_whitespace();
+        let keyword = tokens.next();
+        let vector = |tokens: std::str::SplitWhitespace| -> Result<[f32; 3]> {
+            let values: Vec<f32> = tokens
+                .map(|t| t.parse().with_context(|| format!("Line {}: {} is not a number", number + 1, t)))
+                .collect::<Result<_>>()?;
+            match values[..] {
+                [x, y, z] => Ok([x, y, z]),
+                _ => bail!("Line {}: expected three numbers", number + 1),
+            }
+        };
+        match keyword {
+            Some("facet") => {
+                tokens.next();
+                normal = vector(tokens)?;
+                corners.clear();
+            }
+            Some("vertex") => corners.push(vector(tokens)?),
+            Some("endfacet") => match corners[..] {
+                [a, b, c] => facets.push((normal, [a, b, c])),
+                _ => bail!("Line {}: a facet has {} vertices instead of three", number + 1, corners.len()),
+            },
+            _ => {}
+        }
+    }
+    Ok((name, facets))
+}
+
+impl FormatProcessor for StlProcessor {
+    fn name(&self) -> &'static str {
+        "stl"
+    }
+
+    fn resource_type(&self) -> ResourceType {
+        ResourceType::Model3D
+    }
+
+    fn extensions(&self) -> &[&'static str] {
+        &["stl"]
+    }
+
+    fn mime_types(&self) -> &[&'static str] {
+        &["model/stl"]
+    }
+
+    /// ASCII STL only; binary STL has no signature.
+    fn matches_magic(&self, data: &[u8]) -> bool {
+        data.starts_with(b"solid") && binary_facet_count(data).is_none() && {
+            let head = &data[..data.len().min(1024)];
+            head.windows(5).any(|w| w == b"facet")
+        }
+    }
+
+    // 2: GLBs written with the shared glTF helpers
+    fn version(&self) -> u32 {
+        2
+    }
+
+    fn process(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.process_with(data, &ImportSettings::default(), &[])
+    }
+
+    fn process_with(&self, data: &[u8], settings: &ImportSettings, files: &[ExternalFile]) -> Result<Vec<u8>> {
+        Model3DProcessor::new().process_with(&self.convert(data)?.into_glb()?, settings, files)
+    }
+
+    /// Describes the converted model.
+    fn get_metadata(&self, data: &[u8]) -> Result<ResourceMetadata> {
+        Model3DProcessor::new().get_metadata(data)
+    }
+
+    fn validate(&self, data: &[u8]) -> Result<bool> {
+        self.convert(data)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::FormatRegistry;
+    use std::path::Path;
+
+    #[test]
+    fn test_stl_import() {
+        let ascii = b"solid wedge\n\
+            facet normal 0 0 0\n outer loop\n  vertex 0 0 0\n  vertex 1 0 0\n  vertex 0 1 0\n endloop\nendfacet\n\
+            facet normal 0 -1 0\n outer loop\n  vertex 0 0 0\n  vertex 1 0 0\n  vertex 0 0 1\n endloop\nendfacet\n\
+            endsolid wedge\n";
+        let mut binary = vec![0u8; 80];
+        binary[..5].copy_from_slice(b"solid");
+        binary.extend_from_slice(&2u32.to_le_bytes());
+        for facet in [[[0.0f32; 3], [0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]], [
+            [0.0, -1.0, 0.0],
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0],
+        ]] {
+            binary.extend(facet.iter().flatten().flat_map(|c| c.to_le_bytes()));
+            binary.extend_from_slice(&[0, 0]);
+        }
+
+        let processor = StlProcessor::new();
+        let from_ascii = processor.convert(ascii).unwrap();
+        let from_binary = processor.convert(&binary).unwrap();
+        assert_eq!(from_ascii.name.as_deref(), Some("wedge"));
+        assert_eq!(from_ascii.positions, from_binary.positions);
+        let normals = from_binary.normals.unwrap();
+        assert_eq!((normals[0], normals[3]), ([0.0, 0.0, 1.0], [0.0, -1.0, 0.0]));
+
+        let registry = FormatRegistry::with_defaults();
+        assert_eq!(registry.detect(None, ascii).unwrap().name(), "stl");
+        assert!(registry.detect(None, &binary).is_none());
+        let imported =
+            registry.import(Some(Path::new("wedge.stl")), &binary, None, &ImportSettings::default()).unwrap();
+        let stats = Model3DProcessor::new().stats(&imported.data).unwrap();
+        assert_eq!((stats.vertices, stats.triangles), (6, 2));
+        assert!(processor.convert(b"solid empty\nendsolid empty\n").is_err());
+    }
+}
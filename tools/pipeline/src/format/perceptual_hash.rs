@@ -0,0 +1,177 @@
+use super::TextureProcessor;
+use crate::PerceptualHashes;
+use anyhow::{bail, Result};
+use image::imageops::{self, FilterType};
+use image::{ImageBuffer, Luma, RgbaImage};
+use std::f32::consts::PI;
+
+/// Side of the thumbnail the DCT of the perceptual hash runs over.
+const DCT_SIZE: u32 = 32;
+
+/// Low-frequency coefficients kept along each axis.
+const DCT_KEPT: usize = 8;
+
+type LumaImage = ImageBuffer<Luma<f32>, Vec<f32>>;
+
+impl PerceptualHashes {
+    /// Bits in which the hashes differ, over all three.
+    pub fn distance(&self, other: &PerceptualHashes) -> u32 {
+        (self.average ^ other.average).count_ones()
+            + (self.perceptual ^ other.perceptual).count_ones()
+            + (self.difference ^ other.difference).count_ones()
+    }
+
+    /// 1 for identical hashes, falling towards 0 as they differ; unrelated
+    /// images land around 0.5.
+    pub fn similarity(&self, other: &PerceptualHashes) -> f32 {
+        1.0 - self.distance(other) as f32 / 192.0
+    }
+}
+
+impl TextureProcessor {
+    /// Perceptual hashes of any texture [`display_image`](Self::display_image)
+    /// can show.
+    pub fn perceptual_hashes(&self, data: &[u8]) -> Result<PerceptualHashes> {
+        let Some(image) = self.display_image(data, DCT_SIZE)? else {
+            bail!("Texture has blocks that cannot be decoded");
+        };
+        Ok(perceptual_hashes(&image))
+    }
+}
+
+/// Hashes the luminance of `image` composited over black, so straight and
+/// premultiplied copies of a texture hash alike.
+pub fn perceptual_hashes(image: &RgbaImage) -> PerceptualHashes {
+    let luma = LumaImage::from_fn(image.width(), image.height(), |x, y| {
+        let p = image.get_pixel(x, y);
+        // Resizing clamps floats to 0 to 1
+        let luma = (0.299 * p[0] as f32 + 0.587 * p[1] as f32 + 0.114 * p[2] as f32) / 255.0;
+        Luma([luma * p[3] as f32 / 255.0])
+    });
+
+    PerceptualHashes {
+        average: average_hash(&luma),
+        perceptual: dct_hash(&luma),
+        difference: difference_hash(&luma),
+    }
+}
+
+/// Packs flags into bits, the first flag in the lowest bit.
+fn to_bits(values: impl Iterator<Item = bool>) -> u64 {
+    values.enumerate().fold(0, |bits, (i, set)| bits | (set as u64) << i)
+}
+
+fn average_hash(luma: &LumaImage) -> u64 {
+    let small = imageops::resize(luma, 8, 8, FilterType::Triangle);
+    let mean = small.pixels().map(|p| p[0]).sum::<f32>() / 64.0;
+    to_bits(small.pixels().map(|p| p[0] > mean))
+}
+
+fn difference_hash(luma: &LumaImage) -> u64 {
+    let small = imageops::resize(luma, 9, 8, FilterType::Triangle);
+    to_bits((0..64).map(|i| {
+        let (x, y) = (i % 8, i / 8);
+        small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0]
+    }))
+}
+
+fn dct_hash(luma: &LumaImage) -> u64 {
+    let small = imageops::resize(luma, DCT_SIZE, DCT_SIZE, FilterType::Triangle);
+    let n = DCT_SIZE as usize;
+    let basis: Vec<Vec<f32>> = (0..DCT_KEPT)
+        .map(|k| (0..n).map(|i| (PI * (2 * i + 1) as f32 * k as f32 / (2 * n) as f32).cos()).collect())
+        .collect();
+
+    // Separable 2D DCT-II, only the low frequencies kept
+    let rows: Vec<[f32; DCT_KEPT]> = (0..n)
+        .map(|y| std::array::from_fn(|u| (0..n).map(|x| small.get_pixel(x as u32, y as u32)[0] * basis[u][x]).sum()))
+        .collect();
+    let coefficients: Vec<f32> = (0..DCT_KEPT * DCT_KEPT)
+        .map(|i| {
+            let (u, v) = (i % DCT_KEPT, i / DCT_KEPT);
+            (0..n).map(|y| rows[y][u] * basis[v][y]).sum()
+        })
+        .collect();
+
+    let mut sorted = coefficients.clone();
+    sorted.sort_by(f32::total_cmp);
+    let median = (sorted[31] + sorted[32]) / 2.0;
+    to_bits(coefficients.iter().map(|&c| c > median))
+}
+
+/// Groups items whose hashes are at least `threshold` similar, linking
+/// chains of similar items into one group. Only groups of two or more are
+/// returned, each in input order and the groups by their first item.
+pub fn cluster_similar(hashes: &[PerceptualHashes], threshold: f32) -> Vec<Vec<usize>> {
+    let mut parent: Vec<usize> = (0..hashes.len()).collect();
+    fn root(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+
+    for a in 0..hashes.len() {
+        for b in a + 1..hashes.len() {
+            if hashes[a].similarity(&hashes[b]) >= threshold {
+                let (ra, rb) = (root(&mut parent, a), root(&mut parent, b));
+                parent[ra.max(rb)] = ra.min(rb);
+            }
+        }
+    }
+
+    let mut clusters: Vec<Vec<usize>> = Vec::new();
+    let mut cluster_of_root = vec![usize::MAX; hashes.len()];
+    for i in 0..hashes.len() {
+        let r = root(&mut parent, i);
+        if cluster_of_root[r] == usize::MAX {
+            cluster_of_root[r] = clusters.len();
+            clusters.push(Vec::new());
+        }
+        clusters[cluster_of_root[r]].push(i);
+    }
+    clusters.retain(|cluster| cluster.len() > 1);
+    clusters
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Pixel, Rgba};
+
+    #[test]
+    fn test_near_duplicates_cluster() {
+        let pattern = |x: u32, y: u32| {
+            let v = ((x as f32 / 9.0).sin() * (y as f32 / 13.0).cos() * 100.0 + 128.0) as u8;
+            Rgba([v, v / 2, 255 - v, 255])
+        };
+        let original = RgbaImage::from_fn(200, 160, pattern);
+        // Re-exported smaller, slightly brightened and as JPEG
+        let resized = imageops::resize(&original, 150, 120, FilterType::CatmullRom);
+        let brighter = RgbaImage::from_fn(200, 160, |x, y| pattern(x, y).map_without_alpha(|c| c.saturating_add(6)));
+        let mut jpeg = Vec::new();
+        image::DynamicImage::ImageRgba8(original.clone())
+            .to_rgb8()
+            .write_to(&mut std::io::Cursor::new(&mut jpeg), image::ImageFormat::Jpeg)
+            .unwrap();
+        let recompressed = image::load_from_memory(&jpeg).unwrap().to_rgba8();
+        let other = RgbaImage::from_fn(200, 160, |x, y| {
+            let v = if (x / 20 + y / 20) % 2 == 0 { 230 } else { 20 };
+            Rgba([v, v, v, 255])
+        });
+
+        let hashes: Vec<PerceptualHashes> =
+            [&original, &other, &resized, &brighter, &recompressed].map(perceptual_hashes).to_vec();
+        for copy in &hashes[2..] {
+            assert!(hashes[0].similarity(copy) > 0.9, "{}", hashes[0].similarity(copy));
+        }
+        assert!(hashes[0].similarity(&hashes[1]) < 0.75, "{}", hashes[0].similarity(&hashes[1]));
+        assert_eq!(cluster_similar(&hashes, 0.9), vec![vec![0, 2, 3, 4]]);
+
+        // Hex in JSON, as 64-bit numbers do not survive JavaScript
+        let json = serde_json::to_value(hashes[0]).unwrap();
+        assert_eq!(json["average"].as_str().unwrap().len(), 16);
+        assert_eq!(serde_json::from_value::<PerceptualHashes>(json).unwrap(), hashes[0]);
+    }
+}
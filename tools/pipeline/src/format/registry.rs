@@ -0,0 +1,332 @@
+use super::{
+    AudioProcessor, ExternalFile, FormatProcessor, ImportSettings, Model3DProcessor, ObjProcessor, PlyProcessor, StlProcessor,
+    TextureProcessor,
+};
+use crate::{Provenance, ResourceMetadata, ResourceProperties, ResourceType, METADATA_SCHEMA_VERSION};
+use anyhow::{bail, Result};
+use std::path::Path;
+
+/// Result of running a file through its processor, ready to be stored.
+#[derive(Debug, Clone)]
+pub struct ImportedResource {
+    pub metadata: ResourceMetadata,
+    pub provenance: Provenance,
+    pub data: Vec<u8>,
+    /// Files the resource refers to, each also one of its dependencies.
+    pub external: Vec<ExternalFile>,
+}
+
+
+/// Maps extensions, MIME types and magic bytes to format processors.
+///
+/// Processors registered later take precedence, so library users can override
+/// the built-in handling of a format by registering their own processor.
+pub struct FormatRegistry {
+    processors: Vec<Box<dyn FormatProcessor + Send + Sync>>,
+}
+
+impl Default for FormatRegistry {
+    fn default() -> Self {
+        Self::with_defaults()
+    }
+}
+
+impl FormatRegistry {
+    /// An empty registry with no processors.
+    pub fn new() -> Self {
+        Self {
+            processors: Vec::new(),
+        }
+    }
+
+    /// A registry with the texture, model and audio processors. The OBJ,
+    /// STL and PLY converters come before the glTF processor, which handles
+    /// their stored output.
+    pub fn with_defaults() -> Self {
+        let mut registry = Self::new();
+        registry.register(TextureProcessor::new());
+        registry.register(ObjProcessor::new());
+        registry.register(StlProcessor::new());
+        registry.register(PlyProcessor::new());
+        registry.register(Model3DProcessor::new());
+        registry.register(AudioProcessor::new());
+        registry
+    }
+
+    pub fn register(&mut self, processor: impl FormatProcessor + Send + Sync + 'static) {
+        self.processors.push(Box::new(processor));
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &(dyn FormatProcessor + Send + Sync)> {
+        self.processors.iter().rev().map(|p| p.as_ref())
+    }
+
+    pub fn by_extension(&self, extension: &str) -> Option<&(dyn FormatProcessor + Send + Sync)> {
+        let extension = extension.trim_start_matches('.').to_ascii_lowercase();
+        self.iter().find(|p| p.extensions().contains(&extension.as_str()))
+    }
+
+    pub fn by_mime_type(&self, mime_type: &str) -> Option<&(dyn FormatProcessor + Send + Sync)> {
+        let mime_type = mime_type.to_ascii_lowercase();
+        self.iter().find(|p| p.mime_types().contains(&mime_type.as_str()))
+    }
+
+    pub fn by_magic(&self, data: &[u8]) -> Option<&(dyn FormatProcessor + Send + Sync)> {
+        self.iter().find(|p| p.matches_magic(data))
+    }
+
+    pub fn by_resource_type(&self, resource_type: &ResourceType) -> Option<&(dyn FormatProcessor + Send + Sync)> {
+        self.iter().find(|p| &p.resource_type() == resource_type)
+    }
+
+    /// Picks a processor from the data's magic bytes, falling back to the
+    /// file extension for formats without a reliable signature.
+    pub fn detect(&self, path: Option<&Path>, data: &[u8]) -> Option<&(dyn FormatProcessor + Send + Sync)> {
+        self.by_magic(data).or_else(|| {
+            path.and_then(|p| p.extension())
+                .and_then(|ext| ext.to_str())
+                .and_then(|ext| self.by_extension(ext))
+        })
+    }
+
+    fn resolve(
+        &self,
+        path: Option<&Path>,
+        data: &[u8],
+        resource_type: Option<&ResourceType>,
+    ) -> Option<&(dyn FormatProcessor + Send + Sync)> {
+        match resource_type {
+            Some(resource_type) => self.by_resource_type(resource_type),
+            None => self.detect(path, data),
+        }
+    }
+
+    /// Name of the processor [`import`](Self::import) would use, as recorded
+    /// in provenance.
+    pub fn importer(&self, path: Option<&Path>, data: &[u8], resource_type: Option<&ResourceType>) -> &'static str {
+        self.resolve(path, data, resource_type).map_or("binary", |processor| processor.name())
+    }
+
+    /// Loads the files `data` refers to relative to `path`, for
+    /// [`cache_key`](Self::cache_key) and
+    /// [`import_with_dependencies`](Self::import_with_dependencies) to share.
+    pub fn dependencies(
+        &self,
+        path: Option<&Path>,
+        data: &[u8],
+        resource_type: Option<&ResourceType>,
+    ) -> Result<Vec<ExternalFile>> {
+        match self.resolve(path, data, resource_type) {
+            Some(processor) => Self::extract_dependencies(processor, path, data),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Identifies the result of [`import`](Self::import) before running it:
+    /// the source content, the processor and its version, the settings
+    /// section that applies and the files from
+    /// [`dependencies`](Self::dependencies). Equal keys give equal imports.
+    pub fn cache_key(
+        &self,
+        path: Option<&Path>,
+        data: &[u8],
+        resource_type: Option<ResourceType>,
+        settings: &ImportSettings,
+        external: &[ExternalFile],
+    ) -> String {
+        let processor = self.resolve(path, data, resource_type.as_ref());
+        let (name, version, resource_type) = match processor {
+            Some(p) => (p.name(), p.version(), p.resource_type()),
+            None => ("binary", 0, ResourceType::Binary),
+        };
+
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(blake3::hash(data).as_bytes());
+        hasher.update(name.as_bytes());
+        hasher.update(&version.to_le_bytes());
+        hasher.update(format!("{:?}", resource_type).as_bytes());
+        hasher.update(settings.section_for(&resource_type).to_string().as_bytes());
+        for file in external {
+            hasher.update(blake3::hash(&file.data).as_bytes());
+        }
+        hasher.finalize().to_string()
+    }
+
+    fn extract_dependencies(
+        processor: &(dyn FormatProcessor + Send + Sync),
+        path: Option<&Path>,
+        data: &[u8],
+    ) -> Result<Vec<ExternalFile>> {
+        match processor.dependency_extractor() {
+            Some(extractor) => extractor.extract_dependencies(data, path.and_then(Path::parent)),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Describes a file another resource refers to without processing it,
+    /// since the referring resource depends on exactly its content. Files
+    /// their processor rejects are imported as [`ResourceType::Binary`].
+    pub fn import_dependency(&self, file: &ExternalFile) -> Result<ImportedResource> {
+        let processor = self
+            .by_resource_type(&file.resource_type)
+            .filter(|processor| processor.validate(&file.data).unwrap_or(false));
+        let Some(processor) = processor else {
+            return self.import(Some(&file.path), &file.data, Some(ResourceType::Binary), &ImportSettings::default());
+        };
+
+        Ok(ImportedResource {
+            metadata: processor.get_metadata(&file.data)?,
+            provenance: Provenance::now(processor.name()),
+            data: file.data.clone(),
+            external: Vec::new(),
+        })
+    }
+
+    /// Validates, processes and describes `data`, loading the files it
+    /// refers to relative to `path`.
+    ///
+    /// An explicit `resource_type` overrides detection. Data no processor
+    /// claims is imported unchanged as [`ResourceType::Binary`].
+    pub fn import(
+        &self,
+        path: Option<&Path>,
+        data: &[u8],
+        resource_type: Option<ResourceType>,
+        settings: &ImportSettings,
+    ) -> Result<ImportedResource> {
+        self.import_resolved(path, data, resource_type, settings, None)
+    }
+
+    /// Like [`import`](Self::import), with the files `data` refers to already
+    /// loaded by [`dependencies`](Self::dependencies).
+    pub fn import_with_dependencies(
+        &self,
+        path: Option<&Path>,
+        data: &[u8],
+        resource_type: Option<ResourceType>,
+        settings: &ImportSettings,
+        external: Vec<ExternalFile>,
+    ) -> Result<ImportedResource> {
+        self.import_resolved(path, data, resource_type, settings, Some(external))
+    }
+
+    fn import_resolved(
+        &self,
+        path: Option<&Path>,
+        data: &[u8],
+        resource_type: Option<ResourceType>,
+        settings: &ImportSettings,
+        external: Option<Vec<ExternalFile>>,
+    ) -> Result<ImportedResource> {
+        let processor = self.resolve(path, data, resource_type.as_ref());
+
+        let Some(processor) = processor else {
+            if let Some(resource_type) = resource_type.filter(|t| t != &ResourceType::Binary) {
+                bail!("No processor registered for {:?}", resource_type);
+            }
+            return Ok(ImportedResource {
+                metadata: ResourceMetadata {
+                    schema_version: METADATA_SCHEMA_VERSION,
+                    hash: blake3::hash(data).to_string(),
+                    resource_type: ResourceType::Binary,
+                    size: data.len() as u64,
+                    dependencies: Vec::new(),
+                    properties: ResourceProperties::None,
+                },
+                provenance: Provenance::now("binary"),
+                data: data.to_vec(),
+                external: Vec::new(),
+            });
+        };
+
+        if !processor.validate(data)? {
+            bail!("Data is not a valid {:?} resource", processor.resource_type());
+        }
+        let external = match external {
+            Some(external) => external,
+            None => Self::extract_dependencies(processor, path, data)?,
+        };
+
+        let processed = processor.process_with(data, settings, &external)?;
+        let mut metadata = processor.get_metadata(&processed)?;
+        for file in &external {
+            let hash = blake3::hash(&file.data).to_string();
+            if !metadata.dependencies.contains(&hash) {
+                metadata.dependencies.push(hash);
+            }
+        }
+
+        Ok(ImportedResource {
+            metadata,
+            provenance: Provenance::now(processor.name()),
+            data: processed,
+            external,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TextProcessor;
+
+    impl FormatProcessor for TextProcessor {
+        fn resource_type(&self) -> ResourceType {
+            ResourceType::Binary
+        }
+
+        fn extensions(&self) -> &[&'static str] {
+            &["txt"]
+        }
+
+        fn process(&self, data: &[u8]) -> Result<Vec<u8>> {
+            Ok(data.to_ascii_uppercase())
+        }
+
+        fn get_metadata(&self, data: &[u8]) -> Result<ResourceMetadata> {
+            Ok(ResourceMetadata {
+                schema_version: METADATA_SCHEMA_VERSION,
+                hash: blake3::hash(data).to_string(),
+                resource_type: ResourceType::Binary,
+                size: data.len() as u64,
+                dependencies: Vec::new(),
+                properties: ResourceProperties::None,
+            })
+        }
+
+        fn validate(&self, data: &[u8]) -> Result<bool> {
+            Ok(data.is_ascii())
+        }
+    }
+
+    #[test]
+    fn test_detection_and_custom_processors() {
+        let mut registry = FormatRegistry::with_defaults();
+        let gltf = br#"{"asset": {"version": "2.0"}}"#;
+
+        let model = registry.detect(Some(Path::new("scene.bin")), gltf).unwrap();
+        assert_eq!(model.resource_type(), ResourceType::Model3D);
+        assert_eq!(registry.by_extension(".WAV").unwrap().resource_type(), ResourceType::Audio);
+        assert_eq!(registry.by_mime_type("image/png").unwrap().resource_type(), ResourceType::Texture);
+
+        let imported = registry.import(Some(Path::new("notes.txt")), b"hello", None, &ImportSettings::default()).unwrap();
+        assert_eq!(imported.metadata.resource_type, ResourceType::Binary);
+        assert_eq!(imported.data, b"hello");
+
+        registry.register(TextProcessor);
+        let imported = registry.import(Some(Path::new("notes.txt")), b"hello", None, &ImportSettings::default()).unwrap();
+        assert_eq!(imported.data, b"HELLO");
+        assert_eq!(imported.metadata.hash, blake3::hash(b"HELLO").to_string());
+
+        let png = Path::new("a.png");
+        let key = registry.cache_key(Some(png), b"\x89PNG", None, &ImportSettings::default(), &[]);
+        let resized = ImportSettings {
+            texture: serde_json::from_str(r#"{"max_size": 64}"#).unwrap(),
+            ..Default::default()
+        };
+        assert_ne!(key, registry.cache_key(Some(png), b"\x89PNG", None, &resized, &[]));
+        let audio = ImportSettings { audio: serde_json::from_str(r#"{"format": "flac"}"#).unwrap(), ..Default::default() };
+        assert_eq!(key, registry.cache_key(Some(png), b"\x89PNG", None, &audio, &[]));
+    }
+}
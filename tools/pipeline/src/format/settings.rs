@@ -0,0 +1,215 @@
+use super::{AudioTranscodeSettings, ModelImportSettings, TextureImportSettings};
+use crate::ResourceType;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Suffix of the per-asset settings file, e.g. `music.wav.import.json`.
+pub const SIDECAR_SUFFIX: &str = ".import.json";
+
+/// Name of the per-directory settings file, which applies to every asset in
+/// its directory and below unless a nearer file overrides it.
+pub const DIRECTORY_SETTINGS_FILE: &str = ".pipeline-import.json";
+
+/// Repository configuration file inside the storage directory.
+pub const CONFIG_FILE: &str = "config.json";
+
+/// How an asset is cooked on import. Each section is optional; a missing
+/// section means the processor's default treatment.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ImportSettings {
+    #[serde(default)]
+    pub audio: Option<AudioTranscodeSettings>,
+    #[serde(default)]
+    pub texture: Option<TextureImportSettings>,
+    #[serde(default)]
+    pub model: Option<ModelImportSettings>,
+}
+
+impl ImportSettings {
+    /// Fills fields missing from `self` with those from `fallback`, section
+    /// by section.
+    pub fn or(self, fallback: ImportSettings) -> ImportSettings {
+        fn merge<T>(section: Option<T>, fallback: Option<T>, or: fn(T, T) -> T) -> Option<T> {
+            match (section, fallback) {
+                (Some(section), Some(fallback)) => Some(or(section, fallback)),
+                (section, fallback) => section.or(fallback),
+            }
+        }
+        ImportSettings {
+            audio: merge(self.audio, fallback.audio, AudioTranscodeSettings::or),
+            texture: merge(self.texture, fallback.texture, TextureImportSettings::or),
+            model: merge(self.model, fallback.model, ModelImportSettings::or),
+        }
+    }
+
+    /// The section that applies to `resource_type` as JSON, or `null` when it
+    /// is not set. Part of the import cache key.
+    pub fn section_for(&self, resource_type: &ResourceType) -> serde_json::Value {
+        let section = match resource_type {
+            ResourceType::Audio => serde_json::to_value(&self.audio),
+            ResourceType::Texture => serde_json::to_value(&self.texture),
+            ResourceType::Model3D => serde_json::to_value(&self.model),
+            _ => Ok(serde_json::Value::Null),
+        };
+        section.unwrap_or_default()
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read import settings: {}", path.display()))?;
+        serde_json::from_str(&text)
+            .with_context(|| format!("Invalid import settings: {}", path.display()))
+    }
+
+    pub fn sidecar_path(asset: &Path) -> PathBuf {
+        let mut name = asset.file_name().unwrap_or_default().to_os_string();
+        name.push(SIDECAR_SUFFIX);
+        asset.with_file_name(name)
+    }
+
+    /// Settings for an asset: its sidecar file, then the directory settings
+    /// files from the asset's directory upwards, nearest first, then the
+    /// repository config's rules.
+    pub fn for_asset(asset: &Path, config: &ImportConfig) -> Result<Self> {
+        let mut settings = ImportSettings::default();
+
+        let sidecar = Self::sidecar_path(asset);
+        if sidecar.is_file() {
+            settings = Self::load(&sidecar)?;
+        }
+
+        for dir in asset.ancestors().skip(1) {
+            let path = dir.join(DIRECTORY_SETTINGS_FILE);
+            if path.is_file() {
+                settings = settings.or(Self::load(&path)?);
+            }
+        }
+
+        Ok(settings.or(config.settings_for(asset)?))
+    }
+}
+
+/// Import settings for every asset whose path matches `pattern`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImportRule {
+    /// Glob relative to the repository root. `*` and `?` stay within one path
+    /// component and `**` spans any number of them; a pattern without `/`
+    /// matches file names at any depth.
+    pub pattern: String,
+    #[serde(flatten)]
+    pub settings: ImportSettings,
+}
+
+/// The repository's `config.json`, next to its objects.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ImportConfig {
+    /// Directory that rule patterns are relative to.
+    #[serde(skip)]
+    pub root: PathBuf,
+    /// Later rules take precedence over earlier ones.
+    #[serde(default)]
+    pub import: Vec<ImportRule>,
+}
+
+impl ImportConfig {
+    /// Reads `config.json` from the storage directory. Patterns are relative
+    /// to the directory holding the storage directory. A missing file means
+    /// no rules.
+    pub fn load(storage_path: &Path) -> Result<Self> {
+        let path = storage_path.join(CONFIG_FILE);
+        let mut config = if path.is_file() {
+            let text = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read config: {}", path.display()))?;
+            serde_json::from_str(&text).with_context(|| format!("Invalid config: {}", path.display()))?
+        } else {
+            ImportConfig::default()
+        };
+        config.root = match storage_path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        Ok(config)
+    }
+
+    /// Merged settings of every rule matching `asset`, last rule first.
+    pub fn settings_for(&self, asset: &Path) -> Result<ImportSettings> {
+        let root = std::path::absolute(&self.root)?;
+        let asset = std::path::absolute(asset)?;
+        let Ok(relative) = asset.strip_prefix(&root) else {
+            return Ok(ImportSettings::default());
+        };
+        let relative = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let name = relative.rsplit('/').next().unwrap_or_default();
+
+        Ok(self
+            .import
+            .iter()
+            .rev()
+            .filter(|rule| match rule.pattern.contains('/') {
+                true => glob_match(rule.pattern.trim_start_matches("./").as_bytes(), relative.as_bytes()),
+                false => glob_match(rule.pattern.as_bytes(), name.as_bytes()),
+            })
+            .fold(ImportSettings::default(), |settings, rule| settings.or(rule.settings.clone())))
+    }
+}
+
+fn glob_match(pattern: &[u8], path: &[u8]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        [b'*', b'*', b'/', rest @ ..] => {
+            (0..=path.len()).any(|i| (i == 0 || path[i - 1] == b'/') && glob_match(rest, &path[i..]))
+        }
+        [b'*', b'*', rest @ ..] => (0..=path.len()).any(|i| glob_match(rest, &path[i..])),
+        [b'*', rest @ ..] => (0..=path.len())
+            .take_while(|&i| i == 0 || path[i - 1] != b'/')
+            .any(|i| glob_match(rest, &path[i..])),
+        [b'?', rest @ ..] => matches!(path, [c, tail @ ..] if *c != b'/' && glob_match(rest, tail)),
+        [c, rest @ ..] => matches!(path, [p, tail @ ..] if p == c && glob_match(rest, tail)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{BlockFormat, TextureContainer};
+
+    #[test]
+    fn test_config_rules() {
+        let config: ImportConfig = serde_json::from_str(
+            r#"{"import": [
+                {"pattern": "*.png", "texture": {"max_size": 512}},
+                {"pattern": "textures/**/normals/*_n.png", "texture": {"container": "ktx2", "format": "bc5", "normal_map": true}}
+            ]}"#,
+        )
+        .unwrap();
+        let config = ImportConfig { root: PathBuf::from("/project"), ..config };
+
+        let settings = config.settings_for(Path::new("/project/textures/rock/normals/rock_n.png")).unwrap();
+        let texture = settings.texture.unwrap();
+        assert_eq!((texture.container, texture.format()), (Some(TextureContainer::Ktx2), BlockFormat::Bc5));
+        assert!(texture.normal_map() && texture.mipmaps());
+        // Both rules match, and the later one only adds fields
+        assert_eq!(texture.max_size, Some(512));
+
+        let settings = config.settings_for(Path::new("/project/ui/icons/close.png")).unwrap();
+        assert_eq!(settings.texture.unwrap().max_size, Some(512));
+
+        // A sidecar setting one field keeps the rest from the directory
+        let sidecar: ImportSettings = serde_json::from_str(r#"{"texture": {"mipmaps": false}}"#).unwrap();
+        let directory: ImportSettings = serde_json::from_str(r#"{"texture": {"format": "bc7", "max_size": 256}}"#).unwrap();
+        let texture = sidecar.or(directory).texture.unwrap();
+        assert_eq!((texture.format(), texture.max_size, texture.mipmaps()), (BlockFormat::Bc7, Some(256), false));
+        assert_eq!(config.settings_for(Path::new("/project/music.wav")).unwrap(), ImportSettings::default());
+        assert_eq!(config.settings_for(Path::new("/elsewhere/a.png")).unwrap(), ImportSettings::default());
+
+        assert!(glob_match(b"a/**/b", b"a/b"));
+        assert!(glob_match(b"a/**/b", b"a/x/y/b"));
+        assert!(!glob_match(b"a/*", b"a/x/y"));
+        assert!(glob_match(b"tex?.png", b"tex1.png"));
+    }
+}
@@ -1,9 +1,161 @@
-use crate::ResourceMetadata;
-use crate::ResourceType;
-use anyhow::{Context, Result};
-use image::ImageFormat;
-use std::time::{SystemTime, UNIX_EPOCH};
+use super::{
+    apply_alpha_mode, apply_float_alpha_mode, build_float_mip_chain, build_mip_chain, compress, compress_float,
+    decode_hdr_layers, decode_linear, decompress, decompress_float, dynamic_range, encode_exr, fit_thumbnail, is_hdr,
+    perceptual_hashes, read_container_info, resize_float, resize_linear, tonemap, AlphaMode, BlockFormat, ExternalFile,
+    ImportSettings, MipOptions, TextureContainer, ThumbnailOptions, TonemapOptions, UnsupportedBlockMode,
+};
+use crate::{ColorSpace, ResourceMetadata, ResourceProperties, ResourceType, TextureProperties, METADATA_SCHEMA_VERSION};
+use anyhow::{bail, Context, Result};
+use image::{DynamicImage, GrayImage, ImageFormat, Rgb, RgbImage, RgbaImage};
+use serde::{Deserialize, Serialize};
 
+/// Side of the square window used for SSIM, and the stride between windows.
+const SSIM_WINDOW: u32 = 8;
+const SSIM_STRIDE: u32 = 4;
+
+/// Per-asset texture import settings. Without a container the texture is
+/// stored as PNG, after the resolution cap and alpha mode are applied.
+/// Unset fields take their default, or the value of a fallback section
+/// merged in with [`or`](Self::or).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TextureImportSettings {
+    /// GPU container to cook into, or none for PNG.
+    #[serde(default)]
+    pub container: Option<TextureContainer>,
+    /// Block compression inside the container.
+    #[serde(default)]
+    pub format: Option<BlockFormat>,
+    /// Embed the full mip chain down to 1x1. On by default.
+    #[serde(default)]
+    pub mipmaps: Option<bool>,
+    /// How the pixel values are encoded. Defaults to sRGB for colour formats
+    /// and linear for normal maps, BC4 and BC5, which have no sRGB variants.
+    #[serde(default)]
+    pub color_space: Option<ColorSpace>,
+    #[serde(default)]
+    pub alpha_mode: Option<AlphaMode>,
+    /// Alpha-test threshold for cutout textures, preserved across mips.
+    #[serde(default)]
+    pub alpha_cutoff: Option<f32>,
+    #[serde(default)]
+    pub normal_map: Option<bool>,
+    /// Largest allowed width or height; bigger textures are scaled down,
+    /// keeping their aspect ratio.
+    #[serde(default)]
+    pub max_size: Option<u32>,
+    /// Layer of a multi-layer OpenEXR image to import; the first by default.
+    #[serde(default)]
+    pub layer: Option<String>,
+}
+
+impl TextureImportSettings {
+    /// Fills fields missing from `self` with those from `fallback`.
+    pub fn or(self, fallback: TextureImportSettings) -> TextureImportSettings {
+        TextureImportSettings {
+            container: self.container.or(fallback.container),
+            format: self.format.or(fallback.format),
+            mipmaps: self.mipmaps.or(fallback.mipmaps),
+            color_space: self.color_space.or(fallback.color_space),
+            alpha_mode: self.alpha_mode.or(fallback.alpha_mode),
+            alpha_cutoff: self.alpha_cutoff.or(fallback.alpha_cutoff),
+            normal_map: self.normal_map.or(fallback.normal_map),
+            max_size: self.max_size.or(fallback.max_size),
+            layer: self.layer.or(fallback.layer),
+        }
+    }
+
+    pub fn format(&self) -> BlockFormat {
+        self.format.unwrap_or_default()
+    }
+
+    pub fn mipmaps(&self) -> bool {
+        self.mipmaps.unwrap_or(true)
+    }
+
+    pub fn alpha_mode(&self) -> AlphaMode {
+        self.alpha_mode.unwrap_or_default()
+    }
+
+    pub fn normal_map(&self) -> bool {
+        self.normal_map.unwrap_or(false)
+    }
+
+    pub fn mip_options(&self) -> MipOptions {
+        let linear = self.normal_map() || !self.format().supports_srgb();
+        MipOptions {
+            color_space: self
+                .color_space
+                .unwrap_or(if linear { ColorSpace::Linear } else { ColorSpace::Srgb }),
+            alpha_mode: self.alpha_mode(),
+            alpha_cutoff: self.alpha_cutoff,
+            normal_map: self.normal_map(),
+        }
+    }
+
+    /// Size after applying `max_size`, never below 1x1.
+    pub fn target_size(&self, width: u32, height: u32) -> (u32, u32) {
+        match self.max_size {
+            Some(max) if width.max(height) > max => {
+                let scale = max as f64 / width.max(height) as f64;
+                let scaled = |v: u32| ((v as f64 * scale).round() as u32).clamp(1, max);
+                (scaled(width), scaled(height))
+            }
+            _ => (width, height),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImageInfo {
+    pub width: u32,
+    pub height: u32,
+    pub format: Option<String>,
+    pub color_type: String,
+    pub channels: u8,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TextureDiff {
+    pub old: ImageInfo,
+    pub new: ImageInfo,
+    /// Set when the dimensions differ and `new` was resampled to `old`'s size
+    /// before the pixel metrics were computed.
+    pub resampled: bool,
+    /// Set when either side is HDR. Errors are then in linear values rather
+    /// than 8-bit levels, and PSNR is relative to the old image's brightest
+    /// channel.
+    pub float: bool,
+    pub changed_pixels: u64,
+    pub total_pixels: u64,
+    pub max_abs_error: f64,
+    pub mean_abs_error: f64,
+    /// Peak signal-to-noise ratio in dB, infinite for identical images.
+    pub psnr: f64,
+    pub ssim: f64,
+    /// PNG highlighting changed regions over a dimmed copy of the old image.
+    #[serde(skip)]
+    pub heatmap: Vec<u8>,
+}
+
+impl TextureDiff {
+    pub fn dimensions_changed(&self) -> bool {
+        self.old.width != self.new.width || self.old.height != self.new.height
+    }
+
+    pub fn format_changed(&self) -> bool {
+        self.old.format != self.new.format
+    }
+
+    pub fn channels_changed(&self) -> bool {
+        self.old.channels != self.new.channels || self.old.color_type != self.new.color_type
+    }
+
+    pub fn is_identical(&self) -> bool {
+        !self.dimensions_changed() && self.changed_pixels == 0
+    }
+}
+
+#[derive(Default)]
 pub struct TextureProcessor;
 
 impl TextureProcessor {
@@ -32,70 +184,561 @@ impl TextureProcessor {
         Ok(buffer)
     }
 
-    pub fn generate_mipmaps(&self, data: &[u8], levels: u32) -> Result<Vec<Vec<u8>>> {
-        let img = image::load_from_memory(data)?;
-        let mut mipmaps = Vec::new();
+    /// The full mip chain down to 1x1 as uncompressed RGBA8 in a single
+    /// container. See [`build_mip_chain`] for how levels are filtered.
+    pub fn generate_mipmaps(&self, data: &[u8], options: &MipOptions, container: TextureContainer) -> Result<Vec<u8>> {
+        let image = image::load_from_memory(data)
+            .with_context(|| "Failed to load image")?
+            .to_rgba8();
+        let (width, height) = image.dimensions();
 
-        let mut current_width = img.width();
-        let mut current_height = img.height();
+        let levels: Vec<Vec<u8>> = build_mip_chain(&image, options)
+            .into_iter()
+            .map(|level| level.into_raw())
+            .collect();
+        let srgb = options.color_space == ColorSpace::Srgb && !options.normal_map;
+        let premultiplied = options.alpha_mode == AlphaMode::Premultiplied && !options.normal_map;
 
-        for _ in 0..levels {
-            if current_width < 1 || current_height < 1 {
-                break;
-            }
+        Ok(container.write(BlockFormat::Rgba8, srgb, premultiplied, width, height, &levels))
+    }
+
+    /// Applies a texture's import settings: caps the resolution, then either
+    /// block-compresses the image and optionally its mip chain into a KTX2 or
+    /// DDS file, or stores it as PNG in the requested alpha mode. HDR images
+    /// and float formats go through [`cook_float`](Self::cook_float).
+    pub fn cook(&self, data: &[u8], settings: &TextureImportSettings) -> Result<Vec<u8>> {
+        if is_hdr(data) || (settings.format().is_float() && settings.container.is_some()) {
+            return self.cook_float(data, settings);
+        }
+
+        let mut image = image::load_from_memory(data)
+            .with_context(|| "Failed to load image")?
+            .to_rgba8();
+        let options = settings.mip_options();
+
+        let (width, height) = settings.target_size(image.width(), image.height());
+        if (width, height) != image.dimensions() {
+            image = resize_linear(&image, width, height, &options);
+        }
+
+        let Some(container) = settings.container else {
+            return Self::encode_png(apply_alpha_mode(&image, &options));
+        };
 
-            let resized = img.resize_exact(
-                current_width,
-                current_height,
-                image::imageops::FilterType::Lanczos3,
-            );
+        let chain = if settings.mipmaps() {
+            build_mip_chain(&image, &options)
+        } else {
+            vec![apply_alpha_mode(&image, &options)]
+        };
+        let levels: Vec<Vec<u8>> = chain.iter().map(|level| compress(level, settings.format())).collect();
+        let srgb = settings.format().supports_srgb() && options.color_space == ColorSpace::Srgb;
+        let premultiplied = options.alpha_mode == AlphaMode::Premultiplied && !options.normal_map;
 
-            let mut buffer = Vec::new();
-            resized.write_to(&mut std::io::Cursor::new(&mut buffer), ImageFormat::Png)?;
-            mipmaps.push(buffer);
+        Ok(container.write(settings.format(), srgb, premultiplied, width, height, &levels))
+    }
+
+    /// [`cook`](Self::cook) at float precision throughout. Without a
+    /// container the result is OpenEXR, or the source itself when no setting
+    /// changes it.
+    pub fn cook_float(&self, data: &[u8], settings: &TextureImportSettings) -> Result<Vec<u8>> {
+        // Float formats have no sRGB variants, but 8-bit sources are still
+        // sRGB unless the settings say otherwise
+        let source_space = match settings.normal_map() {
+            true => ColorSpace::Linear,
+            false => settings.color_space.unwrap_or(ColorSpace::Srgb),
+        };
+        let mut image = decode_linear(data, settings.layer.as_deref(), source_space)?;
 
-            current_width /= 2;
-            current_height /= 2;
+        let (width, height) = settings.target_size(image.width(), image.height());
+        let resized = (width, height) != image.dimensions();
+        if resized {
+            image = resize_float(&image, width, height);
         }
 
-        Ok(mipmaps)
+        let Some(container) = settings.container else {
+            if is_hdr(data) && !resized && settings.layer.is_none() && settings.alpha_mode() == AlphaMode::Straight {
+                return Ok(data.to_vec());
+            }
+            return encode_exr(&apply_float_alpha_mode(&image, settings.alpha_mode()));
+        };
+        if !settings.format().is_float() {
+            bail!("HDR textures need a float format (rgba16f or bc6h), not {:?}", settings.format());
+        }
+
+        let chain = if settings.mipmaps() {
+            build_float_mip_chain(&image, settings.alpha_mode())
+        } else {
+            vec![apply_float_alpha_mode(&image, settings.alpha_mode())]
+        };
+        let levels: Vec<Vec<u8>> = chain.iter().map(|level| compress_float(level, settings.format())).collect();
+        let premultiplied = settings.alpha_mode() == AlphaMode::Premultiplied;
+
+        Ok(container.write(settings.format(), false, premultiplied, width, height, &levels))
     }
 
     pub fn get_dimensions(&self, data: &[u8]) -> Result<(u32, u32)> {
         let img = image::load_from_memory(data)?;
         Ok((img.width(), img.height()))
     }
+
+    /// An 8-bit view of any texture for display. HDR images are tonemapped
+    /// with automatic exposure; containers are decoded from their smallest
+    /// mip level still `min_size` pixels on its longest side. `None` for
+    /// containers with block modes that cannot be decoded.
+    pub fn display_image(&self, data: &[u8], min_size: u32) -> Result<Option<RgbaImage>> {
+        if is_hdr(data) {
+            let image = decode_linear(data, None, ColorSpace::Linear)?;
+            return Ok(Some(tonemap(&image, &TonemapOptions::default())));
+        }
+        if TextureContainer::detect(data).is_none() {
+            return Ok(Some(image::load_from_memory(data).with_context(|| "Failed to load image")?.to_rgba8()));
+        }
+
+        let info = read_container_info(data)?;
+        let dimensions = |level: usize| ((info.width >> level).max(1), (info.height >> level).max(1));
+        let level = (0..info.level_ranges.len())
+            .rev()
+            .find(|&level| dimensions(level).0.max(dimensions(level).1) >= min_size)
+            .unwrap_or(0);
+        let (width, height) = dimensions(level);
+        let level_data = &data[info.level_ranges[level].clone()];
+        let decoded = match info.format.is_float() {
+            true => decompress_float(level_data, info.format, width, height)
+                .map(|image| tonemap(&image, &TonemapOptions::default())),
+            false => decompress(level_data, info.format, width, height),
+        };
+        match decoded {
+            Err(e) if e.is::<UnsupportedBlockMode>() => Ok(None),
+            decoded => decoded.map(Some),
+        }
+    }
+
+    pub fn get_properties(&self, data: &[u8]) -> Result<TextureProperties> {
+        if TextureContainer::detect(data).is_some() {
+            let info = read_container_info(data)?;
+            return Ok(TextureProperties {
+                width: info.width,
+                height: info.height,
+                format: Some(format!("{:?}", info.container)),
+                color_type: Some(format!("{:?}", info.format)),
+                color_space: if info.srgb { ColorSpace::Srgb } else { ColorSpace::Linear },
+                dynamic_range: None,
+                layers: Vec::new(),
+                // Containers from other encoders may use block modes we cannot decode
+                perceptual_hashes: self.perceptual_hashes(data).ok(),
+            });
+        }
+
+        if is_hdr(data) {
+            let layers = decode_hdr_layers(data)?;
+            let first = &layers[0].image;
+            let named = layers.iter().any(|layer| layer.name.is_some());
+            return Ok(TextureProperties {
+                width: first.width(),
+                height: first.height(),
+                format: image::guess_format(data).ok().map(|f| format!("{:?}", f)),
+                color_type: Some(format!("{:?}", image::ColorType::Rgba32F)),
+                color_space: ColorSpace::Linear,
+                dynamic_range: Some(dynamic_range(first)),
+                layers: match named {
+                    true => layers.iter().map(|layer| layer.name.clone().unwrap_or_default()).collect(),
+                    false => Vec::new(),
+                },
+                perceptual_hashes: Some(perceptual_hashes(&tonemap(first, &TonemapOptions::default()))),
+            });
+        }
+
+        let img = image::load_from_memory(data)?;
+        let color_space = match img.color() {
+            image::ColorType::Rgb32F | image::ColorType::Rgba32F => ColorSpace::Linear,
+            _ => ColorSpace::Srgb,
+        };
+
+        Ok(TextureProperties {
+            width: img.width(),
+            height: img.height(),
+            format: image::guess_format(data).ok().map(|f| format!("{:?}", f)),
+            color_type: Some(format!("{:?}", img.color())),
+            color_space,
+            dynamic_range: None,
+            layers: Vec::new(),
+            perceptual_hashes: Some(perceptual_hashes(&img.to_rgba8())),
+        })
+    }
+
+    /// Decodes both textures and compares them pixel by pixel. Containers are
+    /// compared on their largest mip level, and HDR textures in linear float
+    /// without clamping.
+    pub fn visual_diff(&self, old: &[u8], new: &[u8]) -> Result<TextureDiff> {
+        let (old_img, old_info) = Self::diff_image(old).with_context(|| "Failed to load old image")?;
+        let (new_img, new_info) = Self::diff_image(new).with_context(|| "Failed to load new image")?;
+
+        let is_float = |img: &DynamicImage| matches!(img.color(), image::ColorType::Rgb32F | image::ColorType::Rgba32F);
+        let float = is_float(&old_img) || is_float(&new_img);
+        let (width, height) = (old_img.width(), old_img.height());
+        let resampled = (new_img.width(), new_img.height()) != (width, height);
+        let new_img = match resampled {
+            true => new_img.resize_exact(width, height, image::imageops::FilterType::Triangle),
+            false => new_img,
+        };
+        // 8-bit levels, or linear values for HDR
+        let samples = |img: &DynamicImage| -> Vec<f32> {
+            match float {
+                true => img.to_rgba32f().into_raw(),
+                false => img.to_rgba8().into_raw().into_iter().map(f32::from).collect(),
+            }
+        };
+        let (old_samples, new_samples) = (samples(&old_img), samples(&new_img));
+        let peak = match float {
+            true => old_samples.chunks_exact(4).flat_map(|p| &p[..3]).fold(1.0f32, |peak, &c| peak.max(c)) as f64,
+            false => 255.0,
+        };
+
+        let mut changed_pixels = 0u64;
+        let mut max_abs_error = 0.0f64;
+        let mut abs_sum = 0.0;
+        let mut sq_sum = 0.0;
+        let mut error_map = Vec::with_capacity((width * height) as usize);
+
+        for (a, b) in old_samples.chunks_exact(4).zip(new_samples.chunks_exact(4)) {
+            let mut pixel_max = 0.0f64;
+            for c in 0..4 {
+                let d = (a[c] as f64 - b[c] as f64).abs();
+                abs_sum += d;
+                sq_sum += d * d;
+                pixel_max = pixel_max.max(d);
+            }
+            if pixel_max > 0.0 {
+                changed_pixels += 1;
+            }
+            max_abs_error = max_abs_error.max(pixel_max);
+            error_map.push((pixel_max / peak).min(1.0));
+        }
+
+        let total_pixels = width as u64 * height as u64;
+        let sample_count = (total_pixels * 4).max(1) as f64;
+        let mse = sq_sum / sample_count;
+        let psnr = if mse == 0.0 {
+            f64::INFINITY
+        } else {
+            10.0 * (peak * peak / mse).log10()
+        };
+
+        let luma = |samples: &[f32]| -> Vec<f64> {
+            samples.chunks_exact(4).map(|p| 0.2126 * p[0] as f64 + 0.7152 * p[1] as f64 + 0.0722 * p[2] as f64).collect()
+        };
+        let ssim = Self::ssim(&luma(&old_samples), &luma(&new_samples), width, height, peak);
+        // The heatmap shows HDR images the way their previews do
+        let base = match float {
+            true => DynamicImage::ImageRgba8(tonemap(&old_img.to_rgba32f(), &TonemapOptions::default())).to_luma8(),
+            false => old_img.to_luma8(),
+        };
+        let heatmap = Self::render_heatmap(&base, &error_map)?;
+
+        Ok(TextureDiff {
+            old: old_info,
+            new: new_info,
+            resampled,
+            float,
+            changed_pixels,
+            total_pixels,
+            max_abs_error,
+            mean_abs_error: abs_sum / sample_count,
+            psnr,
+            ssim,
+            heatmap,
+        })
+    }
+
+    /// A texture decoded for diffing, with mip 0 standing in for containers.
+    fn diff_image(data: &[u8]) -> Result<(DynamicImage, ImageInfo)> {
+        if is_hdr(data) {
+            let image = DynamicImage::ImageRgba32F(decode_linear(data, None, ColorSpace::Linear)?);
+            let info = ImageInfo {
+                width: image.width(),
+                height: image.height(),
+                format: image::guess_format(data).ok().map(|f| format!("{:?}", f)),
+                color_type: format!("{:?}", image.color()),
+                channels: image.color().channel_count(),
+            };
+            return Ok((image, info));
+        }
+        if TextureContainer::detect(data).is_none() {
+            let image = image::load_from_memory(data)?;
+            let info = Self::image_info(data, &image);
+            return Ok((image, info));
+        }
+
+        let info = read_container_info(data)?;
+        let level = &data[info.level_ranges[0].clone()];
+        let image = match info.format.is_float() {
+            true => DynamicImage::ImageRgba32F(decompress_float(level, info.format, info.width, info.height)?),
+            false => DynamicImage::ImageRgba8(decompress(level, info.format, info.width, info.height)?),
+        };
+        let image_info = ImageInfo {
+            width: info.width,
+            height: info.height,
+            format: Some(format!("{:?}", info.container)),
+            color_type: format!("{:?}", info.format),
+            channels: image.color().channel_count(),
+        };
+        Ok((image, image_info))
+    }
+
+    fn image_info(data: &[u8], img: &DynamicImage) -> ImageInfo {
+        ImageInfo {
+            width: img.width(),
+            height: img.height(),
+            format: image::guess_format(data).ok().map(|f| format!("{:?}", f)),
+            color_type: format!("{:?}", img.color()),
+            channels: img.color().channel_count(),
+        }
+    }
+
+    /// Mean SSIM over luminance with values up to `peak`, using square windows
+    /// laid out on a fixed stride.
+    fn ssim(a: &[f64], b: &[f64], width: u32, height: u32, peak: f64) -> f64 {
+        let c1 = (0.01 * peak) * (0.01 * peak);
+        let c2 = (0.03 * peak) * (0.03 * peak);
+
+        let win_w = SSIM_WINDOW.min(width);
+        let win_h = SSIM_WINDOW.min(height);
+        if win_w == 0 || win_h == 0 {
+            return 1.0;
+        }
+
+        let mut total = 0.0;
+        let mut windows = 0u32;
+        let mut y = 0;
+        while y + win_h <= height {
+            let mut x = 0;
+            while x + win_w <= width {
+                let n = (win_w * win_h) as f64;
+                let (mut sa, mut sb, mut saa, mut sbb, mut sab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+                for wy in y..y + win_h {
+                    for wx in x..x + win_w {
+                        let pa = a[(wy * width + wx) as usize];
+                        let pb = b[(wy * width + wx) as usize];
+                        sa += pa;
+                        sb += pb;
+                        saa += pa * pa;
+                        sbb += pb * pb;
+                        sab += pa * pb;
+                    }
+                }
+                let (mu_a, mu_b) = (sa / n, sb / n);
+                let var_a = saa / n - mu_a * mu_a;
+                let var_b = sbb / n - mu_b * mu_b;
+                let cov = sab / n - mu_a * mu_b;
+
+                total += ((2.0 * mu_a * mu_b + c1) * (2.0 * cov + c2))
+                    / ((mu_a * mu_a + mu_b * mu_b + c1) * (var_a + var_b + c2));
+                windows += 1;
+                x += SSIM_STRIDE;
+            }
+            y += SSIM_STRIDE;
+        }
+
+        total / windows as f64
+    }
+
+    /// `error_map` holds each pixel's largest channel error as a share of the
+    /// peak value.
+    fn render_heatmap(base: &GrayImage, error_map: &[f64]) -> Result<Vec<u8>> {
+        let (width, height) = base.dimensions();
+        let mut heatmap = RgbImage::new(width, height);
+
+        for (i, (x, y, luma)) in base.enumerate_pixels().enumerate() {
+            let dimmed = luma[0] / 3;
+            let error = error_map[i];
+            let pixel = if error == 0.0 {
+                Rgb([dimmed, dimmed, dimmed])
+            } else {
+                // Square-root ramp so that small differences remain visible,
+                // running from red for subtle changes to yellow for large ones.
+                let t = error.sqrt();
+                let green = ((t - 0.5).max(0.0) * 2.0 * 255.0) as u8;
+                Rgb([255, green.max(dimmed), dimmed])
+            };
+            heatmap.put_pixel(x, y, pixel);
+        }
+
+        Self::encode_png(heatmap)
+    }
+
+    /// Encodes a generated image as PNG, as used for diff heatmaps and
+    /// previews.
+    pub fn encode_png(image: impl Into<DynamicImage>) -> Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        image
+            .into()
+            .write_to(&mut std::io::Cursor::new(&mut buffer), ImageFormat::Png)
+            .with_context(|| "Failed to encode PNG")?;
+        Ok(buffer)
+    }
 }
 
 impl super::FormatProcessor for TextureProcessor {
+    fn name(&self) -> &'static str {
+        "texture"
+    }
+
+    fn resource_type(&self) -> ResourceType {
+        ResourceType::Texture
+    }
+
+    fn extensions(&self) -> &[&'static str] {
+        &[
+            "png", "jpg", "jpeg", "bmp", "gif", "tga", "tif", "tiff", "webp", "ico", "hdr", "exr", "qoi", "pnm", "ktx2",
+            "dds",
+        ]
+    }
+
+    fn mime_types(&self) -> &[&'static str] {
+        &[
+            "image/png",
+            "image/jpeg",
+            "image/bmp",
+            "image/gif",
+            "image/x-tga",
+            "image/tiff",
+            "image/webp",
+            "image/x-icon",
+            "image/vnd.radiance",
+            "image/x-exr",
+            "image/qoi",
+            "image/x-portable-anymap",
+            "image/ktx2",
+            "image/vnd-ms.dds",
+        ]
+    }
+
+    fn matches_magic(&self, data: &[u8]) -> bool {
+        TextureContainer::detect(data).is_some() || image::guess_format(data).is_ok()
+    }
+
+    // 2: alpha coverage is kept right for opaque and faint mip chains
+    fn version(&self) -> u32 {
+        2
+    }
+
     fn process(&self, data: &[u8]) -> Result<Vec<u8>> {
-        // Default processing: convert to PNG if not already
-        if let Ok(format) = image::guess_format(data) {
-            if format == ImageFormat::Png {
-                return Ok(data.to_vec());
-            }
+        // Default processing: convert to PNG if not already. GPU containers
+        // are already cooked and HDR images would lose their range, so both
+        // are kept as they are.
+        if let Ok(ImageFormat::Png) = image::guess_format(data) {
+            return Ok(data.to_vec());
+        }
+        if is_hdr(data) {
+            decode_hdr_layers(data)?;
+            return Ok(data.to_vec());
+        }
+        if TextureContainer::detect(data).is_some() {
+            read_container_info(data)?;
+            return Ok(data.to_vec());
         }
         self.convert_format(data, ImageFormat::Png)
     }
 
+    fn process_with(&self, data: &[u8], settings: &ImportSettings, _files: &[ExternalFile]) -> Result<Vec<u8>> {
+        match &settings.texture {
+            Some(texture) => self.cook(data, texture),
+            None => self.process(data),
+        }
+    }
+
+    fn thumbnail(&self, data: &[u8], options: &ThumbnailOptions, _files: &[ExternalFile]) -> Result<Option<Vec<u8>>> {
+        let Some(image) = self.display_image(data, options.size)? else {
+            return Ok(None);
+        };
+        Ok(Some(Self::encode_png(fit_thumbnail(&image, options.size))?))
+    }
+
     fn get_metadata(&self, data: &[u8]) -> Result<ResourceMetadata> {
-        let (width, height) = self.get_dimensions(data)?;
+        let properties = self.get_properties(data)?;
 
         Ok(ResourceMetadata {
+            schema_version: METADATA_SCHEMA_VERSION,
             hash: blake3::hash(data).to_string(),
             resource_type: ResourceType::Texture,
             size: data.len() as u64,
-            create_at: SystemTime::now()
-                .duration_since(UNIX_EPOCH)?
-                .as_secs() as i64,
-            dependencies: vec![format!("texture:{}x{}", width, height)],
+            dependencies: Vec::new(),
+            properties: ResourceProperties::Texture(properties),
         })
     }
 
     fn validate(&self, data: &[u8]) -> Result<bool> {
+        if TextureContainer::detect(data).is_some() {
+            return read_container_info(data).map(|_| true);
+        }
+        if is_hdr(data) {
+            return Ok(decode_hdr_layers(data).is_ok());
+        }
         match image::load_from_memory(data) {
             Ok(_) => Ok(true),
             Err(_) => Ok(false),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbaImage;
+
+    #[test]
+    fn test_visual_diff() {
+        let old = RgbaImage::from_pixel(16, 16, image::Rgba([40, 80, 120, 255]));
+        let mut new = old.clone();
+        new.put_pixel(3, 5, image::Rgba([240, 80, 120, 255]));
+
+        let png = |image: RgbaImage| TextureProcessor::encode_png(image).unwrap();
+        let processor = TextureProcessor::new();
+        let same = processor.visual_diff(&png(old.clone()), &png(old.clone())).unwrap();
+        assert!(same.is_identical());
+        assert!(same.psnr.is_infinite());
+        assert!((same.ssim - 1.0).abs() < 1e-9);
+
+        let diff = processor.visual_diff(&png(old), &png(new)).unwrap();
+        assert_eq!(diff.changed_pixels, 1);
+        assert_eq!(diff.max_abs_error, 200.0);
+        assert!(diff.ssim < 1.0);
+
+        let heatmap = image::load_from_memory(&diff.heatmap).unwrap().to_rgb8();
+        assert_eq!(heatmap.get_pixel(3, 5)[0], 255);
+        assert_ne!(heatmap.get_pixel(0, 0)[0], 255);
+
+        // Cooked containers are compared on their top mip
+        let settings = TextureImportSettings {
+            container: Some(TextureContainer::Ktx2),
+            format: Some(BlockFormat::Bc1),
+            ..Default::default()
+        };
+        let mut moved = RgbaImage::from_pixel(16, 16, image::Rgba([40, 80, 120, 255]));
+        for x in 0..4 {
+            moved.put_pixel(x, 8, image::Rgba([240, 240, 240, 255]));
+        }
+        let cooked_old = processor.cook(&png(RgbaImage::from_pixel(16, 16, image::Rgba([40, 80, 120, 255]))), &settings).unwrap();
+        let cooked_new = processor.cook(&png(moved), &settings).unwrap();
+        assert!(processor.visual_diff(&cooked_old, &cooked_old).unwrap().is_identical());
+        let diff = processor.visual_diff(&cooked_old, &cooked_new).unwrap();
+        assert_eq!((diff.new.width, diff.new.format.as_deref(), diff.new.color_type.as_str()), (16, Some("Ktx2"), "Bc1"));
+        assert!(!diff.resampled && diff.changed_pixels >= 4 && diff.changed_pixels <= 16, "{}", diff.changed_pixels);
+
+        // HDR values past 1.0 still count, as EXR and as BC6H
+        let bright = |value: f32| {
+            let mut image = image::Rgba32FImage::from_pixel(16, 16, image::Rgba([0.5, 0.5, 0.5, 1.0]));
+            image.put_pixel(7, 7, image::Rgba([value, value, value, 1.0]));
+            encode_exr(&image).unwrap()
+        };
+        let diff = processor.visual_diff(&bright(2.0), &bright(6.0)).unwrap();
+        assert!(diff.float && diff.changed_pixels == 1, "{:?}", diff);
+        assert_eq!(diff.max_abs_error, 4.0);
+        assert!(diff.psnr.is_finite());
+        let settings = TextureImportSettings { format: Some(BlockFormat::Bc6h), ..settings };
+        let diff = processor
+            .visual_diff(&processor.cook(&bright(2.0), &settings).unwrap(), &processor.cook(&bright(6.0), &settings).unwrap())
+            .unwrap();
+        assert!(diff.float && diff.max_abs_error > 2.0, "{:?}", diff);
+    }
+}
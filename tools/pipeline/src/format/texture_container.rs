@@ -0,0 +1,443 @@
+use super::{mip_level_count, BlockFormat};
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+pub const KTX2_IDENTIFIER: [u8; 12] = [0xAB, b'K', b'T', b'X', b' ', b'2', b'0', 0xBB, b'\r', b'\n', 0x1A, b'\n'];
+const KTX2_HEADER_LEN: usize = 80;
+const KTX2_LEVEL_INDEX_ENTRY_LEN: usize = 24;
+
+const DDS_MAGIC: &[u8; 4] = b"DDS ";
+const DDS_HEADER_LEN: usize = 124;
+const DDS_DX10_HEADER_LEN: usize = 20;
+
+/// GPU-ready texture file formats with the whole mip chain in one file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TextureContainer {
+    Ktx2,
+    Dds,
+}
+
+/// What a KTX2 or DDS header says about its contents.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContainerInfo {
+    pub container: TextureContainer,
+    pub format: BlockFormat,
+    pub srgb: bool,
+    /// Colour is stored multiplied by alpha.
+    pub premultiplied: bool,
+    pub width: u32,
+    pub height: u32,
+    pub levels: u32,
+    /// Where each mip level's data lies in the file, largest first.
+    pub level_ranges: Vec<Range<usize>>,
+}
+
+impl TextureContainer {
+    pub fn detect(data: &[u8]) -> Option<TextureContainer> {
+        if data.starts_with(&KTX2_IDENTIFIER) {
+            Some(TextureContainer::Ktx2)
+        } else if data.starts_with(DDS_MAGIC) {
+            Some(TextureContainer::Dds)
+        } else {
+            None
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            TextureContainer::Ktx2 => "ktx2",
+            TextureContainer::Dds => "dds",
+        }
+    }
+
+    /// Writes `levels`, largest first, each already encoded in `format`.
+    pub fn write(
+        self,
+        format: BlockFormat,
+        srgb: bool,
+        premultiplied: bool,
+        width: u32,
+        height: u32,
+        levels: &[Vec<u8>],
+    ) -> Vec<u8> {
+        match self {
+            TextureContainer::Ktx2 => write_ktx2(format, srgb, premultiplied, width, height, levels),
+            TextureContainer::Dds => write_dds(format, srgb, premultiplied, width, height, levels),
+        }
+    }
+}
+
+fn vk_format(format: BlockFormat, srgb: bool) -> u32 {
+    match (format, srgb) {
+        (BlockFormat::Rgba8, false) => 37,
+        (BlockFormat::Rgba8, true) => 43,
+        (BlockFormat::Bc1, false) => 133,
+        (BlockFormat::Bc1, true) => 134,
+        (BlockFormat::Bc3, false) => 137,
+        (BlockFormat::Bc3, true) => 138,
+        (BlockFormat::Bc4, _) => 139,
+        (BlockFormat::Bc5, _) => 141,
+        (BlockFormat::Bc7, false) => 145,
+        (BlockFormat::Bc7, true) => 146,
+        (BlockFormat::Rgba16f, _) => 97,
+        (BlockFormat::Bc6h, _) => 143,
+    }
+}
+
+fn dxgi_format(format: BlockFormat, srgb: bool) -> u32 {
+    match (format, srgb) {
+        (BlockFormat::Rgba8, false) => 28,
+        (BlockFormat::Rgba8, true) => 29,
+        (BlockFormat::Bc1, false) => 71,
+        (BlockFormat::Bc1, true) => 72,
+        (BlockFormat::Bc3, false) => 77,
+        (BlockFormat::Bc3, true) => 78,
+        (BlockFormat::Bc4, _) => 80,
+        (BlockFormat::Bc5, _) => 83,
+        (BlockFormat::Bc7, false) => 98,
+        (BlockFormat::Bc7, true) => 99,
+        (BlockFormat::Rgba16f, _) => 10,
+        (BlockFormat::Bc6h, _) => 95,
+    }
+}
+
+/// The format and colour space a format code stands for.
+fn lookup(code: u32, to_code: fn(BlockFormat, bool) -> u32) -> Option<(BlockFormat, bool)> {
+    BlockFormat::ALL
+        .iter()
+        .flat_map(|&format| [(format, false), (format, true)])
+        .find(|&(format, srgb)| (srgb <= format.supports_srgb()) && to_code(format, srgb) == code)
+}
+
+fn level_dimension(side: u32, level: usize) -> u32 {
+    (side >> level).max(1)
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Bit offset, bit length, channel id, and lower and upper values of a DFD
+/// sample.
+type DfdSample = (u16, u8, u8, u32, u32);
+
+/// A Khronos data format descriptor with one basic block.
+fn ktx2_dfd(format: BlockFormat, srgb: bool, premultiplied: bool) -> Vec<u8> {
+    const CHANNEL_ALPHA: u8 = 15;
+    const QUALIFIER_LINEAR: u8 = 0x10;
+    const QUALIFIER_SIGNED: u8 = 0x40;
+    const QUALIFIER_FLOAT: u8 = 0x80;
+    const FLOAT_ONE: u32 = 0x3F80_0000;
+    const FLOAT_MINUS_ONE: u32 = 0xBF80_0000;
+    const FLAG_ALPHA_PREMULTIPLIED: u8 = 1;
+
+    let compressed = |offset: u16, bits: u8, channel: u8| (offset, bits, channel, 0, u32::MAX);
+    let (color_model, samples): (u8, Vec<DfdSample>) = match format {
+        BlockFormat::Rgba8 => {
+            let alpha = if srgb { CHANNEL_ALPHA | QUALIFIER_LINEAR } else { CHANNEL_ALPHA };
+            (1, vec![(0, 8, 0, 0, 255), (8, 8, 1, 0, 255), (16, 8, 2, 0, 255), (24, 8, alpha, 0, 255)])
+        }
+        BlockFormat::Bc1 => (128, vec![compressed(0, 64, 1)]),
+        BlockFormat::Bc3 => (130, vec![compressed(0, 64, CHANNEL_ALPHA), compressed(64, 64, 0)]),
+        BlockFormat::Bc4 => (131, vec![compressed(0, 64, 0)]),
+        BlockFormat::Bc5 => (132, vec![compressed(0, 64, 0), compressed(64, 64, 1)]),
+        BlockFormat::Bc7 => (134, vec![compressed(0, 128, 0)]),
+        BlockFormat::Rgba16f => {
+            let half = |offset: u16, channel: u8| {
+                (offset, 16, channel | QUALIFIER_FLOAT | QUALIFIER_SIGNED, FLOAT_MINUS_ONE, FLOAT_ONE)
+            };
+            (1, vec![half(0, 0), half(16, 1), half(32, 2), half(48, CHANNEL_ALPHA)])
+        }
+        BlockFormat::Bc6h => (133, vec![(0, 128, QUALIFIER_FLOAT, 0, FLOAT_ONE)]),
+    };
+
+    let block_size = 24 + 16 * samples.len() as u32;
+    let mut dfd = Vec::new();
+    push_u32(&mut dfd, 4 + block_size);
+    // Khronos vendor, basic descriptor type, version 2
+    push_u32(&mut dfd, 0);
+    push_u32(&mut dfd, 2 | (block_size << 16));
+    let transfer = if srgb { 2 } else { 1 };
+    let flags = if premultiplied { FLAG_ALPHA_PREMULTIPLIED } else { 0 };
+    dfd.extend_from_slice(&[color_model, 1, transfer, flags]);
+    let extent = (format.block_dimension() - 1) as u8;
+    dfd.extend_from_slice(&[extent, extent, 0, 0]);
+    dfd.extend_from_slice(&[format.block_bytes() as u8, 0, 0, 0, 0, 0, 0, 0]);
+
+    for (offset, bits, channel, lower, upper) in samples {
+        push_u32(&mut dfd, offset as u32 | ((bits as u32 - 1) << 16) | ((channel as u32) << 24));
+        push_u32(&mut dfd, 0);
+        push_u32(&mut dfd, lower);
+        push_u32(&mut dfd, upper);
+    }
+    dfd
+}
+
+pub fn write_ktx2(
+    format: BlockFormat,
+    srgb: bool,
+    premultiplied: bool,
+    width: u32,
+    height: u32,
+    levels: &[Vec<u8>],
+) -> Vec<u8> {
+    let dfd = ktx2_dfd(format, srgb, premultiplied);
+    let dfd_offset = KTX2_HEADER_LEN + KTX2_LEVEL_INDEX_ENTRY_LEN * levels.len();
+
+    // Mip data is stored smallest first, each level aligned to the block size
+    let alignment = format.block_bytes().max(4);
+    let mut offsets = vec![0; levels.len()];
+    let mut end = dfd_offset + dfd.len();
+    for (level, data) in levels.iter().enumerate().rev() {
+        offsets[level] = end.next_multiple_of(alignment);
+        end = offsets[level] + data.len();
+    }
+
+    let mut out = Vec::with_capacity(end);
+    out.extend_from_slice(&KTX2_IDENTIFIER);
+    push_u32(&mut out, vk_format(format, srgb));
+    // Type size is 1 for block-compressed and 8-bit formats
+    push_u32(&mut out, if format == BlockFormat::Rgba16f { 2 } else { 1 });
+    push_u32(&mut out, width);
+    push_u32(&mut out, height);
+    push_u32(&mut out, 0);
+    push_u32(&mut out, 0);
+    push_u32(&mut out, 1);
+    push_u32(&mut out, levels.len() as u32);
+    push_u32(&mut out, 0);
+
+    push_u32(&mut out, dfd_offset as u32);
+    push_u32(&mut out, dfd.len() as u32);
+    push_u32(&mut out, 0);
+    push_u32(&mut out, 0);
+    push_u64(&mut out, 0);
+    push_u64(&mut out, 0);
+
+    for (offset, data) in offsets.iter().zip(levels) {
+        push_u64(&mut out, *offset as u64);
+        push_u64(&mut out, data.len() as u64);
+        push_u64(&mut out, data.len() as u64);
+    }
+    out.extend_from_slice(&dfd);
+
+    for (offset, data) in offsets.iter().zip(levels).rev() {
+        out.resize(*offset, 0);
+        out.extend_from_slice(data);
+    }
+    out
+}
+
+pub fn write_dds(
+    format: BlockFormat,
+    srgb: bool,
+    premultiplied: bool,
+    width: u32,
+    height: u32,
+    levels: &[Vec<u8>],
+) -> Vec<u8> {
+    const DDSD_CAPS: u32 = 0x1;
+    const DDSD_HEIGHT: u32 = 0x2;
+    const DDSD_WIDTH: u32 = 0x4;
+    const DDSD_PITCH: u32 = 0x8;
+    const DDSD_PIXELFORMAT: u32 = 0x1000;
+    const DDSD_MIPMAPCOUNT: u32 = 0x20000;
+    const DDSD_LINEARSIZE: u32 = 0x80000;
+    const DDPF_FOURCC: u32 = 0x4;
+    const DDSCAPS_COMPLEX: u32 = 0x8;
+    const DDSCAPS_TEXTURE: u32 = 0x1000;
+    const DDSCAPS_MIPMAP: u32 = 0x400000;
+    const D3D10_RESOURCE_DIMENSION_TEXTURE2D: u32 = 3;
+    const DDS_ALPHA_MODE_STRAIGHT: u32 = 1;
+    const DDS_ALPHA_MODE_PREMULTIPLIED: u32 = 2;
+
+    let (size_flag, pitch) = match format {
+        BlockFormat::Rgba8 | BlockFormat::Rgba16f => (DDSD_PITCH, width * format.block_bytes() as u32),
+        _ => (DDSD_LINEARSIZE, format.level_size(width, height) as u32),
+    };
+    let caps = match levels.len() {
+        0 | 1 => DDSCAPS_TEXTURE,
+        _ => DDSCAPS_TEXTURE | DDSCAPS_COMPLEX | DDSCAPS_MIPMAP,
+    };
+
+    let mut out = Vec::new();
+    out.extend_from_slice(DDS_MAGIC);
+    push_u32(&mut out, DDS_HEADER_LEN as u32);
+    push_u32(&mut out, DDSD_CAPS | DDSD_HEIGHT | DDSD_WIDTH | DDSD_PIXELFORMAT | DDSD_MIPMAPCOUNT | size_flag);
+    push_u32(&mut out, height);
+    push_u32(&mut out, width);
+    push_u32(&mut out, pitch);
+    push_u32(&mut out, 0);
+    push_u32(&mut out, levels.len() as u32);
+    out.extend_from_slice(&[0; 44]);
+
+    // Pixel format pointing at the DX10 extension header
+    push_u32(&mut out, 32);
+    push_u32(&mut out, DDPF_FOURCC);
+    out.extend_from_slice(b"DX10");
+    out.extend_from_slice(&[0; 20]);
+
+    push_u32(&mut out, caps);
+    out.extend_from_slice(&[0; 16]);
+
+    push_u32(&mut out, dxgi_format(format, srgb));
+    push_u32(&mut out, D3D10_RESOURCE_DIMENSION_TEXTURE2D);
+    push_u32(&mut out, 0);
+    push_u32(&mut out, 1);
+    push_u32(&mut out, if premultiplied { DDS_ALPHA_MODE_PREMULTIPLIED } else { DDS_ALPHA_MODE_STRAIGHT });
+
+    for data in levels {
+        out.extend_from_slice(data);
+    }
+    out
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    match data.get(offset..offset + 4) {
+        Some(bytes) => Ok(u32::from_le_bytes(bytes.try_into().unwrap())),
+        None => bail!("Truncated texture header"),
+    }
+}
+
+/// Reads the header of a KTX2 or DDS file, checking that the mip levels it
+/// declares fit in the data.
+pub fn read_container_info(data: &[u8]) -> Result<ContainerInfo> {
+    let Some(container) = TextureContainer::detect(data) else {
+        bail!("Not a KTX2 or DDS file");
+    };
+
+    let (code, width, height, levels) = match container {
+        TextureContainer::Ktx2 => (read_u32(data, 12)?, read_u32(data, 20)?, read_u32(data, 24)?, read_u32(data, 40)?),
+        TextureContainer::Dds => (0, read_u32(data, 16)?, read_u32(data, 12)?, read_u32(data, 28)?),
+    };
+    let levels = levels.max(1);
+
+    let (format, srgb, premultiplied, data_start) = match container {
+        TextureContainer::Ktx2 => {
+            let Some((format, srgb)) = lookup(code, vk_format) else {
+                bail!("Unsupported KTX2 format {}", code);
+            };
+            // Flags byte of the DFD's basic block, after the total size and
+            // the block header
+            let dfd_offset = read_u32(data, 48)? as usize;
+            let flags = data.get(dfd_offset + 15).copied().unwrap_or_default();
+            (format, srgb, flags & 1 != 0, None)
+        }
+        TextureContainer::Dds => {
+            let four_cc = data.get(84..88).unwrap_or_default();
+            let legacy = match four_cc {
+                b"DXT1" => Some(BlockFormat::Bc1),
+                b"DXT5" => Some(BlockFormat::Bc3),
+                b"ATI1" | b"BC4U" => Some(BlockFormat::Bc4),
+                b"ATI2" | b"BC5U" => Some(BlockFormat::Bc5),
+                _ => None,
+            };
+            match legacy {
+                Some(format) => (format, false, false, Some(4 + DDS_HEADER_LEN)),
+                None if four_cc == b"DX10" => {
+                    let code = read_u32(data, 4 + DDS_HEADER_LEN)?;
+                    let Some((format, srgb)) = lookup(code, dxgi_format) else {
+                        bail!("Unsupported DXGI format {}", code);
+                    };
+                    let premultiplied = read_u32(data, 4 + DDS_HEADER_LEN + 16)? & 0x7 == 2;
+                    (format, srgb, premultiplied, Some(4 + DDS_HEADER_LEN + DDS_DX10_HEADER_LEN))
+                }
+                None => bail!("Unsupported DDS pixel format {:?}", String::from_utf8_lossy(four_cc)),
+            }
+        }
+    };
+
+    if width == 0 || height == 0 {
+        bail!("Texture has no pixels");
+    }
+    if levels > mip_level_count(width, height) {
+        bail!("{}x{} texture claims {} mip levels", width, height, levels);
+    }
+
+    let mut level_ranges = Vec::with_capacity(levels as usize);
+    for level in 0..levels as usize {
+        let expected = format.level_size(level_dimension(width, level), level_dimension(height, level));
+        let (offset, len) = match data_start {
+            Some(start) => {
+                let before: usize = (0..level)
+                    .map(|l| format.level_size(level_dimension(width, l), level_dimension(height, l)))
+                    .sum();
+                (start + before, expected)
+            }
+            None => {
+                let entry = KTX2_HEADER_LEN + level * KTX2_LEVEL_INDEX_ENTRY_LEN;
+                let offset = read_u32(data, entry)? as usize | ((read_u32(data, entry + 4)? as usize) << 32);
+                let len = read_u32(data, entry + 8)? as usize | ((read_u32(data, entry + 12)? as usize) << 32);
+                (offset, len)
+            }
+        };
+        if len != expected || offset.checked_add(len).is_none_or(|end| end > data.len()) {
+            bail!("Mip level {} is truncated or has the wrong size", level);
+        }
+        level_ranges.push(offset..offset + len);
+    }
+
+    Ok(ContainerInfo { container, format, srgb, premultiplied, width, height, levels, level_ranges })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::compress;
+    use image::RgbaImage;
+
+    #[test]
+    fn test_containers_read_back() {
+        let image = RgbaImage::from_fn(20, 12, |x, y| image::Rgba([x as u8 * 10, y as u8 * 20, 128, 255]));
+        let encode = |format| -> Vec<Vec<u8>> {
+            [(20, 12), (10, 6), (5, 3), (2, 1), (1, 1)]
+                .iter()
+                .map(|&(w, h)| compress(&image::imageops::thumbnail(&image, w, h), format))
+                .collect()
+        };
+        let levels = encode(BlockFormat::Bc7);
+
+        let ktx2 = TextureContainer::Ktx2.write(BlockFormat::Bc7, true, true, 20, 12, &levels);
+        let reader = ktx2::Reader::new(&ktx2[..]).unwrap();
+        let header = reader.header();
+        assert_eq!(header.format, Some(ktx2::Format::BC7_SRGB_BLOCK));
+        assert_eq!((header.pixel_width, header.pixel_height, header.level_count), (20, 12, 5));
+        for (level, expected) in reader.levels().zip(&levels) {
+            assert_eq!(level.data, &expected[..]);
+        }
+
+        let dds = TextureContainer::Dds.write(BlockFormat::Bc1, false, false, 20, 12, &encode(BlockFormat::Bc1));
+        let parsed = ddsfile::Dds::read(&dds[..]).unwrap();
+        assert_eq!(parsed.get_dxgi_format(), Some(ddsfile::DxgiFormat::BC1_UNorm));
+        assert_eq!((parsed.get_width(), parsed.get_height(), parsed.get_num_mipmap_levels()), (20, 12, 5));
+        assert_eq!(parsed.header10.unwrap().alpha_mode, ddsfile::AlphaMode::Straight);
+
+        let hdr = TextureContainer::Ktx2.write(BlockFormat::Bc6h, false, false, 20, 12, &encode(BlockFormat::Bc6h));
+        assert_eq!(ktx2::Reader::new(&hdr[..]).unwrap().header().format, Some(ktx2::Format::BC6H_UFLOAT_BLOCK));
+        let hdr = TextureContainer::Dds.write(BlockFormat::Rgba16f, false, false, 20, 12, &encode(BlockFormat::Rgba16f));
+        let parsed = ddsfile::Dds::read(&hdr[..]).unwrap();
+        assert_eq!(parsed.get_dxgi_format(), Some(ddsfile::DxgiFormat::R16G16B16A16_Float));
+        assert_eq!(read_container_info(&hdr).unwrap().format, BlockFormat::Rgba16f);
+
+        for (data, container) in [(&ktx2, TextureContainer::Ktx2), (&dds, TextureContainer::Dds)] {
+            let info = read_container_info(data).unwrap();
+            assert_eq!((info.container, info.width, info.height, info.levels), (container, 20, 12, 5));
+            assert_eq!(info.premultiplied, container == TextureContainer::Ktx2);
+            assert_eq!(info.level_ranges.len(), 5);
+            assert_eq!(data[info.level_ranges[4].clone()].len(), info.format.block_bytes());
+            assert!(read_container_info(&data[..data.len() - 1]).is_err());
+        }
+
+        // A 1x1 texture cannot have more than one level
+        let single = TextureContainer::Dds.write(BlockFormat::Bc1, false, false, 1, 1, &[vec![0; 8]]);
+        let mut claimed = single.clone();
+        claimed[28..32].copy_from_slice(&33u32.to_le_bytes());
+        assert_eq!(read_container_info(&single).unwrap().levels, 1);
+        assert!(read_container_info(&claimed).is_err());
+    }
+}
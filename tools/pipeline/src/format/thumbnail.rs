@@ -0,0 +1,204 @@
+use super::{ExternalFile, FormatProcessor, FormatRegistry, TextureProcessor};
+use crate::storage::{ContentStore, ObjectType};
+use crate::{ResourceMetadata, ResourceType};
+use anyhow::Result;
+use image::RgbaImage;
+use serde::{Deserialize, Serialize};
+
+/// Prefix of the derived-object kinds thumbnails are linked under.
+pub const THUMBNAIL: &str = "thumbnail";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ThumbnailOptions {
+    /// Longest side in pixels; images are shrunk to fit but never enlarged.
+    pub size: u32,
+}
+
+impl Default for ThumbnailOptions {
+    fn default() -> Self {
+        Self { size: 256 }
+    }
+}
+
+/// A stored thumbnail PNG.
+#[derive(Debug, Clone)]
+pub struct Thumbnail {
+    pub hash: String,
+    pub metadata: ResourceMetadata,
+    pub data: Vec<u8>,
+    /// Whether it was rendered now rather than found in the store.
+    pub created: bool,
+}
+
+/// The derived kind of a thumbnail, naming the processor version that made
+/// it so upgrades render thumbnails anew.
+pub fn thumbnail_kind(processor: &dyn FormatProcessor, options: &ThumbnailOptions) -> String {
+    format!("{}:{}:v{}:{}", THUMBNAIL, processor.name(), processor.version(), options.size)
+}
+
+/// Shrinks `image` to fit in a `size` square, keeping its aspect ratio.
+pub fn fit_thumbnail(image: &RgbaImage, size: u32) -> RgbaImage {
+    let (width, height) = image.dimensions();
+    let longest = width.max(height);
+    if longest <= size {
+        return image.clone();
+    }
+    let scale = |side: u32| ((side as u64 * size as u64 + longest as u64 / 2) / longest as u64).max(1) as u32;
+    image::imageops::thumbnail(image, scale(width), scale(height))
+}
+
+impl FormatRegistry {
+    /// The thumbnail of a stored object, rendered by its processor and
+    /// stored as a texture depending on the object the first time. `None`
+    /// when no processor can picture it.
+    pub fn thumbnail(&self, store: &mut ContentStore, hash: &str, options: &ThumbnailOptions) -> Result<Option<Thumbnail>> {
+        let object = store.retrieve_object(hash)?;
+        let processor = match &object.object_type {
+            ObjectType::Resource(metadata) => self.by_resource_type(&metadata.resource_type),
+            _ => self.by_magic(&object.data),
+        };
+        let Some(processor) = processor else {
+            return Ok(None);
+        };
+
+        // A model stored again with other files looks different under the
+        // same hash, so those files are part of the kind
+        let references = store.get_references(hash)?;
+        let mut kind = thumbnail_kind(processor, options);
+        if !references.is_empty() {
+            let files = blake3::hash(serde_json::to_string(&references)?.as_bytes()).to_hex();
+            kind = format!("{}:{}", kind, &files[..16]);
+        }
+        if let Some(existing) = store.get_derived(hash)?.get(&kind)
+            && let Ok(stored) = store.retrieve_object(existing)
+            && let ObjectType::Resource(metadata) = stored.object_type
+        {
+            return Ok(Some(Thumbnail { hash: existing.clone(), metadata, data: stored.data, created: false }));
+        }
+
+        // Files the object refers to come from the objects they were stored as
+        let mut files = Vec::new();
+        for (uri, reference) in references {
+            let stored = store.retrieve_object(&reference)?;
+            let resource_type = match &stored.object_type {
+                ObjectType::Resource(metadata) => metadata.resource_type.clone(),
+                _ => ResourceType::Binary,
+            };
+            files.push(ExternalFile { path: uri.clone().into(), uri, data: stored.data, resource_type });
+        }
+
+        let Some(png) = processor.thumbnail(&object.data, options, &files)? else {
+            return Ok(None);
+        };
+        let mut metadata = TextureProcessor::new().get_metadata(&png)?;
+        metadata.dependencies.push(hash.to_string());
+        let thumbnail = store.store_object(ObjectType::Resource(metadata.clone()), png.clone())?;
+        store.link_derived(hash, &kind, &thumbnail)?;
+
+        Ok(Some(Thumbnail { hash: thumbnail, metadata, data: png, created: true }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::model_loader::data_uri;
+    use crate::format::{encode_wav16, ImportSettings};
+
+    #[test]
+    fn test_thumbnails() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = ContentStore::new(dir.path()).unwrap();
+        let registry = FormatRegistry::with_defaults();
+        let options = ThumbnailOptions::default();
+        let mut store_data = |data: &[u8]| {
+            let imported = registry.import(None, data, None, &ImportSettings::default()).unwrap();
+            store.store_object(ObjectType::Resource(imported.metadata), imported.data).unwrap()
+        };
+
+        let texture = TextureProcessor::encode_png(RgbaImage::from_pixel(600, 300, image::Rgba([200, 40, 40, 255]))).unwrap();
+        let samples: Vec<i16> = (0..8000).map(|i| ((i as f32 * 0.05).sin() * 12000.0) as i16).collect();
+        let audio = encode_wav16(&samples, 8000, 2);
+
+        // A red quad facing the camera's side of the scene, in a data URI
+        let positions: Vec<u8> = [[-1.0f32, -1.0, 0.0], [1.0, -1.0, 0.0], [1.0, 1.0, 0.0], [-1.0, 1.0, 0.0]]
+            .iter()
+            .flat_map(|p| p.iter().flat_map(|c| c.to_le_bytes()))
+            .chain([0u16, 1, 2, 0, 2, 3].iter().flat_map(|i| i.to_le_bytes()))
+            .collect();
+        let uri = data_uri(&positions);
+        let model = format!(
+            r#"{{
+                "asset": {{"version": "2.0"}},
+                "scene": 0,
+                "scenes": [{{"nodes": [0]}}],
+                "nodes": [{{"mesh": 0, "scale": [2, 2, 2]}}],
+                "meshes": [{{"primitives": [{{"attributes": {{"POSITION": 0}}, "indices": 1, "material": 0}}]}}],
+                "materials": [{{"pbrMetallicRoughness": {{"baseColorFactor": [1, 0, 0, 1]}}}}],
+                "buffers": [{{"byteLength": 60, "uri": "{}"}}],
+                "bufferViews": [{{"buffer": 0, "byteLength": 48}}, {{"buffer": 0, "byteOffset": 48, "byteLength": 12}}],
+                "accessors": [
+                    {{"bufferView": 0, "componentType": 5126, "count": 4, "type": "VEC3", "min": [-1, -1, 0], "max": [1, 1, 0]}},
+                    {{"bufferView": 1, "componentType": 5123, "count": 6, "type": "SCALAR"}}
+                ]
+            }}"#,
+            uri,
+        );
+
+        // The same quad with its buffer in a file of its own
+        let split = model.replace(&uri, "quad.bin");
+
+        let texture = store_data(&texture);
+        let audio = store_data(&audio);
+        let model = store_data(model.as_bytes());
+        let buffer = store_data(&positions);
+        let metadata = crate::format::Model3DProcessor::new().get_metadata(split.as_bytes()).unwrap();
+        let split = store.store_object(ObjectType::Resource(metadata), split.into_bytes()).unwrap();
+
+        let thumb = registry.thumbnail(&mut store, &texture, &options).unwrap().unwrap();
+        let image = image::load_from_memory(&thumb.data).unwrap().to_rgba8();
+        assert_eq!(image.dimensions(), (256, 128));
+        assert_eq!(image.get_pixel(100, 60).0, [200, 40, 40, 255]);
+        assert_eq!(thumb.metadata.resource_type, ResourceType::Texture);
+        assert_eq!(thumb.metadata.dependencies, vec![texture.clone()]);
+        assert!(thumb.created);
+
+        // The second request finds the stored thumbnail
+        let again = registry.thumbnail(&mut store, &texture, &options).unwrap().unwrap();
+        assert_eq!((again.hash.as_str(), again.created), (thumb.hash.as_str(), false));
+        let kind = format!("{}:texture:v2:256", THUMBNAIL);
+        assert_eq!(store.get_derived(&texture).unwrap().get(&kind), Some(&thumb.hash));
+
+        let waveform = registry.thumbnail(&mut store, &audio, &options).unwrap().unwrap();
+        assert_eq!(image::load_from_memory(&waveform.data).unwrap().width(), 256);
+
+        let view = registry.thumbnail(&mut store, &model, &options).unwrap().unwrap();
+        let view = image::load_from_memory(&view.data).unwrap().to_rgba8();
+        assert_eq!(view.dimensions(), (256, 256));
+        assert_eq!(view.get_pixel(0, 0)[3], 0);
+        let centre = view.get_pixel(128, 128);
+        assert!(centre[3] == 255 && centre[0] > 100 && centre[1] == 0 && centre[2] == 0, "{:?}", centre);
+
+        // Renders from the stored buffer once the model records it
+        assert!(registry.thumbnail(&mut store, &split, &options).unwrap().is_none());
+        let references = [("quad.bin".to_string(), buffer)].into_iter().collect();
+        store.record_references(&split, &references).unwrap();
+        let split_view = registry.thumbnail(&mut store, &split, &options).unwrap().unwrap();
+        assert_eq!(image::load_from_memory(&split_view.data).unwrap().to_rgba8(), view);
+
+        // and renders anew when stored again with a narrower buffer
+        let scaled: Vec<u8> = positions[..48]
+            .chunks_exact(4)
+            .enumerate()
+            .flat_map(|(i, c)| (f32::from_le_bytes(c.try_into().unwrap()) * [0.25, 1.0, 1.0][i % 3]).to_le_bytes())
+            .chain(positions[48..].iter().copied())
+            .collect();
+        let scaled = store.store_object(ObjectType::Blob, scaled).unwrap();
+        store.record_references(&split, &[("quad.bin".to_string(), scaled)].into_iter().collect()).unwrap();
+        let scaled_view = registry.thumbnail(&mut store, &split, &options).unwrap().unwrap();
+        assert!(scaled_view.created && scaled_view.hash != split_view.hash);
+
+        let blob = store.store_object(ObjectType::Blob, b"plain bytes".to_vec()).unwrap();
+        assert!(registry.thumbnail(&mut store, &blob, &options).unwrap().is_none());
+    }
+}
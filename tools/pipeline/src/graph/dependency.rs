@@ -1,7 +1,10 @@
 use crate::ResourceMetadata;
+use anyhow::{Context, Result};
 use petgraph::graph::{DiGraph, NodeIndex};
 use petgraph::visit::{Dfs, EdgeRef};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::path::Path;
 
 #[derive(Debug, Clone)]
 pub struct ResourceNode {
@@ -9,12 +12,25 @@ pub struct ResourceNode {
     pub metadata: ResourceMetadata,
 }
 
+/// On-disk form of the graph: every resource, then `(from, to)` edges by hash.
+#[derive(Serialize, Deserialize)]
+struct GraphFile {
+    resources: Vec<ResourceMetadata>,
+    edges: Vec<(String, String)>,
+}
+
 #[derive(Debug)]
 pub struct DependencyGraph {
     graph: DiGraph<ResourceNode, ()>,
     pub node_indices: HashMap<String, NodeIndex>,
 }
 
+impl Default for DependencyGraph {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DependencyGraph {
     pub fn new() -> Self {
         Self {
@@ -23,7 +39,49 @@ impl DependencyGraph {
         }
     }
     
+    /// Reads a graph written by [`save`](Self::save); a missing file is an
+    /// empty graph.
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut graph = Self::new();
+        if !path.exists() {
+            return Ok(graph);
+        }
+
+        let file: GraphFile = serde_json::from_str(&std::fs::read_to_string(path)?)
+            .with_context(|| format!("Invalid dependency graph: {}", path.display()))?;
+        for metadata in file.resources {
+            graph.add_resource(metadata);
+        }
+        for (from, to) in file.edges {
+            graph.add_dependency(&from, &to);
+        }
+        Ok(graph)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let resources = self.graph.node_weights().map(|node| node.metadata.clone()).collect();
+        let edges = self
+            .graph
+            .edge_references()
+            .map(|edge| (self.graph[edge.source()].hash.clone(), self.graph[edge.target()].hash.clone()))
+            .collect();
+
+        let file = GraphFile { resources, edges };
+        std::fs::write(path, serde_json::to_string_pretty(&file)?)?;
+        Ok(())
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.node_indices.contains_key(hash)
+    }
+
+    /// Adds a resource, or updates its metadata if it is already present.
     pub fn add_resource(&mut self, metadata: ResourceMetadata) -> NodeIndex {
+        if let Some(&index) = self.node_indices.get(&metadata.hash) {
+            self.graph[index].metadata = metadata;
+            return index;
+        }
+
         let node = ResourceNode {
             hash: metadata.hash.clone(),
             metadata: metadata.clone(),
@@ -39,13 +97,26 @@ impl DependencyGraph {
             self.node_indices.get(from_hash),
             self.node_indices.get(to_hash),
         ) {
-            self.graph.add_edge(from_idx, to_idx, ());
+            self.graph.update_edge(from_idx, to_idx, ());
             true
         } else { 
             false
         }
     }
     
+    /// Makes edges to `dependencies` the only ones leaving `from_hash`, for a
+    /// resource stored again whose content is unchanged but whose
+    /// dependencies are not. Dependencies not in the graph are skipped.
+    pub fn replace_dependencies(&mut self, from_hash: &str, dependencies: &[String]) {
+        let Some(&from_idx) = self.node_indices.get(from_hash) else {
+            return;
+        };
+        self.graph.retain_edges(|graph, edge| graph.edge_endpoints(edge).is_none_or(|(from, _)| from != from_idx));
+        for dependency in dependencies {
+            self.add_dependency(from_hash, dependency);
+        }
+    }
+
     pub fn get_dependencies(&self, hash: &str) -> Vec<ResourceNode> {
         let mut dependencies = Vec::new();
         
@@ -73,6 +144,23 @@ impl DependencyGraph {
         
         dependents
     }
+
+    /// Everything that depends on `hash` directly or through other
+    /// resources, nearest first: what a change to it affects.
+    pub fn get_affected(&self, hash: &str) -> Vec<ResourceNode> {
+        let Some(&start) = self.node_indices.get(hash) else {
+            return Vec::new();
+        };
+        let reversed = petgraph::visit::Reversed(&self.graph);
+        let mut bfs = petgraph::visit::Bfs::new(reversed, start);
+        let mut affected = Vec::new();
+        while let Some(index) = bfs.next(reversed) {
+            if index != start {
+                affected.push(self.graph[index].clone());
+            }
+        }
+        affected
+    }
     
     pub fn find_circular_dependencies(&self) -> Vec<Vec<String>> {
         let mut cycles = Vec::new();
@@ -132,4 +220,65 @@ impl DependencyGraph {
             order.push(node.clone());
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ResourceProperties, ResourceType, METADATA_SCHEMA_VERSION};
+
+    fn resource(hash: &str, dependencies: &[&str]) -> ResourceMetadata {
+        ResourceMetadata {
+            schema_version: METADATA_SCHEMA_VERSION,
+            hash: hash.to_string(),
+            resource_type: ResourceType::Texture,
+            size: 0,
+            dependencies: dependencies.iter().map(|d| d.to_string()).collect(),
+            properties: ResourceProperties::None,
+        }
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let mut graph = DependencyGraph::new();
+        graph.add_resource(resource("sprite", &[]));
+        graph.add_resource(resource("atlas", &["sprite"]));
+        assert!(graph.add_dependency("atlas", "sprite"));
+        // Re-adding neither duplicates the node nor the edge
+        graph.add_resource(resource("sprite", &[]));
+        graph.add_dependency("atlas", "sprite");
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("graph.json");
+        graph.save(&path).unwrap();
+
+        let loaded = DependencyGraph::load(&path).unwrap();
+        assert_eq!(loaded.node_indices.len(), 2);
+        let dependencies: Vec<String> = loaded.get_dependencies("atlas").into_iter().map(|n| n.hash).collect();
+        assert_eq!(dependencies, vec!["sprite"]);
+        assert_eq!(loaded.get_dependents("sprite").len(), 1);
+
+        // A change to the sprite reaches whatever uses the atlas too
+        let mut graph = loaded;
+        graph.add_resource(resource("level", &["atlas"]));
+        graph.add_dependency("level", "atlas");
+        let affected: Vec<String> = graph.get_affected("sprite").into_iter().map(|n| n.hash).collect();
+        assert_eq!(affected, vec!["atlas", "level"]);
+        assert!(graph.get_affected("level").is_empty());
+        assert!(DependencyGraph::load(&dir.path().join("missing.json")).unwrap().node_indices.is_empty());
+
+        // A model stored again after its buffer changed keeps its hash but
+        // depends on the new buffer alone
+        graph.add_resource(resource("model", &["bin-1", "albedo"]));
+        graph.add_resource(resource("bin-1", &[]));
+        graph.add_resource(resource("bin-2", &[]));
+        graph.add_resource(resource("albedo", &[]));
+        graph.replace_dependencies("model", &["bin-1".to_string(), "albedo".to_string()]);
+        graph.replace_dependencies("model", &["bin-2".to_string(), "albedo".to_string()]);
+        let mut dependencies: Vec<String> = graph.get_dependencies("model").into_iter().map(|n| n.hash).collect();
+        dependencies.sort();
+        assert_eq!(dependencies, vec!["albedo", "bin-2"]);
+        assert!(graph.get_affected("bin-1").is_empty());
+        assert_eq!(graph.get_dependents("albedo").len(), 1);
+    }
+}
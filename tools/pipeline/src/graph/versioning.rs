@@ -20,6 +20,12 @@ pub struct VersionManager {
     branches: HashMap<String, String>, // branch_name -> version_id
 }
 
+impl Default for VersionManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl VersionManager {
     pub fn new() -> Self {
         let mut version = HashMap::new();
@@ -165,4 +171,77 @@ impl VersionManager {
         history.sort_by_key(|v| v.timestamp);
         history
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{FormatRegistry, ImportSettings};
+    use crate::metadata::Provenance;
+    use crate::storage::{ContentStore, ObjectType};
+    use std::path::Path;
+
+    fn write_tree(root: &Path) {
+        std::fs::create_dir_all(root.join("textures")).unwrap();
+        std::fs::create_dir_all(root.join("models")).unwrap();
+
+        let texture = image::RgbaImage::from_fn(8, 8, |x, y| image::Rgba([x as u8 * 30, y as u8 * 30, 0, 255]));
+        texture.save(root.join("textures/checker.png")).unwrap();
+        std::fs::write(
+            root.join("models/box.gltf"),
+            br#"{"asset": {"version": "2.0"}, "nodes": [{"name": "Box"}]}"#,
+        )
+        .unwrap();
+        std::fs::write(root.join("notes.bin"), b"\x00\x01\x02 raw bytes").unwrap();
+    }
+
+    fn import_tree(root: &Path, storage: &Path, imported_at: i64) -> (String, Vec<(String, Vec<u8>)>) {
+        let registry = FormatRegistry::with_defaults();
+        let mut store = ContentStore::new(storage).unwrap();
+        let mut resources = HashMap::new();
+        let mut objects = Vec::new();
+
+        for name in ["textures/checker.png", "models/box.gltf", "notes.bin"] {
+            let path = root.join(name);
+            let data = std::fs::read(&path).unwrap();
+            let imported = registry.import(Some(&path), &data, None, &ImportSettings::default()).unwrap();
+
+            let hash = store
+                .store_object(ObjectType::Resource(imported.metadata.clone()), imported.data)
+                .unwrap();
+            let provenance = Provenance { imported_at, ..imported.provenance };
+            store.record_provenance(&hash, &provenance).unwrap();
+
+            let object_path = storage.join(&hash[0..2]).join(&hash[2..]);
+            objects.push((hash, std::fs::read(object_path).unwrap()));
+            resources.insert(name.to_string(), imported.metadata);
+        }
+
+        let mut versions = VersionManager::new();
+        let id = versions
+            .create_version(vec!["initial".to_string()], "Import".to_string(), resources, "ci")
+            .unwrap();
+        (id, objects)
+    }
+
+    #[test]
+    fn test_reimport_is_reproducible() {
+        let tree = tempfile::tempdir().unwrap();
+        let first_store = tempfile::tempdir().unwrap();
+        let second_store = tempfile::tempdir().unwrap();
+        write_tree(tree.path());
+
+        // A day apart, so import times must not reach the version id
+        let (first_id, first_objects) = import_tree(tree.path(), first_store.path(), 1_700_000_000);
+        let (second_id, second_objects) = import_tree(tree.path(), second_store.path(), 1_700_086_400);
+
+        assert_eq!(first_id, second_id);
+        assert_eq!(first_objects, second_objects);
+
+        let store = ContentStore::new(second_store.path()).unwrap();
+        let provenance = store.get_provenance(&second_objects[0].0).unwrap();
+        assert_eq!(provenance.len(), 1);
+        assert_eq!(provenance[0].imported_at, 1_700_086_400);
+        assert!(provenance[0].importer.starts_with("texture"));
+    }
+}
@@ -2,11 +2,15 @@ pub mod storage;
 pub mod format;
 pub mod graph;
 pub mod cli;
+pub mod metadata;
+
+pub use metadata::*;
 
 use bincode::{Decode, Encode};
 use serde::{Deserialize, Serialize};
+use std::str::FromStr;
 
-#[derive(Debug, Clone, Serialize, Deserialize, Encode, Decode)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Encode, Decode)]
 pub enum ResourceType {
     Texture,
     Model3D,
@@ -14,11 +18,32 @@ pub enum ResourceType {
     Binary,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Encode, Decode)]
+impl FromStr for ResourceType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "texture" => Ok(ResourceType::Texture),
+            "model3d" | "model" => Ok(ResourceType::Model3D),
+            "audio" => Ok(ResourceType::Audio),
+            "binary" => Ok(ResourceType::Binary),
+            _ => Err(anyhow::anyhow!("Unknown resource type: {}", s)),
+        }
+    }
+}
+
+/// Facts derived purely from a resource's content, so importing the same data
+/// twice yields equal metadata. See [`Provenance`] for import details.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Encode, Decode)]
 pub struct ResourceMetadata {
+    /// Kept first so that older layouts can be told apart when decoding.
+    #[serde(default = "metadata::legacy_schema_version")]
+    pub schema_version: u32,
     pub hash: String,
     pub resource_type: ResourceType,
     pub size: u64,
-    pub create_at: i64,
+    /// Hashes of other resources this one references.
     pub dependencies: Vec<String>,
+    #[serde(default)]
+    pub properties: ResourceProperties,
 }
\ No newline at end of file
@@ -0,0 +1,283 @@
+use crate::{ResourceMetadata, ResourceType};
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+
+/// Version of the [`ResourceMetadata`] layout written by this build.
+///
+/// Version 1 had no typed properties and encoded facts such as
+/// `"texture:1024x1024"` in `dependencies`. Version 2 added typed properties
+/// and dropped the import timestamp, which now lives in [`Provenance`].
+pub const METADATA_SCHEMA_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Encode, Decode)]
+pub enum ColorSpace {
+    Srgb,
+    Linear,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Encode, Decode)]
+pub struct TextureProperties {
+    pub width: u32,
+    pub height: u32,
+    pub format: Option<String>,
+    pub color_type: Option<String>,
+    pub color_space: ColorSpace,
+    /// Set for floating-point textures.
+    #[serde(default)]
+    pub dynamic_range: Option<DynamicRange>,
+    /// Layer names of multi-layer images such as OpenEXR, in file order.
+    #[serde(default)]
+    pub layers: Vec<String>,
+    #[serde(default)]
+    pub perceptual_hashes: Option<PerceptualHashes>,
+}
+
+/// 64-bit fingerprints of a texture's appearance, which stay close in
+/// Hamming distance when it is resized, recompressed or slightly edited.
+/// Written as hex strings, since JSON numbers cannot hold 64 bits exactly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Encode, Decode)]
+pub struct PerceptualHashes {
+    /// Which pixels of an 8x8 thumbnail are brighter than its mean.
+    #[serde(with = "hex_u64")]
+    pub average: u64,
+    /// Which low-frequency DCT coefficients of a 32x32 thumbnail are above
+    /// their median.
+    #[serde(with = "hex_u64")]
+    pub perceptual: u64,
+    /// Which pixels of a 9x8 thumbnail are brighter than their right-hand
+    /// neighbour.
+    #[serde(with = "hex_u64")]
+    pub difference: u64,
+}
+
+mod hex_u64 {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:016x}", value))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        u64::from_str_radix(&hex, 16).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Luminance statistics of an HDR texture, in linear scene units.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Encode, Decode)]
+pub struct DynamicRange {
+    /// Smallest non-zero luminance.
+    pub min_luminance: f32,
+    pub max_luminance: f32,
+    /// Geometric mean, the usual key for auto exposure.
+    pub average_luminance: f32,
+    /// Ratio of the maximum to the minimum in stops.
+    pub stops: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Encode, Decode)]
+pub struct Bounds {
+    pub min: [f32; 3],
+    pub max: [f32; 3],
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Encode, Decode)]
+pub struct ModelProperties {
+    pub scenes: u32,
+    pub meshes: u32,
+    pub nodes: u32,
+    pub materials: u32,
+    pub animations: u32,
+    pub skins: u32,
+    /// Union of the mesh position bounds, in mesh space.
+    pub bounds: Option<Bounds>,
+}
+
+/// A sustain loop, in sample frames from the start of the audio. `end` is
+/// exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Encode, Decode)]
+pub struct LoopPoint {
+    pub start: u64,
+    pub end: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Encode, Decode)]
+pub struct AudioProperties {
+    pub codec: String,
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub bits_per_sample: Option<u32>,
+    pub duration_secs: f64,
+    #[serde(default)]
+    pub loop_points: Vec<LoopPoint>,
+}
+
+/// Facts about a resource's content, typed per resource type.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, Encode, Decode)]
+pub enum ResourceProperties {
+    #[default]
+    None,
+    Texture(TextureProperties),
+    Model3D(ModelProperties),
+    Audio(AudioProperties),
+}
+
+/// Where and when a resource was imported. Unlike [`ResourceMetadata`] this
+/// differs between imports of the same content, so it never feeds into
+/// content hashes or version ids.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+    pub imported_at: i64,
+    pub importer: String,
+    pub host: String,
+}
+
+impl Provenance {
+    /// Provenance for an import happening now on this machine.
+    pub fn now(importer: &str) -> Self {
+        let host = std::env::var("HOSTNAME")
+            .or_else(|_| std::env::var("COMPUTERNAME"))
+            .ok()
+            .or_else(|| {
+                std::fs::read_to_string("/etc/hostname")
+                    .ok()
+                    .map(|name| name.trim().to_string())
+            })
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| "unknown".to_string());
+
+        Self {
+            imported_at: chrono::Utc::now().timestamp(),
+            importer: format!("{} ({} {})", importer, env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            host,
+        }
+    }
+}
+
+pub(crate) fn legacy_schema_version() -> u32 {
+    1
+}
+
+/// Layout of [`ResourceMetadata`] at schema version 1, kept for decoding
+/// objects written before typed properties existed.
+#[derive(Debug, Clone, Encode, Decode)]
+pub(crate) struct LegacyResourceMetadata {
+    pub hash: String,
+    pub resource_type: ResourceType,
+    pub size: u64,
+    pub create_at: i64,
+    pub dependencies: Vec<String>,
+}
+
+impl From<LegacyResourceMetadata> for ResourceMetadata {
+    fn from(legacy: LegacyResourceMetadata) -> Self {
+        ResourceMetadata {
+            schema_version: legacy_schema_version(),
+            hash: legacy.hash,
+            resource_type: legacy.resource_type,
+            size: legacy.size,
+            dependencies: legacy.dependencies,
+            properties: ResourceProperties::None,
+        }
+        .migrate()
+    }
+}
+
+impl ResourceMetadata {
+    /// Upgrades metadata from an older schema to [`METADATA_SCHEMA_VERSION`].
+    ///
+    /// Version 1 property strings are moved out of `dependencies` into typed
+    /// properties and its import timestamp is dropped. Counts, hashes and
+    /// other facts version 1 never recorded stay empty until the resource is
+    /// re-imported.
+    pub fn migrate(mut self) -> Self {
+        if self.schema_version >= METADATA_SCHEMA_VERSION {
+            return self;
+        }
+
+        let mut texture: Option<TextureProperties> = None;
+        let mut model: Option<ModelProperties> = None;
+        let mut dependencies = Vec::new();
+
+        for dependency in std::mem::take(&mut self.dependencies) {
+            if let Some((width, height)) = dependency
+                .strip_prefix("texture:")
+                .and_then(|size| size.split_once('x'))
+                .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+            {
+                texture = Some(TextureProperties {
+                    width,
+                    height,
+                    format: None,
+                    color_type: None,
+                    color_space: ColorSpace::Srgb,
+                    dynamic_range: None,
+                    layers: Vec::new(),
+                    perceptual_hashes: None,
+                });
+            } else if let Some((key, value)) = dependency
+                .strip_prefix("gltf:")
+                .and_then(|fact| fact.split_once('='))
+                .and_then(|(key, value)| Some((key.to_string(), value.parse::<u32>().ok()?)))
+            {
+                let model = model.get_or_insert(ModelProperties {
+                    scenes: 0,
+                    meshes: 0,
+                    nodes: 0,
+                    materials: 0,
+                    animations: 0,
+                    skins: 0,
+                    bounds: None,
+                });
+                match key.as_str() {
+                    "scenes" => model.scenes = value,
+                    "meshes" => model.meshes = value,
+                    "nodes" => model.nodes = value,
+                    _ => {}
+                }
+            } else if dependency != "audio:raw" {
+                dependencies.push(dependency);
+            }
+        }
+
+        self.properties = match (texture, model) {
+            (Some(texture), _) => ResourceProperties::Texture(texture),
+            (_, Some(model)) => ResourceProperties::Model3D(model),
+            _ => ResourceProperties::None,
+        };
+        self.dependencies = dependencies;
+        self.schema_version = METADATA_SCHEMA_VERSION;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate_legacy_metadata() {
+        let legacy = LegacyResourceMetadata {
+            hash: "abc".to_string(),
+            resource_type: ResourceType::Model3D,
+            size: 10,
+            create_at: 0,
+            dependencies: vec![
+                "gltf:scenes=1".to_string(),
+                "gltf:meshes=3".to_string(),
+                "gltf:nodes=4".to_string(),
+                "0123abcd".to_string(),
+            ],
+        };
+
+        let metadata = ResourceMetadata::from(legacy);
+        assert_eq!(metadata.schema_version, METADATA_SCHEMA_VERSION);
+        assert_eq!(metadata.dependencies, vec!["0123abcd".to_string()]);
+        match metadata.properties {
+            ResourceProperties::Model3D(model) => {
+                assert_eq!((model.scenes, model.meshes, model.nodes), (1, 3, 4));
+            }
+            other => panic!("unexpected properties: {:?}", other),
+        }
+    }
+}
@@ -1,9 +1,10 @@
-use crate::ResourceMetadata;
-use anyhow::{Context, Result};
+use crate::metadata::LegacyResourceMetadata;
+use crate::{Provenance, ResourceMetadata, METADATA_SCHEMA_VERSION};
+use anyhow::{anyhow, Context, Result};
 use bincode::{Decode, Encode};
 use blake3::Hasher;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::path::{Path, PathBuf};
 use tracing::info;
@@ -16,6 +17,40 @@ pub enum ObjectType {
     Resource(ResourceMetadata),
 }
 
+/// Object layouts written with older metadata schema versions, generic over
+/// the metadata layout of that version.
+#[derive(Debug, Clone, Decode)]
+enum LegacyObjectType<M> {
+    Blob,
+    Tree,
+    Commit,
+    Resource(M),
+}
+
+#[derive(Debug, Clone, Decode)]
+struct LegacyContentObject<M> {
+    object_type: LegacyObjectType<M>,
+    data: Vec<u8>,
+    hash: String,
+}
+
+impl<M: Into<ResourceMetadata>> From<LegacyContentObject<M>> for ContentObject {
+    fn from(legacy: LegacyContentObject<M>) -> Self {
+        let object_type = match legacy.object_type {
+            LegacyObjectType::Blob => ObjectType::Blob,
+            LegacyObjectType::Tree => ObjectType::Tree,
+            LegacyObjectType::Commit => ObjectType::Commit,
+            LegacyObjectType::Resource(metadata) => ObjectType::Resource(metadata.into()),
+        };
+
+        ContentObject {
+            object_type,
+            data: legacy.data,
+            hash: legacy.hash,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Encode, Decode)]
 pub struct ContentObject {
     pub object_type: ObjectType,
@@ -62,8 +97,34 @@ impl ContentObject {
         let data = fs::read(&object_path)
             .with_context(|| format!("Failed to read object: {}", hash))?;
 
-        let (object, _): (ContentObject, usize) = bincode::decode_from_slice(&data, bincode::config::standard())?;
-        Ok(object)
+        Self::decode(&data).with_context(|| format!("Failed to decode object: {}", hash))
+    }
+
+    /// Decodes an object in the current layout, falling back to the version 1
+    /// layout and migrating its metadata.
+    ///
+    /// Current metadata starts with the schema version, while version 1
+    /// starts with the hash length, which is never a valid version.
+    fn decode(data: &[u8]) -> Result<Self> {
+        fn decode_exact<T: Decode<()>>(data: &[u8]) -> Option<T> {
+            match bincode::decode_from_slice(data, bincode::config::standard()) {
+                Ok((value, read)) if read == data.len() => Some(value),
+                _ => None,
+            }
+        }
+
+        if let Some(object) = decode_exact::<ContentObject>(data)
+            && match &object.object_type {
+                ObjectType::Resource(metadata) => metadata.schema_version == METADATA_SCHEMA_VERSION,
+                _ => true,
+            }
+        {
+            return Ok(object);
+        }
+
+        decode_exact::<LegacyContentObject<LegacyResourceMetadata>>(data)
+            .map(ContentObject::from)
+            .ok_or_else(|| anyhow!("Unrecognized object layout"))
     }
 }
 
@@ -112,7 +173,214 @@ impl ContentStore {
             .exists()
     }
 
+    /// Hashes of every object on disk, sorted.
+    pub fn list_objects(&self) -> Result<Vec<String>> {
+        let mut hashes = Vec::new();
+        for entry in fs::read_dir(&self.storage_path)? {
+            let entry = entry?;
+            let prefix = entry.file_name().to_string_lossy().into_owned();
+            if prefix.len() != 2 || !prefix.bytes().all(|b| b.is_ascii_hexdigit()) || !entry.file_type()?.is_dir() {
+                continue;
+            }
+            for object in fs::read_dir(entry.path())? {
+                hashes.push(format!("{}{}", prefix, object?.file_name().to_string_lossy()));
+            }
+        }
+        hashes.sort();
+        Ok(hashes)
+    }
+
     pub fn get_storage_path(&self) -> &Path {
         &self.storage_path
     }
-}
\ No newline at end of file
+
+    fn provenance_path(&self, hash: &str) -> PathBuf {
+        self.storage_path.join("provenance").join(format!("{}.jsonl", hash))
+    }
+
+    /// Appends an import record for the object, kept outside the object itself
+    /// so that identical content always produces identical objects.
+    pub fn record_provenance(&self, hash: &str, provenance: &Provenance) -> Result<()> {
+        use std::io::Write;
+
+        let path = self.provenance_path(hash);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{}", serde_json::to_string(provenance)?)?;
+        Ok(())
+    }
+
+    fn derived_path(&self, hash: &str) -> PathBuf {
+        self.storage_path.join("derived").join(format!("{}.json", hash))
+    }
+
+    /// Links an object generated from `source`, such as a preview image,
+    /// under `kind`. A later link of the same kind replaces the earlier one.
+    pub fn link_derived(&self, source: &str, kind: &str, derived: &str) -> Result<()> {
+        let mut links = self.get_derived(source)?;
+        links.insert(kind.to_string(), derived.to_string());
+
+        let path = self.derived_path(source);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(&links)?)?;
+        Ok(())
+    }
+
+    /// Hashes of the objects derived from `source`, by kind.
+    pub fn get_derived(&self, source: &str) -> Result<BTreeMap<String, String>> {
+        let path = self.derived_path(source);
+        if !path.exists() {
+            return Ok(BTreeMap::new());
+        }
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    fn references_path(&self, hash: &str) -> PathBuf {
+        self.storage_path.join("references").join(format!("{}.json", hash))
+    }
+
+    /// Records the stored object each URI in `hash` refers to, such as a
+    /// glTF model's buffers and images, replacing what was recorded before.
+    pub fn record_references(&self, hash: &str, references: &BTreeMap<String, String>) -> Result<()> {
+        let path = self.references_path(hash);
+        if references.is_empty() {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(references)?)?;
+        Ok(())
+    }
+
+    /// Hashes of the objects `hash` refers to, by the URI it uses.
+    pub fn get_references(&self, hash: &str) -> Result<BTreeMap<String, String>> {
+        let path = self.references_path(hash);
+        if !path.exists() {
+            return Ok(BTreeMap::new());
+        }
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    fn import_cache_path(&self, key: &str) -> PathBuf {
+        self.storage_path.join("cache").join(key)
+    }
+
+    /// Remembers that the import identified by `key` produced `hash`.
+    pub fn record_import_cache(&self, key: &str, hash: &str) -> Result<()> {
+        let path = self.import_cache_path(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, hash)?;
+        Ok(())
+    }
+
+    /// The object an earlier import with the same `key` produced, if it is
+    /// still in the store.
+    pub fn cached_import(&self, key: &str) -> Result<Option<String>> {
+        let path = self.import_cache_path(key);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let hash = fs::read_to_string(path)?.trim().to_string();
+        Ok((hash.len() > 2 && self.object_exists(&hash)).then_some(hash))
+    }
+
+    fn source_paths_path(&self) -> PathBuf {
+        self.storage_path.join("paths.json")
+    }
+
+    /// Remembers that the file at `path` was last stored as `hash`, returning
+    /// the hash it was stored as before if its content has changed since.
+    pub fn record_source_path(&self, path: &Path, hash: &str) -> Result<Option<String>> {
+        let index_path = self.source_paths_path();
+        let mut paths: BTreeMap<String, String> = match index_path.exists() {
+            true => serde_json::from_str(&fs::read_to_string(&index_path)?)?,
+            false => BTreeMap::new(),
+        };
+
+        let key = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()).to_string_lossy().into_owned();
+        let previous = paths.insert(key, hash.to_string());
+        if previous.as_deref() != Some(hash) {
+            fs::write(index_path, serde_json::to_string_pretty(&paths)?)?;
+        }
+        Ok(previous.filter(|previous| previous != hash))
+    }
+
+    /// All recorded imports of the object, oldest first.
+    pub fn get_provenance(&self, hash: &str) -> Result<Vec<Provenance>> {
+        let path = self.provenance_path(hash);
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+
+        fs::read_to_string(path)?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| Ok(serde_json::from_str(line)?))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ResourceType;
+
+    #[test]
+    fn test_load_legacy_object() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = b"fake texture".to_vec();
+        let hash = ContentObject::compute_hash(&data);
+
+        #[derive(Encode)]
+        struct V1Metadata {
+            hash: String,
+            resource_type: ResourceType,
+            size: u64,
+            create_at: i64,
+            dependencies: Vec<String>,
+        }
+
+        // Variant index 3 is `Resource`, as in the v1 `ObjectType`
+        let mut encoded = bincode::encode_to_vec(3u32, bincode::config::standard()).unwrap();
+        encoded.extend(bincode::encode_to_vec(
+            (
+                V1Metadata {
+                    hash: hash.clone(),
+                    resource_type: ResourceType::Texture,
+                    size: data.len() as u64,
+                    create_at: 0,
+                    dependencies: vec!["texture:64x32".to_string()],
+                },
+                data,
+                hash.clone(),
+            ),
+            bincode::config::standard(),
+        ).unwrap());
+
+        fs::create_dir_all(dir.path().join(&hash[0..2])).unwrap();
+        fs::write(dir.path().join(&hash[0..2]).join(&hash[2..]), encoded).unwrap();
+
+        let object = ContentObject::load_from_disk(dir.path(), &hash).unwrap();
+        assert_eq!(ContentStore::new(dir.path()).unwrap().list_objects().unwrap(), vec![hash.clone()]);
+        let ObjectType::Resource(metadata) = object.object_type else {
+            panic!("expected a resource");
+        };
+        assert_eq!(metadata.schema_version, METADATA_SCHEMA_VERSION);
+        assert!(metadata.dependencies.is_empty());
+        assert!(matches!(
+            metadata.properties,
+            crate::ResourceProperties::Texture(crate::TextureProperties { width: 64, height: 32, .. }),
+        ));
+    }
+}
@@ -1,12 +1,168 @@
 
+use anyhow::{ensure, Result};
+use bincode::{Decode, Encode};
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::cmp::min;
+use std::fmt;
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::path::Path;
+
+/// Segments buffered per rayon thread in each batch of a streaming diff.
+const SEGMENTS_PER_THREAD: usize = 4;
+
+/// A region of the new input whose bytes differ from the old input. Bytes
+/// dropped from the end of the old input are reported as a range starting at
+/// the end of the new input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangedRange {
+    pub offset: u64,
+    pub len: u64,
+    /// The new input's bytes in the range, short of `len` by any dropped tail.
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct StreamingDiff {
+    pub old_len: u64,
+    pub new_len: u64,
+    pub old_hash: String,
+    pub new_hash: String,
+    pub changed: Vec<ChangedRange>,
+}
+
+impl StreamingDiff {
+    pub fn is_identical(&self) -> bool {
+        self.old_len == self.new_len && self.changed.is_empty()
+    }
+
+    pub fn changed_bytes(&self) -> u64 {
+        self.changed.iter().map(|range| range.len).sum()
+    }
+
+    /// A patch that turns the old input into the new one. Each range replaces
+    /// the old bytes at its offset, so positions hold in forward order.
+    pub fn into_patch(self) -> Patch {
+        let old_len = self.old_len;
+        let operations = self
+            .changed
+            .into_iter()
+            .map(|range| {
+                let delete_len = min(range.offset + range.len, old_len).saturating_sub(range.offset);
+                (range.offset as usize, delete_len as usize, range.data)
+            })
+            .collect();
+        Patch {
+            base_len: self.old_len,
+            base_hash: self.old_hash,
+            target_len: self.new_len,
+            target_hash: self.new_hash,
+            operations,
+        }
+    }
+
+    fn push(&mut self, range: ChangedRange) {
+        match self.changed.last_mut() {
+            Some(last) if last.offset + last.len == range.offset => {
+                last.len += range.len;
+                last.data.extend(range.data);
+            }
+            _ => self.changed.push(range),
+        }
+    }
+}
+
+/// A diff together with the identity of the base it applies to and the result
+/// it must produce.
+#[derive(Debug, Clone, Serialize, Deserialize, Encode, Decode)]
+pub struct Patch {
+    pub base_len: u64,
+    pub base_hash: String,
+    pub target_len: u64,
+    pub target_hash: String,
+    pub operations: Vec<(usize, usize, Vec<u8>)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatchError {
+    BaseLengthMismatch { expected: u64, actual: u64 },
+    BaseHashMismatch { expected: String, actual: String },
+    /// The operation's position lies past the end of the data it applies to.
+    PositionOutOfRange { operation: usize, position: usize, len: usize },
+    /// The operation deletes past the end of the data it applies to.
+    DeleteOutOfRange { operation: usize, position: usize, delete_len: usize, len: usize },
+    TargetLengthMismatch { expected: u64, actual: u64 },
+    TargetHashMismatch { expected: String, actual: String },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchError::BaseLengthMismatch { expected, actual } => {
+                write!(f, "Base is {} bytes, patch expects {}", actual, expected)
+            }
+            PatchError::BaseHashMismatch { expected, actual } => {
+                write!(f, "Base hash {} does not match patch base {}", actual, expected)
+            }
+            PatchError::PositionOutOfRange { operation, position, len } => write!(
+                f,
+                "Operation {}: position {} is past the end of {} bytes",
+                operation, position, len,
+            ),
+            PatchError::DeleteOutOfRange { operation, position, delete_len, len } => write!(
+                f,
+                "Operation {}: deleting {} bytes at {} overruns {} bytes",
+                operation, delete_len, position, len,
+            ),
+            PatchError::TargetLengthMismatch { expected, actual } => {
+                write!(f, "Patched result is {} bytes, expected {}", actual, expected)
+            }
+            PatchError::TargetHashMismatch { expected, actual } => {
+                write!(f, "Patched result hash {} does not match expected {}", actual, expected)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+/// Fills `buf` from `reader`, returning fewer bytes only at end of input.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(filled)
+}
+
+/// First and one-past-last differing offsets within a segment; bytes past the
+/// end of `old` always count as changed.
+fn changed_span(old: &[u8], new: &[u8]) -> Option<(usize, usize)> {
+    let common = min(old.len(), new.len());
+    if old[..common] == new[..common] && common == new.len() {
+        return None;
+    }
+
+    let first = old.iter().zip(new).position(|(a, b)| a != b).unwrap_or(common);
+    let last = if new.len() > old.len() {
+        new.len()
+    } else {
+        common - old[first..common].iter().rev().zip(new[first..].iter().rev()).position(|(a, b)| a != b).unwrap_or(0)
+    };
+    Some((first, last))
+}
 
 pub struct DiffEngine;
 
 impl DiffEngine {
     pub fn compute_chunked_diff(old: &[u8], new: &[u8], chunk_size: usize) -> Vec<(usize, Vec<u8>)> {
-        let chunks = new.par_chunks(chunk_size).enumerate().filter_map(|(i, chunk)| {
+        new.par_chunks(chunk_size).enumerate().filter_map(|(i, chunk)| {
             let start = i * chunk_size;
             let end = min(start + chunk_size, new.len());
 
@@ -15,9 +171,78 @@ impl DiffEngine {
             } else {
                 None
             }
-        }).collect();
+        }).collect()
+    }
+
+    /// Compares two streams in batches of `segment_size` segments, diffing the
+    /// segments of each batch in parallel.
+    ///
+    /// Memory use is bounded by two batch buffers of
+    /// `segment_size * threads * SEGMENTS_PER_THREAD` bytes regardless of input
+    /// size, plus the changed bytes kept in the result. Offsets in the result
+    /// are relative to the new input; a truncated tail is reported as a final
+    /// range at `new_len`. See [`StreamingDiff::into_patch`] to apply it.
+    pub fn compute_streaming_diff(
+        mut old: impl Read,
+        mut new: impl Read,
+        segment_size: usize,
+    ) -> Result<StreamingDiff> {
+        ensure!(segment_size > 0, "Segment size must be non-zero");
+
+        let batch_len = segment_size * rayon::current_num_threads() * SEGMENTS_PER_THREAD;
+        let mut old_buf = vec![0u8; batch_len];
+        let mut new_buf = vec![0u8; batch_len];
+        let mut diff = StreamingDiff::default();
+        let (mut old_hasher, mut new_hasher) = (blake3::Hasher::new(), blake3::Hasher::new());
+
+        loop {
+            let old_read = read_full(&mut old, &mut old_buf)?;
+            let new_read = read_full(&mut new, &mut new_buf)?;
+            if old_read == 0 && new_read == 0 {
+                break;
+            }
+            old_hasher.update(&old_buf[..old_read]);
+            new_hasher.update(&new_buf[..new_read]);
+
+            let base = diff.new_len;
+            let old_batch = &old_buf[..old_read];
+            let ranges: Vec<ChangedRange> = new_buf[..new_read]
+                .par_chunks(segment_size)
+                .enumerate()
+                .filter_map(|(i, chunk)| {
+                    let start = i * segment_size;
+                    let old_chunk = &old_batch[min(start, old_read)..min(start + chunk.len(), old_read)];
+                    changed_span(old_chunk, chunk).map(|(first, last)| ChangedRange {
+                        offset: base + (start + first) as u64,
+                        len: (last - first) as u64,
+                        data: chunk[first..last].to_vec(),
+                    })
+                })
+                .collect();
+
+            for range in ranges {
+                diff.push(range);
+            }
+            diff.old_len += old_read as u64;
+            diff.new_len += new_read as u64;
+        }
 
-        chunks
+        if diff.old_len > diff.new_len {
+            diff.push(ChangedRange { offset: diff.new_len, len: diff.old_len - diff.new_len, data: Vec::new() });
+        }
+        diff.old_hash = old_hasher.finalize().to_string();
+        diff.new_hash = new_hasher.finalize().to_string();
+
+        Ok(diff)
+    }
+
+    /// Streaming diff of two files on disk, see [`DiffEngine::compute_streaming_diff`].
+    pub fn compute_file_diff(
+        old: impl AsRef<Path>,
+        new: impl AsRef<Path>,
+        segment_size: usize,
+    ) -> Result<StreamingDiff> {
+        Self::compute_streaming_diff(File::open(old)?, File::open(new)?, segment_size)
     }
 
     pub fn compute_binary_diff(old: &[u8], new: &[u8]) -> Vec<(usize, usize, Vec<u8>)> {
@@ -76,23 +301,75 @@ impl DiffEngine {
         diffs
     }
 
-    pub fn apply_diff(base: &[u8], diff: &[(usize, usize, Vec<u8>)]) -> Vec<u8> {
+    /// Applies operations in order, each relative to the result of the ones
+    /// before it. Any operation that does not fit the data is rejected.
+    pub fn apply_diff(base: &[u8], diff: &[(usize, usize, Vec<u8>)]) -> Result<Vec<u8>, PatchError> {
         let mut result = base.to_vec();
         
         // Process operations in forward order to maintain correct positioning
-        for &(pos, delete_len, ref insert_data) in diff {
-            if pos <= result.len() {
-                // First delete, then insert at the same position
-                if delete_len > 0 && pos + delete_len <= result.len() {
-                    result.drain(pos..pos + delete_len);
-                }
-                if !insert_data.is_empty() {
-                    result.splice(pos..pos, insert_data.iter().cloned());
-                }
+        for (operation, &(pos, delete_len, ref insert_data)) in diff.iter().enumerate() {
+            let len = result.len();
+            if pos > len {
+                return Err(PatchError::PositionOutOfRange { operation, position: pos, len });
             }
+
+            let end = pos
+                .checked_add(delete_len)
+                .filter(|&end| end <= len)
+                .ok_or(PatchError::DeleteOutOfRange { operation, position: pos, delete_len, len })?;
+
+            // Delete and insert at the same position in one step
+            result.splice(pos..end, insert_data.iter().cloned());
         }
         
-        result
+        Ok(result)
+    }
+
+    pub fn create_patch(old: &[u8], new: &[u8]) -> Patch {
+        Patch {
+            base_len: old.len() as u64,
+            base_hash: blake3::hash(old).to_string(),
+            target_len: new.len() as u64,
+            target_hash: blake3::hash(new).to_string(),
+            operations: Self::compute_binary_diff(old, new),
+        }
+    }
+
+    /// Applies a patch after checking that `base` is the data it was made
+    /// from, then verifies the result against the patch's target.
+    pub fn apply_patch(base: &[u8], patch: &Patch) -> Result<Vec<u8>, PatchError> {
+        if base.len() as u64 != patch.base_len {
+            return Err(PatchError::BaseLengthMismatch {
+                expected: patch.base_len,
+                actual: base.len() as u64,
+            });
+        }
+
+        let base_hash = blake3::hash(base).to_string();
+        if base_hash != patch.base_hash {
+            return Err(PatchError::BaseHashMismatch {
+                expected: patch.base_hash.clone(),
+                actual: base_hash,
+            });
+        }
+
+        let result = Self::apply_diff(base, &patch.operations)?;
+        if result.len() as u64 != patch.target_len {
+            return Err(PatchError::TargetLengthMismatch {
+                expected: patch.target_len,
+                actual: result.len() as u64,
+            });
+        }
+
+        let target_hash = blake3::hash(&result).to_string();
+        if target_hash != patch.target_hash {
+            return Err(PatchError::TargetHashMismatch {
+                expected: patch.target_hash.clone(),
+                actual: target_hash,
+            });
+        }
+
+        Ok(result)
     }
 }
 
@@ -106,8 +383,81 @@ mod tests {
         let new = b"hello there";
         
         let diff = DiffEngine::compute_binary_diff(old, new);
-        let patched = DiffEngine::apply_diff(old, &diff);
+        let patched = DiffEngine::apply_diff(old, &diff).unwrap();
         
         assert_eq!(patched, new);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_apply_rejects_malformed_patches() {
+        let base = b"hello world";
+
+        assert_eq!(
+            DiffEngine::apply_diff(base, &[(0, 5, b"howdy".to_vec()), (12, 0, vec![])]),
+            Err(PatchError::PositionOutOfRange { operation: 1, position: 12, len: 11 }),
+        );
+        assert_eq!(
+            DiffEngine::apply_diff(base, &[(6, usize::MAX, vec![])]),
+            Err(PatchError::DeleteOutOfRange { operation: 0, position: 6, delete_len: usize::MAX, len: 11 }),
+        );
+
+        let patch = DiffEngine::create_patch(base, b"hello there");
+        assert_eq!(DiffEngine::apply_patch(base, &patch).unwrap(), b"hello there");
+        assert!(matches!(
+            DiffEngine::apply_patch(b"jello world", &patch),
+            Err(PatchError::BaseHashMismatch { .. }),
+        ));
+
+        let mut tampered = patch.clone();
+        tampered.operations[0].2[0] ^= 1;
+        assert!(matches!(
+            DiffEngine::apply_patch(base, &tampered),
+            Err(PatchError::TargetHashMismatch { .. }),
+        ));
+    }
+
+    #[test]
+    fn test_streaming_diff() {
+        let old: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let mut new = old.clone();
+        new[10..20].fill(0xAA);
+        new[70_000] ^= 0xFF;
+        new.extend_from_slice(b"tail");
+
+        let diff = DiffEngine::compute_streaming_diff(&old[..], &new[..], 1024).unwrap();
+        assert_eq!(diff.old_len, old.len() as u64);
+        assert_eq!(diff.new_len, new.len() as u64);
+        assert_eq!(diff.changed, vec![
+            ChangedRange { offset: 10, len: 10, data: vec![0xAA; 10] },
+            ChangedRange { offset: 70_000, len: 1, data: vec![new[70_000]] },
+            ChangedRange { offset: 100_000, len: 4, data: b"tail".to_vec() },
+        ]);
+        assert_eq!(DiffEngine::apply_patch(&old, &diff.into_patch()).unwrap(), new);
+
+        let same = DiffEngine::compute_streaming_diff(&old[..], &old[..], 1024).unwrap();
+        assert!(same.is_identical());
+
+        // Shrinking past a batch boundary still reports the dropped tail
+        let batch_len = 1024 * rayon::current_num_threads() * SEGMENTS_PER_THREAD;
+        let longer: Vec<u8> = (0..batch_len as u32 * 2 + 500).map(|i| (i % 251) as u8).collect();
+        let truncated = &longer[..batch_len / 2];
+        let shrunk = DiffEngine::compute_streaming_diff(&longer[..], truncated, 1024).unwrap();
+        assert!(!shrunk.is_identical());
+        assert_eq!(shrunk.new_len, truncated.len() as u64);
+        assert_eq!(shrunk.changed, vec![ChangedRange {
+            offset: truncated.len() as u64,
+            len: (longer.len() - truncated.len()) as u64,
+            data: Vec::new(),
+        }]);
+        assert_eq!(shrunk.changed_bytes(), (longer.len() - truncated.len()) as u64);
+        assert_eq!(DiffEngine::apply_patch(&longer, &shrunk.into_patch()).unwrap(), truncated);
+
+        // A change running into a dropped tail becomes one replacement
+        let mut edited = truncated.to_vec();
+        *edited.last_mut().unwrap() ^= 0xFF;
+        let patch = DiffEngine::compute_streaming_diff(&longer[..], &edited[..], 1024).unwrap().into_patch();
+        assert_eq!(patch.operations.len(), 1);
+        assert_eq!(DiffEngine::apply_patch(&longer, &patch).unwrap(), edited);
+        assert!(DiffEngine::apply_patch(&edited, &patch).is_err());
+    }
+}